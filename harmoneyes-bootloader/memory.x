@@ -0,0 +1,36 @@
+MEMORY {
+    /*
+        Note: The units of `K` are Kibibytes (KiB) where 1 KiB = 1024 bytes
+
+        The bootloader sits between the firmware update space and the
+        settings, and the MBR that comes with the S140 softdevice jumps to it
+        instead of the softdevice because its address is written to the UICR.
+        This has to match the layout in `harmoneyes-controller/memory.x`.
+    */
+    ACTIVE : ORIGIN = 0x00000000 + 156K, LENGTH = 408K
+    DFU : ORIGIN = 0x00000000 + 564K, LENGTH = 412K
+    FLASH : ORIGIN = 0x00000000 + 976K, LENGTH = 28K
+    BOOTLOADER_STATE : ORIGIN = 0x00000000 + 1004K, LENGTH = 4K
+    /*
+        The MBR keeps its own state in the first 8 bytes of RAM, and the
+        firmware keeps its crash reports in the last 2K, which the bootloader's
+        stack mustn't run over on the way through.
+    */
+    RAM : ORIGIN = 0x20000000 + 8, LENGTH = 256K - 8 - 2K
+    UICR_BOOTLOADER_ADDRESS : ORIGIN = 0x10001014, LENGTH = 4
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
+
+__bootloader_active_start = ORIGIN(ACTIVE);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE);
+
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
+
+SECTIONS {
+    .uicr_bootloader_address : {
+        LONG(ORIGIN(FLASH))
+    } > UICR_BOOTLOADER_ADDRESS
+}
@@ -0,0 +1,56 @@
+//! The bootloader for the Harmoneyes controller.
+//!
+//! On every boot it checks whether the controller firmware has left an update waiting in the `DFU` partition and
+//! swaps it with the running image if so, or swaps the old image back if the new one never confirmed itself. Then it
+//! starts the watchdog, which the firmware has to keep feeding, and jumps to the firmware through the softdevice.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_nrf::{BootLoader, BootLoaderConfig, WatchdogFlash};
+use embassy_nrf::{nvmc::Nvmc, wdt::{self, HaltConfig, SleepConfig}};
+use embassy_sync::blocking_mutex::Mutex;
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_nrf::init(Default::default());
+
+    // These have to match `watchdog_config` in the controller's `dfu.rs`
+    let mut wdt_config = wdt::Config::default();
+    // The watchdog runs off the 32.768 kHz low frequency clock
+    wdt_config.timeout_ticks = 32768 * 5;
+    wdt_config.action_during_sleep = SleepConfig::RUN;
+    wdt_config.action_during_debug_halt = HaltConfig::PAUSE;
+
+    // Swapping a whole image takes longer than the watchdog timeout, so the flash feeds it between operations
+    let flash = WatchdogFlash::start(Nvmc::new(p.NVMC), p.WDT, wdt_config);
+    let flash = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    let bootloader: BootLoader = BootLoader::prepare(config);
+
+    // SAFETY: The active partition holds a complete image, either the one that was flashed or one that was verified
+    // before it was swapped in
+    unsafe { bootloader.load(active_offset) }
+}
+
+/// Any fault in the bootloader is most likely bad flash contents, and resetting gives the swap another go.
+#[unsafe(no_mangle)]
+#[cfg_attr(target_os = "none", unsafe(link_section = ".HardFault.user"))]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
@@ -0,0 +1,651 @@
+//! The non-interactive subcommands, meant to be run from scripts as much as by hand.
+//!
+//! Every command prints human readable text by default, or a single JSON document (one JSON object
+//! per line for commands that stream) when `--json` is given.
+
+use std::{collections::{HashMap, HashSet}, error::Error, path::Path, process, time::Duration};
+
+use clap::ValueEnum;
+use harmoneyes_core::{config::{Key, Value}, haptics::{Motor, Wrist}, link::Presence, power::Mode, protocol::{DeviceInfo, DeviceKind, Request, Response}, telemetry::{Event, Telemetry}};
+use serde::Serialize;
+use serde_json::json;
+use tokio::{io::{AsyncBufReadExt, BufReader}, sync::{mpsc, watch}, time::{sleep, timeout}};
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::{device::{self, Connection, DeviceEntry}, session::{Record, SessionWriter}, update};
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// The options shared by every command.
+pub struct Context {
+    pub json: bool,
+    /// Only talk to the device with this serial number.
+    pub device: Option<String>,
+    /// Talk to the device on this port instead of searching for one.
+    pub port: Option<String>
+}
+
+impl Context {
+    fn print<T: Serialize>(&self, value: &T, human: impl FnOnce()) {
+        if self.json {
+            println!("{}", serde_json::to_string(value).expect("Failed to serialize output"));
+        } else {
+            human();
+        }
+    }
+
+    /// Every connected device that matches `--device`.
+    fn devices(&self) -> Result<Vec<DeviceEntry>> {
+        let devices: Vec<DeviceEntry> = device::find_devices()?
+            .into_iter()
+            .filter(|entry| self.device.as_ref().is_none_or(|serial| *serial == entry.serial))
+            .collect();
+
+        if devices.is_empty() {
+            return Err(match &self.device {
+                Some(serial) => format!("no device with serial number {serial} is connected").into(),
+                None => "no devices are connected".into(),
+            });
+        }
+
+        Ok(devices)
+    }
+
+    /// The protocol ports of every device that matches `--device`, or the one given by `--port`.
+    fn ports(&self) -> Result<Vec<String>> {
+        if let Some(port) = &self.port {
+            return Ok(vec![port.clone()]);
+        }
+
+        Ok(self.devices()?.into_iter().filter_map(|entry| entry.port).collect())
+    }
+
+    /// The protocol port of the one device this command should talk to.
+    fn port(&self) -> Result<String> {
+        let mut ports = self.ports()?;
+
+        match ports.len() {
+            0 => Err("the device's serial port could not be found".into()),
+            1 => Ok(ports.remove(0)),
+            _ => Err("more than one device is connected, choose one with --device or --port".into()),
+        }
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        Ok(Connection::open(&self.port()?)?)
+    }
+}
+
+pub fn done(response: Response) -> Result<()> {
+    match response {
+        Response::Done => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: Response) -> Box<dyn Error> {
+    match response {
+        Response::Failed(failure) => failure.description().into(),
+        response => format!("the device sent an unexpected response: {response:?}").into(),
+    }
+}
+
+pub fn list(ctx: &Context) -> Result<()> {
+    let devices = ctx.devices()?;
+
+    ctx.print(&devices, || {
+        for entry in &devices {
+            println!(
+                "{:<10} {:<16} {:<16} logs: {}",
+                entry.kind.name(),
+                entry.serial,
+                entry.port.as_deref().unwrap_or("-"),
+                entry.logger_port.as_deref().unwrap_or("-")
+            );
+        }
+    });
+
+    Ok(())
+}
+
+async fn info_of(connection: &mut Connection) -> Result<DeviceInfo> {
+    match connection.request(&Request::Info).await? {
+        Response::Info(info) => Ok(info),
+        response => Err(unexpected(response)),
+    }
+}
+
+pub async fn info(ctx: &Context) -> Result<()> {
+    let info = info_of(&mut ctx.connect()?).await?;
+
+    let output = json!({
+        "kind": info.kind.name(),
+        "serial": info.serial.as_str(),
+        "name": info.name.as_str(),
+        "firmware_version": info.firmware_version.as_str(),
+        "uptime_ms": info.uptime_ms,
+        "performer_id": info.performer_id,
+        "section": info.section
+    });
+
+    ctx.print(&output, || {
+        println!("Kind:      {}", info.kind.name());
+        println!("Serial:    {}", info.serial);
+        if !info.name.as_str().is_empty() {
+            println!("Name:      {}", info.name);
+        }
+        if info.performer_id != 0 {
+            println!("Performer: {}", info.performer_id);
+        }
+        if info.section != 0 {
+            println!("Section:   {}", info.section);
+        }
+        println!("Firmware:  {}", info.firmware_version);
+        println!("Uptime:    {:.1} s", info.uptime_ms as f64 / 1000.0);
+    });
+
+    Ok(())
+}
+
+pub async fn logs(ctx: &Context, follow: bool) -> Result<()> {
+    let port = match &ctx.port {
+        Some(port) => port.clone(),
+        None => {
+            let mut ports: Vec<String> = ctx.devices()?.into_iter().filter_map(|entry| entry.logger_port).collect();
+            match ports.len() {
+                0 => return Err("the device's log port could not be found".into()),
+                1 => ports.remove(0),
+                _ => return Err("more than one device is connected, choose one with --device or --port".into()),
+            }
+        }
+    };
+
+    let stream = tokio_serial::new(&port, 115_200).open_native_async()?;
+    let mut lines = BufReader::new(stream).lines();
+
+    loop {
+        // Without --follow, stop once the device has gone quiet
+        let line = if follow {
+            lines.next_line().await?
+        } else {
+            match timeout(Duration::from_secs(1), lines.next_line()).await {
+                Ok(line) => line?,
+                Err(_) => break,
+            }
+        };
+
+        let Some(line) = line else { break };
+
+        ctx.print(&json!({ "line": line }), || println!("{line}"));
+    }
+
+    Ok(())
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::U8(value) => json!(value),
+        Value::U16(value) => json!(value),
+        Value::Name(value) => json!(value.as_str()),
+    }
+}
+
+fn parse_key(name: &str) -> Result<Key> {
+    Key::from_name(name).ok_or_else(|| {
+        let keys: Vec<&str> = Key::ALL.iter().map(|key| key.name()).collect();
+        format!("unknown setting {name}, expected one of: {}", keys.join(", ")).into()
+    })
+}
+
+pub async fn config_get(ctx: &Context, key: Option<&str>) -> Result<()> {
+    let keys = match key {
+        Some(name) => vec![parse_key(name)?],
+        None => Key::ALL.to_vec(),
+    };
+
+    let mut connection = ctx.connect()?;
+    let mut values = Vec::new();
+
+    for key in keys {
+        match connection.request(&Request::ConfigGet { key }).await? {
+            Response::Config { key, value } => values.push((key, value)),
+            response => return Err(unexpected(response)),
+        }
+    }
+
+    let output: serde_json::Map<String, serde_json::Value> = values.iter()
+        .map(|(key, value)| (key.name().to_string(), value_to_json(value)))
+        .collect();
+
+    ctx.print(&output, || {
+        for (key, value) in &values {
+            println!("{:<18} {value}", key.name());
+        }
+    });
+
+    Ok(())
+}
+
+pub async fn config_set(ctx: &Context, key: &str, value: &str) -> Result<()> {
+    let key = parse_key(key)?;
+    let value = key.parse(value).ok_or_else(|| format!("{value} is not a valid value for {}", key.name()))?;
+
+    done(ctx.connect()?.request(&Request::ConfigSet { key, value }).await?)?;
+
+    ctx.print(&json!({ key.name(): value_to_json(&value) }), || println!("{} = {value}", key.name()));
+
+    Ok(())
+}
+
+pub async fn config_reset(ctx: &Context) -> Result<()> {
+    done(ctx.connect()?.request(&Request::ConfigReset).await?)?;
+
+    ctx.print(&json!({ "reset": true }), || println!("Restored the default settings"));
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum MotorChoice {
+    #[default]
+    All,
+    Front,
+    Back,
+    Left,
+    Right
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum WristChoice {
+    #[default]
+    Left,
+    Right
+}
+
+impl WristChoice {
+    fn wrist(self) -> Wrist {
+        match self {
+            WristChoice::Left => Wrist::Left,
+            WristChoice::Right => Wrist::Right,
+        }
+    }
+}
+
+/// Runs the chosen motors in turn on the cuff on `wrist`, which a cuff plugged in directly ignores.
+pub async fn haptic_test(ctx: &Context, wrist: WristChoice, choice: MotorChoice, duration_ms: u64) -> Result<()> {
+    let wrist = wrist.wrist();
+    let motors = match choice {
+        MotorChoice::All => Motor::ALL.to_vec(),
+        MotorChoice::Front => vec![Motor::Front],
+        MotorChoice::Back => vec![Motor::Back],
+        MotorChoice::Left => vec![Motor::Left],
+        MotorChoice::Right => vec![Motor::Right],
+    };
+
+    let mut connection = ctx.connect()?;
+
+    for motor in motors {
+        done(connection.request(&Request::HapticTest { wrist, motor, duration_ms }).await?)?;
+
+        ctx.print(
+            &json!({ "wrist": wrist.name(), "motor": motor.name(), "duration_ms": duration_ms }),
+            || println!("Ran the {} motor on the {} cuff for {duration_ms} ms", motor.name(), wrist.name())
+        );
+
+        // Leave a gap between motors so they can be told apart
+        sleep(Duration::from_millis(duration_ms.saturating_mul(2))).await;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ModeChoice {
+    Active,
+    Idle,
+    Sleep
+}
+
+/// Puts the device into a power mode and, with `band`, has it broadcast the mode to every controller in range.
+/// Mesh messages aren't acknowledged, so there's no telling here which controllers heard it.
+pub async fn power(ctx: &Context, choice: ModeChoice, band: bool) -> Result<()> {
+    let mode = match choice {
+        ModeChoice::Active => Mode::Active,
+        ModeChoice::Idle => Mode::Idle,
+        ModeChoice::Sleep => Mode::Sleep,
+    };
+
+    done(ctx.connect()?.request(&Request::SetPowerMode { mode, band }).await?)?;
+
+    let who = if band { "the band" } else { "the device" };
+    ctx.print(&json!({ "mode": mode.name(), "band": band }), || println!("Put {who} into {} mode", mode.name()));
+
+    Ok(())
+}
+
+/// Fetches every crash report the device kept, most recent first, and with `clear` throws them away afterwards.
+pub async fn crashes(ctx: &Context, clear: bool) -> Result<()> {
+    let mut connection = ctx.connect()?;
+    let mut reports = Vec::new();
+
+    for index in 0..=u8::MAX {
+        match connection.request(&Request::CrashGet { index }).await? {
+            Response::Crash(Some(report)) => reports.push(report),
+            Response::Crash(None) => break,
+            response => return Err(unexpected(response)),
+        }
+    }
+
+    if clear {
+        done(connection.request(&Request::CrashClear).await?)?;
+    }
+
+    let output: Vec<serde_json::Value> = reports.iter()
+        .map(|report| json!({
+            "uptime_ms": report.uptime_ms,
+            "reset_reason": report.reset_reason.to_string(),
+            "message": report.message.as_str(),
+            "lines": report.lines.iter().collect::<Vec<_>>(),
+        }))
+        .collect();
+
+    ctx.print(&output, || {
+        if reports.is_empty() {
+            println!("No crashes recorded");
+        }
+
+        for (i, report) in reports.iter().enumerate() {
+            println!("Crash {} after {} ms up, reset by {}", i + 1, report.uptime_ms, report.reset_reason);
+            println!("  {}", report.message);
+            for line in report.lines.iter() {
+                println!("  | {line}");
+            }
+        }
+
+        if clear {
+            println!("Cleared the crash reports");
+        }
+    });
+
+    Ok(())
+}
+
+/// Streams telemetry from every selected device into a session file until Ctrl-C is pressed or `seconds` elapse. Low
+/// battery alerts are also flagged as they arrive, whether they were raised by a recorded controller or heard from
+/// another performer's over the mesh, and so are performers losing their cuff or getting it back.
+pub async fn record(ctx: &Context, out: &Path, seconds: Option<u64>) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel::<(String, Event)>(256);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut tasks = Vec::new();
+
+    for port in ctx.ports()? {
+        let mut connection = Connection::open(&port)?;
+        let serial = info_of(&mut connection).await?.serial.to_string();
+        done(connection.request(&Request::Stream { enabled: true }).await?)?;
+
+        if !ctx.json {
+            eprintln!("Recording {serial} on {port}");
+        }
+
+        let tx = tx.clone();
+        let mut stop_rx = stop_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = stop_rx.changed() => {
+                        let _ = connection.send(&Request::Stream { enabled: false }).await;
+                        break;
+                    },
+                    received = connection.receive() => match received {
+                        Ok(Response::Telemetry(event)) => {
+                            if tx.send((serial.clone(), event)).await.is_err() {
+                                break;
+                            }
+                        },
+                        Ok(_) => {},
+                        Err(e) => {
+                            eprintln!("Stopped recording {serial}: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    drop(tx);
+
+    let mut writer = SessionWriter::create(out)?;
+    let mut count: u64 = 0;
+    let mut alerts = Alerts::default();
+
+    let stop = async {
+        match seconds {
+            Some(seconds) => sleep(Duration::from_secs(seconds)).await,
+            None => { let _ = tokio::signal::ctrl_c().await; },
+        }
+    };
+    tokio::pin!(stop);
+
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            received = rx.recv() => {
+                let Some((serial, event)) = received else { break };
+
+                if let Some(alert) = alerts.alert(&serial, &event.telemetry) && !ctx.json {
+                    eprintln!("{alert}");
+                }
+
+                let mut frame = [0; Event::MAX_ENCODED_LENGTH];
+                let len = event.encode(&mut frame).map_err(|e| format!("{e:?}"))?;
+                writer.write(&Record::new(&serial, &frame[..len]))?;
+                count += 1;
+            }
+        }
+    }
+
+    let _ = stop_tx.send(true);
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    writer.flush()?;
+
+    ctx.print(&json!({ "session": out, "events": count }), || println!("Recorded {count} events to {}", out.display()));
+
+    Ok(())
+}
+
+/// Works out what to flag while recording.
+#[derive(Default)]
+struct Alerts {
+    /// The presence last heard of each cuff. Every keep alive carries it, so it's only flagged when it changes, and
+    /// cuffs are taken to be there until heard otherwise.
+    cuffs: HashMap<(u16, u8), Presence>,
+    /// The controllers that have heard another with their own address, which is flagged once each.
+    clashes: HashSet<String>
+}
+
+impl Alerts {
+    /// What to flag about `telemetry` heard by `serial`, if anything.
+    fn alert(&mut self, serial: &str, telemetry: &Telemetry) -> Option<String> {
+        match telemetry {
+            Telemetry::BatteryAlert { source, alert } => {
+                let remaining = alert.minutes_remaining.map(|minutes| format!(", about {minutes} minutes left")).unwrap_or_default();
+                Some(format!("Battery {} for controller {source:04x} (heard by {serial}): {}%{remaining}", alert.level.name(), alert.percent))
+            },
+            Telemetry::CuffPresence { source, wrist, presence } => {
+                let changed = self.cuffs.insert((*source, wrist.code()), *presence).unwrap_or(Presence::Present) != *presence;
+                (changed && *presence != Presence::Probing)
+                    .then(|| format!("The {} cuff is {} for controller {source:04x} (heard by {serial})", wrist.name(), presence.name()))
+            },
+            Telemetry::CuffLink { wrist, quality } if quality.is_poor() => {
+                Some(format!("The {} cuff of {serial} missed {} of the last {} keep alives", wrist.name(), quality.lost, quality.sent))
+            },
+            Telemetry::Collision { warning } => Some(format!(
+                "{serial} warned of a collision with {:04x} at {:.2} m, closing at {:.2} m/s",
+                warning.peer,
+                warning.distance_mm as f64 / 1000.0,
+                warning.closing_mm_per_s as f64 / 1000.0
+            )),
+            Telemetry::AddressClash { address } => self.clashes.insert(serial.to_string()).then(|| {
+                format!("{serial} heard another controller ranging with its address {address:04x}, so distances to the two get mixed up")
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum FlashTarget {
+    #[default]
+    Controller,
+    Cuff
+}
+
+/// Flashes a firmware image. Controllers are updated over USB through their bootloader with an image signed by `key`,
+/// or with a debug probe if `probe` is set. Cuffs are restarted into the USB bootloader in their ROM and the image is
+/// copied onto the drive it shows up as, or if `through_controller` is set the signed image is sent to the controller
+/// the cuff is cabled to, which passes it on.
+pub async fn flash(ctx: &Context, image: &Path, target: FlashTarget, key: &Path, probe: bool, through_controller: bool) -> Result<()> {
+    match target {
+        FlashTarget::Controller if through_controller => return Err("only a cuff can be updated through a controller".into()),
+        FlashTarget::Controller if probe => flash_with_probe(image)?,
+        FlashTarget::Controller => flash_over_usb(ctx, image, key, DeviceKind::Controller).await?,
+        FlashTarget::Cuff if through_controller => flash_over_usb(ctx, image, key, DeviceKind::Cuff).await?,
+        FlashTarget::Cuff => flash_cuff(ctx, image).await?,
+    }
+
+    ctx.print(&json!({ "image": image, "flashed": true }), || println!("Flashed {}", image.display()));
+
+    Ok(())
+}
+
+/// Sends a signed image for `target` to the connected controller, which is either updated itself or passes the image
+/// on to its cuff.
+async fn flash_over_usb(ctx: &Context, image_path: &Path, key: &Path, target: DeviceKind) -> Result<()> {
+    let image = update::read_image(image_path)?;
+    let signature = update::sign(key, &image)?;
+
+    let mut connection = ctx.connect()?;
+
+    let info = info_of(&mut connection).await?;
+    if info.kind != DeviceKind::Controller {
+        return Err(format!("{} is a {}, not a controller", info.serial, info.kind.name()).into());
+    }
+
+    if !ctx.json {
+        match target {
+            DeviceKind::Controller => eprintln!("Updating {} from firmware {}", info.serial, info.firmware_version),
+            DeviceKind::Cuff => eprintln!("Updating the cuff cabled to {}", info.serial),
+        }
+    }
+
+    let mut last_percent = None;
+    update::upload(&mut connection, target, &image, signature, |sent| {
+        let percent = sent * 100 / image.len();
+        if !ctx.json && last_percent != Some(percent) {
+            eprint!("\rSent {percent:>3}%");
+            last_percent = Some(percent);
+        }
+    }).await?;
+
+    if !ctx.json {
+        match target {
+            DeviceKind::Controller => eprintln!("\rThe signature was accepted, {} is restarting into the new firmware", info.serial),
+            DeviceKind::Cuff => eprintln!("\rThe signature was accepted, the cuff is restarting into the new firmware"),
+        }
+        eprintln!("It will go back to the old firmware if the new one doesn't start properly");
+    }
+
+    Ok(())
+}
+
+async fn flash_cuff(ctx: &Context, image: &Path) -> Result<()> {
+    let uf2 = update::is_uf2(image)?;
+
+    // A cuff that is already in its bootloader doesn't show up as a serial port, so there's nothing to restart
+    match cuff_port(ctx)? {
+        Some(port) => {
+            let mut connection = Connection::open(&port)?;
+            let info = info_of(&mut connection).await?;
+
+            if !ctx.json {
+                eprintln!("Restarting {} into its USB bootloader", info.serial);
+            }
+            done(connection.request(&Request::RebootToBootloader).await?)?;
+        },
+        None if !ctx.json => eprintln!("No running cuff was found, looking for one that is already in its bootloader"),
+        None => {},
+    }
+
+    let drive = update::wait_for_uf2_drive().await?;
+
+    if uf2 {
+        update::copy_uf2(image, &drive)?;
+        return Ok(());
+    }
+
+    // elf2uf2-rs finds the drive itself
+    let status = process::Command::new("elf2uf2-rs")
+        .arg("-d")
+        .arg(image)
+        .status()
+        .map_err(|e| format!("failed to run elf2uf2-rs: {e}"))?;
+    if !status.success() {
+        return Err(format!("flashing failed ({status})").into());
+    }
+
+    Ok(())
+}
+
+/// The protocol port of the cuff to flash, found by its USB vendor and product IDs, or the one given by `--port`.
+fn cuff_port(ctx: &Context) -> Result<Option<String>> {
+    if let Some(port) = &ctx.port {
+        return Ok(Some(port.clone()));
+    }
+
+    let mut ports: Vec<String> = device::find_devices()?
+        .into_iter()
+        .filter(|entry| entry.kind == DeviceKind::Cuff)
+        .filter(|entry| ctx.device.as_ref().is_none_or(|serial| *serial == entry.serial))
+        .filter_map(|entry| entry.port)
+        .collect();
+
+    match ports.len() {
+        0 => Ok(None),
+        1 => Ok(Some(ports.remove(0))),
+        _ => Err("more than one cuff is connected, choose one with --device or --port".into()),
+    }
+}
+
+/// Flashes a controller with a debug probe.
+fn flash_with_probe(image: &Path) -> Result<()> {
+    let status = process::Command::new("probe-rs")
+        .args(["download", "--verify", "--chip", "nRF52840_xxAA"])
+        .arg(image)
+        .status()
+        .and_then(|status| match status.success() {
+            true => process::Command::new("probe-rs").args(["reset", "--chip", "nRF52840_xxAA"]).status(),
+            false => Ok(status),
+        })
+        .map_err(|e| format!("failed to run probe-rs: {e}"))?;
+
+    if !status.success() {
+        return Err(format!("flashing failed ({status})").into());
+    }
+
+    Ok(())
+}
+
+/// Makes a key pair for signing controller firmware updates.
+pub fn keygen(ctx: &Context, stem: &Path) -> Result<()> {
+    let (key, public) = update::generate_key(stem)?;
+
+    ctx.print(&json!({ "key": key, "public_key": public }), || {
+        println!("Wrote the private key to {} and the public key to {}", key.display(), public.display());
+        println!("Keep the private key secret, and build the controller firmware with the public key (see its README)");
+    });
+
+    Ok(())
+}
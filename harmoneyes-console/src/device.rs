@@ -0,0 +1,138 @@
+//! Finding Harmoneyes devices and talking to them over their USB serial ports.
+//!
+//! Every device exposes two serial ports: one that carries its logs as plain text and one that
+//! carries the framed console protocol from `harmoneyes_core::protocol`.
+
+use std::{collections::{BTreeMap, VecDeque}, io, time::Duration};
+
+use harmoneyes_core::{constants, framing::{self, Accumulator}, protocol::{DeviceKind, Request, Response, MAX_FRAME_LENGTH, MAX_MESSAGE_LENGTH}};
+use serde::Serialize;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, time::timeout};
+use tokio_serial::{SerialPortBuilderExt, SerialPortType, SerialStream};
+
+/// How long to wait for a device to answer a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceEntry {
+    #[serde(serialize_with = "serialize_kind")]
+    pub kind: DeviceKind,
+    pub serial: String,
+    /// The port carrying the console protocol.
+    pub port: Option<String>,
+    /// The port carrying the device's logs.
+    pub logger_port: Option<String>
+}
+
+fn serialize_kind<S: serde::Serializer>(kind: &DeviceKind, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(kind.name())
+}
+
+/// Lists every connected device by looking for serial ports with the Harmoneyes USB vendor and product IDs.
+pub fn find_devices() -> io::Result<Vec<DeviceEntry>> {
+    let mut devices: BTreeMap<(u16, String), DeviceEntry> = BTreeMap::new();
+
+    for port in tokio_serial::available_ports()? {
+        let SerialPortType::UsbPort(info) = &port.port_type else {
+            continue;
+        };
+
+        if info.vid != constants::USB_VENDOR_ID {
+            continue;
+        }
+
+        let kind = match info.pid {
+            constants::controller::USB_PRODUCT_ID => DeviceKind::Controller,
+            constants::cuff::USB_PRODUCT_ID => DeviceKind::Cuff,
+            _ => continue
+        };
+
+        let serial = info.serial_number.clone().unwrap_or_default();
+
+        let entry = devices.entry((info.pid, serial.clone())).or_insert_with(|| DeviceEntry {
+            kind,
+            serial,
+            port: None,
+            logger_port: None
+        });
+
+        // Depending on the platform the interface is either the communication interface or the data interface that
+        // comes right after it, so round down to the communication interface.
+        match info.interface.map(|interface| interface & !1) {
+            Some(constants::USB_LOGGER_INTERFACE) => entry.logger_port = Some(port.port_name),
+            Some(constants::USB_SERIAL_INTERFACE) => entry.port = Some(port.port_name),
+            _ => {}
+        }
+    }
+
+    Ok(devices.into_values().collect())
+}
+
+/// An open connection to the console protocol port of a device.
+pub struct Connection {
+    stream: SerialStream,
+    accumulator: Box<Accumulator<MAX_FRAME_LENGTH>>,
+    pending: VecDeque<u8>
+}
+
+impl Connection {
+    pub fn open(port: &str) -> io::Result<Self> {
+        // The baud rate doesn't matter for USB serial ports
+        let stream = tokio_serial::new(port, 115_200).open_native_async()?;
+
+        Ok(Self { stream, accumulator: Box::new(Accumulator::new()), pending: VecDeque::new() })
+    }
+
+    pub async fn send(&mut self, request: &Request) -> io::Result<()> {
+        let mut message = [0; MAX_MESSAGE_LENGTH];
+        let mut frame = [0; MAX_FRAME_LENGTH];
+
+        let len = request.encode(&mut message).map_err(invalid_data)?;
+        let len = framing::encode(&message[..len], &mut frame).map_err(invalid_data)?;
+
+        self.stream.write_all(&frame[..len]).await
+    }
+
+    /// Waits for the next message from the device, which may be telemetry.
+    pub async fn receive(&mut self) -> io::Result<Response> {
+        loop {
+            while let Some(byte) = self.pending.pop_front() {
+                match self.accumulator.push(byte) {
+                    None => {},
+                    Some(Ok(message)) => return Response::decode(message).map_err(invalid_data),
+                    Some(Err(e)) => return Err(invalid_data(e)),
+                }
+            }
+
+            let mut buf = [0; 256];
+            let n = self.stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the device disconnected"));
+            }
+            self.pending.extend(&buf[..n]);
+        }
+    }
+
+    /// Sends a request and waits for its answer, skipping over any telemetry that arrives in the meantime.
+    pub async fn request(&mut self, request: &Request) -> io::Result<Response> {
+        self.request_within(request, RESPONSE_TIMEOUT).await
+    }
+
+    /// Like `request`, for requests that take the device longer than usual to carry out.
+    pub async fn request_within(&mut self, request: &Request, limit: Duration) -> io::Result<Response> {
+        self.send(request).await?;
+
+        timeout(limit, async {
+            loop {
+                match self.receive().await? {
+                    Response::Telemetry(_) => continue,
+                    response => return Ok(response),
+                }
+            }
+        }).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the device did not respond"))?
+    }
+}
+
+fn invalid_data(e: harmoneyes_core::codec::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}"))
+}
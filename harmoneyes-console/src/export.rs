@@ -0,0 +1,400 @@
+//! Converts a recorded session into one table per telemetry stream so that it can be loaded into
+//! a notebook or spreadsheet.
+//!
+//! Every table starts with the same three columns: `timestamp_us` (microseconds since the Unix
+//! epoch at which the console received the event), `uptime_ms` (milliseconds since the device
+//! booted) and `device` (the serial number of the device that sent the event).
+
+use std::{fs, io, path::{Path, PathBuf}};
+
+use clap::ValueEnum;
+use harmoneyes_core::{ranging, telemetry::Telemetry};
+
+use crate::session::{Record, SessionReader};
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Format {
+    #[default]
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            #[cfg(feature = "parquet")]
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+enum Column {
+    UInt64(Vec<u64>),
+    Float64(Vec<f64>),
+    Utf8(Vec<String>)
+}
+
+impl Column {
+    fn len(&self) -> usize {
+        match self {
+            Column::UInt64(values) => values.len(),
+            Column::Float64(values) => values.len(),
+            Column::Utf8(values) => values.len(),
+        }
+    }
+
+    fn cell(&self, row: usize) -> String {
+        match self {
+            Column::UInt64(values) => values[row].to_string(),
+            Column::Float64(values) => values[row].to_string(),
+            Column::Utf8(values) => values[row].clone(),
+        }
+    }
+}
+
+struct Table {
+    name: &'static str,
+    columns: Vec<(&'static str, Column)>
+}
+
+impl Table {
+    fn new(name: &'static str, columns: Vec<(&'static str, Column)>) -> Self {
+        let mut all = vec![
+            ("timestamp_us", Column::UInt64(Vec::new())),
+            ("uptime_ms", Column::UInt64(Vec::new())),
+            ("device", Column::Utf8(Vec::new())),
+        ];
+        all.extend(columns);
+
+        Self { name, columns: all }
+    }
+
+    fn rows(&self) -> usize {
+        self.columns[0].1.len()
+    }
+
+    /// Appends one row. The values must line up with the columns the table was created with.
+    fn push(&mut self, record: &Record, uptime_ms: u64, values: Vec<Value>) {
+        let values = [Value::UInt64(record.host_time_us), Value::UInt64(uptime_ms), Value::Utf8(record.device.clone())]
+            .into_iter()
+            .chain(values);
+
+        for ((_, column), value) in self.columns.iter_mut().zip(values) {
+            match (column, value) {
+                (Column::UInt64(values), Value::UInt64(value)) => values.push(value),
+                (Column::Float64(values), Value::Float64(value)) => values.push(value),
+                (Column::Utf8(values), Value::Utf8(value)) => values.push(value),
+                _ => unreachable!("Value does not match the column type")
+            }
+        }
+    }
+
+    fn write_csv(&self, path: &Path) -> io::Result<()> {
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(self.columns.iter().map(|(name, _)| *name))?;
+        for row in 0..self.rows() {
+            writer.write_record(self.columns.iter().map(|(_, column)| column.cell(row)))?;
+        }
+
+        writer.flush()
+    }
+
+    #[cfg(feature = "parquet")]
+    fn write_parquet(&self, path: &Path) -> io::Result<()> {
+        use std::sync::Arc;
+
+        use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+        use arrow_schema::{DataType, Field, Schema};
+        use parquet::arrow::ArrowWriter;
+
+        let fields: Vec<Field> = self.columns.iter().map(|(name, column)| {
+            let data_type = match column {
+                Column::UInt64(_) => DataType::UInt64,
+                Column::Float64(_) => DataType::Float64,
+                Column::Utf8(_) => DataType::Utf8,
+            };
+            Field::new(*name, data_type, false)
+        }).collect();
+
+        let arrays: Vec<ArrayRef> = self.columns.iter().map(|(_, column)| -> ArrayRef {
+            match column {
+                Column::UInt64(values) => Arc::new(UInt64Array::from(values.clone())),
+                Column::Float64(values) => Arc::new(Float64Array::from(values.clone())),
+                Column::Utf8(values) => Arc::new(StringArray::from(values.clone())),
+            }
+        }).collect();
+
+        let schema = Arc::new(Schema::new(fields));
+        let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(io::Error::other)?;
+
+        let mut writer = ArrowWriter::try_new(fs::File::create(path)?, schema, None).map_err(io::Error::other)?;
+        writer.write(&batch).map_err(io::Error::other)?;
+        writer.close().map_err(io::Error::other)?;
+
+        Ok(())
+    }
+}
+
+enum Value {
+    UInt64(u64),
+    Float64(f64),
+    Utf8(String)
+}
+
+/// What was written by [`export`].
+pub struct Summary {
+    pub tables: Vec<(PathBuf, usize)>,
+    /// The number of records that couldn't be decoded, usually because they were recorded from
+    /// newer firmware than this console understands.
+    pub skipped: usize
+}
+
+/// Reads the session at `session` and writes `distances`, `battery`, `mesh`, `haptics`, `battery_alerts`,
+/// `cuff_batteries`, `uwb_faults`, `cuff_presence`, `cuff_links`, `collisions` and `address_clashes` tables into `out_dir`.
+pub fn export(session: &Path, out_dir: &Path, format: Format) -> io::Result<Summary> {
+    let mut distances = Table::new("distances", vec![
+        ("peer", Column::UInt64(Vec::new())),
+        ("tof_ticks", Column::UInt64(Vec::new())),
+        ("distance_m", Column::Float64(Vec::new())),
+    ]);
+    let mut battery = Table::new("battery", vec![
+        ("millivolts", Column::UInt64(Vec::new())),
+        ("percent", Column::UInt64(Vec::new())),
+        ("charge", Column::Utf8(Vec::new())),
+    ]);
+    let mut mesh = Table::new("mesh", vec![
+        ("direction", Column::Utf8(Vec::new())),
+        ("length", Column::UInt64(Vec::new())),
+        ("payload", Column::Utf8(Vec::new())),
+    ]);
+    let mut haptics = Table::new("haptics", vec![
+        ("wrist", Column::Utf8(Vec::new())),
+        ("motor", Column::Utf8(Vec::new())),
+        ("duration_ms", Column::UInt64(Vec::new())),
+    ]);
+    let mut battery_alerts = Table::new("battery_alerts", vec![
+        ("source", Column::UInt64(Vec::new())),
+        ("level", Column::Utf8(Vec::new())),
+        ("percent", Column::UInt64(Vec::new())),
+        // Empty when the device couldn't tell yet
+        ("minutes_remaining", Column::Utf8(Vec::new())),
+    ]);
+    let mut cuff_batteries = Table::new("cuff_batteries", vec![
+        ("source", Column::UInt64(Vec::new())),
+        ("wrist", Column::Utf8(Vec::new())),
+        ("millivolts", Column::UInt64(Vec::new())),
+        ("percent", Column::UInt64(Vec::new())),
+        // Empty when the cuff couldn't tell yet
+        ("minutes_remaining", Column::Utf8(Vec::new())),
+    ]);
+    let mut uwb_faults = Table::new("uwb_faults", vec![
+        ("errors", Column::UInt64(Vec::new())),
+        ("retries", Column::UInt64(Vec::new())),
+        ("reconfigures", Column::UInt64(Vec::new())),
+        ("resets", Column::UInt64(Vec::new())),
+        ("back_offs", Column::UInt64(Vec::new())),
+    ]);
+    let mut cuff_presence = Table::new("cuff_presence", vec![
+        ("source", Column::UInt64(Vec::new())),
+        ("wrist", Column::Utf8(Vec::new())),
+        ("presence", Column::Utf8(Vec::new())),
+    ]);
+    let mut cuff_links = Table::new("cuff_links", vec![
+        ("wrist", Column::Utf8(Vec::new())),
+        ("sent", Column::UInt64(Vec::new())),
+        ("lost", Column::UInt64(Vec::new())),
+        // Empty when none of the keep alives were answered
+        ("latency_ms", Column::Utf8(Vec::new())),
+    ]);
+    let mut collisions = Table::new("collisions", vec![
+        ("peer", Column::UInt64(Vec::new())),
+        ("distance_m", Column::Float64(Vec::new())),
+        ("closing_m_s", Column::Float64(Vec::new())),
+    ]);
+    let mut address_clashes = Table::new("address_clashes", vec![
+        ("address", Column::UInt64(Vec::new())),
+    ]);
+
+    let mut skipped = 0;
+
+    for record in SessionReader::open(session)? {
+        let record = record?;
+
+        let Ok(event) = record.event() else {
+            skipped += 1;
+            continue;
+        };
+
+        match event.telemetry {
+            Telemetry::Distance { peer, tof } => distances.push(&record, event.uptime_ms, vec![
+                Value::UInt64(peer as u64),
+                Value::UInt64(tof),
+                Value::Float64(ranging::tof_to_meters(tof)),
+            ]),
+            Telemetry::Battery { millivolts, percent, charge } => battery.push(&record, event.uptime_ms, vec![
+                Value::UInt64(millivolts as u64),
+                Value::UInt64(percent as u64),
+                Value::Utf8(charge.name().to_string()),
+            ]),
+            Telemetry::Mesh { sent, length, payload } => mesh.push(&record, event.uptime_ms, vec![
+                Value::Utf8(if sent { "sent" } else { "received" }.to_string()),
+                Value::UInt64(length as u64),
+                Value::Utf8(payload[..length as usize].iter().map(|byte| format!("{byte:02x}")).collect()),
+            ]),
+            Telemetry::Haptic { wrist, motor, duration_ms } => haptics.push(&record, event.uptime_ms, vec![
+                Value::Utf8(wrist.name().to_string()),
+                Value::Utf8(motor.name().to_string()),
+                Value::UInt64(duration_ms),
+            ]),
+            Telemetry::BatteryAlert { source, alert } => battery_alerts.push(&record, event.uptime_ms, vec![
+                Value::UInt64(source as u64),
+                Value::Utf8(alert.level.name().to_string()),
+                Value::UInt64(alert.percent as u64),
+                Value::Utf8(alert.minutes_remaining.map(|minutes| minutes.to_string()).unwrap_or_default()),
+            ]),
+            Telemetry::CuffBattery { source, wrist, report } => cuff_batteries.push(&record, event.uptime_ms, vec![
+                Value::UInt64(source as u64),
+                Value::Utf8(wrist.name().to_string()),
+                Value::UInt64(report.millivolts as u64),
+                Value::UInt64(report.percent as u64),
+                Value::Utf8(report.minutes_remaining.map(|minutes| minutes.to_string()).unwrap_or_default()),
+            ]),
+            Telemetry::UwbFaults { faults } => uwb_faults.push(&record, event.uptime_ms, vec![
+                Value::UInt64(faults.errors as u64),
+                Value::UInt64(faults.retries as u64),
+                Value::UInt64(faults.reconfigures as u64),
+                Value::UInt64(faults.resets as u64),
+                Value::UInt64(faults.back_offs as u64),
+            ]),
+            Telemetry::CuffPresence { source, wrist, presence } => cuff_presence.push(&record, event.uptime_ms, vec![
+                Value::UInt64(source as u64),
+                Value::Utf8(wrist.name().to_string()),
+                Value::Utf8(presence.name().to_string()),
+            ]),
+            Telemetry::CuffLink { wrist, quality } => cuff_links.push(&record, event.uptime_ms, vec![
+                Value::Utf8(wrist.name().to_string()),
+                Value::UInt64(quality.sent as u64),
+                Value::UInt64(quality.lost as u64),
+                Value::Utf8(quality.latency_ms.map(|latency_ms| latency_ms.to_string()).unwrap_or_default()),
+            ]),
+            Telemetry::Collision { warning } => collisions.push(&record, event.uptime_ms, vec![
+                Value::UInt64(warning.peer as u64),
+                Value::Float64(warning.distance_mm as f64 / 1000.0),
+                Value::Float64(warning.closing_mm_per_s as f64 / 1000.0),
+            ]),
+            Telemetry::AddressClash { address } => address_clashes.push(&record, event.uptime_ms, vec![
+                Value::UInt64(address as u64),
+            ]),
+        }
+    }
+
+    fs::create_dir_all(out_dir)?;
+
+    let mut tables = Vec::new();
+
+    for table in [distances, battery, mesh, haptics, battery_alerts, cuff_batteries, uwb_faults, cuff_presence, cuff_links, collisions, address_clashes] {
+        let path = out_dir.join(table.name).with_extension(format.extension());
+
+        match format {
+            Format::Csv => table.write_csv(&path)?,
+            #[cfg(feature = "parquet")]
+            Format::Parquet => table.write_parquet(&path)?,
+        }
+
+        tables.push((path, table.rows()));
+    }
+
+    Ok(Summary { tables, skipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use harmoneyes_core::{battery::ChargeState, proximity::Warning, telemetry::Event};
+
+    use super::*;
+    use crate::session::SessionWriter;
+
+    /// Reads back an exported CSV table as its header and rows.
+    fn read_csv(path: &Path) -> (Vec<String>, Vec<Vec<String>>) {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let header = reader.headers().unwrap().iter().map(String::from).collect();
+        let rows = reader.records().map(|row| row.unwrap().iter().map(String::from).collect()).collect();
+
+        (header, rows)
+    }
+
+    #[test]
+    fn exports_each_stream_of_a_session_to_its_own_table() {
+        // Removed when it goes out of scope, even if an assertion fails first
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let session = dir.join("session.hses");
+
+        let events = [
+            ("HEC0000000A1B2C", Event { uptime_ms: 1000, telemetry: Telemetry::Distance { peer: 0x7424, tof: 100 } }),
+            ("HEC0000000A1B2C", Event {
+                uptime_ms: 2000,
+                telemetry: Telemetry::Battery { millivolts: 3900, percent: 76, charge: ChargeState::Discharging }
+            }),
+            ("HEC0000000D3E4F", Event {
+                uptime_ms: 3000,
+                telemetry: Telemetry::Collision { warning: Warning { peer: 0x1d0c, distance_mm: 300, closing_mm_per_s: -250 } }
+            }),
+        ];
+
+        let mut writer = SessionWriter::create(&session).unwrap();
+        for (device, event) in &events {
+            let mut frame = [0; Event::MAX_ENCODED_LENGTH];
+            let len = event.encode(&mut frame).unwrap();
+            writer.write(&Record::new(device, &frame[..len])).unwrap();
+        }
+        // An event from newer firmware, with a tag this console doesn't know
+        let mut unknown = 4000u64.to_le_bytes().to_vec();
+        unknown.push(0xFF);
+        writer.write(&Record::new("HEC0000000A1B2C", &unknown)).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let summary = export(&session, dir, Format::Csv).unwrap();
+        assert_eq!(summary.skipped, 1);
+
+        let rows: Vec<_> = summary.tables.iter()
+            .map(|(path, rows)| (path.file_name().unwrap().to_str().unwrap().to_string(), *rows))
+            .collect();
+        assert_eq!(rows, [
+            ("distances.csv".to_string(), 1),
+            ("battery.csv".to_string(), 1),
+            ("mesh.csv".to_string(), 0),
+            ("haptics.csv".to_string(), 0),
+            ("battery_alerts.csv".to_string(), 0),
+            ("cuff_batteries.csv".to_string(), 0),
+            ("uwb_faults.csv".to_string(), 0),
+            ("cuff_presence.csv".to_string(), 0),
+            ("cuff_links.csv".to_string(), 0),
+            ("collisions.csv".to_string(), 1),
+            ("address_clashes.csv".to_string(), 0),
+        ]);
+
+        let (header, rows) = read_csv(&dir.join("distances.csv"));
+        assert_eq!(header, ["timestamp_us", "uptime_ms", "device", "peer", "tof_ticks", "distance_m"]);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][1..], ["1000", "HEC0000000A1B2C", "29732", "100", &ranging::tof_to_meters(100).to_string()]);
+        assert!(rows[0][0].parse::<u64>().unwrap() > 0);
+
+        let (header, rows) = read_csv(&dir.join("battery.csv"));
+        assert_eq!(header, ["timestamp_us", "uptime_ms", "device", "millivolts", "percent", "charge"]);
+        assert_eq!(rows[0][1..], ["2000", "HEC0000000A1B2C", "3900", "76", ChargeState::Discharging.name()]);
+
+        let (header, rows) = read_csv(&dir.join("collisions.csv"));
+        assert_eq!(header, ["timestamp_us", "uptime_ms", "device", "peer", "distance_m", "closing_m_s"]);
+        assert_eq!(rows[0][1..], ["3000", "HEC0000000D3E4F", "7436", "0.3", "-0.25"]);
+
+        // Streams that weren't recorded still get a table, with just the columns
+        let (header, rows) = read_csv(&dir.join("cuff_links.csv"));
+        assert_eq!(header, ["timestamp_us", "uptime_ms", "device", "wrist", "sent", "lost", "latency_ms"]);
+        assert!(rows.is_empty());
+    }
+}
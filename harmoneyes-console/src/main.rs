@@ -1,30 +1,210 @@
-use std::{cell::OnceCell, collections::HashMap, pin::pin, time::Duration};
+use std::{cell::OnceCell, collections::BTreeMap, path::PathBuf, pin::pin, process::ExitCode, time::Duration};
 
+use clap::{Parser, Subcommand};
 use crossterm::event::{EventStream, KeyCode, KeyModifiers};
-use futures::{future::{join, select}, stream::FuturesUnordered, StreamExt};
-use ratatui::{buffer::Buffer, layout::Rect, text::{Line, Text}, widgets::{Block, Paragraph, Widget}, DefaultTerminal, Frame};
+use device::DeviceEntry;
+use futures::{future::{join, select}, StreamExt};
+use ratatui::{buffer::Buffer, layout::Rect, text::Line, widgets::{Block, Paragraph, Widget}, DefaultTerminal};
 use tokio::{sync::Mutex, time::interval};
-use tokio_serial::{SerialPort, SerialPortInfo, SerialPortType};
-use tokio_util::bytes::BufMut;
 
+mod cli;
+mod device;
+mod export;
+mod session;
+mod update;
+
+/// The Harmoneyes console. Run without a subcommand to open the interactive dashboard.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Print machine readable JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+    /// Only talk to the device with this serial number
+    #[arg(short, long, global = true)]
+    device: Option<String>,
+    /// Talk to the device on this serial port instead of searching for one
+    #[arg(short, long, global = true)]
+    port: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the connected devices
+    List,
+    /// Show a device's serial number, firmware version and uptime
+    Info,
+    /// Print a device's logs
+    Logs {
+        /// Keep printing logs as they arrive instead of stopping once the device goes quiet
+        #[arg(short, long)]
+        follow: bool
+    },
+    /// Read and change a device's settings
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand
+    },
+    /// Drive the haptic motors
+    Haptic {
+        #[command(subcommand)]
+        command: HapticCommand
+    },
+    /// Put the band into a power mode, such as to sleep between reps and wake for the next one
+    Power {
+        #[arg(value_enum)]
+        mode: cli::ModeChoice,
+        /// Only change the device this is plugged into instead of broadcasting to every controller in range
+        #[arg(long)]
+        local: bool
+    },
+    /// Show why a controller last crashed, from the reports it kept across the reset
+    Crashes {
+        /// Throw the reports away once they've been shown
+        #[arg(long)]
+        clear: bool
+    },
+    /// Record telemetry from the connected devices into a session file
+    Record {
+        /// The session file to write [default: session.hses]
+        out: Option<PathBuf>,
+        /// Stop after this many seconds instead of waiting for Ctrl-C
+        #[arg(short, long)]
+        seconds: Option<u64>
+    },
+    /// Flash a firmware image onto a device
+    Flash {
+        /// The firmware image: a raw binary for devices updated through a controller, an ELF file for a debug probe, or
+        /// an ELF or UF2 file for cuffs updated over their own USB port
+        image: PathBuf,
+        #[arg(short, long, value_enum, default_value_t)]
+        target: cli::FlashTarget,
+        /// The private key to sign updates sent through a controller with
+        #[arg(short, long, default_value = "update.key")]
+        key: PathBuf,
+        /// Flash a controller with a debug probe instead of over USB
+        #[arg(long)]
+        probe: bool,
+        /// Send a cuff update through the controller it's cabled to instead of over the cuff's own USB port
+        #[arg(long)]
+        through_controller: bool
+    },
+    /// Make a key pair for signing controller firmware updates
+    Keygen {
+        /// Where to write the keys, without an extension
+        #[arg(default_value = "update")]
+        out: PathBuf
+    },
+    /// Convert a recorded session into one table per telemetry stream
+    Export {
+        /// The recorded session file
+        session: PathBuf,
+        /// The directory to write the tables into [default: the session path without its extension]
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: export::Format
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print one setting, or all of them
+    Get {
+        key: Option<String>
+    },
+    /// Change a setting
+    Set {
+        key: String,
+        value: String
+    },
+    /// Restore the default settings
+    Reset
+}
+
+#[derive(Subcommand)]
+enum HapticCommand {
+    /// Run each motor in turn so they can be checked by hand
+    Test {
+        #[arg(short, long, value_enum, default_value_t)]
+        motor: cli::MotorChoice,
+        /// Which cuff to run them on, when the controller has one on each wrist
+        #[arg(short, long, value_enum, default_value_t)]
+        wrist: cli::WristChoice,
+        #[arg(long, default_value_t = 250)]
+        duration_ms: u64
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    App::new().run().await;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let ctx = cli::Context { json: cli.json, device: cli.device, port: cli.port };
+
+    let result = match cli.command {
+        None => {
+            App::new().run().await;
+            Ok(())
+        },
+        Some(Command::List) => cli::list(&ctx),
+        Some(Command::Info) => cli::info(&ctx).await,
+        Some(Command::Logs { follow }) => cli::logs(&ctx, follow).await,
+        Some(Command::Config { command }) => match command {
+            ConfigCommand::Get { key } => cli::config_get(&ctx, key.as_deref()).await,
+            ConfigCommand::Set { key, value } => cli::config_set(&ctx, &key, &value).await,
+            ConfigCommand::Reset => cli::config_reset(&ctx).await,
+        },
+        Some(Command::Haptic { command: HapticCommand::Test { motor, wrist, duration_ms } }) => cli::haptic_test(&ctx, wrist, motor, duration_ms).await,
+        Some(Command::Power { mode, local }) => cli::power(&ctx, mode, !local).await,
+        Some(Command::Crashes { clear }) => cli::crashes(&ctx, clear).await,
+        Some(Command::Record { out, seconds }) => {
+            let out = out.unwrap_or_else(|| PathBuf::from("session").with_extension(session::EXTENSION));
+            cli::record(&ctx, &out, seconds).await
+        },
+        Some(Command::Flash { image, target, key, probe, through_controller }) => {
+            cli::flash(&ctx, &image, target, &key, probe, through_controller).await
+        },
+        Some(Command::Keygen { out }) => cli::keygen(&ctx, &out),
+        Some(Command::Export { session, out, format }) => {
+            let out = out.unwrap_or_else(|| session.with_extension(""));
+
+            match export::export(&session, &out, format) {
+                Ok(summary) => {
+                    for (path, rows) in summary.tables {
+                        println!("{rows:>8} rows  {}", path.display());
+                    }
+                    if summary.skipped > 0 {
+                        eprintln!("Skipped {} records that could not be decoded", summary.skipped);
+                    }
+                    Ok(())
+                },
+                Err(e) => Err(format!("failed to export {}: {e}", session.display()).into())
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
 struct App {
     terminal: OnceCell<Mutex<DefaultTerminal>>,
-    tab: Mutex<usize>,
-    connections: Mutex<HashMap<String, ConnectionHandler>>
+    devices: Mutex<BTreeMap<String, DeviceEntry>>
 }
 
 impl App {
     fn new() -> Self {
         Self {
             terminal: OnceCell::new(),
-            tab: Mutex::new(0),
-            connections: Mutex::new(HashMap::new())
+            devices: Mutex::new(BTreeMap::new())
         }
     }
 
@@ -48,22 +228,14 @@ impl App {
         let mut interval = interval(Duration::from_millis(250));
 
         loop {
-            let ports = tokio_serial::available_ports().expect("");
-
-            let device_ports: Vec<SerialPortInfo> = ports.into_iter().filter(|port| {
-                if let SerialPortType::UsbPort(info) = &port.port_type {
-                    return info.vid == harmoneyes_core::constants::USB_VENDOR_ID && info.pid == harmoneyes_core::constants::controller::USB_PRODUCT_ID;
-                }
-                return false;
-            }).collect();
+            let found = device::find_devices().unwrap_or_default();
 
             // New scope to make clear where the mutex guard is dropped
             {
-                let mut connections = self.connections.lock().await;
-                for port in device_ports {
-                    if !connections.contains_key(&port.port_name) {
-                        connections.insert(port.port_name.clone(), ConnectionHandler::new(port));
-                    }
+                let mut devices = self.devices.lock().await;
+                devices.clear();
+                for entry in found {
+                    devices.insert(entry.serial.clone(), entry);
                 }
             }
 
@@ -89,15 +261,11 @@ impl App {
     async fn make_view(&self) -> AppView {
 
         let connection_keys = {
-            let mut connection_keys = Vec::new();
-
-            let lock = self.connections.lock().await;
-
-            for key in lock.keys() {
-                connection_keys.push(key.clone());
-            }
+            let lock = self.devices.lock().await;
 
-            connection_keys
+            lock.values()
+                .map(|entry| format!("{} {}", entry.kind.name(), entry.serial))
+                .collect()
         };
 
         AppView::new(connection_keys)
@@ -116,28 +284,22 @@ impl App {
 
         let mut events = EventStream::new();
 
-        loop {
-            match events.next().await {
-                Some(res) => match res {
-                    Ok(e) => match e {
-                        Event::FocusGained => {},
-                        Event::FocusLost => {},
-                        Event::Key(key_event) => {
-                            if key_event.is_press() && key_event.modifiers.contains(KeyModifiers::CONTROL) && key_event.code == KeyCode::Char('c') {
-                                break
-                            }
-                            if key_event.code == KeyCode::Left || key_event.code == KeyCode::Char('A') { self.tab_left().await; }
-                            if key_event.code == KeyCode::Right || key_event.code == KeyCode::Char('D') { self.tab_right().await; }
-                        },
-                        Event::Mouse(mouse_event) => {},
-                        Event::Paste(_) => {},
-                        Event::Resize(_, _) => {},
-                    },
-                    Err(e) => {
-                        
+        while let Some(res) = events.next().await {
+            if let Ok(e) = res {
+                match e {
+                    Event::FocusGained => {},
+                    Event::FocusLost => {},
+                    Event::Key(key_event) => {
+                        if key_event.is_press() && key_event.modifiers.contains(KeyModifiers::CONTROL) && key_event.code == KeyCode::Char('c') {
+                            break
+                        }
+                        if key_event.code == KeyCode::Left || key_event.code == KeyCode::Char('A') { self.tab_left().await; }
+                        if key_event.code == KeyCode::Right || key_event.code == KeyCode::Char('D') { self.tab_right().await; }
                     },
-                },
-                None => break,
+                    Event::Mouse(_) => {},
+                    Event::Paste(_) => {},
+                    Event::Resize(_, _) => {},
+                }
             }
         }
     }
@@ -161,7 +323,7 @@ impl Widget for AppView {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let content_block = Block::bordered();
 
-        let lines: Vec<Line> = self.connection_keys.into_iter().map(|k| Line::from(k)).collect();
+        let lines: Vec<Line> = self.connection_keys.into_iter().map(Line::from).collect();
 
         let p = Paragraph::new(lines)
             .block(content_block);
@@ -169,37 +331,3 @@ impl Widget for AppView {
         p.render(area, buf);
     }
 }
-
-
-
-
-
-
-
-
-struct ConnectionHandler {
-    dropped: bool,
-    port: Mutex<Option<Box<dyn SerialPort + 'static>>>
-}
-
-impl ConnectionHandler {
-    pub fn new(port_info: SerialPortInfo) -> Self {
-        match tokio_serial::new(port_info.port_name, 9600).open() {
-            Ok(port) => Self {
-                dropped: false,
-                port: Mutex::new(Some(port))
-            },
-            _ => Self {
-                dropped: true,
-                port: Mutex::new(None)
-            }
-        }
-    }
-
-    pub async fn drive(&self) {
-        if !self.dropped {
-            
-        }
-    }
-}
-
@@ -0,0 +1,122 @@
+//! Recorded sessions are the raw telemetry events received from every connected device, stamped
+//! with the time the console received them.
+//!
+//! The file starts with the magic bytes `HSES` and a format version, followed by one record per
+//! event: the host time in microseconds since the Unix epoch, the device serial number prefixed by
+//! its length, and the encoded event prefixed by its length.
+
+use std::{fs::File, io::{self, BufReader, BufWriter, ErrorKind, Read, Write}, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use harmoneyes_core::{codec, telemetry::Event};
+
+const MAGIC: &[u8; 4] = b"HSES";
+const VERSION: u8 = 1;
+
+pub const EXTENSION: &str = "hses";
+
+pub struct Record {
+    /// Microseconds since the Unix epoch at which the console received the event.
+    pub host_time_us: u64,
+    /// The serial number of the device that sent the event.
+    pub device: String,
+    /// The event exactly as it was received.
+    pub frame: Vec<u8>
+}
+
+impl Record {
+    pub fn new(device: &str, frame: &[u8]) -> Self {
+        let host_time_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+
+        Self { host_time_us, device: device.to_string(), frame: frame.to_vec() }
+    }
+
+    pub fn event(&self) -> Result<Event, codec::Error> {
+        Event::decode(&self.frame)
+    }
+}
+
+pub struct SessionWriter {
+    out: BufWriter<File>
+}
+
+impl SessionWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+
+        Ok(Self { out })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let device = record.device.as_bytes();
+        let device_len = u8::try_from(device.len()).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "device name is too long"))?;
+        let frame_len = u16::try_from(record.frame.len()).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "event is too long"))?;
+
+        self.out.write_all(&record.host_time_us.to_le_bytes())?;
+        self.out.write_all(&[device_len])?;
+        self.out.write_all(device)?;
+        self.out.write_all(&frame_len.to_le_bytes())?;
+        self.out.write_all(&record.frame)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct SessionReader {
+    input: BufReader<File>
+}
+
+impl SessionReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a Harmoneyes session file"));
+        }
+        if header[4] != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("unsupported session version {}", header[4])));
+        }
+
+        Ok(Self { input })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut host_time_us = [0; 8];
+        match self.input.read_exact(&mut host_time_us) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut device_len = [0; 1];
+        self.input.read_exact(&mut device_len)?;
+        let mut device = vec![0; device_len[0] as usize];
+        self.input.read_exact(&mut device)?;
+
+        let mut frame_len = [0; 2];
+        self.input.read_exact(&mut frame_len)?;
+        let mut frame = vec![0; u16::from_le_bytes(frame_len) as usize];
+        self.input.read_exact(&mut frame)?;
+
+        Ok(Some(Record {
+            host_time_us: u64::from_le_bytes(host_time_us),
+            device: String::from_utf8_lossy(&device).into_owned(),
+            frame
+        }))
+    }
+}
+
+impl Iterator for SessionReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
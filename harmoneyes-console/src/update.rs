@@ -0,0 +1,166 @@
+//! Signing firmware images and sending them to a controller's bootloader over the console protocol, and copying
+//! images onto a cuff's USB bootloader.
+//!
+//! Images are signed with an Ed25519 key pair made by `keygen`. The private key stays with whoever builds releases,
+//! and the public key is built into the controller firmware, which refuses any image that wasn't signed with the
+//! matching private key. Both keys are stored as their raw 32 bytes. Cuff images sent through a controller are signed
+//! the same way, since it's the controller that checks them.
+
+use std::{fs, io::Read, path::{Path, PathBuf}, time::Duration};
+
+use ed25519_dalek::{Signer, SigningKey, SECRET_KEY_LENGTH};
+use harmoneyes_core::{protocol::{DeviceKind, Request}, update::{Chunk, Signature, CHUNK_LENGTH}};
+use rand_core::OsRng;
+use sha2::{Digest, Sha512};
+use tokio::time::{sleep, Instant};
+
+use crate::{cli::{done, Result}, device::Connection};
+
+/// The extension of a private key file.
+pub const KEY_EXTENSION: &str = "key";
+/// The extension of a public key file.
+pub const PUBLIC_KEY_EXTENSION: &str = "pub";
+
+/// How long the controller can take to erase the space for an update.
+const ERASE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the controller can take to check the signature, which means reading back the whole image. A cuff also
+/// reads back the whole image to check it once the controller has passed it on.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a cuff can take to show up as a drive after restarting into its bootloader.
+const DRIVE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The first word of every UF2 block.
+const UF2_MAGIC: &[u8; 4] = b"UF2\n";
+
+/// The file the RP2040 bootloader puts on its drive, which names the board.
+const UF2_INFO_FILE: &str = "INFO_UF2.TXT";
+
+/// Makes a new key pair, returning the paths of the private and public keys.
+pub fn generate_key(stem: &Path) -> Result<(PathBuf, PathBuf)> {
+    let key_path = stem.with_extension(KEY_EXTENSION);
+    let public_path = stem.with_extension(PUBLIC_KEY_EXTENSION);
+
+    if key_path.exists() {
+        return Err(format!("{} already exists, refusing to overwrite it", key_path.display()).into());
+    }
+
+    let key = SigningKey::generate(&mut OsRng);
+
+    fs::write(&key_path, key.to_bytes())?;
+    fs::write(&public_path, key.verifying_key().to_bytes())?;
+
+    Ok((key_path, public_path))
+}
+
+/// Signs the SHA-512 digest of the image, which is what the controller's bootloader library checks.
+pub fn sign(key_path: &Path, image: &[u8]) -> Result<Signature> {
+    let key = fs::read(key_path).map_err(|e| format!("failed to read the signing key {}: {e}", key_path.display()))?;
+    let key: [u8; SECRET_KEY_LENGTH] = key
+        .try_into()
+        .map_err(|_| format!("{} is not an Ed25519 private key", key_path.display()))?;
+
+    Ok(SigningKey::from_bytes(&key).sign(&Sha512::digest(image)).to_bytes())
+}
+
+/// Reads a firmware image, which has to be a raw binary since it's written straight to flash.
+pub fn read_image(path: &Path) -> Result<Vec<u8>> {
+    let image = fs::read(path)?;
+
+    if image.starts_with(b"\x7fELF") {
+        return Err(format!(
+            "{} is an ELF file, convert it to a raw binary first with `cargo objcopy --release -- -O binary <out>.bin`",
+            path.display()
+        ).into());
+    }
+
+    if image.starts_with(UF2_MAGIC) {
+        return Err(format!("{} is a UF2 file, updates through a controller need a raw binary", path.display()).into());
+    }
+
+    if image.is_empty() {
+        return Err(format!("{} is empty", path.display()).into());
+    }
+
+    Ok(image)
+}
+
+/// Sends a signed image for `target` to the controller, calling `progress` with the number of bytes sent after each
+/// chunk. The target restarts into the new image once the controller has checked the signature.
+pub async fn upload(
+    connection: &mut Connection,
+    target: DeviceKind,
+    image: &[u8],
+    signature: Signature,
+    mut progress: impl FnMut(usize)
+) -> Result<()> {
+    let size = u32::try_from(image.len()).map_err(|_| "the image is too large")?;
+
+    done(connection.request_within(&Request::UpdateBegin { target, size }, ERASE_TIMEOUT).await?)?;
+
+    for (i, data) in image.chunks(CHUNK_LENGTH).enumerate() {
+        let offset = (i * CHUNK_LENGTH) as u32;
+        // `chunks` never gives out more than `CHUNK_LENGTH` bytes
+        let chunk = Chunk::new(data).unwrap();
+
+        done(connection.request(&Request::UpdateWrite { target, offset, chunk }).await?)?;
+        progress(offset as usize + data.len());
+    }
+
+    done(connection.request_within(&Request::UpdateFinish { target, signature }, VERIFY_TIMEOUT).await?)
+}
+
+/// Whether an image is already in the UF2 format, rather than an ELF file that needs converting.
+pub fn is_uf2(path: &Path) -> Result<bool> {
+    let mut magic = [0; 4];
+    fs::File::open(path)?.read_exact(&mut magic)?;
+
+    Ok(magic == *UF2_MAGIC)
+}
+
+/// Waits for an RP2040 in its USB bootloader to be mounted as a drive, returning where.
+pub async fn wait_for_uf2_drive() -> Result<PathBuf> {
+    let deadline = Instant::now() + DRIVE_TIMEOUT;
+
+    loop {
+        if let Some(drive) = find_uf2_drive() {
+            return Ok(drive);
+        }
+
+        if Instant::now() >= deadline {
+            return Err("the cuff's bootloader drive did not appear, make sure it's mounted".into());
+        }
+
+        sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Copies a UF2 image onto the bootloader's drive, after which the RP2040 flashes it and restarts.
+pub fn copy_uf2(image: &Path, drive: &Path) -> Result<()> {
+    fs::copy(image, drive.join("harmoneyes-cuff.uf2"))?;
+    Ok(())
+}
+
+fn find_uf2_drive() -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+
+    // Linux lists its mounts in /proc, macOS mounts drives under /Volumes, and Windows gives them a letter
+    if let Ok(mounts) = fs::read_to_string("/proc/mounts") {
+        candidates.extend(mounts.lines().filter_map(|line| line.split_whitespace().nth(1)).map(unescape_mount_point));
+    }
+    if let Ok(volumes) = fs::read_dir("/Volumes") {
+        candidates.extend(volumes.flatten().map(|entry| entry.path()));
+    }
+    if cfg!(windows) {
+        candidates.extend((b'D'..=b'Z').map(|letter| PathBuf::from(format!("{}:\\", letter as char))));
+    }
+
+    candidates.into_iter().find(|drive| {
+        fs::read_to_string(drive.join(UF2_INFO_FILE)).is_ok_and(|info| info.contains("RPI-RP2"))
+    })
+}
+
+/// Mount points in /proc/mounts have their spaces written as octal escapes.
+fn unescape_mount_point(path: &str) -> PathBuf {
+    PathBuf::from(path.replace("\\040", " "))
+}
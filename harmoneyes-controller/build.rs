@@ -33,7 +33,33 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    update_key(out);
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
-}
\ No newline at end of file
+}
+
+/// Copies the public key that firmware updates are checked against into the output directory for `dfu.rs` to
+/// include. The key is read from the file named by `HARMONEYES_UPDATE_KEY`, or `update.pub` in the crate root, both
+/// of which come from `harmoneyes-console keygen`. Without a key the firmware refuses every update.
+fn update_key(out: &PathBuf) {
+    println!("cargo:rerun-if-env-changed=HARMONEYES_UPDATE_KEY");
+    println!("cargo:rerun-if-changed=update.pub");
+
+    let path = env::var_os("HARMONEYES_UPDATE_KEY").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("update.pub"));
+
+    let key = match std::fs::read(&path) {
+        Ok(key) if key.len() == 32 => key,
+        Ok(_) => panic!("{} is not a 32 byte Ed25519 public key", path.display()),
+        Err(_) => {
+            println!("cargo:warning=No update key found at {}, firmware updates will be refused", path.display());
+            vec![0; 32]
+        }
+    };
+
+    File::create(out.join("update.pub"))
+        .unwrap()
+        .write_all(&key)
+        .unwrap();
+}
@@ -9,10 +9,34 @@ MEMORY {
         S140 Softdevice v7.3.0 which is listed as requiring 156 kB (just
         under 153 KiB) of flash memory and at a minimum 5.6 kB (specifically
         0x1678, 5752 bytes, or just under 6 KiB) of RAM.
+
+        The rest of flash is split between the running firmware (`FLASH`), the
+        space a firmware update is written to before the bootloader swaps it in
+        (`DFU`, which needs one page more than `FLASH`), the bootloader and its
+        state, and the settings (see `config.rs`). This has to match the layout
+        in `harmoneyes-bootloader/memory.x`.
+
+        The top of RAM (`CRASH`) is kept for crash reports (see `crash.rs`),
+        which have to survive the reset and the bootloader running in between,
+        so it's left out of RAM here and in the bootloader.
     */
-    FLASH : ORIGIN = 0x00000000 + 156K, LENGTH = 1024K - 156K - 4K
-    STORAGE : ORIGIN = 0x00000000 + 1024K - 4K, LENGTH = 4K
-    RAM : ORIGIN = 0x20000000 + 48K, LENGTH = 256K - 48K
+    FLASH : ORIGIN = 0x00000000 + 156K, LENGTH = 408K
+    DFU : ORIGIN = 0x00000000 + 564K, LENGTH = 412K
+    BOOTLOADER : ORIGIN = 0x00000000 + 976K, LENGTH = 28K
+    BOOTLOADER_STATE : ORIGIN = 0x00000000 + 1004K, LENGTH = 4K
+    STORAGE : ORIGIN = 0x00000000 + 1024K - 16K, LENGTH = 16K
+    RAM : ORIGIN = 0x20000000 + 48K, LENGTH = 256K - 48K - 2K
+    CRASH : ORIGIN = 0x20000000 + 256K - 2K, LENGTH = 2K
 }
 
-__storage = ORIGIN(STORAGE);
\ No newline at end of file
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
+
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
+
+__storage_start = ORIGIN(STORAGE);
+__storage_end = ORIGIN(STORAGE) + LENGTH(STORAGE);
+
+__crash_start = ORIGIN(CRASH);
+__crash_end = ORIGIN(CRASH) + LENGTH(CRASH);
@@ -31,13 +31,65 @@
 //! turn off when the battery voltage (ADC * 2) crosses below a threshold
 //! of about 3.3V and will stop charging when the battery voltage
 //! crosses above a threshold of about 4.2V
+//!
+//! ## State of Charge
+//!
+//! The voltage is turned into a percentage by `harmoneyes_core::battery::Gauge`,
+//! which looks it up on a LiPo discharge curve rather than interpolating
+//! linearly between 3.3V and 4.2V, and allows for the sag while the UWB
+//! radio and the cuff's motors are drawing current.
+//!
+//! ## Low Battery
+//!
+//! The rate the charge is falling at gives an estimate of the time left,
+//! and as either runs low the controller raises a warning, then critical,
+//! then shutdown imminent alert. Each one buzzes the cuff so the performer
+//! knows, turns the LED a warning colour so the crew can see, and is
+//! broadcast over the mesh so that a console on any controller can flag
+//! the performer. The broadcast repeats while the alert stands since mesh
+//! messages aren't acknowledged.
+//!
+//! ## Charging
+//!
+//! The charger on the Feather can't be asked what it's doing, so whether
+//! the battery is charging or full is worked out by
+//! `harmoneyes_core::battery::Charger` from whether USB power is present
+//! (see `power`) and whether the voltage is still climbing. The voltage
+//! reads high while the charger is pushing current in and drops back once
+//! it's unplugged, so the estimates are started over whenever USB power
+//! comes or goes, and low battery alerts stand down while it's plugged in.
 
-use defmt::info;
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
 use embassy_nrf::{bind_interrupts, interrupt::{self, InterruptExt, Priority}, peripherals::{P0_29, SAADC}, saadc::{self, ChannelConfig, Gain, Input, Reference, Saadc, Time}};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Ticker};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use harmoneyes_core::{battery::{Alert, ChargeState, Charger, Escalation, Gauge, Level, Load, Report, Runtime}, haptics::{Motor, Wrist}, health::Task, link::Presence, mesh, status::State, telemetry::Telemetry};
+
+/// How often a standing low battery alert is broadcast again.
+const ALERT_REPEAT: Duration = Duration::from_secs(30);
+
+/// How long each pulse of the low battery pattern runs the motors for, and the gap after it.
+const PULSE_MS: u64 = 150;
+
+pub static BATTERY: Mutex<CriticalSectionRawMutex, Option<Reading>> = Mutex::new(None);
 
-pub static BATTERY: Mutex<CriticalSectionRawMutex, Option<BatteryCharge>> = Mutex::new(None);
+/// The latest battery sample and the state of charge worked out from it and the ones before.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Reading {
+    pub millivolts: u16,
+    pub percent: u8,
+    /// How long the battery has left at the rate it's been running down, once that's known.
+    pub minutes_remaining: Option<u16>
+}
+
+impl Reading {
+    /// The reading as it's passed on to other controllers over the mesh.
+    pub fn report(&self) -> Report {
+        Report { millivolts: self.millivolts, percent: self.percent, minutes_remaining: self.minutes_remaining }
+    }
+}
 
 bind_interrupts!(struct Irqs {
     SAADC => saadc::InterruptHandler;
@@ -59,26 +111,137 @@ pub async fn task(
     let mut saadc = Saadc::new(adc, Irqs, saadc_config(), [channel_conf]);
 
     let mut ticker = Ticker::every(Duration::from_millis(2500));
+    let mut gauge = Gauge::new();
+    let mut runtime = Runtime::new();
+    let mut escalation = Escalation::new();
+    let mut shown_level = None;
+    let mut charger = Charger::new();
+    let mut last_alert: Option<Instant> = None;
 
     loop {
+        crate::health::check_in(Task::Bat);
+
         // Record the battery
         let mut buf = [0; 1];
         saadc.sample(&mut buf).await;
 
-        let bat = BatteryCharge::new(buf[0]);
+        let millivolts = BatteryCharge::new(buf[0]).as_millivolts() as u16;
+
+        let was = charger.state();
+        let charge = charger.update(crate::power::vbus_present(), millivolts);
+        if charge != was {
+            info!("Battery is {}", charge.name());
+            show_charge(charge);
+
+            // Plugging in or unplugging moves the voltage, so none of the samples so far are any use
+            if (charge == ChargeState::Discharging) != (was == ChargeState::Discharging) {
+                gauge.reset();
+                runtime.reset();
+                escalation = Escalation::new();
+            }
+        }
+
+        let load = Load {
+            uwb: crate::uwb::RANGING.load(Ordering::Relaxed),
+            motors: crate::cuff::motors_running()
+        };
+        let percent = gauge.push(millivolts, load);
+
+        // info!("New Battery Reading {{\n    Raw: {} mV\n    Resting: {} mV\n    Charge: {}%\n}}", millivolts, gauge.millivolts(), percent);
+
+        crate::usb::report(Telemetry::Battery { millivolts, percent, charge });
+
+        runtime.push(Instant::now().as_millis(), percent);
+        let minutes_remaining = runtime.minutes_remaining();
+
+        BATTERY.lock().await.replace(Reading { millivolts, percent, minutes_remaining });
 
-        // info!("New Battery Reading {{\n    Raw: {}\n    Parsed: {} mV\n    Parsed: {}%\n}}", bat.0, bat.as_millivolts(), bat.as_ratio() * 100.0);
+        // The battery can't run out while it's plugged in
+        let raised = if charge != ChargeState::Discharging { None } else { escalation.update(percent, minutes_remaining) };
+        if escalation.level() != shown_level {
+            shown_level = escalation.level();
+            match shown_level {
+                Some(level) => crate::status::set(State::LowBattery(level)),
+                None => crate::status::clear(State::LowBattery(Level::Warning)),
+            }
+        }
 
-        BATTERY.lock().await.replace(BatteryCharge::new(buf[0]));
+        if let Some(level) = escalation.level() {
+            if raised.is_some() || last_alert.is_none_or(|at| at.elapsed() >= ALERT_REPEAT) {
+                raise(Alert { level, percent, minutes_remaining }, raised.is_some()).await;
+                last_alert = Some(Instant::now());
+            }
+        } else {
+            last_alert = None;
+        }
 
         ticker.next().await;
     }
 }
 
+/// Shows the charge state on the status LED.
+fn show_charge(charge: ChargeState) {
+    match charge {
+        ChargeState::Discharging => {
+            crate::status::clear(State::Charging);
+            crate::status::clear(State::Charged);
+        },
+        ChargeState::Charging => {
+            crate::status::clear(State::Charged);
+            crate::status::set(State::Charging);
+        },
+        ChargeState::Full => {
+            crate::status::clear(State::Charging);
+            crate::status::set(State::Charged);
+        },
+    }
+}
+
 fn saadc_config() -> saadc::Config {
     Default::default()
 }
 
+/// Lets everyone know the battery is running low. The performer only gets buzzed when the alert is new, so that a
+/// repeat broadcast doesn't get mistaken for a cue.
+async fn raise(alert: Alert, escalated: bool) {
+    if escalated {
+        warn!("Battery {}: {}%, {} minutes left", alert.level.name(), alert.percent, alert.minutes_remaining);
+        crate::crash::note(format_args!("Battery {} at {}%", alert.level.name(), alert.percent));
+    }
+
+    crate::usb::report(Telemetry::BatteryAlert { source: crate::identity::address(), alert });
+    crate::ble::OUTBOX.send(mesh::battery_alert(&alert)).await;
+
+    if escalated {
+        buzz(alert.level).await;
+    }
+}
+
+/// Pulses every motor at once, more times the more urgent the alert, which doesn't feel like any directional cue.
+async fn buzz(level: Level) {
+    let pulses = match level {
+        Level::Warning => 2,
+        Level::Critical => 3,
+        Level::ShutdownImminent => 5,
+    };
+
+    for _ in 0..pulses {
+        for wrist in Wrist::ALL {
+            if crate::cuff::presence(wrist) != Some(Presence::Present) {
+                continue;
+            }
+
+            for motor in Motor::ALL {
+                if crate::cuff::haptic(wrist, motor, PULSE_MS).await.is_err() {
+                    warn!("The {} cuff did not take the low battery pattern", wrist.name());
+                    return;
+                }
+            }
+        }
+        Timer::after_millis(2 * PULSE_MS).await;
+    }
+}
+
 
 
 
@@ -93,15 +256,6 @@ impl BatteryCharge {
         Self(raw)
     }
 
-    pub fn as_ratio(&self) -> f32 {
-        // 2816 is the raw value that corresponds to 3.3V
-        // (3300 / 2) * (4096 / 2400)
-        // 3584 is the raw value that corresponds to 4.2V
-        // (4200 / 2) * (4096 / 2400)
-        // This method just uses a linear interpolation between those two values.
-        (((self.0 - 2816) as f32) / ((3584 - 2816) as f32)).clamp(0.0, 1.0)
-    }
-
     pub fn as_millivolts(&self) -> f32 {
         // 2400 is the reference voltage in mV (0.6V * 4)
         // 4096 is for the 12-bit resolution (2^12)
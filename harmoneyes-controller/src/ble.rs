@@ -4,9 +4,10 @@ use embassy_futures::join::join;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embassy_time::{Duration, Instant, Ticker, Timer};
 use futures::future::{select, Either};
-use nrf_softdevice::{ble::{advertisement_builder::{AdvertisementDataType, ExtendedAdvertisementBuilder, ExtendedAdvertisementPayload}, central, peripheral, Phy, PhySet}, Softdevice};
+use harmoneyes_core::{haptics::Wrist, health::Task, mesh, power::Mode, telemetry::Telemetry, wireless};
+use nrf_softdevice::{ble::{advertisement_builder::{AdvertisementDataType, ExtendedAdvertisementBuilder, ExtendedAdvertisementPayload}, central, peripheral, Address, Phy, PhySet}, Softdevice};
 
-pub static OUTBOX: Channel<CriticalSectionRawMutex, [u8; 242], 1> = Channel::new();
+pub static OUTBOX: Channel<CriticalSectionRawMutex, [u8; mesh::MESSAGE_LENGTH], 1> = Channel::new();
 
 #[embassy_executor::task]
 pub async fn task(sd: &'static Softdevice) {
@@ -17,26 +18,92 @@ pub async fn task(sd: &'static Softdevice) {
 }
 
 async fn listen(sd: &'static Softdevice) {
-    match central::scan(sd, &scan_config(), |params| {
-        // We exclusively use nonconnectable and nonscannable advertising
-        if params.type_.connectable() == 0 && params.type_.scannable() == 0 {
-            let mut data = unsafe { slice::from_raw_parts(params.data.p_data, params.data.len as usize) };
-            // Every packet has a header that is the mesh AD type followed by the magic string "Harmoneyes"
-            if data.len() > 12 && data[1..12] == [0x2A, 0x48, 0x61, 0x72, 0x6d, 0x6f, 0x6e, 0x65, 0x79, 0x65, 0x73] {
-                let data = &data[12..];
-                // info!("Harmoneyes Data: {}", data);
+    let mut lending_scanner = false;
+
+    loop {
+        // Connecting to a wireless cuff needs the scanner, which is otherwise kept busy here
+        if lending_scanner {
+            crate::wireless::scanner_returned().await;
+        }
+        let _scanner = crate::wireless::SCANNER.lock().await;
+
+        // Scanning starts over with a different duty cycle whenever the power mode changes
+        let mode = crate::mode::current();
+
+        let scan = pin!(central::scan(sd, &scan_config(mode), |params| {
+            // We exclusively use nonconnectable and nonscannable advertising
+            if params.type_.connectable() == 0 && params.type_.scannable() == 0 {
+                let data = unsafe { slice::from_raw_parts(params.data.p_data, params.data.len as usize) };
+                // Every packet has a header that is the mesh AD type followed by the magic string "Harmoneyes"
+                if let Some(packet) = mesh::parse(data) {
+                    crate::usb::report(Telemetry::mesh(false, packet.message));
+
+                    // Another performer's battery is running low, which the console flags
+                    if let Some(alert) = mesh::parse_battery_alert(packet.message) {
+                        crate::usb::report(Telemetry::BatteryAlert { source: packet.source, alert });
+                    }
+
+                    // So the console can keep an eye on every performer's cuffs, not just this one's
+                    if let Some(keep_alive) = mesh::parse_keep_alive(packet.message) {
+                        for (wrist, cuff) in Wrist::ALL.into_iter().zip(keep_alive.cuffs) {
+                            let Some(cuff) = cuff else { continue };
+
+                            crate::usb::report(Telemetry::CuffPresence { source: packet.source, wrist, presence: cuff.presence });
+                            if let Some(report) = cuff.battery {
+                                crate::usb::report(Telemetry::CuffBattery { source: packet.source, wrist, report });
+                            }
+                        }
+                    }
+
+                    // The director is putting the band to sleep or waking it up
+                    if let Some(mode) = mesh::parse_power_mode(packet.message) {
+                        crate::mode::set(mode);
+                    }
+                    // info!("Harmoneyes Data: {}", data);
+                }
+            } else if params.type_.connectable() != 0 {
+                let data = unsafe { slice::from_raw_parts(params.data.p_data, params.data.len as usize) };
+                // A wireless cuff, which is only connected to if it's the one paired with a wrist
+                if let Some(serial) = wireless::parse_advertisement(data) {
+                    crate::wireless::heard(serial, Address::from_raw(params.peer_addr));
+                }
             }
+            return None::<()>
+        }));
+
+        let changed = pin!(crate::mode::changed_from(mode));
+        let wanted = pin!(crate::wireless::scanner_wanted());
+        // The scan is stopped, and the scanner handed over, at the end of the loop
+        lending_scanner = false;
+        match select(scan, select(changed, select(pin!(check_in_while_scanning()), wanted))).await {
+            Either::First(_) => {
+                info!("We have a problem");
+                crate::crash::note(format_args!("Scanning stopped"));
+                Timer::after_secs(1).await;
+            },
+            Either::Second(Either::Second(Either::Second(()))) => lending_scanner = true,
+            Either::Second(_) => {},
         }
-        return None::<()>
-    }).await {
-        Ok(_) => info!("We have a problem"),
-        Err(_) => info!("We have a problem"),
-    };
+    }
+}
+
+/// Checks in for as long as the scan keeps going. Packets only come in while somebody else is around, so the scan
+/// is counted as working once it has been running for a second, and one that keeps stopping never checks in.
+async fn check_in_while_scanning() -> ! {
+    loop {
+        Timer::after_secs(1).await;
+        crate::health::check_in(Task::Ble);
+    }
 }
 
 async fn advertise(sd: &'static Softdevice) {
     loop {
-        let message = OUTBOX.receive().await;
+        // The console service is advertised for a GATT client whenever there's no mesh message to send
+        let message = match select(pin!(OUTBOX.receive()), pin!(crate::gatt::advertise(sd))).await {
+            Either::First(message) => message,
+            Either::Second(never) => never,
+        };
+        crate::usb::report(Telemetry::mesh(true, &message));
         // info!("Sending a new message");
         
         let ad = peripheral::NonconnectableAdvertisement::ExtendedNonscannableUndirected {
@@ -49,29 +116,32 @@ async fn advertise(sd: &'static Softdevice) {
     }
 }
 
-fn scan_config() -> central::ScanConfig<'static> {
+/// Scans all of the time while active, and only for 100 ms of every second while idle or every 2.5 s while asleep.
+fn scan_config(mode: Mode) -> central::ScanConfig<'static> {
     let mut config = central::ScanConfig::default();
 
     config.extended = true;
     config.phys = PhySet::Coded;
-    config.interval = 500;
-
-    config
-}
 
-fn build_ad_data(message: [u8; 242]) -> ExtendedAdvertisementPayload {
-    let mut raw = [0; 252];
-
-    for (i, char) in "Harmoneyes".chars().enumerate() {
-        raw[i] = char as u8;
+    // In units of 0.625 ms
+    match mode {
+        Mode::Active => config.interval = 500,
+        Mode::Idle => {
+            config.interval = 1600;
+            config.window = 160;
+        },
+        Mode::Sleep => {
+            config.interval = 4000;
+            config.window = 160;
+        },
     }
 
-    for (i, byte) in message.into_iter().enumerate() {
-        raw[i + 10] = byte;
-    }
+    config
+}
 
+fn build_ad_data(message: [u8; mesh::MESSAGE_LENGTH]) -> ExtendedAdvertisementPayload {
     ExtendedAdvertisementBuilder::new()
-        .raw(AdvertisementDataType::from_u8(0x2A), &raw)
+        .raw(AdvertisementDataType::from_u8(mesh::AD_TYPE), &mesh::encode(crate::identity::address(), &message))
         .build()
 }
 
@@ -0,0 +1,193 @@
+//! # Configuration
+//!
+//! The settings that can be changed from the console, kept in the `STORAGE` section of flash so that they survive
+//! a reset.
+//!
+//! The settings are kept as a log of records spread over several pages, laid out as `harmoneyes_core::config::store`
+//! describes, which is also where the records are encoded and checked. This only reads and writes the flash.
+//!
+//! Every flash operation goes through the softdevice so that it doesn't disturb the radio (see `flash.rs`).
+
+use defmt::{info, warn, Debug2Format};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage_async::nor_flash::NorFlash;
+use harmoneyes_core::config::{store::{self, Header}, Config, Key, Value, SCHEMA_VERSION};
+
+use crate::flash::{self, Region};
+
+pub static CONFIG: Mutex<CriticalSectionRawMutex, Config> = Mutex::new(Config::DEFAULT);
+
+static STORE: Mutex<CriticalSectionRawMutex, Option<Store<Region>>> = Mutex::new(None);
+
+/// The size of an nRF52840 flash page.
+const PAGE_SIZE: u32 = 4096;
+/// How many pages the `STORAGE` section in `memory.x` spans.
+const PAGES: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The value is the wrong type or out of range for the setting.
+    Invalid,
+    /// The setting was changed but couldn't be saved.
+    Flash
+}
+
+/// Loads the settings out of flash. This needs the flash to have been taken from the softdevice.
+pub async fn initialize() {
+    let mut store = Store::new(flash::storage());
+
+    match store.load().await {
+        Ok(config) => {
+            info!("Loaded settings from page {} of flash", store.page);
+            *CONFIG.lock().await = config;
+        },
+        Err(e) => warn!("Failed to load settings, using the defaults: {}", Debug2Format(&e)),
+    }
+
+    *STORE.lock().await = Some(store);
+}
+
+pub async fn get(key: Key) -> Value {
+    CONFIG.lock().await.get(key)
+}
+
+/// Changes a setting and saves it to flash.
+///
+/// `CONFIG` is only held long enough to copy the settings out and to put the changed ones back, since every range
+/// measurement reads it and a flash write can take a while to get a turn with the radio. Holding `STORE` throughout
+/// keeps changes from overtaking each other.
+pub async fn set(key: Key, value: Value) -> Result<(), Error> {
+    let mut store = STORE.lock().await;
+
+    let mut updated = *CONFIG.lock().await;
+    updated.set(key, value).map_err(|_| Error::Invalid)?;
+
+    if let Some(store) = store.as_mut() {
+        store.set(&updated, key, &value).await.map_err(|e| {
+            warn!("Failed to save {}: {}", key.name(), Debug2Format(&e));
+            Error::Flash
+        })?;
+    }
+
+    *CONFIG.lock().await = updated;
+
+    Ok(())
+}
+
+/// Restores every setting to its default.
+pub async fn reset() -> Result<(), Error> {
+    let mut store = STORE.lock().await;
+
+    if let Some(store) = store.as_mut() {
+        store.compact(&Config::DEFAULT).await.map_err(|e| {
+            warn!("Failed to reset the settings: {}", Debug2Format(&e));
+            Error::Flash
+        })?;
+    }
+
+    *CONFIG.lock().await = Config::DEFAULT;
+
+    Ok(())
+}
+
+struct Store<F> {
+    flash: F,
+    /// The page that records are currently being added to.
+    page: u32,
+    sequence: u32,
+    /// Where in the current page the next record goes.
+    offset: u32
+}
+
+impl<F: NorFlash> Store<F> {
+    fn new(flash: F) -> Self {
+        // Until a page is found, the first one to be written will be page 0 with sequence number 1
+        Self { flash, page: PAGES - 1, sequence: 0, offset: PAGE_SIZE }
+    }
+
+    fn address(&self, page: u32, offset: u32) -> u32 {
+        page * PAGE_SIZE + offset
+    }
+
+    /// Finds the newest page and replays its records.
+    async fn load(&mut self) -> Result<Config, F::Error> {
+        let mut headers = [None; PAGES as usize];
+        for page in 0..PAGES {
+            let mut header = [0u8; store::HEADER_SIZE];
+            self.flash.read(self.address(page, 0), &mut header).await?;
+            headers[page as usize] = Header::decode(&header);
+        }
+
+        let mut config = Config::DEFAULT;
+
+        let Some((page, header)) = store::newest(&headers) else {
+            return Ok(config);
+        };
+
+        // Only read once on boot, so the whole page is read in one go rather than a record at a time
+        let mut contents = [0u8; PAGE_SIZE as usize];
+        self.flash.read(self.address(page as u32, 0), &mut contents).await?;
+        let replayed = store::replay(&contents, header.schema, &mut config);
+
+        if replayed.torn > 0 {
+            warn!("Skipped {} records on page {} that were cut off by a reset", replayed.torn, page);
+        }
+        if replayed.corrupt {
+            // Nothing can be appended after garbage, so a fresh page is started next time a setting changes
+            warn!("Found a corrupt record on page {}", page);
+        }
+
+        self.page = page as u32;
+        self.sequence = header.sequence;
+        self.offset = replayed.offset as u32;
+
+        if header.schema < SCHEMA_VERSION {
+            info!("Migrating settings from schema {} to {}", header.schema, SCHEMA_VERSION);
+            self.compact(&config).await?;
+        }
+
+        Ok(config)
+    }
+
+    /// Saves a changed setting, moving on to the next page if the current one is full.
+    async fn set(&mut self, config: &Config, key: Key, value: &Value) -> Result<(), F::Error> {
+        let (record, size) = store::encode_record(key, value);
+
+        if self.offset + size as u32 > PAGE_SIZE {
+            return self.compact(config).await;
+        }
+
+        self.flash.write(self.address(self.page, self.offset), &record[..size]).await?;
+        self.offset += size as u32;
+
+        Ok(())
+    }
+
+    /// Copies every setting that isn't at its default onto a freshly erased page.
+    async fn compact(&mut self, config: &Config) -> Result<(), F::Error> {
+        let page = store::next_page(self.page as usize, PAGES as usize) as u32;
+        let start = self.address(page, 0);
+
+        self.flash.erase(start, start + PAGE_SIZE).await?;
+
+        let mut offset = store::HEADER_SIZE as u32;
+
+        for (key, value) in store::compacted(config) {
+            let (record, size) = store::encode_record(key, &value);
+            self.flash.write(self.address(page, offset), &record[..size]).await?;
+            offset += size as u32;
+        }
+
+        // The magic goes last, so that the page doesn't count until the sequence number is there too
+        let header = Header::after(self.sequence);
+        let encoded = header.encode();
+        self.flash.write(start + store::WORD_SIZE as u32, &encoded[store::WORD_SIZE..]).await?;
+        self.flash.write(start, &encoded[..store::WORD_SIZE]).await?;
+
+        self.page = page;
+        self.sequence = header.sequence;
+        self.offset = offset;
+
+        Ok(())
+    }
+}
@@ -1,61 +1,101 @@
-use defmt::info;
-use embassy_futures::join::join;
+use defmt::{info, warn};
+use embassy_futures::{join::join4, select::{select, Either}};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Duration, Instant, Ticker, Timer};
+use harmoneyes_core::{coord::{Coordinator, KeepAlives}, haptics::{Direction, Pulses, Wrist}, health::Task, mesh::{self, Cuff, KeepAlive}, power::Mode, proximity::Warning, ranging::{self, BlockAverage}, telemetry::Telemetry};
 
 use crate::{ble, uwb::DISTANCES};
 
-/// A task for coordinating the distance information from nearby devices
+/// Collision warnings for `warn_of_collisions` to pass on, so that distances keep being handled while the cuffs pulse.
+static COLLISIONS: Signal<CriticalSectionRawMutex, Warning> = Signal::new();
+
+/// Cues that keep the interval from the guide, for `keep_spacing` to pass on.
+static GUIDE_CUES: Signal<CriticalSectionRawMutex, Direction> = Signal::new();
+
+/// A task for coordinating the distance information from nearby devices, with the decisions left to
+/// `harmoneyes_core::coord`
 #[embassy_executor::task]
 pub async fn task() {
-    join(random_bluetooth(), handle_distances()).await;
+    join4(random_bluetooth(), handle_distances(), warn_of_collisions(), keep_spacing()).await;
 }
 
 
 async fn handle_distances() {
-    const WIDTH: usize = 5;
+    let mut average: BlockAverage<5> = BlockAverage::new();
+    let mut coordinator = Coordinator::new();
 
     loop {
-        let mut next = 0;
-        let mut buf = [0; WIDTH];
+        let (peer, distance) = match select(DISTANCES.receive(), crate::mode::changed_from(Mode::Active)).await {
+            Either::First(measurement) => measurement,
+            Either::Second(_) => {
+                // Ranging has stopped, so nobody's distance or speed from before counts once it starts again
+                coordinator.clear();
+                while crate::mode::current() != Mode::Active {
+                    crate::mode::changed_from(crate::mode::current()).await;
+                }
+                continue;
+            },
+        };
+
+        crate::usb::report(Telemetry::Distance { peer, tof: distance });
+
+        let (limits, spacing) = {
+            let config = crate::config::CONFIG.lock().await;
+            (config.collision_limits(), config.guide())
+        };
+        let decision = coordinator.update(limits, spacing, peer, ranging::tof_to_millimeters(distance), Instant::now().as_millis());
+
+        if let Some(warning) = decision.warning {
+            COLLISIONS.signal(warning);
+        }
 
-        loop {
-            let distance = DISTANCES.receive().await;
+        if let Some(cue) = decision.cue {
+            info!("Moving {} to keep {} mm from guide {:04x}", cue.correction.name(), cue.spacing.target_mm, peer);
+            GUIDE_CUES.signal(cue.direction);
+        }
 
-            if next < WIDTH {
-                buf[next] = distance;
-                next += 1;
-            } else {
+        if let Some(average) = average.push(distance) {
+            info!("Distance {}", average);
+        }
+    }
+}
 
-                let average = {
-                    let mut sum = 0;
-                    for distance in buf {
-                        sum += distance;
-                    }
-                    sum / WIDTH as u64
-                };
 
-                info!("Distance {}", average);
+/// Pulses the cuffs whenever somebody is too close or closing in too fast (see `harmoneyes_core::proximity`).
+async fn warn_of_collisions() {
+    loop {
+        let warning = COLLISIONS.wait().await;
 
+        warn!("Peer {:04x} is {} mm away and closing at {} mm/s", warning.peer, warning.distance_mm, warning.closing_mm_per_s);
+        crate::usb::report(Telemetry::Collision { warning });
 
-                buf = [0; WIDTH];
-                next = 0;
-            }
-        }
+        crate::cuff::pulse(Pulses::URGENT).await;
     }
 }
 
+/// Cues the performer back to the interval from their guide (see `harmoneyes_core::guide`).
+async fn keep_spacing() {
+    loop {
+        let direction = GUIDE_CUES.wait().await;
+        let duration_ms = crate::config::CONFIG.lock().await.haptic_duration_ms;
+
+        crate::cuff::cue(direction, duration_ms as u64).await;
+    }
+}
 
 async fn random_bluetooth() {
     let period: u32 = 1000;
     let mut ticker = Ticker::every(Duration::from_millis(period as u64));
 
-    let mut count: u32 = 0;
+    let mut keep_alives = KeepAlives::new();
 
     loop {
+        crate::health::check_in(Task::Coord);
         random_timeout(period).await.await;
 
-        keep_alive(count).await;
-        count += 1;
+        if let Some(count) = keep_alives.tick(crate::mode::current()) {
+            keep_alive(count).await;
+        }
 
         ticker.next().await; // Keep this at the bottom of the call stack
     }
@@ -67,15 +107,23 @@ async fn random_bluetooth() {
 
 /// The code here will run periodically after a random duration of milliseconds anywhere from 0 to 1000
 async fn keep_alive(count: u32) {
-    let mut buf = [0; 242];
-    for (i, char) in "Signal ".chars().enumerate() {
-        buf[i] = char as u8;
-    }
+    let config = *crate::config::CONFIG.lock().await;
+    let keep_alive = KeepAlive {
+        count,
+        battery: crate::bat::BATTERY.lock().await.map(|reading| reading.report()),
+        cuffs: cuffs().await,
+        performer_id: config.performer_id,
+        section: config.section
+    };
+
+    ble::OUTBOX.send(mesh::keep_alive(&keep_alive)).await;
+}
 
-    for (i, byte) in count.to_ne_bytes().into_iter().enumerate() {
-        buf[i + 7] = byte;
-    }
-    ble::OUTBOX.send(buf).await;
+/// What the band hears about each cuff, or `None` for a wrist that has no cuff set up.
+async fn cuffs() -> [Option<Cuff>; Wrist::ALL.len()] {
+    let batteries = *crate::cuff::BATTERIES.lock().await;
+
+    Wrist::ALL.map(|wrist| crate::cuff::presence(wrist).map(|presence| Cuff { presence, battery: batteries[wrist.index()] }))
 }
 
 async fn random_timeout(range: u32) -> Timer {
@@ -0,0 +1,175 @@
+//! # Crash log
+//!
+//! When the controller panics in a release build it writes a crash report (see `harmoneyes_core::crash`) into a
+//! corner of RAM that's left alone across a reset, then resets itself so that it's back up straight away rather than
+//! sitting dead in the middle of a show. Tasks `note` the things worth knowing about as they happen, such as mode
+//! changes and radio faults, and the last few go in the report to show what led up to it. The `CRASH` region in `memory.x` is kept out of the way of the firmware and
+//! the bootloader, and RAM keeps its contents through every reset short of losing power.
+//!
+//! On the next boot the report is finished off with why the chip says it was reset and kept, along with a few before
+//! it, until the console fetches and clears them. A task that stalls is written down the same way before the watchdog
+//! resets the controller (see `health`), and other resets by the watchdog or from a lockup are kept too, even though
+//! nothing got to write a report.
+//!
+//! Reports are kept encoded with a checksum, so whatever was in RAM after the chip was powered on, or after an update
+//! changed the layout, is thrown away rather than read as a report.
+
+use core::{cell::RefCell, fmt::{self, Write}, mem::size_of, ptr};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use harmoneyes_core::{codec::FixedStr, crash::{Lines, Report, ResetReason}, crc::crc16};
+
+/// How many reports are kept. Older ones are dropped to make room.
+const REPORTS: usize = 4;
+
+/// "HCRS", marking the region as holding crash reports.
+const MAGIC: u32 = 0x5352_4348;
+
+/// The lines noted since boot, for the panic handler to copy into a report.
+static LINES: Mutex<CriticalSectionRawMutex, RefCell<Lines>> = Mutex::new(RefCell::new(Lines::new()));
+
+/// An encoded report. Every field is plain bytes, so anything left in RAM can be read as one and checked.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Slot {
+    len: u16,
+    crc: u16,
+    bytes: [u8; Report::MAX_ENCODED_LENGTH]
+}
+
+impl Slot {
+    const EMPTY: Self = Self { len: 0, crc: 0, bytes: [0; Report::MAX_ENCODED_LENGTH] };
+
+    fn store(report: &Report) -> Self {
+        let mut slot = Self::EMPTY;
+        // The buffer is sized for the largest report
+        let len = report.encode(&mut slot.bytes).unwrap_or(0);
+        slot.len = len as u16;
+        slot.crc = crc16(&slot.bytes[..len]);
+        slot
+    }
+
+    fn load(&self) -> Option<Report> {
+        let len = self.len as usize;
+        if len == 0 || len > self.bytes.len() || crc16(&self.bytes[..len]) != self.crc {
+            return None;
+        }
+
+        Report::decode(&self.bytes[..len]).ok()
+    }
+}
+
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    /// The report written by the panic handler, which is moved into `reports` once the reset reason is known.
+    pending: Slot,
+    /// Most recent first.
+    reports: [Slot; REPORTS]
+}
+
+/// The retained region of RAM.
+///
+/// # Safety
+/// Only one reference can be live at a time, which every caller makes sure of by holding a critical section.
+unsafe fn retained() -> &'static mut Retained {
+    unsafe extern "C" {
+        static mut __crash_start: u8;
+        static __crash_end: u8;
+    }
+
+    let start = &raw mut __crash_start;
+    let end = &raw const __crash_end;
+    assert!(end as usize - start as usize >= size_of::<Retained>(), "The CRASH region in memory.x is too small");
+
+    // SAFETY: The region is reserved for this in `memory.x`, is word aligned, and every bit pattern is a valid
+    // `Retained` since it's nothing but integers
+    unsafe { &mut *(start as *mut Retained) }
+}
+
+/// Reads and clears the reset reason, and finishes off any report the panic handler left. This has to be called
+/// before the softdevice is enabled, since the softdevice takes over the `POWER` peripheral.
+pub fn initialize() {
+    let reason = ResetReason(nrf_pac::POWER.resetreas().read().0);
+    // The bits stay set until they're cleared, so that the next reset isn't mistaken for this one
+    nrf_pac::POWER.resetreas().write_value(nrf_pac::power::regs::Resetreas(reason.0));
+
+    info!("Reset by {}", defmt::Display2Format(&reason));
+
+    critical_section::with(|_| {
+        // SAFETY: Inside a critical section
+        let retained = unsafe { retained() };
+
+        if retained.magic != MAGIC {
+            // SAFETY: Writing a whole new value over whatever the RAM held at power on
+            unsafe {
+                ptr::write(retained, Retained { magic: MAGIC, pending: Slot::EMPTY, reports: [Slot::EMPTY; REPORTS] });
+            }
+        }
+
+        let report = match retained.pending.load() {
+            Some(report) => Some(Report { reset_reason: reason, ..report }),
+            None if reason.is_failure() => Some(Report {
+                uptime_ms: 0,
+                reset_reason: reason,
+                message: FixedStr::truncated("reset without panicking"),
+                lines: Lines::new()
+            }),
+            None => None,
+        };
+        retained.pending = Slot::EMPTY;
+
+        if let Some(report) = report {
+            warn!("The controller crashed: {}", report.message.as_str());
+            retained.reports.copy_within(..REPORTS - 1, 1);
+            retained.reports[0] = Slot::store(&report);
+        }
+    });
+}
+
+/// Notes a line to go in the report if the controller crashes.
+pub fn note(args: fmt::Arguments) {
+    let mut line = FixedStr::empty();
+    let _ = line.write_fmt(args);
+
+    LINES.lock(|lines| lines.borrow_mut().push(line));
+}
+
+/// The crash report at `index`, with the most recent at 0.
+pub fn report(index: usize) -> Option<Report> {
+    critical_section::with(|_| {
+        // SAFETY: Inside a critical section
+        let retained = unsafe { retained() };
+        retained.reports.get(index).and_then(Slot::load)
+    })
+}
+
+pub fn clear() {
+    critical_section::with(|_| {
+        // SAFETY: Inside a critical section
+        let retained = unsafe { retained() };
+        retained.reports = [Slot::EMPTY; REPORTS];
+    });
+}
+
+/// Writes down a report for the next boot to pick up, for when the controller is about to go down.
+pub fn record(message: fmt::Arguments) {
+    let mut text = FixedStr::empty();
+    let _ = text.write_fmt(message);
+
+    // A panic partway through `note` would leave the lines borrowed, in which case they're left out
+    let lines = LINES.lock(|lines| lines.try_borrow().map(|lines| *lines).unwrap_or_default());
+
+    let report = Report {
+        uptime_ms: embassy_time::Instant::now().as_millis(),
+        reset_reason: ResetReason::default(),
+        message: text,
+        lines
+    };
+
+    critical_section::with(|_| {
+        // SAFETY: Inside a critical section
+        unsafe { retained() }.pending = Slot::store(&report);
+    });
+}
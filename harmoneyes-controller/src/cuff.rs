@@ -0,0 +1,299 @@
+//! # Cuff
+//!
+//! A cuff has its own battery, which it keeps track of just like `bat` does for the controller's, and exposes
+//! through a status register (see `harmoneyes_core::registers`). This task reads it from
+//! each cuff every so often, streams it to the console and keeps the latest for the mesh keep alive, so that a dying
+//! cuff battery is noticed as well as a dying controller one.
+//!
+//! It also keeps the link to each cuff alive (see `harmoneyes_core::link`), so that a cuff stops its motors if the
+//! controller goes down or its cable comes loose. Whether the keep alives are answered is how the controller knows
+//! a cuff is there: every so often while they aren't the bus is freed up in case the cuff is holding it, or a
+//! wireless cuff is reconnected to, and once a cuff counts as lost the rest of the band hears about it in the mesh
+//! keep alive. How long the keep alives take and how many go missing is streamed to the console too.
+//!
+//! Which wrists have a cuff, and whether it's cabled or wireless, comes from the config (see `Config::cuff_link`),
+//! and is checked on every keep alive so that fitting or removing one doesn't need a restart. Commands go to either
+//! kind the same way (see `transport`).
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::join::join;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use harmoneyes_core::{battery::Report, config::CuffLink, haptics::{self, Direction, Motor, Pulses, Run, Wrist}, health::Task, link::{self, Presence, Quality, Tracker}, power::Mode, registers::{Contents, Register, MAX_CONTENTS_LENGTH}, telemetry::Telemetry};
+
+use crate::transport::{self, Error, Transport};
+
+/// How often the cuffs' batteries are read. The cuffs sample them more often than this, so nothing is missed.
+const BATTERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Stands in for a presence code on a wrist that has no cuff set up.
+const NOT_FITTED: u8 = 0xFF;
+
+/// The latest report of each cuff's battery, indexed by `Wrist::index`, or `None` if the cuff hasn't given one.
+pub static BATTERIES: Mutex<CriticalSectionRawMutex, [Option<Report>; Wrist::ALL.len()]> = Mutex::new([None; Wrist::ALL.len()]);
+
+/// Whether each cuff is answering, as its code, or `NOT_FITTED`.
+static PRESENCE: [AtomicU8; Wrist::ALL.len()] = [const { AtomicU8::new(NOT_FITTED) }; Wrist::ALL.len()];
+
+/// When each motor on each wrist will stop, in milliseconds of uptime, so the battery gauge knows how many are running.
+static MOTORS_UNTIL: [[AtomicU32; Motor::ALL.len()]; Wrist::ALL.len()] =
+    [const { [const { AtomicU32::new(0) }; Motor::ALL.len()] }; Wrist::ALL.len()];
+
+/// Whether the cuff on `wrist` is answering, or `None` if that wrist has no cuff.
+pub fn presence(wrist: Wrist) -> Option<Presence> {
+    Presence::from_code(PRESENCE[wrist.index()].load(Ordering::Relaxed))
+}
+
+/// The wrists whose cuffs are answering, indexed by `Wrist::index`.
+pub fn present() -> [bool; Wrist::ALL.len()] {
+    Wrist::ALL.map(|wrist| presence(wrist) == Some(Presence::Present))
+}
+
+/// Runs the motors that cue `direction` on the cuffs that are answering (see `haptics::route`). Returns whether
+/// any cuff took it.
+pub async fn cue(direction: Direction, duration_ms: u64) -> bool {
+    let mut taken = false;
+
+    for (wrist, motor) in haptics::route(direction, present()).into_iter().flatten() {
+        match haptic(wrist, motor, duration_ms).await {
+            Ok(()) => taken = true,
+            Err(e) => warn!("The {} cuff did not take the {} cue: {}", wrist.name(), direction.name(), e),
+        }
+    }
+
+    taken
+}
+
+/// Runs `pulses` on every motor of the cuffs that are answering, for a warning that isn't about a direction. Returns
+/// whether any cuff took it.
+pub async fn pulse(pulses: Pulses) -> bool {
+    let mut taken = false;
+
+    for _ in 0..pulses.count {
+        for wrist in Wrist::ALL.into_iter().filter(|wrist| present()[wrist.index()]) {
+            for motor in Motor::ALL {
+                if let Err(e) = haptic(wrist, motor, pulses.on_ms as u64).await {
+                    warn!("The {} cuff did not take the pulse: {}", wrist.name(), e);
+                    break;
+                }
+                taken = true;
+            }
+        }
+
+        Timer::after_millis(pulses.on_ms as u64 + pulses.off_ms as u64).await;
+    }
+
+    taken
+}
+
+/// Tells the cuff on `wrist` to run one of its motors for `duration_ms` milliseconds, as strongly as the performer
+/// has set (see `Config::haptic_intensity`).
+pub async fn haptic(wrist: Wrist, motor: Motor, duration_ms: u64) -> Result<(), Error> {
+    let intensity = crate::config::CONFIG.lock().await.haptic_intensity;
+    let run = Run { motor, duration_ms, intensity };
+
+    transport::to(wrist).await?.send(&run.command()).await?;
+
+    // Clamped so that the wrapping comparison in `motors_running` still works
+    let until = uptime_ms().wrapping_add(duration_ms.min(i32::MAX as u64) as u32);
+    MOTORS_UNTIL[wrist.index()][motor as usize].store(until, Ordering::Relaxed);
+
+    crate::usb::report(Telemetry::Haptic { wrist, motor, duration_ms });
+
+    Ok(())
+}
+
+/// Tells the cuff on `wrist` which power mode the controller is in.
+pub async fn power_mode(wrist: Wrist, mode: Mode) -> Result<(), Error> {
+    transport::to(wrist).await?.send(&mode.command()).await
+}
+
+/// Lets the cuff on `wrist` know the controller is still there, so it doesn't stop its motors.
+async fn keep_alive(wrist: Wrist) -> Result<(), Error> {
+    transport::to(wrist).await?.send(&link::keep_alive()).await
+}
+
+/// Reads one of the status registers of the cuff on `wrist`. Returns `None` if the cuff didn't answer or the answer
+/// was damaged.
+async fn read_register(wrist: Wrist, register: Register) -> Option<Contents> {
+    let mut contents = [0; MAX_CONTENTS_LENGTH];
+    let contents = &mut contents[..register.length()];
+
+    transport::to(wrist).await.ok()?.exchange(&[register.code()], contents).await.ok()?;
+
+    Contents::decode(contents).ok().filter(|contents| contents.register() == register)
+}
+
+/// How many of the cuffs' motors are running, going by the commands that have been sent to them.
+pub fn motors_running() -> u8 {
+    let now = uptime_ms();
+
+    MOTORS_UNTIL.iter().flatten()
+        .filter(|until| (until.load(Ordering::Relaxed).wrapping_sub(now) as i32) > 0)
+        .count() as u8
+}
+
+fn uptime_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+#[embassy_executor::task]
+pub async fn task() {
+    join(read_batteries(), keep_links_alive()).await;
+}
+
+async fn read_batteries() -> ! {
+    let mut ticker = Ticker::every(BATTERY_INTERVAL);
+
+    loop {
+        crate::health::check_in(Task::Cuff);
+        ticker.next().await;
+
+        // The cuffs are asleep too, and their batteries aren't going anywhere
+        if crate::mode::current() == Mode::Sleep {
+            continue;
+        }
+
+        for wrist in Wrist::ALL {
+            // A cuff that isn't there has nothing to say
+            if presence(wrist) != Some(Presence::Present) {
+                continue;
+            }
+
+            let report = match read_register(wrist, Register::Battery).await {
+                Some(Contents::Battery(report)) => report,
+                None => {
+                    warn!("The {} cuff did not report its battery", wrist.name());
+                    crate::crash::note(format_args!("{} cuff did not answer", wrist.name()));
+                    None
+                },
+            };
+
+            if let Some(report) = report {
+                info!("The {} cuff's battery is {} mV, {}%", wrist.name(), report.millivolts, report.percent);
+                crate::usb::report(Telemetry::CuffBattery { source: crate::identity::address(), wrist, report });
+            }
+
+            BATTERIES.lock().await[wrist.index()] = report;
+        }
+    }
+}
+
+/// Sends keep alives in every mode, since a cuff that's asleep still needs to know whether the controller is there,
+/// and keeps track of whether and how quickly each cuff answers them.
+async fn keep_links_alive() -> ! {
+    let mut ticker = Ticker::every(Duration::from_millis(link::INTERVAL_MS));
+    let mut trackers = [Tracker::new(); Wrist::ALL.len()];
+    let mut qualities = [Quality::new(); Wrist::ALL.len()];
+    let mut links: [Option<CuffLink>; Wrist::ALL.len()] = [None; Wrist::ALL.len()];
+
+    loop {
+        ticker.next().await;
+
+        let config = *crate::config::CONFIG.lock().await;
+        let mut recover_bus = false;
+        let mut bus_answered = false;
+
+        for wrist in Wrist::ALL {
+            let tracker = &mut trackers[wrist.index()];
+            let quality = &mut qualities[wrist.index()];
+            let link = config.cuff_link(wrist);
+
+            // A cuff that has been swapped for another, or between the cable and wireless, starts over
+            if link != links[wrist.index()] {
+                links[wrist.index()] = link;
+                *tracker = Tracker::new();
+                *quality = Quality::new();
+                BATTERIES.lock().await[wrist.index()] = None;
+
+                let code = match link {
+                    Some(_) => {
+                        info!("Looking for a cuff on the {} wrist", wrist.name());
+                        Presence::Probing.code()
+                    },
+                    None => {
+                        info!("No longer looking for a cuff on the {} wrist", wrist.name());
+                        NOT_FITTED
+                    },
+                };
+                PRESENCE[wrist.index()].store(code, Ordering::Relaxed);
+            }
+
+            let Some(link) = link else { continue };
+
+            let sent = Instant::now();
+            let (changed, report) = match keep_alive(wrist).await {
+                Ok(()) => {
+                    bus_answered |= matches!(link, CuffLink::Cable(_));
+                    (tracker.answered(), quality.answered(sent.elapsed().as_millis() as u32))
+                },
+                Err(e) => {
+                    // Only logged while the cuff still counts as present, since a missing one would fill the log
+                    if tracker.presence() == Presence::Present {
+                        warn!("The {} cuff did not answer a keep alive: {}", wrist.name(), e);
+                    }
+                    (tracker.failed(), quality.failed())
+                },
+            };
+
+            if tracker.should_recover_bus() {
+                match link {
+                    CuffLink::Cable(_) => recover_bus = true,
+                    // The wireless equivalent of a stuck bus is a connection that's up but not getting through
+                    CuffLink::Wireless(_) => crate::wireless::disconnect(wrist).await,
+                }
+            }
+
+            // A cuff that has never answered probably isn't there, which says nothing about the link to it
+            if let Some(report) = report
+                && tracker.presence() != Presence::Probing
+            {
+                if report.is_poor() {
+                    warn!("The {} cuff missed {} of the last {} keep alives", wrist.name(), report.lost, report.sent);
+                }
+                crate::usb::report(Telemetry::CuffLink { wrist, quality: report });
+            }
+
+            if let Some(presence) = changed {
+                changed_presence(wrist, presence).await;
+            }
+        }
+
+        // Either cabled cuff could be the one holding the bus, and freeing it up is the same either way. It isn't held if
+        // the other one got through though.
+        if recover_bus && !bus_answered {
+            if crate::twi::recover_bus().await {
+                info!("Freed up the two-wire bus");
+            } else {
+                warn!("The two-wire bus is still held after trying to free it up");
+            }
+        }
+    }
+}
+
+async fn changed_presence(wrist: Wrist, presence: Presence) {
+    PRESENCE[wrist.index()].store(presence.code(), Ordering::Relaxed);
+    crate::usb::report(Telemetry::CuffPresence { source: crate::identity::address(), wrist, presence });
+
+    match presence {
+        Presence::Present => {
+            info!("The {} cuff is present", wrist.name());
+            crate::crash::note(format_args!("{} cuff present", wrist.name()));
+
+            // The cuff might have restarted while it was gone, so it's caught up on the mode
+            let mode = crate::mode::current();
+            if power_mode(wrist, mode).await.is_err() {
+                warn!("The {} cuff did not take the {} mode", wrist.name(), mode.name());
+            }
+        },
+        Presence::Lost => {
+            warn!("The {} cuff is lost", wrist.name());
+            crate::crash::note(format_args!("{} cuff lost", wrist.name()));
+            // So the last report doesn't go out as if it were current
+            BATTERIES.lock().await[wrist.index()] = None;
+        },
+        Presence::Probing => {},
+    }
+}
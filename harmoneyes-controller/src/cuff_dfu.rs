@@ -0,0 +1,203 @@
+//! # Cuff firmware updates
+//!
+//! Updates for the cuff arrive from the console over USB just like the controller's own, but rather than being
+//! written to flash here they're passed on to the cuff a frame at a time over the two-wire interface (see
+//! `harmoneyes_core::transfer`). The cuff has no key of its own, so the signature is checked here against a digest
+//! worked out as the image goes past, and the cuff is only told to swap the image in once it checks out.
+//!
+//! With a cuff on each wrist, both are passed the same image side by side and the update fails if either does. Each
+//! cuff checks the image against its CRC and swaps it in as soon as it's told to finish, so they're told one after
+//! the other and a cuff that fails at that point can leave the other already restarting into the new image. That's
+//! reported to the console as its own failure, and sending the update again brings the pair back into step. Wireless
+//! cuffs aren't passed updates, and have to be plugged in to the cable to be updated.
+
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use harmoneyes_core::{config::CuffLink, crc::Crc32, haptics::Wrist, transfer::{Fault, Message, Status, DATA_LENGTH}, update::{Signature, PUBLIC_KEY_LENGTH}};
+use salty::{PublicKey, Sha512};
+
+use crate::dfu::{Error, PUBLIC_KEY};
+
+/// How many times a frame is sent without the cuff getting any further before giving up on it.
+const ATTEMPTS: u32 = 5;
+
+static UPDATE: Mutex<CriticalSectionRawMutex, Option<Update>> = Mutex::new(None);
+
+/// An update that is partway through being passed on.
+struct Update {
+    /// The wrists whose cuffs are being updated.
+    wrists: [bool; Wrist::ALL.len()],
+    size: u32,
+    /// How much of the image the cuff has taken so far.
+    received: u32,
+    /// The digest the signature is checked against.
+    digest: Sha512,
+    /// The checksum the cuff checks what it wrote against.
+    crc: Crc32
+}
+
+/// Starts passing an update on to every cuff that's answering, throwing away any earlier one that didn't finish.
+pub async fn begin(size: u32) -> Result<(), Error> {
+    if PUBLIC_KEY == [0; PUBLIC_KEY_LENGTH] {
+        warn!("Refusing a cuff update since the firmware was built without an update key");
+        return Err(Error::Rejected);
+    }
+
+    let mut update = UPDATE.lock().await;
+    *update = None;
+
+    // Updates only go over the cable, where the cuff can hold the clock while it writes to flash
+    let config = *crate::config::CONFIG.lock().await;
+    let present = crate::cuff::present();
+    let wrists = Wrist::ALL.map(|wrist| present[wrist.index()] && matches!(config.cuff_link(wrist), Some(CuffLink::Cable(_))));
+    if wrists == [false; Wrist::ALL.len()] {
+        warn!("There's no cabled cuff to pass the update on to");
+        return Err(Error::Unreachable);
+    }
+
+    for wrist in updating(wrists) {
+        match send(wrist, &Message::Begin { size }).await? {
+            Status::Receiving { received: 0 } => {},
+            status => return Err(refused(wrist, status)),
+        }
+
+        info!("Passing a firmware update of {} bytes on to the {} cuff", size, wrist.name());
+    }
+
+    *update = Some(Update { wrists, size, received: 0, digest: Sha512::new(), crc: Crc32::new() });
+
+    Ok(())
+}
+
+/// Passes the next piece of the image on to the cuffs, resending any frame that doesn't make it.
+pub async fn write(offset: u32, data: &[u8]) -> Result<(), Error> {
+    let mut guard = UPDATE.lock().await;
+    let update = guard.as_mut().ok_or(Error::Rejected)?;
+
+    if offset != update.received || offset + data.len() as u32 > update.size {
+        return Err(Error::Rejected);
+    }
+
+    for wrist in updating(update.wrists) {
+        if let Err(e) = pass_on(wrist, offset, data, update.size).await {
+            *guard = None;
+            return Err(e);
+        }
+    }
+
+    update.digest.update(data);
+    update.crc.update(data);
+    update.received = offset + data.len() as u32;
+
+    Ok(())
+}
+
+/// Passes a piece of the image on to the cuff on `wrist`, a frame at a time.
+async fn pass_on(wrist: Wrist, offset: u32, data: &[u8], size: u32) -> Result<(), Error> {
+    let end = offset + data.len() as u32;
+    let mut position = offset;
+    let mut attempts = 0;
+
+    while position < end {
+        let start = (position - offset) as usize;
+        let frame = &data[start..data.len().min(start + DATA_LENGTH)];
+
+        match crate::twi::transfer(wrist, &Message::Data { offset: position, data: frame }).await {
+            // The cuff says how far it has got, which is past this frame unless the frame was lost on the way
+            Some(Status::Receiving { received }) if received > position && received <= end => {
+                position = received;
+                attempts = 0;
+                continue;
+            },
+            Some(Status::Receiving { received }) if received == position => {},
+            None => {},
+            Some(status) => return Err(refused(wrist, status)),
+        }
+
+        attempts += 1;
+        if attempts >= ATTEMPTS {
+            warn!("The {} cuff stopped taking the update at {} of {} bytes", wrist.name(), position, size);
+            return Err(Error::Unreachable);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the signature of the finished image and, if it's valid, tells the cuffs to restart into it.
+pub async fn finish(signature: &Signature) -> Result<(), Error> {
+    let Some(update) = UPDATE.lock().await.take() else {
+        return Err(Error::Rejected);
+    };
+
+    if update.received != update.size {
+        warn!("The cuff update finished after only {} of {} bytes", update.received, update.size);
+        return Err(Error::Rejected);
+    }
+
+    // The same check the bootloader library makes of the controller's own images
+    let digest = update.digest.finalize();
+    let verified = PublicKey::try_from(&PUBLIC_KEY).and_then(|key| key.verify(&digest, &salty::Signature::from(signature)));
+    if verified.is_err() {
+        warn!("The cuff update failed verification");
+        return Err(Error::Rejected);
+    }
+
+    let crc = update.crc.finish();
+    let mut accepted = false;
+    for wrist in updating(update.wrists) {
+        let result = match send(wrist, &Message::Finish { crc }).await {
+            Ok(Status::Complete) => Ok(()),
+            Ok(status) => Err(refused(wrist, status)),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                info!("The {} cuff accepted the update and is restarting into it", wrist.name());
+                accepted = true;
+            },
+            Err(_) if accepted => {
+                warn!("The {} cuff didn't take the update after the other did, so they're on different images", wrist.name());
+                return Err(Error::Partial);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// The wrists in `wrists` that are set, in order.
+fn updating(wrists: [bool; Wrist::ALL.len()]) -> impl Iterator<Item = Wrist> {
+    Wrist::ALL.into_iter().filter(move |wrist| wrists[wrist.index()])
+}
+
+/// Sends a frame that doesn't carry any of the image, which the cuff is fine to be sent more than once.
+async fn send(wrist: Wrist, message: &Message<'_>) -> Result<Status, Error> {
+    for _ in 0..ATTEMPTS {
+        if let Some(status) = crate::twi::transfer(wrist, message).await {
+            return Ok(status);
+        }
+    }
+
+    warn!("The {} cuff stopped answering during an update", wrist.name());
+    Err(Error::Unreachable)
+}
+
+/// Works out why the update failed from a status the cuff shouldn't have answered with.
+fn refused(wrist: Wrist, status: Status) -> Error {
+    match status {
+        Status::Failed(fault) => {
+            warn!("The {} cuff refused the update: {}", wrist.name(), fault.description());
+            match fault {
+                Fault::Flash => Error::Flash,
+                _ => Error::Rejected,
+            }
+        },
+        _ => {
+            warn!("The {} cuff is out of step with the update", wrist.name());
+            Error::Rejected
+        },
+    }
+}
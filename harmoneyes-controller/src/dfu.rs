@@ -0,0 +1,231 @@
+//! # Firmware updates
+//!
+//! Updates arrive from the console over USB and are written to the `DFU` partition a page at a time. Once the whole
+//! image is there its signature is checked against the public key built into the firmware (see `build.rs`), and if
+//! it's valid the bootloader is told to swap it in on the next reset.
+//!
+//! A freshly swapped in image runs on trial. The bootloader starts the watchdog before jumping to the firmware, and
+//! this task is what keeps it from firing, so if the new image panics or hangs before it has run for
+//! `CONFIRM_AFTER` the device resets and the bootloader swaps the old image back.
+//!
+//! The watchdog also catches tasks that hang. It's only fed while every task is checking in (see `health`), and
+//! once one stops the task is written down for the crash log and the watchdog is left to reset the controller.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::peripheral::SCB;
+use defmt::{info, warn, Debug2Format};
+use embassy_boot_nrf::{AlignedBuffer, FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::{peripherals::WDT, wdt::{self, HaltConfig, SleepConfig, Watchdog}};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use harmoneyes_core::update::{Signature, PUBLIC_KEY_LENGTH};
+
+use crate::flash::{self, Region};
+
+/// The key that update images have to be signed with, for both the controller and the cuff. All zeroes when the
+/// firmware was built without one, which turns updates off.
+pub static PUBLIC_KEY: [u8; PUBLIC_KEY_LENGTH] = *include_bytes!(concat!(env!("OUT_DIR"), "/update.pub"));
+
+/// How long a new image has to run before it confirms itself.
+const CONFIRM_AFTER: Duration = Duration::from_secs(30);
+
+/// How often the watchdog is fed. This has to be well within the timeout the bootloader sets.
+const PET_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The size of an nRF52840 flash page.
+const PAGE_SIZE: usize = 4096;
+
+/// Whether the running image is new and hasn't confirmed itself yet.
+pub static TRIAL: AtomicBool = AtomicBool::new(false);
+
+static UPDATE: Mutex<CriticalSectionRawMutex, Option<Update>> = Mutex::new(None);
+
+/// Asks the task to restart into the new image once the console has been told it was accepted.
+static RESTART: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The image is too large, arrived out of order, or has a bad signature.
+    Rejected,
+    /// The image couldn't be written to flash.
+    Flash,
+    /// The cuff stopped answering while an update was being passed on to it.
+    Unreachable,
+    /// One cuff restarted into the update but the other refused it or stopped answering, leaving them on different
+    /// images.
+    Partial
+}
+
+/// An update that is partway through arriving.
+struct Update {
+    size: u32,
+    /// How much of the image has arrived so far.
+    received: u32,
+    /// The page currently being filled in, which is written out once it's full.
+    page: [u8; PAGE_SIZE]
+}
+
+#[embassy_executor::task]
+pub async fn task(wdt: WDT) -> ! {
+    // Carry on with the watchdog the bootloader started, or start one if the firmware was flashed without it
+    let config = wdt::Config::try_new(&wdt).unwrap_or_else(watchdog_config);
+    let (_watchdog, [mut handle]) = match Watchdog::try_new(wdt, config) {
+        Ok(watchdog) => watchdog,
+        Err(_) => panic!("Failed to take over the watchdog"),
+    };
+
+    let mut aligned = AlignedBuffer([0; 4]);
+    let mut state = FirmwareState::new(flash::bootloader_state(), &mut aligned.0);
+
+    match state.get_state().await {
+        Ok(State::Swap) => {
+            info!("Running a new firmware image on trial");
+            TRIAL.store(true, Ordering::Relaxed);
+        },
+        Ok(_) => {},
+        Err(e) => warn!("Failed to read the bootloader state: {}", Debug2Format(&e)),
+    }
+
+    let confirm_at = Instant::now() + CONFIRM_AFTER;
+    let mut stalled = None;
+
+    loop {
+        if stalled.is_none() {
+            stalled = crate::health::stalled();
+
+            match stalled {
+                Some(task) => {
+                    warn!("The {} task stalled, so the watchdog will reset the controller", task.name());
+                    crate::crash::record(format_args!("{} task stalled", task.name()));
+                },
+                None => handle.pet(),
+            }
+        }
+
+        if TRIAL.load(Ordering::Relaxed) && Instant::now() >= confirm_at {
+            match state.mark_booted().await {
+                Ok(()) => {
+                    info!("Confirmed the new firmware image");
+                    TRIAL.store(false, Ordering::Relaxed);
+                },
+                Err(e) => warn!("Failed to confirm the new firmware image: {}", Debug2Format(&e)),
+            }
+        }
+
+        if let Either::Second(()) = select(Timer::after(PET_INTERVAL), RESTART.wait()).await {
+            // Give the response time to reach the console
+            Timer::after_millis(500).await;
+            info!("Restarting into the new firmware image");
+            SCB::sys_reset();
+        }
+    }
+}
+
+/// Starts an update, throwing away any earlier one that didn't finish.
+pub async fn begin(size: u32) -> Result<(), Error> {
+    if PUBLIC_KEY == [0; PUBLIC_KEY_LENGTH] {
+        warn!("Refusing an update since the firmware was built without an update key");
+        return Err(Error::Rejected);
+    }
+
+    let mut update = UPDATE.lock().await;
+    *update = None;
+
+    // The bootloader needs a page of the DFU partition to itself while swapping
+    let dfu = flash::dfu();
+    if size == 0 || size > dfu.size() - PAGE_SIZE as u32 {
+        warn!("Refusing an update of {} bytes", size);
+        return Err(Error::Rejected);
+    }
+
+    let mut aligned = AlignedBuffer([0; 4]);
+    updater(dfu, &mut aligned.0).prepare_update().await.map_err(|e| {
+        warn!("Failed to erase the update partition: {}", Debug2Format(&e));
+        Error::Flash
+    })?;
+
+    info!("Receiving a firmware update of {} bytes", size);
+    crate::crash::note(format_args!("Update of {} bytes", size));
+    *update = Some(Update { size, received: 0, page: [0xFF; PAGE_SIZE] });
+
+    Ok(())
+}
+
+/// Adds the next piece of the image, writing out each page once it fills up.
+pub async fn write(offset: u32, data: &[u8]) -> Result<(), Error> {
+    let mut guard = UPDATE.lock().await;
+    let update = guard.as_mut().ok_or(Error::Rejected)?;
+
+    if offset != update.received || offset + data.len() as u32 > update.size {
+        return Err(Error::Rejected);
+    }
+
+    let mut data = data;
+
+    while !data.is_empty() {
+        let start = update.received as usize % PAGE_SIZE;
+        let len = data.len().min(PAGE_SIZE - start);
+
+        update.page[start..start + len].copy_from_slice(&data[..len]);
+        update.received += len as u32;
+        data = &data[len..];
+
+        if update.received as usize % PAGE_SIZE == 0 || update.received == update.size {
+            let page_start = (update.received as usize - 1) / PAGE_SIZE * PAGE_SIZE;
+
+            let mut aligned = AlignedBuffer([0; 4]);
+            let result = updater(flash::dfu(), &mut aligned.0).write_firmware(page_start, &update.page).await;
+
+            update.page = [0xFF; PAGE_SIZE];
+
+            if let Err(e) = result {
+                warn!("Failed to write the update: {}", Debug2Format(&e));
+                *guard = None;
+                return Err(Error::Flash);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the signature of the finished image and, if it's valid, restarts into it.
+pub async fn finish(signature: &Signature) -> Result<(), Error> {
+    let Some(update) = UPDATE.lock().await.take() else {
+        return Err(Error::Rejected);
+    };
+
+    if update.received != update.size {
+        warn!("The update finished after only {} of {} bytes", update.received, update.size);
+        return Err(Error::Rejected);
+    }
+
+    let mut aligned = AlignedBuffer([0; 4]);
+    if let Err(e) = updater(flash::dfu(), &mut aligned.0).verify_and_mark_updated(&PUBLIC_KEY, signature, update.size).await {
+        warn!("The update failed verification: {}", Debug2Format(&e));
+        return Err(Error::Rejected);
+    }
+
+    info!("The update was verified");
+    RESTART.signal(());
+
+    Ok(())
+}
+
+fn updater<'a>(dfu: Region, aligned: &'a mut [u8]) -> FirmwareUpdater<'a, Region, Region> {
+    FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state: flash::bootloader_state() }, aligned)
+}
+
+/// The same watchdog settings that the bootloader uses.
+fn watchdog_config() -> wdt::Config {
+    let mut config = wdt::Config::default();
+
+    // The watchdog runs off the 32.768 kHz low frequency clock
+    config.timeout_ticks = 32768 * 5;
+    config.action_during_sleep = SleepConfig::RUN;
+    config.action_during_debug_halt = HaltConfig::PAUSE;
+
+    config
+}
@@ -0,0 +1,95 @@
+//! # Flash
+//!
+//! The softdevice only hands out one flash handle, so it's shared between the settings and firmware updates, each
+//! getting a partition of flash whose bounds come from `memory.x`.
+
+use embassy_embedded_hal::flash::partition::Partition;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, once_lock::OnceLock};
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use nrf_softdevice::{Flash, Softdevice};
+
+static FLASH: OnceLock<Mutex<CriticalSectionRawMutex, SharedFlash>> = OnceLock::new();
+
+/// A partition of flash. Addresses are relative to its start.
+pub type Region = Partition<'static, CriticalSectionRawMutex, SharedFlash>;
+
+/// Takes the flash handle from the softdevice. This has to be called before any of the partitions are used.
+pub fn initialize(sd: &'static Softdevice) {
+    let _ = FLASH.init(Mutex::new(SharedFlash(Flash::take(sd))));
+}
+
+/// Where the settings are kept.
+pub fn storage() -> Region {
+    unsafe extern "C" {
+        static __storage_start: u8;
+        static __storage_end: u8;
+    }
+
+    // SAFETY: Only the addresses of the linker symbols are used, they're never read through
+    unsafe { region(&raw const __storage_start, &raw const __storage_end) }
+}
+
+/// Where a firmware update is written before the bootloader swaps it in.
+pub fn dfu() -> Region {
+    unsafe extern "C" {
+        static __bootloader_dfu_start: u8;
+        static __bootloader_dfu_end: u8;
+    }
+
+    // SAFETY: Only the addresses of the linker symbols are used, they're never read through
+    unsafe { region(&raw const __bootloader_dfu_start, &raw const __bootloader_dfu_end) }
+}
+
+/// Where the bootloader keeps track of whether an update is waiting or on trial.
+pub fn bootloader_state() -> Region {
+    unsafe extern "C" {
+        static __bootloader_state_start: u8;
+        static __bootloader_state_end: u8;
+    }
+
+    // SAFETY: Only the addresses of the linker symbols are used, they're never read through
+    unsafe { region(&raw const __bootloader_state_start, &raw const __bootloader_state_end) }
+}
+
+fn region(start: *const u8, end: *const u8) -> Region {
+    let flash = FLASH.try_get().expect("The flash was used before it was initialized");
+    let (start, end) = (start as u32, end as u32);
+
+    Partition::new(flash, start, end - start)
+}
+
+/// The softdevice's flash handle isn't `Send`, but everything runs on the one thread mode executor so it's never
+/// actually used from two places at once.
+pub struct SharedFlash(Flash);
+
+// SAFETY: See above, and every use goes through the mutex anyway
+unsafe impl Send for SharedFlash {}
+
+impl ErrorType for SharedFlash {
+    type Error = <Flash as ErrorType>::Error;
+}
+
+impl ReadNorFlash for SharedFlash {
+    const READ_SIZE: usize = Flash::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl NorFlash for SharedFlash {
+    const WRITE_SIZE: usize = Flash::WRITE_SIZE;
+    const ERASE_SIZE: usize = Flash::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes).await
+    }
+}
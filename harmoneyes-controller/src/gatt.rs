@@ -0,0 +1,241 @@
+//! # GATT server
+//!
+//! Lets a phone or laptop look after the controller over Bluetooth LE instead of the USB cable (see
+//! `harmoneyes_core::gatt`). While nobody is connected and the controller isn't asleep, `ble` advertises the console
+//! service in between mesh messages, and a client that connects is served here: its requests are answered by the same
+//! code as the console's (see `usb::handle_request`) and notified in fragments, telemetry is notified while it has asked for it and the battery
+//! level is kept up to date.
+//!
+//! Only one client is served at a time, and the softdevice is set up without any security, so the link isn't
+//! encrypted. That's why only the requests in `harmoneyes_core::gatt::admits` are taken.
+
+use core::{pin::pin, sync::atomic::{AtomicBool, Ordering}};
+
+use defmt::{info, warn};
+use embassy_futures::select::select3;
+use embassy_sync::{blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex}, channel::Channel, signal::Signal};
+use embassy_time::{Duration, Ticker, Timer};
+use futures::future::{select, Either};
+use harmoneyes_core::{constants, gatt::{DEFAULT_MTU, MAX_REQUEST_LENGTH, MAX_RESPONSE_LENGTH, MAX_TELEMETRY_LENGTH}, power::Mode, protocol::Response, telemetry::Event};
+use heapless::Vec;
+use nrf_softdevice::{ble::{advertisement_builder::{Flag, LegacyAdvertisementBuilder, ServiceList}, gatt_server, peripheral, Connection}, Softdevice};
+use static_cell::StaticCell;
+
+/// How often the battery level is passed on to a connected client.
+const BATTERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The longest Device Information string, which is the serial number.
+const TEXT_LENGTH: usize = 32;
+
+static SERVER: StaticCell<Server> = StaticCell::new();
+
+/// Connections made while advertising, for the task to serve.
+static CONNECTIONS: Channel<CriticalSectionRawMutex, Connection, 1> = Channel::new();
+
+/// Whether a client is connected, in which case the console service isn't advertised.
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static DISCONNECTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the client has notifications on for the telemetry characteristic.
+static STREAMING: AtomicBool = AtomicBool::new(false);
+static TELEMETRY: Channel<CriticalSectionRawMutex, Event, 8> = Channel::new();
+
+#[nrf_softdevice::gatt_service(uuid = "180a")]
+pub struct DeviceInformationService {
+    #[characteristic(uuid = "2a29", read)]
+    manufacturer: Vec<u8, TEXT_LENGTH>,
+    #[characteristic(uuid = "2a24", read)]
+    model: Vec<u8, TEXT_LENGTH>,
+    #[characteristic(uuid = "2a25", read)]
+    serial: Vec<u8, TEXT_LENGTH>,
+    #[characteristic(uuid = "2a26", read)]
+    firmware_revision: Vec<u8, TEXT_LENGTH>
+}
+
+#[nrf_softdevice::gatt_service(uuid = "180f")]
+pub struct BatteryService {
+    #[characteristic(uuid = "2a19", read, notify)]
+    battery_level: u8
+}
+
+// The UUIDs are those in `harmoneyes_core::gatt`, written out since the macro only takes literals
+#[nrf_softdevice::gatt_service(uuid = "6e4a1001-8b5d-4c3f-9a52-4861726d6f6e")]
+pub struct ConsoleService {
+    #[characteristic(uuid = "6e4a1002-8b5d-4c3f-9a52-4861726d6f6e", write)]
+    request: Vec<u8, MAX_REQUEST_LENGTH>,
+    #[characteristic(uuid = "6e4a1003-8b5d-4c3f-9a52-4861726d6f6e", read, notify)]
+    response: Vec<u8, MAX_RESPONSE_LENGTH>,
+    #[characteristic(uuid = "6e4a1004-8b5d-4c3f-9a52-4861726d6f6e", notify)]
+    telemetry: Vec<u8, MAX_TELEMETRY_LENGTH>
+}
+
+#[nrf_softdevice::gatt_server]
+pub struct Server {
+    device_information: DeviceInformationService,
+    battery: BatteryService,
+    console: ConsoleService
+}
+
+/// Registers the services, which has to happen before the softdevice is shared with the rest of the firmware.
+pub fn initialize(sd: &mut Softdevice) -> &'static Server {
+    let server = SERVER.init(Server::new(sd).expect("Failed to register the GATT services"));
+
+    let information = &server.device_information;
+    let _ = information.manufacturer_set(&text(constants::MANUFACTURER));
+    let _ = information.model_set(&text(constants::controller::NAME));
+    let _ = information.serial_set(&text(crate::identity::serial()));
+    let _ = information.firmware_revision_set(&text(env!("CARGO_PKG_VERSION")));
+
+    server
+}
+
+fn text(value: &str) -> Vec<u8, TEXT_LENGTH> {
+    let value = value.as_bytes();
+    Vec::from_slice(&value[..value.len().min(TEXT_LENGTH)]).unwrap_or_default()
+}
+
+/// Whether a client wants telemetry events, so that `usb::report` only builds them when somebody does.
+pub fn is_streaming() -> bool {
+    STREAMING.load(Ordering::Relaxed)
+}
+
+/// Queues a telemetry event for the client if it has asked for them, dropping it if the client can't keep up.
+pub fn report(event: &Event) {
+    if is_streaming() {
+        let _ = TELEMETRY.try_send(event.clone());
+    }
+}
+
+/// Advertises the console service for a client to connect to, for as long as nobody is connected and the controller
+/// is awake. Called by `ble` in between mesh messages, since there's only one advertising set.
+pub async fn advertise(sd: &'static Softdevice) -> ! {
+    loop {
+        let mode = crate::mode::current();
+
+        if CONNECTED.load(Ordering::Relaxed) || mode == Mode::Sleep {
+            DISCONNECTED.reset();
+            if CONNECTED.load(Ordering::Relaxed) {
+                select(pin!(DISCONNECTED.wait()), pin!(crate::mode::changed_from(mode))).await;
+            } else {
+                crate::mode::changed_from(mode).await;
+            }
+            continue;
+        }
+
+        let adv_data = LegacyAdvertisementBuilder::new()
+            .flags(&[Flag::GeneralDiscovery, Flag::LE_Only])
+            .services_128(ServiceList::Complete, &[harmoneyes_core::gatt::SERVICE_UUID.to_le_bytes()])
+            .build();
+        let scan_data = LegacyAdvertisementBuilder::new()
+            .full_name(crate::identity::serial())
+            .build();
+        let ad = peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data: &adv_data, scan_data: &scan_data };
+
+        let advertise = pin!(peripheral::advertise_connectable(sd, ad, &peripheral::Config::default()));
+        match select(advertise, pin!(crate::mode::changed_from(mode))).await {
+            Either::First(Ok(connection)) => {
+                CONNECTED.store(true, Ordering::Relaxed);
+                // The task only ever waits on one connection at a time, so there's always room
+                let _ = CONNECTIONS.try_send(connection);
+            },
+            Either::First(Err(e)) => {
+                warn!("Could not advertise the console service: {}", e);
+                Timer::after_secs(1).await;
+            },
+            Either::Second(_) => {},
+        }
+    }
+}
+
+/// Serves each client that connects until it disconnects.
+#[embassy_executor::task]
+pub async fn task(server: &'static Server) {
+    loop {
+        let connection = CONNECTIONS.receive().await;
+        info!("A GATT client connected");
+
+        serve(server, &connection).await;
+
+        STREAMING.store(false, Ordering::Relaxed);
+        TELEMETRY.clear();
+        CONNECTED.store(false, Ordering::Relaxed);
+        DISCONNECTED.signal(());
+        info!("The GATT client disconnected");
+    }
+}
+
+async fn serve(server: &Server, connection: &Connection) {
+    // Events are handled synchronously, so requests are answered alongside
+    let requests: Channel<NoopRawMutex, Vec<u8, MAX_REQUEST_LENGTH>, 2> = Channel::new();
+
+    let run = gatt_server::run(connection, server, |event| match event {
+        ServerEvent::Console(ConsoleServiceEvent::RequestWrite(data)) => {
+            if requests.try_send(data).is_err() {
+                warn!("Dropped a GATT request that came in before the last was answered");
+            }
+        },
+        ServerEvent::Console(ConsoleServiceEvent::TelemetryCccdWrite { notifications }) => {
+            STREAMING.store(notifications, Ordering::Relaxed);
+            if !notifications {
+                TELEMETRY.clear();
+            }
+        },
+        _ => {},
+    });
+
+    select3(run, answer(server, connection, &requests), notify(server, connection)).await;
+}
+
+/// Answers each request on the response characteristic.
+async fn answer(server: &Server, connection: &Connection, requests: &Channel<NoopRawMutex, Vec<u8, MAX_REQUEST_LENGTH>, 2>) -> ! {
+    loop {
+        let request = requests.receive().await;
+
+        let response = match harmoneyes_core::gatt::decode_request(&request) {
+            Ok(request) => crate::usb::handle_request(request).await,
+            Err(failure) => Response::Failed(failure),
+        };
+
+        let mut message = [0; MAX_RESPONSE_LENGTH];
+        let len = response.encode(&mut message).expect("Response did not fit in the characteristic");
+        let value = Vec::from_slice(&message[..len]).unwrap_or_default();
+
+        // The client can still read the response if it doesn't have notifications on
+        let _ = server.console.response_set(&value);
+
+        // Fragmented to fit the MTU every client starts with, which doesn't fit the longer responses whole
+        for fragment in harmoneyes_core::gatt::fragments(&message[..len], DEFAULT_MTU) {
+            let mut notification = [0; DEFAULT_MTU];
+            let Ok(len) = fragment.encode(&mut notification) else { break };
+            let value = Vec::from_slice(&notification[..len]).unwrap_or_default();
+
+            if server.console.response_notify(connection, &value).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Notifies telemetry events as they come in, and the battery level every so often.
+async fn notify(server: &Server, connection: &Connection) -> ! {
+    let mut ticker = Ticker::every(BATTERY_INTERVAL);
+
+    loop {
+        match select(pin!(TELEMETRY.receive()), pin!(ticker.next())).await {
+            Either::First(event) => {
+                let mut message = [0; MAX_TELEMETRY_LENGTH];
+                let len = event.encode(&mut message).expect("Event did not fit in the characteristic");
+                let value = Vec::from_slice(&message[..len]).unwrap_or_default();
+
+                let _ = server.console.telemetry_notify(connection, &value);
+            },
+            Either::Second(()) => {
+                if let Some(reading) = *crate::bat::BATTERY.lock().await {
+                    let _ = server.battery.battery_level_set(&reading.percent);
+                    // Fails if the client doesn't have notifications on, and it can still read the level
+                    let _ = server.battery.battery_level_notify(connection, &reading.percent);
+                }
+            },
+        }
+    }
+}
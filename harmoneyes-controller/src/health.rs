@@ -0,0 +1,26 @@
+//! # Task health
+//!
+//! The tasks that keep the controller doing its job check in here as they go (see `harmoneyes_core::health`), and
+//! the watchdog is only fed while every one of them has (see `dfu`). A stalled task is written down for the crash
+//! log before the watchdog resets the controller, so the console can tell which one it was.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use harmoneyes_core::health::{Health, Task};
+
+static HEALTH: Mutex<CriticalSectionRawMutex, RefCell<Health>> = Mutex::new(RefCell::new(Health::new()));
+
+pub fn check_in(task: Task) {
+    HEALTH.lock(|health| health.borrow_mut().check_in(task, Instant::now().as_millis()));
+}
+
+/// Stops expecting `task` to check in until it next does, while it waits on something outside its control.
+pub fn park(task: Task) {
+    HEALTH.lock(|health| health.borrow_mut().park(task));
+}
+
+pub fn stalled() -> Option<Task> {
+    HEALTH.lock(|health| health.borrow().stalled(Instant::now().as_millis()))
+}
@@ -0,0 +1,23 @@
+//! The identity of this controller, worked out from the device ID that Nordic programs into every nRF52840.
+
+use embassy_sync::once_lock::OnceLock;
+use harmoneyes_core::{constants::controller::SERIAL_PREFIX, identity::{self, Serial}};
+
+static SERIAL: OnceLock<Serial> = OnceLock::new();
+
+/// The 64 bit device ID from the factory information configuration registers.
+pub fn device_id() -> u64 {
+    let ficr = embassy_nrf::pac::FICR;
+
+    (ficr.deviceid(1).read() as u64) << 32 | ficr.deviceid(0).read() as u64
+}
+
+/// The serial number reported over USB and to the console.
+pub fn serial() -> &'static str {
+    SERIAL.get_or_init(|| identity::serial(SERIAL_PREFIX, device_id())).as_str()
+}
+
+/// The address used on the bluetooth mesh and as the ultra-wide band short address.
+pub fn address() -> u16 {
+    identity::short_address(device_id())
+}
@@ -10,39 +10,57 @@ use panic_probe as _;
 
 use defmt::info;
 use embassy_executor::Spawner;
-use embassy_nrf::twim::Error;
 use embassy_time::{Duration, Ticker};
+use harmoneyes_core::{haptics::Direction, power::Mode as PowerMode, status::State};
 
 use bat::BATTERY;
 
 mod bat;
+mod config;
+mod crash;
+mod cuff;
+mod cuff_dfu;
+mod dfu;
+mod flash;
+mod health;
+mod identity;
+mod mode;
+mod power;
+mod transport;
 mod twi;
 mod usb;
 mod uwb;
 mod softdevice;
+mod status;
 mod coord;
 mod ws;
 mod ble;
 mod rng;
+mod wireless;
+mod gatt;
 
 /// In the release environment, the end user is not going to be running the device with a debug probe,
-/// so this function serves as an alternate panic handler that will turn on the microcontroller's red led
-/// to indicate that something went wrong and the program is no longer running as normal.
+/// so this function serves as an alternate panic handler that writes down what went wrong for the crash
+/// log (see `crash`) and restarts the controller, so that it's back to work in a moment instead of
+/// sitting dead until someone power cycles it.
 #[cfg(not(debug_assertions))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    use nrf52840_hal as hal;
+    match info.location() {
+        Some(location) => crash::record(format_args!("{}:{}: {}", location.file(), location.line(), info.message())),
+        None => crash::record(format_args!("{}", info.message())),
+    }
 
-    // SAFETY?: We've panicked already, what more can go wrong?
-    let p = unsafe { hal::pac::Peripherals::steal() };
-    
-    let p1 = hal::gpio::p1::Parts::new(p.P1);
-    // Turn the red LED on.
-    p1.p1_15.into_push_pull_output(hal::gpio::Level::High);
+    cortex_m::peripheral::SCB::sys_reset();
+}
 
-    loop {
-        cortex_m::asm::bkpt();
-    }
+/// A hard fault never gets as far as the panic handler, so it's written down for the crash log here instead.
+#[cfg(not(debug_assertions))]
+#[cortex_m_rt::exception]
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    crash::record(format_args!("hard fault at {:#010x}", frame.pc()));
+
+    cortex_m::peripheral::SCB::sys_reset();
 }
 
 #[embassy_executor::main]
@@ -51,10 +69,26 @@ async fn main(spawner: Spawner) -> ! {
     info!("Initializing Embassy");
     let p = embassy_nrf::init(embassy_config());
 
+    // Pick up any crash from before the last reset while the reset reason can still be read
+    crash::initialize();
+    info!("Controller {} has address {:04x}", identity::serial(), identity::address());
+
+    // Spawn the status LED task first, so there's something to see while everything else starts
+    info!("Spawning status LED task");
+    status::set(State::Booting);
+    spawner.must_spawn(status::task(
+        p.PWM0,
+        p.P0_16
+    ));
+
     // Initialize the softdevice
     info!("Initializing softdevice");
     softdevice::initialize(&spawner).await;
 
+    // Spawn the firmware update task, which also keeps the watchdog fed
+    info!("Spawning firmware update task");
+    spawner.must_spawn(dfu::task(p.WDT));
+
     // Spawn the ultra-wide band task
     info!("Spawning ultra-wide band task");
     spawner.must_spawn(uwb::task(
@@ -86,17 +120,22 @@ async fn main(spawner: Spawner) -> ! {
         p.P0_29
     ));
 
+    // Spawn the power mode task
+    info!("Spawning power mode task");
+    spawner.must_spawn(mode::task(p.P1_02));
+
+    // Spawn the cuff monitor task
+    info!("Spawning cuff monitor task");
+    spawner.must_spawn(cuff::task());
+
     // Spawn the USB task
     info!("Spawning USB task");
     spawner.must_spawn(usb::task(
         p.USBD
     ));
 
-    info!("Spawning Neopixel task");
-    spawner.must_spawn(ws::task(
-        p.PWM0,
-        p.P0_16
-    ));
+    status::clear(State::Booting);
+    crash::note(format_args!("Started"));
 
     // A ticker that every 5 seconds will update the color of the cuff according to the battery percentage
 
@@ -105,27 +144,14 @@ async fn main(spawner: Spawner) -> ! {
     loop {
         ticker.next().await;
 
-        let interp = (255.0 * BATTERY.lock().await.as_ref().map_or(0.0, |bat| { bat.as_ratio() })) as u64;
-
-        let mut command: [u8; 9] = [0; 9];
-        command[1..9].copy_from_slice(&u64::to_le_bytes(interp));
-
-        command[0] = 0x09; // Code for front
-
-        match twi::DRIVER.lock().await
-            .get_mut().expect("Two-wire interface driver is not initialized") // SAFETY: We called twi::initialize above
-            .write(harmoneyes_core::constants::cuff::I2C_ADDRESS as u8, &command).await {
-                Err(Error::TxBufferTooLong) => { info!("Transmit buffer was too long") },
-                Err(Error::RxBufferTooLong) => { info!("Receive buffer was too long") },
-                Err(Error::Transmit) => { info!("Data transmission failed") },
-                Err(Error::Receive) => { info!("Data reception failed") },
-                Err(Error::BufferNotInRAM) => { info!("Buffer not in RAM") },
-                Err(Error::AddressNack) => { info!("Address did not acknowledge") },
-                Err(Error::DataNack) => { info!("No acknowledge after data sent") },
-                Err(Error::Overrun) => { info!("Overrun") },
-                Err(Error::Timeout) => { info!("Connection timed out") },
-                _ => {}
-            }
+        // The cue only goes to cuffs that are answering, which `cuff` keeps track of and lets the band know about
+        if mode::current() != PowerMode::Active {
+            continue;
+        }
+
+        let interp = 255 * BATTERY.lock().await.map_or(0, |bat| bat.percent as u64) / 100;
+
+        cuff::cue(Direction::Forward, interp).await;
     }
 }
 
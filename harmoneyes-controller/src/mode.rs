@@ -0,0 +1,152 @@
+//! # Power modes
+//!
+//! The controller is either active, idle or asleep (see `harmoneyes_core::power::Mode`), and each task checks the
+//! mode and waits for it to change rather than keep running flat out:
+//!
+//! - `uwb` only ranges while active, and holds the DW3000 in reset while asleep
+//! - `ble` listens to the mesh less of the time while idle, and less again while asleep
+//! - `coord` sends keep alives less often while idle, and not at all while asleep
+//! - `status` shows idle while idle, and turns the LED off while asleep
+//! - `cuff` stops reading the cuff's battery while asleep
+//!
+//! The mode changes when the console asks, when a power mode command is heard over the mesh, or, to wake up, when
+//! the user switch is pressed. Every change is passed on to the cuff so that it can slow down too.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::{join::join3, select::{select, Either}};
+use embassy_nrf::{gpio::{Input, Pull}, peripherals::P1_02};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel, signal::Signal};
+use embassy_time::{Duration, Timer};
+use harmoneyes_core::{haptics::Wrist, mesh, power::Mode, status::State};
+
+/// How many times a power mode command is broadcast. Mesh messages aren't acknowledged, and an asleep controller only
+/// listens for a fraction of the time, so the command is repeated for longer than the longest gap between listens.
+const BROADCAST_REPEATS: u32 = 20;
+
+/// The gap between repeats of a power mode command.
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The current mode, as its code.
+static MODE: AtomicU8 = AtomicU8::new(Mode::Active.code());
+
+/// Lets every task waiting on the mode know it changed. One subscriber per task that waits, and one spare.
+static CHANGES: PubSubChannel<CriticalSectionRawMutex, Mode, 1, 6, 0> = PubSubChannel::new();
+
+/// A mode to broadcast to the rest of the band.
+static BROADCAST: Signal<CriticalSectionRawMutex, Mode> = Signal::new();
+
+pub fn current() -> Mode {
+    Mode::from_code(MODE.load(Ordering::Relaxed)).unwrap_or_default()
+}
+
+/// Moves this controller and its cuff into `mode`.
+pub fn set(mode: Mode) {
+    if MODE.swap(mode.code(), Ordering::Relaxed) != mode.code() {
+        info!("Going into {} mode", mode.name());
+        crate::crash::note(format_args!("{} mode", mode.name()));
+        show(mode);
+        CHANGES.immediate_publisher().publish_immediate(mode);
+    }
+}
+
+/// Shows the mode on the status LED.
+fn show(mode: Mode) {
+    match mode {
+        Mode::Active => {
+            crate::status::clear(State::Idle);
+            crate::status::clear(State::Asleep);
+        },
+        Mode::Idle => {
+            crate::status::clear(State::Asleep);
+            crate::status::set(State::Idle);
+        },
+        Mode::Sleep => {
+            crate::status::clear(State::Idle);
+            crate::status::set(State::Asleep);
+        },
+    }
+}
+
+/// Moves this controller into `mode` and broadcasts it so the rest of the band follows.
+pub fn broadcast(mode: Mode) {
+    set(mode);
+    BROADCAST.signal(mode);
+}
+
+/// Waits until the mode is anything other than `mode`, and returns it.
+pub async fn changed_from(mode: Mode) -> Mode {
+    // Subscribe before checking so that a change in between isn't missed
+    let Ok(mut changes) = CHANGES.subscriber() else {
+        panic!("Too many tasks are waiting on the power mode");
+    };
+
+    loop {
+        let current = current();
+        if current != mode {
+            return current;
+        }
+
+        changes.next_message_pure().await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn task(button: P1_02) {
+    join3(wake_on_button(Input::new(button, Pull::Up)), repeat_broadcasts(), update_cuff()).await;
+}
+
+/// Wakes just this controller when the user switch is pressed.
+async fn wake_on_button(mut button: Input<'static>) {
+    loop {
+        button.wait_for_falling_edge().await;
+
+        if current() != Mode::Active {
+            info!("Woken by the button");
+            set(Mode::Active);
+        }
+
+        // Let the switch settle
+        Timer::after_millis(50).await;
+    }
+}
+
+/// Sends a power mode command over the mesh a number of times, starting over if another one is asked for.
+async fn repeat_broadcasts() {
+    let mut mode = BROADCAST.wait().await;
+
+    loop {
+        let repeats = async {
+            for _ in 0..BROADCAST_REPEATS {
+                crate::ble::OUTBOX.send(mesh::power_mode(mode)).await;
+                Timer::after(BROADCAST_INTERVAL).await;
+            }
+        };
+
+        mode = match select(repeats, BROADCAST.wait()).await {
+            Either::First(()) => BROADCAST.wait().await,
+            Either::Second(mode) => mode,
+        };
+    }
+}
+
+/// Passes every change of mode on to the cuffs.
+async fn update_cuff() {
+    let mut mode = Mode::Active;
+
+    loop {
+        mode = changed_from(mode).await;
+
+        for wrist in Wrist::ALL {
+            // A missing cuff is caught up on the mode by `cuff` once it answers again
+            if crate::cuff::presence(wrist).is_none() {
+                continue;
+            }
+
+            if crate::cuff::power_mode(wrist, mode).await.is_err() {
+                warn!("The {} cuff did not take the {} mode", wrist.name(), mode.name());
+            }
+        }
+    }
+}
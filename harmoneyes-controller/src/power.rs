@@ -0,0 +1,66 @@
+//! # USB power
+//!
+//! The softdevice owns the POWER peripheral, so rather than the USB driver watching VBUS itself the softdevice is
+//! asked to pass on its USB power events, which are used both to bring the USB port up and down and to tell whether
+//! the battery is being charged (see `bat`).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::info;
+use embassy_nrf::usb::vbus_detect::SoftwareVbusDetect;
+use embassy_sync::once_lock::OnceLock;
+use nrf_softdevice::{raw, SocEvent, Softdevice};
+
+static VBUS_DETECT: OnceLock<SoftwareVbusDetect> = OnceLock::new();
+
+/// Whether USB power is present, which means the battery is being charged or is full.
+static VBUS: AtomicBool = AtomicBool::new(false);
+
+/// Turns on the softdevice's USB power events and takes the state USB power is already in, since the events only
+/// say when it changes.
+pub fn initialize(_sd: &Softdevice) {
+    let mut status = 0;
+
+    unsafe {
+        raw::sd_power_usbregstatus_get(&mut status);
+        raw::sd_power_usbdetected_enable(1);
+        raw::sd_power_usbpwrrdy_enable(1);
+        raw::sd_power_usbremoved_enable(1);
+    }
+
+    let detected = status & raw::NRF_POWER_USBREGSTATUS_VBUSDETECT_MSK != 0;
+    let ready = status & raw::NRF_POWER_USBREGSTATUS_OUTPUTRDY_MSK != 0;
+
+    info!("USB power is {}", if detected { "present" } else { "absent" });
+    VBUS.store(detected, Ordering::Relaxed);
+    let _ = VBUS_DETECT.init(SoftwareVbusDetect::new(detected, ready));
+}
+
+/// Passes the softdevice's USB power events on to the USB driver.
+pub fn handle(event: SocEvent) {
+    let Some(vbus_detect) = VBUS_DETECT.try_get() else {
+        return;
+    };
+
+    match event {
+        SocEvent::PowerUsbDetected => {
+            VBUS.store(true, Ordering::Relaxed);
+            vbus_detect.detected(true);
+        },
+        SocEvent::PowerUsbPowerReady => vbus_detect.ready(),
+        SocEvent::PowerUsbRemoved => {
+            VBUS.store(false, Ordering::Relaxed);
+            vbus_detect.detected(false);
+        },
+        _ => {},
+    }
+}
+
+/// The VBUS detection the USB driver is built with.
+pub async fn vbus_detect() -> &'static SoftwareVbusDetect {
+    VBUS_DETECT.get().await
+}
+
+pub fn vbus_present() -> bool {
+    VBUS.load(Ordering::Relaxed)
+}
@@ -5,15 +5,21 @@ use nrf_softdevice::{raw, Softdevice};
 
 #[embassy_executor::task]
 async fn task(sd: &'static Softdevice) -> ! {
-    sd.run().await
+    sd.run_with_callback(crate::power::handle).await
 }
 
 pub async fn initialize(spawner: &Spawner) {
     let sd = Softdevice::enable(&config());
-    
+    let server = crate::gatt::initialize(sd);
+    crate::power::initialize(sd);
+
     spawner.must_spawn(task(sd));
     spawner.must_spawn(crate::ble::task(sd));
+    spawner.must_spawn(crate::wireless::task(sd));
+    spawner.must_spawn(crate::gatt::task(server));
     crate::rng::initialize(spawner, sd).await;
+    crate::flash::initialize(sd);
+    crate::config::initialize().await;
 }
 
 fn config() -> nrf_softdevice::Config {
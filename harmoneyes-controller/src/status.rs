@@ -0,0 +1,74 @@
+//! # Status LED
+//!
+//! Tasks say what's going on by setting and clearing states (see `harmoneyes_core::status`) through `set` and
+//! `clear`, and this service shows the most important one on the LED:
+//!
+//! - `main` shows booting until every task has been spawned
+//! - `usb` shows updating while an update is being received or passed on
+//! - `uwb` shows ranging while it's exchanging with other controllers, no peers once it hasn't for a while, and a
+//!   fault while it's resetting the radio
+//! - `bat` shows a low battery, charging or charged
+//! - `mode` shows idle, or turns the LED off while asleep
+
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::peripherals::{P0_16, PWM0};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use harmoneyes_core::status::{Command, State, Statuses};
+
+use crate::ws::Ws2812;
+
+/// How often the LED is updated while it's showing a pattern that changes.
+const FRAME: Duration = Duration::from_millis(50);
+
+/// How often the LED is checked while it's showing a pattern that doesn't, in case a state has lapsed.
+const STEADY_FRAME: Duration = Duration::from_secs(1);
+
+static COMMANDS: Channel<CriticalSectionRawMutex, Command, 16> = Channel::new();
+
+pub fn set(state: State) {
+    send(Command::Set(state));
+}
+
+pub fn clear(state: State) {
+    send(Command::Clear(state));
+}
+
+fn send(command: Command) {
+    if COMMANDS.try_send(command).is_err() {
+        warn!("Dropped a change to the status LED");
+    }
+}
+
+#[embassy_executor::task]
+pub async fn task(
+    p_pwm: PWM0,
+    p_pin: P0_16
+) {
+    let mut led = Ws2812::new(p_pwm, p_pin);
+    let mut statuses = Statuses::new();
+    let mut shown = None;
+
+    loop {
+        let now = Instant::now().as_millis();
+
+        while let Ok(command) = COMMANDS.try_receive() {
+            statuses.apply(command, now);
+        }
+
+        let state = statuses.top(now);
+        if shown != Some(state) {
+            info!("Status LED is showing {}", state.name());
+            shown = Some(state);
+        }
+
+        let look = state.look();
+        led.write(look.colour_at(now)).await;
+
+        let frame = if look.is_steady() { STEADY_FRAME } else { FRAME };
+        if let Either::Second(command) = select(Timer::after(frame), COMMANDS.receive()).await {
+            statuses.apply(command, Instant::now().as_millis());
+        }
+    }
+}
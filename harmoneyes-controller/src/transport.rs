@@ -0,0 +1,63 @@
+//! # Transport
+//!
+//! A cuff is reached either over the two-wire interface (`twi`) or wirelessly (`wireless`), depending on what the
+//! config says for its wrist (see `Config::cuff_link`). Both carry exactly the same commands, so `cuff` sends them
+//! through a [`Transport`] and doesn't need to know which one it's talking over.
+
+use defmt::Format;
+use embassy_nrf::twim;
+use harmoneyes_core::{config::CuffLink, haptics::Wrist};
+
+use crate::{twi::Cable, wireless::Radio};
+
+/// A way of getting commands to a cuff.
+pub trait Transport {
+    /// Sends a command that the cuff doesn't answer.
+    async fn send(&mut self, command: &[u8]) -> Result<(), Error>;
+
+    /// Sends a command and reads the cuff's answer into `answer`, which is sized for the answer expected.
+    async fn exchange(&mut self, command: &[u8], answer: &mut [u8]) -> Result<(), Error>;
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq, Eq)]
+pub enum Error {
+    /// There's no cuff set up on the wrist.
+    NotFitted,
+    /// The cabled cuff didn't answer, or the bus is stuck.
+    Cable(twim::Error),
+    /// The wireless cuff isn't connected.
+    Disconnected,
+    /// The wireless cuff is connected but the command didn't make it.
+    Radio
+}
+
+/// Whichever transport reaches the cuff on a wrist.
+pub enum Link {
+    Cable(Cable),
+    Wireless(Radio)
+}
+
+/// The transport to the cuff on `wrist`, going by the config at the time.
+pub async fn to(wrist: Wrist) -> Result<Link, Error> {
+    match crate::config::CONFIG.lock().await.cuff_link(wrist) {
+        Some(CuffLink::Cable(address)) => Ok(Link::Cable(Cable::new(address))),
+        Some(CuffLink::Wireless(_)) => Ok(Link::Wireless(Radio::new(wrist))),
+        None => Err(Error::NotFitted),
+    }
+}
+
+impl Transport for Link {
+    async fn send(&mut self, command: &[u8]) -> Result<(), Error> {
+        match self {
+            Link::Cable(cable) => cable.send(command).await,
+            Link::Wireless(radio) => radio.send(command).await,
+        }
+    }
+
+    async fn exchange(&mut self, command: &[u8], answer: &mut [u8]) -> Result<(), Error> {
+        match self {
+            Link::Cable(cable) => cable.exchange(command, answer).await,
+            Link::Wireless(radio) => radio.exchange(command, answer).await,
+        }
+    }
+}
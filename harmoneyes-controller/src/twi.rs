@@ -1,15 +1,115 @@
-use core::cell::OnceCell;
+//! # Two-wire interface
+//!
+//! The controller talks to its cabled cuffs over I2C, one on each wrist that has one at the address set for it in the
+//! config.
+//! Every transaction gives up after a while rather than waiting forever on a cuff that's holding the clock low, and a
+//! cuff that has been left holding the data line low, by a reset or a glitch on the cable partway through a byte, can
+//! be freed with `recover_bus` (see `cuff`, which decides when).
 
 use defmt::warn;
-use embassy_nrf::{bind_interrupts, interrupt::{self, InterruptExt}, peripherals::{P0_11, P0_12, TWISPI0}, twim::{self, Twim}};
+use embassy_nrf::{bind_interrupts, gpio::{Flex, OutputDrive, Pull}, interrupt::{self, InterruptExt}, peripherals::{P0_11, P0_12, TWISPI0}, twim::{self, Twim}, Peripheral};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{with_timeout, Duration, Timer};
+use harmoneyes_core::{config::CuffLink, haptics::Wrist, transfer::{Message, Status, MAX_FRAME_LENGTH, STATUS_LENGTH}};
 
-pub static DRIVER: Mutex<CriticalSectionRawMutex, OnceCell<Twim<'static, TWISPI0>>> = Mutex::new(OnceCell::new());
+use crate::transport::{Error, Transport};
+
+/// How long the cuff can take to take in a firmware transfer frame, which can mean writing a page of its flash.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the cuff can take over anything else, which is only ever a few bytes.
+const TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Half a period of the clock while freeing up the bus, which is a lot slower than the bus runs but there's no
+/// slowest speed.
+const RECOVERY_HALF_CLOCK: Duration = Duration::from_micros(10);
+
+pub static BUS: Mutex<CriticalSectionRawMutex, Option<Bus>> = Mutex::new(None);
 
 bind_interrupts!(struct Irqs {
     TWISPI0 => twim::InterruptHandler<TWISPI0>;
 });
 
+/// The driver, and the peripherals it's made from so that it can be made again after the bus has been freed up.
+pub struct Bus {
+    twim: Option<Twim<'static, TWISPI0>>,
+    twi: TWISPI0,
+    scl: P0_11,
+    sda: P0_12
+}
+
+impl Bus {
+    fn new(twi: TWISPI0, scl: P0_11, sda: P0_12) -> Self {
+        let mut bus = Self { twim: None, twi, scl, sda };
+        bus.connect();
+        bus
+    }
+
+    fn connect(&mut self) {
+        // SAFETY: The only other owner of the peripherals was the driver, which has been dropped if there was one
+        let (twi, scl, sda) = unsafe { (self.twi.clone_unchecked(), self.scl.clone_unchecked(), self.sda.clone_unchecked()) };
+        self.twim = Some(Twim::new(twi, Irqs, sda, scl, controller_config()));
+    }
+
+    fn twim(&mut self) -> &mut Twim<'static, TWISPI0> {
+        // The driver is only ever missing partway through `recover`
+        self.twim.as_mut().expect("Two-wire interface driver is missing")
+    }
+
+    async fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), twim::Error> {
+        with_timeout(TIMEOUT, self.twim().write(address, bytes)).await.unwrap_or(Err(twim::Error::Timeout))
+    }
+
+    async fn write_read(&mut self, address: u8, bytes: &[u8], buf: &mut [u8]) -> Result<(), twim::Error> {
+        with_timeout(TIMEOUT, self.twim().write_read(address, bytes, buf)).await.unwrap_or(Err(twim::Error::Timeout))
+    }
+
+    /// Clocks out whatever byte the cuff is partway through sending, until it lets go of the data line, then sends a
+    /// stop. Returns whether both lines ended up free.
+    async fn recover(&mut self) -> bool {
+        // Drop the driver first so that its pins are free again
+        self.twim = None;
+
+        // SAFETY: The only other owner of the pins was the driver that was just dropped
+        let (mut scl, mut sda) = unsafe { (Flex::new(self.scl.clone_unchecked()), Flex::new(self.sda.clone_unchecked())) };
+        // Open drain like the bus itself, so that nothing is driven high against the cuff
+        for line in [&mut scl, &mut sda] {
+            line.set_high();
+            line.set_as_input_output(Pull::Up, OutputDrive::Standard0Disconnect1);
+        }
+        Timer::after(RECOVERY_HALF_CLOCK).await;
+
+        // A byte and its acknowledge are nine clocks at most
+        for _ in 0..9 {
+            if sda.is_high() {
+                break;
+            }
+
+            scl.set_low();
+            Timer::after(RECOVERY_HALF_CLOCK).await;
+            scl.set_high();
+            Timer::after(RECOVERY_HALF_CLOCK).await;
+        }
+
+        // A stop is the data line going high while the clock is high
+        scl.set_low();
+        Timer::after(RECOVERY_HALF_CLOCK).await;
+        sda.set_low();
+        Timer::after(RECOVERY_HALF_CLOCK).await;
+        scl.set_high();
+        Timer::after(RECOVERY_HALF_CLOCK).await;
+        sda.set_high();
+        Timer::after(RECOVERY_HALF_CLOCK).await;
+
+        let free = scl.is_high() && sda.is_high();
+
+        drop((scl, sda));
+        self.connect();
+
+        free
+    }
+}
+
 pub async fn initialize(
     twi: TWISPI0,
     scl: P0_11,
@@ -18,13 +118,84 @@ pub async fn initialize(
     // Hark, weary traveler! Take caution of a nearby softdevice. You don't want to anger it.
     interrupt::TWISPI0.set_priority(interrupt::Priority::P3);
 
-    if let Err(_) = DRIVER.lock().await.set(Twim::new(twi, Irqs, sda, scl, controller_config())) {
+    let mut bus = BUS.lock().await;
+    if bus.is_some() {
         warn!("Called twi::initialize when the two-wire interface was already initialized");
+        return;
     }
+
+    *bus = Some(Bus::new(twi, scl, sda));
 }
 
 fn controller_config() -> twim::Config {
     let config = twim::Config::default();
 
     config
-}
\ No newline at end of file
+}
+
+/// Frees up the bus in case the cuff is stuck partway through a transaction. Returns whether the bus is free.
+pub async fn recover_bus() -> bool {
+    BUS.lock().await
+        .as_mut().expect("Two-wire interface driver is not initialized")
+        .recover().await
+}
+
+/// A cuff on the cable, at its own address on the bus.
+pub struct Cable {
+    address: u8
+}
+
+impl Cable {
+    pub const fn new(address: u8) -> Self {
+        Self { address }
+    }
+}
+
+impl Transport for Cable {
+    async fn send(&mut self, command: &[u8]) -> Result<(), Error> {
+        BUS.lock().await
+            .as_mut().expect("Two-wire interface driver is not initialized")
+            .write(self.address, command).await
+            .map_err(Error::Cable)
+    }
+
+    async fn exchange(&mut self, command: &[u8], answer: &mut [u8]) -> Result<(), Error> {
+        BUS.lock().await
+            .as_mut().expect("Two-wire interface driver is not initialized")
+            .write_read(self.address, command, answer).await
+            .map_err(Error::Cable)
+    }
+}
+
+/// The address of the cuff on `wrist`, if it's on the cable.
+async fn address(wrist: Wrist) -> Option<u8> {
+    match crate::config::CONFIG.lock().await.cuff_link(wrist)? {
+        CuffLink::Cable(address) => Some(address),
+        CuffLink::Wireless(_) => None,
+    }
+}
+
+/// Sends one frame of a firmware transfer to the cuff on `wrist` and reads back where it has got to. Returns `None`
+/// if either didn't make it across, in which case it's safe to send the frame again, or if the cuff isn't on the
+/// cable since updates aren't passed on wirelessly.
+pub async fn transfer(wrist: Wrist, message: &Message<'_>) -> Option<Status> {
+    let address = address(wrist).await?;
+    let mut frame = [0; MAX_FRAME_LENGTH];
+    // The buffer is sized for the largest frame
+    let len = message.encode(&mut frame).expect("Transfer frame did not fit in its buffer");
+    let mut status = [0; STATUS_LENGTH];
+
+    let mut bus = BUS.lock().await;
+    let twim = bus.as_mut().expect("Two-wire interface driver is not initialized").twim();
+
+    // The cuff holds the clock low while it writes to flash, so the status read doubles as waiting for it
+    let result = with_timeout(TRANSFER_TIMEOUT, async {
+        twim.write(address, &frame[..len]).await?;
+        twim.read(address, &mut status).await
+    }).await;
+
+    match result {
+        Ok(Ok(())) => Status::decode(&status).ok(),
+        _ => None
+    }
+}
@@ -1,7 +1,12 @@
-use embassy_futures::join::join;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::{join::join, select::{select, Either}};
 use embassy_nrf::{bind_interrupts, interrupt::{self, InterruptExt, Priority}, peripherals::USBD, usb::{self, vbus_detect::SoftwareVbusDetect, Driver}};
-use embassy_usb::{class::cdc_acm::{CdcAcmClass, State}, driver::EndpointError, Builder};
-use defmt::info;
+use embassy_sync::{blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex}, channel::Channel};
+use embassy_time::Instant;
+use embassy_usb::{class::cdc_acm::{CdcAcmClass, Receiver, Sender, State}, driver::EndpointError, Builder};
+use defmt::{info, warn};
+use harmoneyes_core::{codec::FixedStr, framing::{self, Accumulator}, health::Task, protocol::{DeviceInfo, DeviceKind, Failure, Request, Response, MAX_FRAME_LENGTH, MAX_MESSAGE_LENGTH}, status::State as Status, telemetry::{Event, Telemetry}};
 use static_cell::StaticCell;
 
 static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
@@ -11,12 +16,18 @@ static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
 static ACM_STATE: StaticCell<State> = StaticCell::new();
 static LOGGER_STATE: StaticCell<State> = StaticCell::new();
 
-static VBUS_DETECT: StaticCell<SoftwareVbusDetect> = StaticCell::new();
+/// Telemetry events waiting to be sent to the console.
+static TELEMETRY: Channel<CriticalSectionRawMutex, Event, 16> = Channel::new();
+
+/// Whether the console has asked for telemetry.
+static STREAMING: AtomicBool = AtomicBool::new(false);
 
 bind_interrupts!(struct Irqs {
     USBD => usb::InterruptHandler<USBD>;
 });
 
+type UsbDriver = Driver<'static, USBD, &'static SoftwareVbusDetect>;
+
 #[embassy_executor::task]
 pub async fn task(
     usb: USBD
@@ -24,9 +35,7 @@ pub async fn task(
     // Hark, weary traveler! Take caution of a nearby softdevice. You don't want to anger it.
     interrupt::USBD.set_priority(Priority::P3);
 
-    let vbus_detect = VBUS_DETECT.init(SoftwareVbusDetect::new(true, true));
-
-    let driver = usb::Driver::new(usb, Irqs, &*vbus_detect);
+    let driver = usb::Driver::new(usb, Irqs, crate::power::vbus_detect().await);
 
     let mut builder = Builder::new(
         driver,
@@ -37,29 +46,48 @@ pub async fn task(
         CONTROL_BUF.init([0; 64])
     );
 
+    // The logger has to be created first so that it gets the interfaces the console expects (see `USB_LOGGER_INTERFACE`)
     let logger_class = CdcAcmClass::new(&mut builder, LOGGER_STATE.init(State::new()), 64);
+    let logger_fut = embassy_usb_logger::with_class!(1024, log::LevelFilter::Info, logger_class);
+
     let serial_class = CdcAcmClass::new(&mut builder, ACM_STATE.init(State::new()), 64);
 
     let mut usb = builder.build();
 
     // Run the low-level USB interface, the ACM handler, and the logger concurrently
-    join(usb.run(), join(handle_serial(serial_class), handle_logger(logger_class))).await;
+    join(usb.run(), join(handle_serial(serial_class), logger_fut)).await;
 }
 
-async fn handle_logger<'a>(mut logger_class: CdcAcmClass<'a, Driver<'a, USBD, &'a SoftwareVbusDetect>>) -> ! {
-    loop {
-        logger_class.wait_connection().await;
-        info!("Established USB connection");
+/// Queues a telemetry event for the console, and for a GATT client (see `gatt`), if they have asked for them. Events
+/// are dropped rather than waited on when they can't keep up so that reporting never holds up the rest of the device.
+pub fn report(telemetry: Telemetry) {
+    let streaming = STREAMING.load(Ordering::Relaxed);
+    if !streaming && !crate::gatt::is_streaming() {
+        return;
+    }
 
-        let _ = host_logger_connection(&mut logger_class).await;
-        info!("Disconnected from USB");
+    let event = Event { uptime_ms: Instant::now().as_millis(), telemetry };
+    crate::gatt::report(&event);
+
+    if streaming {
+        let _ = TELEMETRY.try_send(event);
     }
 }
 
-async fn handle_serial<'a>(mut serial_class: CdcAcmClass<'a, Driver<'a, USBD, &'a SoftwareVbusDetect>>) -> ! {
+async fn handle_serial(serial_class: CdcAcmClass<'static, UsbDriver>) -> ! {
+    let (mut sender, mut receiver) = serial_class.split();
+    // Waiting for the console isn't something the task can be blamed for, only taking too long to answer it
+    crate::health::park(Task::Usb);
+
     loop {
-        serial_class.wait_connection().await;
-        let _ = host_serial_connection(&mut serial_class).await;
+        receiver.wait_connection().await;
+        info!("Established USB serial connection");
+
+        let _ = host_serial_connection(&mut sender, &mut receiver).await;
+
+        STREAMING.store(false, Ordering::Relaxed);
+        TELEMETRY.clear();
+        info!("Disconnected from USB serial");
     }
 }
 
@@ -75,39 +103,171 @@ impl From<EndpointError> for Disconnected {
 }
 
 /// Handles serial data communication to another device connected over USB.
-async fn host_serial_connection<'a>(serial_class: &mut CdcAcmClass<'a, Driver<'a, USBD, &'a SoftwareVbusDetect>>) -> Result<(), Disconnected> {
-    let mut buf = [0; 64];
+async fn host_serial_connection(sender: &mut Sender<'static, UsbDriver>, receiver: &mut Receiver<'static, UsbDriver>) -> Result<(), Disconnected> {
+    let responses: Channel<NoopRawMutex, Response, 2> = Channel::new();
 
-    loop {
-        let n = serial_class.read_packet(&mut buf).await?;
-        let data = &buf[..n];
-        info!("data: {:?}", data);
-        serial_class.write_packet(data).await?;
+    match select(read_requests(receiver, &responses), write_responses(sender, &responses)).await {
+        Either::First(res) => res,
+        Either::Second(res) => res,
     }
 }
 
-async fn host_logger_connection<'a>(logger_class: &mut CdcAcmClass<'a, Driver<'a, USBD, &'a SoftwareVbusDetect>>) -> Result<(), Disconnected> {
+/// Decodes requests from the console as they arrive and queues up their responses.
+async fn read_requests(receiver: &mut Receiver<'static, UsbDriver>, responses: &Channel<NoopRawMutex, Response, 2>) -> Result<(), Disconnected> {
+    let mut accumulator: Accumulator<MAX_FRAME_LENGTH> = Accumulator::new();
     let mut buf = [0; 64];
 
     loop {
-        let n = logger_class.read_packet(&mut buf).await?;
-        let data = &buf[..n];
-        info!("data: {:?}", data);
-        logger_class.write_packet(data).await?;
+        let n = receiver.read_packet(&mut buf).await?;
+
+        for byte in &buf[..n] {
+            let response = match accumulator.push(*byte) {
+                None => continue,
+                Some(Ok(message)) => match Request::decode(message) {
+                    Ok(request) => {
+                        crate::health::check_in(Task::Usb);
+                        let response = handle_request(request).await;
+                        crate::health::park(Task::Usb);
+                        response
+                    },
+                    Err(_) => Response::Failed(Failure::Malformed),
+                },
+                Some(Err(_)) => Response::Failed(Failure::Malformed),
+            };
+            responses.send(response).await;
+        }
+    }
+}
+
+/// Sends responses and, when the console has asked for it, telemetry.
+async fn write_responses(sender: &mut Sender<'static, UsbDriver>, responses: &Channel<NoopRawMutex, Response, 2>) -> Result<(), Disconnected> {
+    loop {
+        let response = match select(responses.receive(), TELEMETRY.receive()).await {
+            Either::First(response) => response,
+            Either::Second(event) => Response::Telemetry(event),
+        };
+        write_response(sender, &response).await?;
+    }
+}
+
+async fn write_response(sender: &mut Sender<'static, UsbDriver>, response: &Response) -> Result<(), Disconnected> {
+    let mut message = [0; MAX_MESSAGE_LENGTH];
+    let mut frame = [0; MAX_FRAME_LENGTH];
+
+    // Both buffers are sized for the largest message, so neither of these can fail
+    let len = response.encode(&mut message).expect("Response did not fit in the message buffer");
+    let len = framing::encode(&message[..len], &mut frame).expect("Response did not fit in the frame buffer");
+
+    for packet in frame[..len].chunks(64) {
+        sender.write_packet(packet).await?;
+    }
+
+    Ok(())
+}
+
+/// Carries out a request from the console, or from a GATT client (see `gatt`).
+pub async fn handle_request(request: Request) -> Response {
+    match request {
+        Request::Info => {
+            let config = *crate::config::CONFIG.lock().await;
+            Response::Info(DeviceInfo {
+                kind: DeviceKind::Controller,
+                serial: FixedStr::truncated(crate::identity::serial()),
+                name: config.performer_name,
+                firmware_version: FixedStr::truncated(env!("CARGO_PKG_VERSION")),
+                uptime_ms: Instant::now().as_millis(),
+                performer_id: config.performer_id,
+                section: config.section
+            })
+        },
+        Request::ConfigGet { key } => Response::Config { key, value: crate::config::get(key).await },
+        Request::ConfigSet { key, value } => match crate::config::set(key, value).await {
+            Ok(()) => Response::Done,
+            Err(crate::config::Error::Invalid) => Response::Failed(Failure::InvalidValue),
+            Err(crate::config::Error::Flash) => Response::Failed(Failure::StorageFailed),
+        },
+        Request::ConfigReset => match crate::config::reset().await {
+            Ok(()) => Response::Done,
+            Err(_) => Response::Failed(Failure::StorageFailed),
+        },
+        Request::HapticTest { wrist, motor, duration_ms } => match crate::cuff::haptic(wrist, motor, duration_ms).await {
+            Ok(()) => Response::Done,
+            Err(_) => {
+                warn!("The {} cuff did not accept the haptic test", wrist.name());
+                Response::Failed(Failure::CuffUnavailable)
+            },
+        },
+        Request::Stream { enabled } => {
+            STREAMING.store(enabled, Ordering::Relaxed);
+            if !enabled {
+                TELEMETRY.clear();
+            }
+            Response::Done
+        },
+        Request::UpdateBegin { target, size } => update_response(show_update(match target {
+            DeviceKind::Controller => crate::dfu::begin(size).await,
+            DeviceKind::Cuff => crate::cuff_dfu::begin(size).await,
+        })),
+        Request::UpdateWrite { target, offset, chunk } => update_response(show_update(match target {
+            DeviceKind::Controller => crate::dfu::write(offset, chunk.as_slice()).await,
+            DeviceKind::Cuff => crate::cuff_dfu::write(offset, chunk.as_slice()).await,
+        })),
+        Request::UpdateFinish { target, signature } => {
+            crate::status::clear(Status::Updating);
+            update_response(match target {
+                DeviceKind::Controller => crate::dfu::finish(&signature).await,
+                DeviceKind::Cuff => crate::cuff_dfu::finish(&signature).await,
+            })
+        },
+        // The controller's bootloader is updated through `UpdateBegin` instead
+        Request::RebootToBootloader => Response::Failed(Failure::Unsupported),
+        Request::SetPowerMode { mode, band } => {
+            if band {
+                crate::mode::broadcast(mode);
+            } else {
+                crate::mode::set(mode);
+            }
+            Response::Done
+        },
+        Request::CrashGet { index } => Response::Crash(crate::crash::report(index as usize)),
+        Request::CrashClear => {
+            crate::crash::clear();
+            Response::Done
+        },
+    }
+}
+
+/// Shows an update as going on while each step of it succeeds. The state lapses on its own if the console stops
+/// sending.
+fn show_update(result: Result<(), crate::dfu::Error>) -> Result<(), crate::dfu::Error> {
+    match result {
+        Ok(()) => crate::status::set(Status::Updating),
+        Err(_) => crate::status::clear(Status::Updating),
+    }
+    result
+}
+
+fn update_response(result: Result<(), crate::dfu::Error>) -> Response {
+    match result {
+        Ok(()) => Response::Done,
+        Err(crate::dfu::Error::Rejected) => Response::Failed(Failure::UpdateRejected),
+        Err(crate::dfu::Error::Flash) => Response::Failed(Failure::StorageFailed),
+        Err(crate::dfu::Error::Unreachable) => Response::Failed(Failure::CuffUnavailable),
+        Err(crate::dfu::Error::Partial) => Response::Failed(Failure::UpdatePartial),
     }
 }
 
 fn usb_config() -> embassy_usb::Config<'static> {
     let mut config: embassy_usb::Config<'static> = embassy_usb::Config::new(
-        harmoneyes_core::constants::USB_VENDOR_ID, 
+        harmoneyes_core::constants::USB_VENDOR_ID,
         harmoneyes_core::constants::controller::USB_PRODUCT_ID
     );
 
     config.manufacturer = Some(harmoneyes_core::constants::MANUFACTURER);
     config.product = Some(harmoneyes_core::constants::controller::NAME);
-    config.serial_number = Some(harmoneyes_core::constants::controller::SERIAL_ONE);
+    config.serial_number = Some(crate::identity::serial());
     config.max_power = 100;
     config.max_packet_size_0 = 64;
 
     config
-}
\ No newline at end of file
+}
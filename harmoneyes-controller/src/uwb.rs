@@ -3,305 +3,388 @@
 //! proof of concept to demonstrate the technology and a lot of work would need to be done for it to be in a state where it could
 //! actually be deployed, but feel free to use this as a jumping off point.
 
-use defmt::{debug, info};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{info, warn, Debug2Format};
 use dw3000_ng::{hl::{RxQuality, SendTime}, time::Instant, Ready, SingleBufferReceiving, DW3000};
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
-use embassy_nrf::{bind_interrupts, gpio::{Input, Level, Output, OutputDrive, Pull}, interrupt::{self, InterruptExt, Priority}, peripherals::{P0_07, P0_13, P0_14, P0_15, P0_24, P0_25, P1_08, SPI3}, spim::{self, Spim}};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::{bind_interrupts, gpio::{Input, Level, Output, OutputDrive, Pull}, interrupt::{self, InterruptExt, Priority}, peripherals::{P0_07, P0_13, P0_14, P0_15, P0_24, P0_25, P1_08, SPI3}, spim::{self, Spim}, Peripheral};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex, signal::Signal};
-use embassy_time::{Duration, Timer};
+use embassy_time::Timer;
+use dw3000_ng::mac::ShortAddress;
+use harmoneyes_core::{health::Task, power::Mode, status::State, telemetry::Telemetry, uwb::{Outcome, Ranging, Received, Recovery, Remedy, UwbRadio, RANGING_PAYLOAD_LENGTH}};
+use static_cell::StaticCell;
 
 static POLL_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-pub static DISTANCES: Channel<CriticalSectionRawMutex, u64, 20> = Channel::new();
+/// The short address of each controller ranged with and the time of flight to it.
+pub static DISTANCES: Channel<CriticalSectionRawMutex, (u16, u64), 20> = Channel::new();
+
+/// How long after the last exchange the controller counts as having nobody to range with.
+const PEER_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(5);
 
-static RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Whether the radio is ranging, which the battery gauge allows for since it's the biggest draw on the battery.
+pub static RANGING: AtomicBool = AtomicBool::new(false);
+
+static SPI: StaticCell<Mutex<CriticalSectionRawMutex, Spim<'static, SPI3>>> = StaticCell::new();
 
 bind_interrupts!(struct Irqs {
     SPIM3 => spim::InterruptHandler<SPI3>;
 });
 
-enum Mode {
-    Driver,
-    Listener
-}
+type Device = SpiDevice<'static, CriticalSectionRawMutex, Spim<'static, SPI3>, Output<'static>>;
 
 #[embassy_executor::task]
 pub async fn task(
-    mut spi: SPI3,
-    mut sck: P0_14,
-    mut mosi: P0_13,
-    mut miso: P0_15,
-    mut cs: P0_24,
-    mut irq: P0_25,
-    mut exton: P1_08,
-    mut reset: P0_07,
+    spi: SPI3,
+    sck: P0_14,
+    mosi: P0_13,
+    miso: P0_15,
+    cs: P0_24,
+    irq: P0_25,
+    exton: P1_08,
+    reset: P0_07,
 ) -> ! {
     // Hark, weary traveler! Take caution of a nearby softdevice. You don't want to anger it.
     interrupt::SPIM3.set_priority(Priority::P3);
 
-    loop {
+    let mut radio = Dw3000Radio {
+        spi: SPI.init(Mutex::new(Spim::new(spi, Irqs, sck, miso, mosi, spi_config()))),
+        cs,
         // Start with the device off by holding the reset pin low.
-        let mut reset = Output::new(&mut reset, Level::Low, OutputDrive::Standard0Disconnect1);
-
-        let mut exton = Input::new(&mut exton, Pull::Down);
-        let mut irq = Input::new(&mut irq, Pull::Down);
-
-        let spi: Mutex<CriticalSectionRawMutex, _> = Mutex::new(Spim::new(&mut spi, Irqs, &mut sck, &mut miso, &mut mosi, spi_config()));
-        let cs = Output::new(&mut cs, Level::High, OutputDrive::Standard);
-        let device = SpiDevice::new(&spi, cs);
-
-        let dwm = DW3000::new(device);
-
-        // Make sure we really are pulling the reset pin low
-        reset.set_low();
-        
-        // Wait at least 50ms for the reset
-        Timer::after_millis(50).await;
-
-        // Turn the device on.
-        reset.set_high(); 
+        reset: Output::new(reset, Level::Low, OutputDrive::Standard0Disconnect1),
+        exton: Input::new(exton, Pull::Down),
+        irq: Input::new(irq, Pull::Down),
+        dw: None
+    };
 
-        // Wait for the device to power on
-        info!("Waiting for DWM3000 to turn on");
-        exton.wait_for_high().await;
-        info!("DWM3000 turned on!");
+    let mut ranging = Ranging::new(crate::identity::address());
+    let mut recovery = Recovery::new();
+    // Whether the radio had to be set up again, until it next works
+    let mut faulted = false;
 
-        // Wait for the SPIRDY interrupt indicating that we can start communicating over spi
-        info!("Waiting for DWM3000 to move into IDLE_RC state");
-        match embassy_futures::select::select(irq.wait_for_high(), Timer::after_millis(50)).await {
-            embassy_futures::select::Either::First(_) => info!("Received SPIRDY Interrupt"),
-            embassy_futures::select::Either::Second(_) => info!("SPIRDY Interrupt took too long. Moving forward anyways."),
-        };
-        
-        // Initialize the DWM3000
-        info!("Initializing the DWM3000");
-        let mut dwm = dwm
-            .init().await.expect("Failed to initialize DWM3000")
-            .config(dw_config(), embassy_time::Delay).await.expect("Failed to configure DWM3000");
+    let mut counter = 0;
+    // Whether another controller has been heard with our address, which is only logged the first time
+    let mut clashed = false;
 
-        let (_pan, address) = dwm.get_address().await.expect("Failed to get DWM3000 Address");
-        info!("DWM3000 Address is {}", address);
+    // When the last exchange was, while the status LED is showing ranging
+    let mut last_exchange: Option<embassy_time::Instant> = None;
+    crate::status::set(State::NoPeers);
 
-        // Turn off the SPIRDY interrupt (really this is just to be safe)
-        dwm.disable_interrupts().await.expect("Failed to disable all interrupts");
+    loop {
+        crate::health::check_in(Task::Uwb);
 
-        let mut mode = Mode::Listener;
+        // Nobody has been heard from for a while, or the radio is about to stop
+        if last_exchange.is_some_and(|at| at.elapsed() >= PEER_TIMEOUT || crate::mode::current() != Mode::Active) {
+            last_exchange = None;
+            crate::status::clear(State::Ranging);
+            crate::status::set(State::NoPeers);
+        }
 
-        let mut counter = 0;
+        match crate::mode::current() {
+            Mode::Active => {},
+            mode => {
+                RANGING.store(false, Ordering::Relaxed);
+                if mode == Mode::Sleep {
+                    radio.power_down();
+                }
 
-        let mut last_tx: Option<Instant> = None;
-        let mut last_rx: Option<Instant> = None;
+                crate::health::park(Task::Uwb);
+                crate::mode::changed_from(mode).await;
+                continue;
+            },
+        }
 
-        loop {
-            // Unfortunately some errors with the DW3000 require an entire chip reset in order to go back to functioning properly
-            if RESET.signaled() {
-                info!("An unrecoverable error occured in the DWM3000... Restarting");
-                RESET.reset();
-                break;
+        // Starting up or coming back from sleep the radio has to be brought up
+        if radio.dw.is_none() {
+            if let Err(e) = ranging.recover(&mut radio, Remedy::Reset).await {
+                warn!("Failed to bring up the DWM3000: {}", Debug2Format(&e));
+                faulted |= recover(&mut radio, &mut ranging, &mut recovery).await;
             }
+        }
+        RANGING.store(true, Ordering::Relaxed);
+
+        match ranging.step(&mut radio).await {
+            Ok(outcome) => {
+                recovery.succeeded();
+                if faulted {
+                    info!("The DWM3000 is working again");
+                    crate::status::clear(State::Fault);
+                    faulted = false;
+                }
+
+                if let Outcome::Exchanged { peer, tof } = outcome {
+                    if let Some(tof) = tof {
+                        DISTANCES.send((peer, tof)).await;
+                    }
 
-            match mode {
-                Mode::Driver => {
-                    // Send one message to get things started
-                    mode = Mode::Listener;
-
-                    let (returned_dw, res) = must_transmit(dwm, &mut irq, &[]).await;
-                    dwm = returned_dw;
-
-                    continue;
-                },
-                Mode::Listener => {
-
-                    // Try to receive a packet
-                    let (returned_dw, res) = must_receive(dwm, &mut irq, Duration::from_millis(50)).await; // From testing with a basic ping pong 10ms timeout with no artificial turn-around delay achieved about a 0.15% timeout to response rate which seems pretty good.
-                    dwm = returned_dw;
-
-                    match res {
-                        Some((buf, len, rx_inst, qual)) => {
-
-                            if last_tx.is_some() && rx_inst.value() > last_tx.unwrap().value() && len > 12 {
-                                let last_tx_rx_interval: u64 = rx_inst.value() - last_tx.unwrap().value();
-
-                                let mut buf_frame = [0u8; 8];
-                                for (i, byte) in buf[len-12..len-4].iter().enumerate() {
-                                    if i < buf_frame.len() {
-                                        buf_frame[7-i] = *byte;
-                                    }
-                                }
-
-                                let foreign_rx_tx_interval = u64::from_ne_bytes(buf_frame);
-                                if foreign_rx_tx_interval != 0 && last_tx_rx_interval > foreign_rx_tx_interval {
-                                    DISTANCES.send((last_tx_rx_interval - foreign_rx_tx_interval) / 2).await;
-                                }
-                            }
-
-                            Timer::after(Duration::from_millis(3)).await;
-
-                            let mut payload = [0u8; 8];
-
-                            if last_rx.is_some() && last_tx.is_some() && last_tx.unwrap().value() > last_rx.unwrap().value() {
-                                let last_rx_tx_interval: u64 = last_tx.unwrap().value() - last_rx.unwrap().value();
-
-                                for (i, byte) in last_rx_tx_interval.to_ne_bytes().into_iter().enumerate() {
-                                    payload[7-i] = byte;
-                                }
-                            }
-
-                            // We got a packet! Time to respond.
-                            let (returned_dw, tx_inst) = must_transmit(dwm, &mut irq, &payload).await;
-                            dwm = returned_dw;
-
-                            if counter < 100 {
-                                counter += 1;
-                            } else {
-                                info!("100 packets exchanged");
-                                counter = 0;
-                            }
-
-                            last_tx = Some(tx_inst);
-                            last_rx = Some(rx_inst);
-                        },
-                        None => {
-                            // If the timeout was triggered swap to driver mode.
-                            mode = Mode::Driver;
-                            continue;
-                        },
+                    if last_exchange.is_none() {
+                        crate::status::clear(State::NoPeers);
+                        crate::status::set(State::Ranging);
                     }
-                },
-            }
+                    last_exchange = Some(embassy_time::Instant::now());
+
+                    if counter < 100 {
+                        counter += 1;
+                    } else {
+                        info!("100 packets exchanged");
+                        counter = 0;
+                    }
+                } else if outcome == Outcome::TimedOut {
+                    info!("Receiver timed out");
+                } else if outcome == Outcome::Clashed {
+                    if !clashed {
+                        warn!("Another controller is ranging with our address {:04x}", crate::identity::address());
+                        crate::crash::note(format_args!("Address clash"));
+                        clashed = true;
+                    }
+                    crate::usb::report(Telemetry::AddressClash { address: crate::identity::address() });
+                }
+            },
+            Err(e) => {
+                warn!("An error occured in the DWM3000: {}", Debug2Format(&e));
+                crate::crash::note(format_args!("DWM3000 error"));
+                faulted |= recover(&mut radio, &mut ranging, &mut recovery).await;
+            },
         }
     }
 }
 
-/// This function will just loop the transmit function until it doesn't return an error. Transmission errors are pretty rare, so
-/// this is usually sufficient to do the job, as any errors you do encounter are probably a much more serious issue.
-async fn must_transmit<'a, T>(mut dw: DW3000<T, Ready>, irq_pin: &mut Input<'a>, data: &[u8]) -> (DW3000<T, Ready>, Instant)
-where
-    T: embedded_hal_async::spi::SpiDevice,
-    <T as embedded_hal_async::spi::ErrorType>::Error: defmt::Format
-{
-    let mut counter = 0;
+/// Applies remedies to the radio until one of them works, going further each time one doesn't, and streams the fault
+/// counters as they go up. Returns whether it took more than trying again.
+async fn recover(radio: &mut Dw3000Radio, ranging: &mut Ranging, recovery: &mut Recovery) -> bool {
+    let mut faulted = false;
 
     loop {
-        if counter < 10 {
-            counter += 1;
-        } else {
-            RESET.signal(());
-            return (dw, Instant::new(0).unwrap())
+        let remedy = recovery.failed(radio.dw.is_some());
+        crate::usb::report(Telemetry::UwbFaults { faults: recovery.faults() });
+
+        if remedy != Remedy::Retry {
+            info!("Trying to {} the DWM3000", remedy.name());
+            crate::crash::note(format_args!("DWM3000 {}", remedy.name()));
+            if !faulted {
+                crate::status::set(State::Fault);
+                faulted = true;
+            }
         }
 
-        let (returned_dw, res) = transmit(dw, irq_pin.wait_for_high(), data).await;
-        dw = returned_dw;
+        // Backing off can take longer than the task is allowed to go without checking in
+        if let Remedy::BackOff(wait) = remedy {
+            crate::health::park(Task::Uwb);
+            Timer::after_millis(wait.as_millis() as u64).await;
+        }
+        crate::health::check_in(Task::Uwb);
 
-        match res {
-            Some(inst) => return (dw, inst),
-            None => {
-                continue;
-            },
+        match ranging.recover(radio, remedy).await {
+            Ok(()) => return faulted,
+            Err(e) => warn!("Failed to {} the DWM3000: {}", remedy.name(), Debug2Format(&e)),
         }
     }
 }
 
-async fn transmit<T, I, O>(mut dw: DW3000<T, Ready>, irq: I, data: &[u8]) -> (DW3000<T, Ready>, Option<Instant>)
-where
-    T: embedded_hal_async::spi::SpiDevice,
-    I: Future<Output = O>,
-    <T as embedded_hal_async::spi::ErrorType>::Error: defmt::Format
-{
-    // Disable all interrupts just in case someone was lazy
-    dw.disable_interrupts().await.expect("Failed to disable all interrupts");
-    // Enable transmitting interrupts on the irq pin.
-    dw.enable_tx_interrupts().await.expect("Failed to enable transmitter interrupts");
-    // Put the device into transmitting mode.
-    let mut tx = dw.send(data, SendTime::Now, dw_config()).await.expect("Failed to enter transmitting mode");
-
-    let response = match tx.s_wait().await {
-        // If the transmitter immediately returns a value then return that
-        Ok(inner) => Some(inner),
-        // If the transmitter immediately returns an error then return nothing
-        Err(nb::Error::Other(e)) => {
-            info!("Error in transmitting: {}", e);
-            None
-        },
-        // If the transmitter needs to wait...
-        Err(nb::Error::WouldBlock) => {
-            // ...then wait for the interrupt...
-            irq.await;
-            // ...then if the transmitter returns a value return it.
-            tx.s_wait().await.ok()
-        },
-    };
+#[derive(Debug)]
+pub enum RadioError {
+    /// An earlier error took the driver with it, so the radio has to be reset.
+    NotReady,
+    /// The radio raised its interrupt without having finished sending.
+    NotSent,
+    /// The radio raised its interrupt without having finished receiving.
+    NotReceived,
+    /// The radio didn't turn on after being reset.
+    NotPowered,
+    Dw3000(dw3000_ng::Error<Device>)
+}
 
-    // Take the device out of transmitting mode.
-    let mut dw = tx.finish_sending().await.expect("Failed to leave transmitting mode");
-    // Disable all interrupts.
-    dw.disable_interrupts().await.expect("Failed to disable transmitter interrupts");
+impl From<dw3000_ng::Error<Device>> for RadioError {
+    fn from(e: dw3000_ng::Error<Device>) -> Self {
+        Self::Dw3000(e)
+    }
+}
 
-    (dw, response)
+/// The DWM3000 module and the pins it's wired to.
+struct Dw3000Radio {
+    spi: &'static Mutex<CriticalSectionRawMutex, Spim<'static, SPI3>>,
+    cs: P0_24,
+    reset: Output<'static>,
+    exton: Input<'static>,
+    irq: Input<'static>,
+    /// The driver has to be moved in and out of its sending and receiving states, and an error along the way loses it.
+    dw: Option<DW3000<Device, Ready>>
 }
 
-async fn try_receive<T, I>(rx: &mut DW3000<T, SingleBufferReceiving>, irq: I) -> Result<([u8; 128], usize, Instant, RxQuality), dw3000_ng::Error<T>>
-where
-    T: embedded_hal_async::spi::SpiDevice,
-    I: Future<Output = ()>,
-    <T as embedded_hal_async::spi::ErrorType>::Error: defmt::Format
-{
-    // Allocate a buffer for the received message.
-    let mut buf = [0u8; 128];
+impl Dw3000Radio {
+    /// Holds the radio in reset, where it draws next to nothing, until it's next reset.
+    fn power_down(&mut self) {
+        self.dw = None;
+        self.reset.set_low();
+    }
 
-    match rx.r_wait_buf(&mut buf).await {
-        // If the receiver immediately returns a value then return that
-        Ok(inner) => Ok((buf, inner.0, inner.1, inner.2)),
-        // If the receiver immediately returns an error then return nothing
-        Err(nb::Error::Other(e)) => Err(e),
-        // If the receiver needs to wait...
-        Err(nb::Error::WouldBlock) => {
-            // ...then wait for the interrupt...
-            irq.await;
-            // ...then if the receiver returns a value return it.
-            match rx.r_wait_buf(&mut buf).await {
-                Ok(inner) => Ok((buf, inner.0, inner.1, inner.2)),
-                Err(nb::Error::Other(e)) => Err(e),
-                Err(nb::Error::WouldBlock) => unreachable!()
+    /// Sets the radio up from scratch with a new driver, first pulling its reset pin if `hard` is set.
+    async fn bring_up(&mut self, hard: bool) -> Result<(), RadioError> {
+        // Drop the old driver first so that its chip select pin is free again
+        self.dw = None;
+
+        // SAFETY: The only other owner of the pin was the driver that was just dropped
+        let cs = Output::new(unsafe { self.cs.clone_unchecked() }, Level::High, OutputDrive::Standard);
+        let dwm = DW3000::new(SpiDevice::new(self.spi, cs));
+
+        if hard {
+            // Make sure we really are pulling the reset pin low
+            self.reset.set_low();
+
+            // Wait at least 50ms for the reset
+            Timer::after_millis(50).await;
+
+            // Turn the device on.
+            self.reset.set_high();
+
+            // Wait for the device to power on
+            info!("Waiting for DWM3000 to turn on");
+            if let Either::Second(_) = select(self.exton.wait_for_high(), Timer::after_millis(100)).await {
+                return Err(RadioError::NotPowered);
             }
+            info!("DWM3000 turned on!");
+
+            // Wait for the SPIRDY interrupt indicating that we can start communicating over spi
+            info!("Waiting for DWM3000 to move into IDLE_RC state");
+            match select(self.irq.wait_for_high(), Timer::after_millis(50)).await {
+                Either::First(_) => info!("Received SPIRDY Interrupt"),
+                Either::Second(_) => info!("SPIRDY Interrupt took too long. Moving forward anyways."),
+            };
         }
+
+        // Initialize the DWM3000
+        info!("Initializing the DWM3000");
+        let mut dwm = dwm
+            .init().await?
+            .config(dw_config(), embassy_time::Delay).await?;
+
+        // Calibrated for each module, and applied by the radio to every timestamp it takes
+        let (tx_delay, rx_delay) = {
+            let config = crate::config::CONFIG.lock().await;
+            (config.antenna_delay_tx, config.antenna_delay_rx)
+        };
+        dwm.set_antenna_delay(rx_delay, tx_delay).await?;
+        info!("DWM3000 antenna delays are {} ticks sending and {} receiving", tx_delay, rx_delay);
+
+        // Answer to the same short address that the controller uses on the mesh
+        let (pan, _) = dwm.get_address().await?;
+        dwm.set_address(pan, ShortAddress(crate::identity::address())).await?;
+        info!("DWM3000 Address is {:04x}", crate::identity::address());
+
+        // Turn off the SPIRDY interrupt (really this is just to be safe)
+        dwm.disable_interrupts().await?;
+
+        self.dw = Some(dwm);
+
+        Ok(())
     }
 }
 
-/// This function will loop until it successfully receives a packet. In order to do so, however, it must take ownership of the device, so
-/// in the event that there were no packets to be receieved it would just destroy the device. To solve for this you must pass it a timeout
-/// duration after which point it will return the device to you. The `Option` is only ever a `None` in the event that this occurs.
-async fn must_receive<'a, T>(mut dwm: DW3000<T, Ready>, irq_pin: &mut Input<'a>, time_out: Duration) -> (DW3000<T, Ready>, Option<([u8; 128], usize, Instant, RxQuality)>)
-where
-    T: embedded_hal_async::spi::SpiDevice,
-    <T as embedded_hal_async::spi::ErrorType>::Error: defmt::Format
-{
-    use embassy_futures::select::{select, Either};
+impl UwbRadio for Dw3000Radio {
+    type Error = RadioError;
+
+    async fn transmit(&mut self, payload: &[u8], delay: core::time::Duration) -> Result<u64, Self::Error> {
+        Timer::after_micros(delay.as_micros() as u64).await;
+
+        let mut dw = self.dw.take().ok_or(RadioError::NotReady)?;
+
+        // Disable all interrupts just in case someone was lazy
+        dw.disable_interrupts().await?;
+        // Enable transmitting interrupts on the irq pin.
+        dw.enable_tx_interrupts().await?;
+        // Put the device into transmitting mode.
+        let mut tx = dw.send(payload, SendTime::Now, dw_config()).await?;
+
+        let response = match tx.s_wait().await {
+            // If the transmitter immediately returns a value then return that
+            Ok(inner) => Ok(inner),
+            // If the transmitter immediately returns an error then return that
+            Err(nb::Error::Other(e)) => Err(RadioError::Dw3000(e)),
+            // If the transmitter needs to wait...
+            Err(nb::Error::WouldBlock) => {
+                // ...then wait for the interrupt...
+                self.irq.wait_for_high().await;
+                // ...then if the transmitter returns a value return it.
+                match tx.s_wait().await {
+                    Ok(inner) => Ok(inner),
+                    Err(nb::Error::Other(e)) => Err(RadioError::Dw3000(e)),
+                    Err(nb::Error::WouldBlock) => Err(RadioError::NotSent),
+                }
+            },
+        };
 
-    let after = embassy_time::Instant::now() + time_out;
+        // Take the device out of transmitting mode.
+        let mut dw = tx.finish_sending().await.map_err(|(_, e)| RadioError::Dw3000(e))?;
+        // Disable all interrupts.
+        dw.disable_interrupts().await?;
+
+        self.dw = Some(dw);
+
+        response.map(|inst| inst.value())
+    }
+
+    async fn receive(&mut self, buf: &mut [u8], timeout: core::time::Duration) -> Result<Option<Received>, Self::Error> {
+        let mut dw = self.dw.take().ok_or(RadioError::NotReady)?;
+
+        let after = embassy_time::Instant::now() + embassy_time::Duration::from_micros(timeout.as_micros() as u64);
 
-    loop {
         // Enable receiver interrupts
-        dwm.enable_rx_interrupts().await.expect("Failed to enable receiver interrupts");
+        dw.enable_rx_interrupts().await?;
         // Enter receiving mode
-        let mut rx = dwm.receive(dw_config()).await.expect("Failed to enter receiving mode");
+        let mut rx = dw.receive(dw_config()).await?;
+
+        let mut frame = [0u8; 128];
 
-        let res = select(try_receive(&mut rx, irq_pin.wait_for_high()), Timer::at(after)).await;
+        let res = select(try_receive(&mut rx, &mut frame, self.irq.wait_for_high()), Timer::at(after)).await;
         // Put the device back where we found it
-        dwm = rx.finish_receiving().await.expect("Failed to leave receiving mode");
+        let mut dw = rx.finish_receiving().await.map_err(|(_, e)| RadioError::Dw3000(e))?;
         // Disable the interrupts we enabled
-        dwm.disable_interrupts().await.expect("Failed to disable receiver interrupts");
+        dw.disable_interrupts().await?;
+
+        self.dw = Some(dw);
 
         match res {
-            Either::First(Ok(inner)) => return (dwm, Some(inner)),
-            Either::First(Err(e)) => {
-                info!("Error in receiving: {}", e);
-                RESET.signal(());
-                return (dwm, None);
+            Either::First(Ok((len, rx_inst, _qual))) => {
+                // The ranging payload sits just before the frame's footer
+                let payload: &[u8] = if len > RANGING_PAYLOAD_LENGTH + 4 { &frame[len-4-RANGING_PAYLOAD_LENGTH..len-4] } else { &[] };
+                buf[..payload.len()].copy_from_slice(payload);
+
+                Ok(Some(Received { len: payload.len(), timestamp: rx_inst.value() }))
             },
-            Either::Second(()) => {
-                info!("Receiver timed out");
-                return (dwm, None);
+            Either::First(Err(e)) => Err(e),
+            Either::Second(()) => Ok(None),
+        }
+    }
+
+    async fn reset(&mut self) -> Result<(), Self::Error> {
+        self.bring_up(true).await
+    }
+
+    async fn reconfigure(&mut self) -> Result<(), Self::Error> {
+        self.bring_up(false).await
+    }
+}
+
+async fn try_receive<I>(rx: &mut DW3000<Device, SingleBufferReceiving>, buf: &mut [u8], irq: I) -> Result<(usize, Instant, RxQuality), RadioError>
+where
+    I: Future<Output = ()>
+{
+    match rx.r_wait_buf(buf).await {
+        // If the receiver immediately returns a value then return that
+        Ok(inner) => Ok(inner),
+        // If the receiver immediately returns an error then return nothing
+        Err(nb::Error::Other(e)) => Err(RadioError::Dw3000(e)),
+        // If the receiver needs to wait...
+        Err(nb::Error::WouldBlock) => {
+            // ...then wait for the interrupt...
+            irq.await;
+            // ...then if the receiver returns a value return it.
+            match rx.r_wait_buf(buf).await {
+                Ok(inner) => Ok(inner),
+                Err(nb::Error::Other(e)) => Err(RadioError::Dw3000(e)),
+                Err(nb::Error::WouldBlock) => Err(RadioError::NotReceived)
             }
         }
     }
@@ -0,0 +1,233 @@
+//! # Wireless cuffs
+//!
+//! A cuff can be linked over Bluetooth LE instead of the cable (see `harmoneyes_core::wireless`), with the controller
+//! as the central. The mesh scan in `ble` hears the advertisement of the cuff paired with a wrist, this task borrows
+//! the scanner to connect to it, and the connection is then kept for `cuff` to send commands over until it drops or
+//! `cuff` gives up on it and asks for it to be made again.
+//!
+//! Pairing is by serial number: the controller only connects to the cuff whose serial number is set for the wrist in
+//! the config. The softdevice is set up without any security, so the link isn't encrypted.
+
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::{self, raw::CriticalSectionRawMutex}, mutex::Mutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Ticker};
+use harmoneyes_core::{config::{CuffLink, Name}, haptics::Wrist, health::Task, wireless::{MAX_COMMAND_LENGTH, MAX_RESPONSE_LENGTH}};
+use heapless::Vec;
+use nrf_softdevice::{ble::{central, gatt_client, Address, Connection}, Softdevice};
+
+use crate::transport::{Error, Transport};
+
+/// How long connecting to a cuff and finding its service can take before giving up until it's heard again.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a cuff can take to acknowledge a command, which is a few connection intervals.
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The shortest and longest connection interval in units of 1.25 ms. Short enough that a cue isn't noticeably later
+/// than over the cable.
+const MIN_CONNECTION_INTERVAL: u16 = 12;
+const MAX_CONNECTION_INTERVAL: u16 = 24;
+
+/// Connecting needs the scanner, which `ble` otherwise keeps busy with the mesh and hands over while this is held.
+pub static SCANNER: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+static SCANNER_WANTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SCANNER_RETURNED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The serial number of the cuff paired with each wrist, for `heard` to look for.
+static PAIRED: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<[Option<Name>; Wrist::ALL.len()]>> =
+    blocking_mutex::Mutex::new(RefCell::new([None; Wrist::ALL.len()]));
+
+/// Where the cuff paired with each wrist was last heard advertising from.
+static HEARD: [Signal<CriticalSectionRawMutex, Address>; Wrist::ALL.len()] = [const { Signal::new() }; Wrist::ALL.len()];
+
+static CONNECTIONS: [Mutex<CriticalSectionRawMutex, Option<Connected>>; Wrist::ALL.len()] =
+    [const { Mutex::new(None) }; Wrist::ALL.len()];
+
+// The UUIDs are those in `harmoneyes_core::wireless`, written out since the macro only takes literals
+#[nrf_softdevice::gatt_client(uuid = "6e4a0001-8b5d-4c3f-9a52-4861726d6f6e")]
+struct CuffClient {
+    #[characteristic(uuid = "6e4a0002-8b5d-4c3f-9a52-4861726d6f6e", write)]
+    command: Vec<u8, MAX_COMMAND_LENGTH>,
+    #[characteristic(uuid = "6e4a0003-8b5d-4c3f-9a52-4861726d6f6e", read)]
+    response: Vec<u8, MAX_RESPONSE_LENGTH>
+}
+
+/// A connection to a wireless cuff and its service.
+struct Connected {
+    serial: Name,
+    connection: Connection,
+    client: CuffClient
+}
+
+/// Called from the mesh scan for every wireless cuff it hears, to note down where the paired ones are.
+pub fn heard(serial: &str, address: Address) {
+    PAIRED.lock(|paired| {
+        for wrist in Wrist::ALL {
+            if paired.borrow()[wrist.index()].is_some_and(|paired| paired.as_str() == serial) {
+                HEARD[wrist.index()].signal(address);
+            }
+        }
+    });
+}
+
+/// Waits until a cuff is about to be connected to, at which point `ble` should stop scanning and give up `SCANNER`.
+pub async fn scanner_wanted() {
+    SCANNER_WANTED.wait().await
+}
+
+/// Waits until the scanner isn't needed for connecting any more.
+pub async fn scanner_returned() {
+    SCANNER_RETURNED.wait().await
+}
+
+/// Drops the connection to the cuff on `wrist`, so that it's connected to afresh the next time it's heard.
+pub async fn disconnect(wrist: Wrist) {
+    if let Some(connected) = CONNECTIONS[wrist.index()].lock().await.take() {
+        info!("Dropping the connection to the {} cuff", wrist.name());
+        let _ = connected.connection.disconnect();
+    }
+}
+
+/// Keeps each wrist that has a wireless cuff connected to it.
+#[embassy_executor::task]
+pub async fn task(sd: &'static Softdevice) {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+
+    loop {
+        crate::health::check_in(Task::Wireless);
+        ticker.next().await;
+
+        let config = *crate::config::CONFIG.lock().await;
+
+        for wrist in Wrist::ALL {
+            let serial = match config.cuff_link(wrist) {
+                Some(CuffLink::Wireless(serial)) => Some(serial),
+                _ => None,
+            };
+            PAIRED.lock(|paired| paired.borrow_mut()[wrist.index()] = serial);
+
+            let connected = {
+                let mut connection = CONNECTIONS[wrist.index()].lock().await;
+
+                // Gone, or to a cuff that's no longer the one paired with the wrist
+                if let Some(connected) = connection.as_ref()
+                    && (!connected.connection.is_connected() || Some(connected.serial) != serial)
+                {
+                    info!("The connection to the {} cuff has gone", wrist.name());
+                    let _ = connected.connection.disconnect();
+                    *connection = None;
+                }
+
+                connection.is_some()
+            };
+
+            let Some(serial) = serial else { continue };
+
+            // Not holding on to the connection while connecting, so `cuff` finds out straight away that it isn't there
+            if !connected && let Some(address) = HEARD[wrist.index()].try_take() {
+                *CONNECTIONS[wrist.index()].lock().await = connect(sd, wrist, serial, address).await;
+            }
+        }
+    }
+}
+
+async fn connect(sd: &'static Softdevice, wrist: Wrist, serial: Name, address: Address) -> Option<Connected> {
+    SCANNER_WANTED.signal(());
+    let scanner = SCANNER.lock().await;
+    SCANNER_WANTED.reset();
+
+    let whitelist = [&address];
+    let mut config = central::ConnectConfig::default();
+    config.scan_config.whitelist = Some(&whitelist);
+    config.conn_params.min_conn_interval = MIN_CONNECTION_INTERVAL;
+    config.conn_params.max_conn_interval = MAX_CONNECTION_INTERVAL;
+
+    let connection = with_timeout(CONNECT_TIMEOUT, central::connect(sd, &config)).await;
+
+    drop(scanner);
+    SCANNER_RETURNED.signal(());
+
+    let connection = match connection {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            warn!("Could not connect to the {} cuff: {}", wrist.name(), e);
+            return None;
+        },
+        Err(_) => {
+            warn!("Timed out connecting to the {} cuff", wrist.name());
+            return None;
+        },
+    };
+
+    let client = match with_timeout(CONNECT_TIMEOUT, gatt_client::discover::<CuffClient>(&connection)).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(e)) => {
+            warn!("The {} cuff doesn't have the cuff service: {}", wrist.name(), e);
+            let _ = connection.disconnect();
+            return None;
+        },
+        Err(_) => {
+            warn!("Timed out finding the {} cuff's service", wrist.name());
+            let _ = connection.disconnect();
+            return None;
+        },
+    };
+
+    info!("Connected to the {} cuff {}", wrist.name(), serial.as_str());
+    crate::crash::note(format_args!("{} cuff connected", wrist.name()));
+
+    Some(Connected { serial, connection, client })
+}
+
+/// The wireless cuff on a wrist, through whatever connection there is to it at the time.
+pub struct Radio {
+    wrist: Wrist
+}
+
+impl Radio {
+    pub const fn new(wrist: Wrist) -> Self {
+        Self { wrist }
+    }
+}
+
+impl Transport for Radio {
+    async fn send(&mut self, command: &[u8]) -> Result<(), Error> {
+        let connection = CONNECTIONS[self.wrist.index()].lock().await;
+        let connected = connection.as_ref().ok_or(Error::Disconnected)?;
+
+        write(connected, command).await
+    }
+
+    async fn exchange(&mut self, command: &[u8], answer: &mut [u8]) -> Result<(), Error> {
+        // Held across both so that nothing else is asked for in between
+        let connection = CONNECTIONS[self.wrist.index()].lock().await;
+        let connected = connection.as_ref().ok_or(Error::Disconnected)?;
+
+        write(connected, command).await?;
+
+        let response = match with_timeout(TIMEOUT, connected.client.response_read()).await {
+            Ok(Ok(response)) => response,
+            _ => return Err(Error::Radio),
+        };
+
+        if response.len() != answer.len() {
+            return Err(Error::Radio);
+        }
+        answer.copy_from_slice(&response);
+
+        Ok(())
+    }
+}
+
+/// Writes a command and waits for the cuff to acknowledge it.
+async fn write(connected: &Connected, command: &[u8]) -> Result<(), Error> {
+    let command = Vec::from_slice(command).map_err(|_| Error::Radio)?;
+
+    match with_timeout(TIMEOUT, connected.client.command_write(&command)).await {
+        Ok(Ok(())) => Ok(()),
+        _ => Err(Error::Radio),
+    }
+}
@@ -1,12 +1,12 @@
 use defmt::unwrap;
 use embassy_nrf::{peripherals::{P0_16, PWM0}, pwm::{Config, Prescaler, SequenceConfig, SequenceLoad, SequencePwm, SingleSequenceMode, SingleSequencer}};
 use embassy_time::Timer;
+use harmoneyes_core::status::Colour;
 
-// WS2812B LED light demonstration. Drives just one light.
+// WS2812B LED driver. Drives just one light.
 // The following reference on WS2812B may be of use:
 // https://cdn-shop.adafruit.com/datasheets/WS2812B.pdf.
-// This demo lights up a single LED in blue. It then proceeds
-// to pulsate the LED rapidly.
+// What the LED shows is up to the status LED service (see `status`).
 //
 // /!\ NOTE FOR nRF52840-DK users /!\
 //
@@ -25,56 +25,50 @@ const T1H: u16 = 0x8000 | 13; // Duty = 13/20 ticks (0.8us/1.25us) for a 1
 const T0H: u16 = 0x8000 | 7; // Duty 7/20 ticks (0.4us/1.25us) for a 0
 const RES: u16 = 0x8000;
 
-// Provides data to a WS2812b (Neopixel) LED and makes it go blue. The data
-// line is assumed to be P1_05.
+/// The PWM sequence for a colour, which the LED wants in green, red, blue order.
+fn sequence_words((red, green, blue): Colour) -> [u16; 25] {
+    let mut words = [RES; 25];
 
-#[embassy_executor::task]
-pub async fn task(
-    p_pwm: PWM0,
-    p_pin: P0_16
-) {
-    let mut config = Config::default();
-    config.sequence_load = SequenceLoad::Common;
-    config.prescaler = Prescaler::Div1;
-    config.max_duty = 20; // 1.25us (1s / 16Mhz * 20)
-    let mut pwm = unwrap!(SequencePwm::new_1ch(p_pwm, p_pin, config));
+    for (i, byte) in [green, red, blue].into_iter().enumerate() {
+        for bit in 0..8 {
+            words[i * 8 + bit] = if byte & (0x80 >> bit) != 0 { T1H } else { T0H };
+        }
+    }
 
-    // Declare the bits of 24 bits in a buffer we'll be
-    // mutating later.
-    let mut seq_words = [
-        T0H, T0H, T0H, T0H, T0H, T0H, T0H, T0H, // G
-        T0H, T0H, T0H, T0H, T0H, T0H, T0H, T0H, // R
-        T1H, T1H, T1H, T1H, T1H, T1H, T1H, T1H, // B
-        RES,
-    ];
-    let mut seq_config = SequenceConfig::default();
-    seq_config.end_delay = 799; // 50us (20 ticks * 40) - 1 tick because we've already got one RES;
+    words
+}
 
-    let mut color_bit = 16;
-    let mut bit_value = T0H;
+/// A single WS2812B (Neopixel) LED on P0_16.
+pub struct Ws2812 {
+    pwm: SequencePwm<'static, PWM0>
+}
 
-    loop {
-        let sequences = SingleSequencer::new(&mut pwm, &seq_words, seq_config.clone());
-        unwrap!(sequences.start(SingleSequenceMode::Times(1)));
+impl Ws2812 {
+    pub fn new(
+        p_pwm: PWM0,
+        p_pin: P0_16
+    ) -> Self {
+        let mut config = Config::default();
+        config.sequence_load = SequenceLoad::Common;
+        config.prescaler = Prescaler::Div1;
+        config.max_duty = 20; // 1.25us (1s / 16Mhz * 20)
 
-        Timer::after_millis(50).await;
+        Self { pwm: unwrap!(SequencePwm::new_1ch(p_pwm, p_pin, config)) }
+    }
 
-        if bit_value == T0H {
-            if color_bit == 20 {
-                bit_value = T1H;
-            } else {
-                color_bit += 1;
-            }
-        } else {
-            if color_bit == 16 {
-                bit_value = T0H;
-            } else {
-                color_bit -= 1;
-            }
-        }
+    /// Turns the LED to `colour`, where it stays until the next write.
+    pub async fn write(&mut self, colour: Colour) {
+        let words = sequence_words(colour);
 
-        drop(sequences);
+        let mut seq_config = SequenceConfig::default();
+        seq_config.end_delay = 799; // 50us (20 ticks * 40) - 1 tick because we've already got one RES;
+
+        let sequences = SingleSequencer::new(&mut self.pwm, &words, seq_config);
+        unwrap!(sequences.start(SingleSequenceMode::Times(1)));
 
-        seq_words[color_bit] = bit_value;
+        // The sequence takes around 80us, and dropping the sequencer before it's done would cut it short
+        Timer::after_micros(200).await;
+
+        drop(sequences);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,844 @@
+//! Working out how much charge is left in a single cell LiPo battery from its voltage.
+//!
+//! A LiPo's voltage hardly moves across the middle of its discharge, then falls away quickly near the end, so a
+//! straight line between full and empty badly overstates the charge left in the middle and understates it near the
+//! end. [`state_of_charge`] looks the voltage up on a measured discharge curve instead.
+//!
+//! The voltage also sags while the battery is under load, by the current drawn times the battery's internal
+//! resistance, so a reading taken while the UWB radio is transmitting or the motors are running looks emptier than
+//! it is. [`Gauge`] adds that sag back on based on which parts of the device were running, averages the last few
+//! samples, and only moves the percentage it reports once the estimate has moved far enough to be sure.
+//!
+//! [`Runtime`] watches how fast that percentage falls to estimate how long the battery has left, and [`Escalation`]
+//! turns the two into a low battery [`Level`] that only ever gets more urgent until the battery is charged.
+//!
+//! None of that means much while the battery is on charge, which [`Charger`] works out from whether USB power is
+//! present and which way the voltage is heading.
+
+use crate::codec::{Error, Reader, Writer};
+
+/// The resting voltage of a cell at each state of charge, from full to empty. Below the last point the regulator
+/// turns off, so that's counted as empty.
+const DISCHARGE_CURVE: [(u16, u8); 21] = [
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 75),
+    (3950, 70),
+    (3910, 65),
+    (3870, 60),
+    (3850, 55),
+    (3840, 50),
+    (3820, 45),
+    (3800, 40),
+    (3790, 35),
+    (3770, 30),
+    (3750, 25),
+    (3730, 20),
+    (3710, 15),
+    (3690, 10),
+    (3610, 5),
+    (3300, 0),
+];
+
+/// The internal resistance of the battery plus its protection circuit and wiring, in milliohms.
+pub const INTERNAL_RESISTANCE_MILLIOHMS: u32 = 200;
+
+/// How many samples [`Gauge`] averages over.
+pub const AVERAGED_SAMPLES: usize = 8;
+
+/// How far the estimate has to move from the reported percentage before the report changes.
+pub const HYSTERESIS_PERCENT: u8 = 2;
+
+/// Which of the power hungry parts of the device were running while a sample was taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Load {
+    /// Whether the UWB radio was ranging.
+    pub uwb: bool,
+    /// How many of the cuff's motors were running.
+    pub motors: u8
+}
+
+impl Load {
+    /// The current the device draws with nothing else running.
+    const IDLE_MILLIAMPS: u32 = 15;
+    /// The average current the UWB radio draws while ranging, which is mostly spent listening.
+    const UWB_MILLIAMPS: u32 = 60;
+    /// The current each motor draws.
+    const MOTOR_MILLIAMPS: u32 = 70;
+
+    /// A rough estimate of the current the device draws under this load.
+    pub const fn milliamps(&self) -> u32 {
+        Self::IDLE_MILLIAMPS + if self.uwb { Self::UWB_MILLIAMPS } else { 0 } + self.motors as u32 * Self::MOTOR_MILLIAMPS
+    }
+
+    /// How far the battery voltage sags under this load.
+    pub const fn sag_millivolts(&self) -> u16 {
+        (self.milliamps() * INTERNAL_RESISTANCE_MILLIOHMS / 1000) as u16
+    }
+}
+
+/// Looks up the state of charge in percent of a cell resting at the given voltage.
+pub fn state_of_charge(millivolts: u16) -> u8 {
+    let (full, _) = DISCHARGE_CURVE[0];
+    if millivolts >= full {
+        return 100;
+    }
+
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let (upper_mv, upper_percent) = pair[0];
+        let (lower_mv, lower_percent) = pair[1];
+
+        if millivolts >= lower_mv {
+            // Linear between the two nearest points on the curve
+            let span = (upper_percent - lower_percent) as u32;
+            let above = (millivolts - lower_mv) as u32;
+            let width = (upper_mv - lower_mv) as u32;
+            return lower_percent + ((above * span + width / 2) / width) as u8;
+        }
+    }
+
+    0
+}
+
+/// Turns a stream of battery voltage samples into a steady state of charge.
+#[derive(Clone, Debug)]
+pub struct Gauge {
+    /// The most recent load compensated samples, overwritten in a ring starting at `next`.
+    samples: [u16; AVERAGED_SAMPLES],
+    len: usize,
+    next: usize,
+    reported: Option<u8>
+}
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self { samples: [0; AVERAGED_SAMPLES], len: 0, next: 0, reported: None }
+    }
+
+    /// Adds a sample of the battery voltage taken under `load` and returns the state of charge to report.
+    pub fn push(&mut self, millivolts: u16, load: Load) -> u8 {
+        self.samples[self.next] = millivolts.saturating_add(load.sag_millivolts());
+        self.next = (self.next + 1) % AVERAGED_SAMPLES;
+        self.len = (self.len + 1).min(AVERAGED_SAMPLES);
+
+        let estimate = state_of_charge(self.millivolts());
+
+        let reported = match self.reported {
+            Some(reported) if estimate.abs_diff(reported) < HYSTERESIS_PERCENT => reported,
+            _ => estimate,
+        };
+        self.reported = Some(reported);
+
+        reported
+    }
+
+    /// The average of the recent load compensated samples, or zero before the first one.
+    pub fn millivolts(&self) -> u16 {
+        if self.len == 0 {
+            return 0;
+        }
+
+        let sum: u32 = self.samples[..self.len].iter().map(|&mv| mv as u32).sum();
+        (sum / self.len as u32) as u16
+    }
+
+    /// The state of charge last returned from [`Gauge::push`].
+    pub fn percent(&self) -> Option<u8> {
+        self.reported
+    }
+
+    /// Forgets every sample, for when the battery has been swapped or the charger has been plugged in or out and the
+    /// old samples no longer say anything about it.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How often [`Runtime`] keeps a sample of the state of charge.
+pub const RUNTIME_INTERVAL_MS: u64 = 60_000;
+
+/// How many samples [`Runtime`] keeps, so the discharge rate is taken over roughly this many minutes.
+pub const RUNTIME_SAMPLES: usize = 10;
+
+/// How far above the warning threshold the battery has to charge before a low battery alert is stood down.
+pub const RECOVERY_PERCENT: u8 = 5;
+
+/// Estimates how long the battery has left from how fast its state of charge has been falling.
+#[derive(Clone, Debug)]
+pub struct Runtime {
+    /// Uptime in milliseconds and state of charge, overwritten in a ring starting at `next`.
+    samples: [(u64, u8); RUNTIME_SAMPLES],
+    len: usize,
+    next: usize
+}
+
+impl Runtime {
+    pub const fn new() -> Self {
+        Self { samples: [(0, 0); RUNTIME_SAMPLES], len: 0, next: 0 }
+    }
+
+    /// Adds the state of charge at the given uptime, keeping at most one sample every `RUNTIME_INTERVAL_MS`.
+    pub fn push(&mut self, uptime_ms: u64, percent: u8) {
+        if self.len > 0 && uptime_ms < self.newest().0 + RUNTIME_INTERVAL_MS {
+            return;
+        }
+
+        self.samples[self.next] = (uptime_ms, percent);
+        self.next = (self.next + 1) % RUNTIME_SAMPLES;
+        self.len = (self.len + 1).min(RUNTIME_SAMPLES);
+    }
+
+    /// How many minutes the battery has left at the rate it has been falling, or `None` if it hasn't been falling
+    /// for long enough to tell, or is charging.
+    pub fn minutes_remaining(&self) -> Option<u16> {
+        if self.len < 2 {
+            return None;
+        }
+
+        let (start_ms, start_percent) = self.oldest();
+        let (end_ms, end_percent) = self.newest();
+
+        if end_percent >= start_percent {
+            return None;
+        }
+
+        let fallen = (start_percent - end_percent) as u64;
+        let minutes = end_percent as u64 * (end_ms - start_ms) / fallen / 60_000;
+
+        // The largest value stands in for an unknown time remaining when encoded, see `Alert`
+        Some(minutes.min(u16::MAX as u64 - 1) as u16)
+    }
+
+    /// Forgets every sample, for when the charger has been plugged in or out.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn oldest(&self) -> (u64, u8) {
+        if self.len < RUNTIME_SAMPLES { self.samples[0] } else { self.samples[self.next] }
+    }
+
+    fn newest(&self) -> (u64, u8) {
+        self.samples[(self.next + RUNTIME_SAMPLES - 1) % RUNTIME_SAMPLES]
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How urgently a performer's battery needs attention, from least to most urgent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Time to plan a battery change at the next break.
+    Warning,
+    /// The battery needs changing now.
+    Critical,
+    /// The regulator is about to turn the device off.
+    ShutdownImminent
+}
+
+impl Level {
+    pub const ALL: [Level; 3] = [Level::Warning, Level::Critical, Level::ShutdownImminent];
+
+    /// The level a battery at this state of charge, and with this long left, is at.
+    pub fn assess(percent: u8, minutes_remaining: Option<u16>) -> Option<Self> {
+        Self::ALL.into_iter().rev().find(|level| {
+            let (threshold_percent, threshold_minutes) = level.thresholds();
+            percent <= threshold_percent || minutes_remaining.is_some_and(|minutes| minutes <= threshold_minutes)
+        })
+    }
+
+    /// The state of charge and minutes remaining at or below which the battery is at this level.
+    pub const fn thresholds(self) -> (u8, u16) {
+        match self {
+            Level::Warning => (20, 30),
+            Level::Critical => (10, 10),
+            Level::ShutdownImminent => (3, 3),
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Level::Warning => "warning",
+            Level::Critical => "critical",
+            Level::ShutdownImminent => "shutdown imminent",
+        }
+    }
+
+    const fn code(self) -> u8 {
+        match self {
+            Level::Warning => 0x01,
+            Level::Critical => 0x02,
+            Level::ShutdownImminent => 0x03,
+        }
+    }
+
+    const fn from_code(code: u8) -> Result<Self, Error> {
+        match code {
+            0x01 => Ok(Level::Warning),
+            0x02 => Ok(Level::Critical),
+            0x03 => Ok(Level::ShutdownImminent),
+            code => Err(Error::UnknownTag(code))
+        }
+    }
+}
+
+/// Raises each low battery level once as the battery runs down.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Escalation {
+    level: Option<Level>
+}
+
+impl Escalation {
+    pub const fn new() -> Self {
+        Self { level: None }
+    }
+
+    /// Returns the level the battery has just reached, if it's more urgent than any raised before. The estimate of
+    /// the time remaining jumps around, so a level is only stood down once the battery has charged back up well
+    /// past the warning threshold.
+    pub fn update(&mut self, percent: u8, minutes_remaining: Option<u16>) -> Option<Level> {
+        match Level::assess(percent, minutes_remaining) {
+            Some(level) if self.level.is_none_or(|raised| level > raised) => {
+                self.level = Some(level);
+                Some(level)
+            },
+            None if percent >= Level::Warning.thresholds().0 + RECOVERY_PERCENT => {
+                self.level = None;
+                None
+            },
+            _ => None
+        }
+    }
+
+    /// The most urgent level raised and not yet stood down.
+    pub fn level(&self) -> Option<Level> {
+        self.level
+    }
+}
+
+/// A low battery alert, as broadcast over the mesh and streamed to the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Alert {
+    pub level: Level,
+    pub percent: u8,
+    pub minutes_remaining: Option<u16>
+}
+
+impl Alert {
+    pub const ENCODED_LENGTH: usize = 4;
+
+    /// Stands in for an unknown time remaining.
+    const UNKNOWN_MINUTES: u16 = u16::MAX;
+
+    pub(crate) fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u8(self.level.code())?;
+        w.u8(self.percent)?;
+        w.u16(self.minutes_remaining.unwrap_or(Self::UNKNOWN_MINUTES))
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            level: Level::from_code(r.u8()?)?,
+            percent: r.u8()?,
+            minutes_remaining: match r.u16()? {
+                Self::UNKNOWN_MINUTES => None,
+                minutes => Some(minutes),
+            }
+        })
+    }
+}
+
+/// The state of a battery as reported by another device, such as the cuff to the controller or a controller to the
+/// rest of the mesh.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub millivolts: u16,
+    pub percent: u8,
+    pub minutes_remaining: Option<u16>
+}
+
+impl Report {
+    pub const ENCODED_LENGTH: usize = 5;
+
+    pub(crate) fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u16(self.millivolts)?;
+        w.u8(self.percent)?;
+        w.u16(self.minutes_remaining.unwrap_or(Alert::UNKNOWN_MINUTES))
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            millivolts: r.u16()?,
+            percent: r.u8()?,
+            minutes_remaining: match r.u16()? {
+                Alert::UNKNOWN_MINUTES => None,
+                minutes => Some(minutes),
+            }
+        })
+    }
+
+    /// Writes a report that might not have been taken yet, behind a byte saying whether it's there.
+    pub(crate) fn write_optional(report: Option<Self>, w: &mut Writer) -> Result<(), Error> {
+        w.u8(report.is_some() as u8)?;
+        report.unwrap_or(Self { millivolts: 0, percent: 0, minutes_remaining: None }).write(w)
+    }
+
+    pub(crate) fn read_optional(r: &mut Reader) -> Result<Option<Self>, Error> {
+        let present = r.u8()? != 0;
+        let report = Self::read(r)?;
+
+        Ok(present.then_some(report))
+    }
+}
+
+/// At or above this voltage a battery on charge that has stopped rising is full.
+pub const FULL_MILLIVOLTS: u16 = 4150;
+
+/// A full battery that falls below this voltage while still on USB power has started charging again.
+pub const RECHARGE_MILLIVOLTS: u16 = 4050;
+
+/// How much the voltage has to rise by to count as still charging.
+pub const RISE_MILLIVOLTS: u16 = 10;
+
+/// How many samples in a row the voltage has to stay flat near the top before the battery counts as full.
+pub const PLATEAU_SAMPLES: u16 = 24;
+
+/// Whether the battery is running the device or being charged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChargeState {
+    /// The device is running off the battery.
+    #[default]
+    Discharging,
+    /// USB power is present and the charger is topping the battery up.
+    Charging,
+    /// USB power is present and the battery has stopped taking charge.
+    Full
+}
+
+impl ChargeState {
+    pub const fn name(self) -> &'static str {
+        match self {
+            ChargeState::Discharging => "discharging",
+            ChargeState::Charging => "charging",
+            ChargeState::Full => "full",
+        }
+    }
+
+    pub(crate) const fn code(self) -> u8 {
+        match self {
+            ChargeState::Discharging => 0x01,
+            ChargeState::Charging => 0x02,
+            ChargeState::Full => 0x03,
+        }
+    }
+
+    pub(crate) const fn from_code(code: u8) -> Result<Self, Error> {
+        match code {
+            0x01 => Ok(ChargeState::Discharging),
+            0x02 => Ok(ChargeState::Charging),
+            0x03 => Ok(ChargeState::Full),
+            code => Err(Error::UnknownTag(code))
+        }
+    }
+}
+
+/// Works out the [`ChargeState`] from whether USB power is present and the battery voltage. The charger itself
+/// can't be asked, so a battery on USB power counts as full once its voltage has levelled off near the top.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Charger {
+    state: ChargeState,
+    /// The highest voltage seen since the battery last rose by `RISE_MILLIVOLTS`.
+    peak: u16,
+    /// How many samples since the battery last rose by `RISE_MILLIVOLTS`.
+    flat: u16
+}
+
+impl Charger {
+    pub const fn new() -> Self {
+        Self { state: ChargeState::Discharging, peak: 0, flat: 0 }
+    }
+
+    /// Adds a sample and returns the state the battery is now in.
+    pub fn update(&mut self, vbus: bool, millivolts: u16) -> ChargeState {
+        if millivolts >= self.peak.saturating_add(RISE_MILLIVOLTS) || !vbus {
+            self.peak = millivolts;
+            self.flat = 0;
+        } else {
+            self.flat = self.flat.saturating_add(1);
+        }
+
+        self.state = match self.state {
+            _ if !vbus => ChargeState::Discharging,
+            ChargeState::Discharging => ChargeState::Charging,
+            ChargeState::Charging if millivolts >= FULL_MILLIVOLTS && self.flat >= PLATEAU_SAMPLES => ChargeState::Full,
+            ChargeState::Full if millivolts < RECHARGE_MILLIVOLTS => {
+                self.peak = millivolts;
+                self.flat = 0;
+                ChargeState::Charging
+            },
+            state => state,
+        };
+
+        self.state
+    }
+
+    pub fn state(&self) -> ChargeState {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{fs, string::String, vec::Vec};
+
+    use super::*;
+
+    /// A made up discharge of a cell under load (see the file for how it was made), as seconds, millivolts, and
+    /// whether the radio and how many motors were running.
+    const SYNTHETIC_DISCHARGE: &str = include_str!("../tests/data/discharge-synthetic.csv");
+
+    /// Where discharges recorded from real cells are kept, one per file (see the README there).
+    const RECORDED_DISCHARGES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/recorded");
+
+    /// The samples in a discharge log, each as its time in seconds, millivolts, the load, and the current the cell
+    /// was measured giving, which synthetic logs leave out.
+    fn discharge_log(log: &str) -> impl Iterator<Item = (u32, u16, Load, Option<u32>)> + '_ {
+        log.lines()
+            .filter(|line| !line.starts_with('#') && !line.is_empty())
+            .map(|line| {
+                let mut fields = line.split(',').map(|field| field.trim().parse::<u32>().expect("Bad field in the log"));
+                let mut next = || fields.next().expect("Missing field in the log");
+                let (seconds, millivolts, uwb, motors) = (next(), next() as u16, next() == 1, next() as u8);
+                (seconds, millivolts, Load { uwb, motors }, fields.next())
+            })
+    }
+
+    /// How far the synthetic log is into the discharge in percent, which at a steady current is how much charge has
+    /// gone.
+    fn elapsed_percent(seconds: u32) -> u8 {
+        let (end, _, _, _) = discharge_log(SYNTHETIC_DISCHARGE).last().expect("The log is empty");
+        (seconds * 100 / end) as u8
+    }
+
+    /// Every recorded discharge, by file name.
+    fn recorded_discharges() -> Vec<(String, String)> {
+        let mut logs: Vec<_> = fs::read_dir(RECORDED_DISCHARGES)
+            .expect("The recorded discharges are missing")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "csv"))
+            .map(|path| (path.file_name().unwrap().to_string_lossy().into(), fs::read_to_string(&path).unwrap()))
+            .collect();
+        logs.sort();
+        logs
+    }
+
+    /// Fills the gauge with a cell resting at `millivolts`, less the sag of the device idling.
+    fn settle(gauge: &mut Gauge, millivolts: u16) -> u8 {
+        let mut percent = 0;
+        for _ in 0..AVERAGED_SAMPLES {
+            percent = gauge.push(millivolts - Load::default().sag_millivolts(), Load::default());
+        }
+        percent
+    }
+
+    #[test]
+    fn never_reports_more_charge_as_the_battery_runs_down() {
+        let mut gauge = Gauge::new();
+        let mut last = None;
+
+        for (seconds, millivolts, load, _) in discharge_log(SYNTHETIC_DISCHARGE) {
+            let percent = gauge.push(millivolts, load);
+            assert!(last.is_none_or(|last| percent <= last), "Went up to {percent}% at {seconds} s from {last:?}");
+            last = Some(percent);
+        }
+
+        // The average is still catching up with the last few samples when the regulator cuts out
+        assert!(last.is_some_and(|last| last <= HYSTERESIS_PERCENT));
+    }
+
+    #[test]
+    fn follows_the_charge_drawn_out_of_the_battery() {
+        let mut gauge = Gauge::new();
+
+        for (seconds, millivolts, load, _) in discharge_log(SYNTHETIC_DISCHARGE) {
+            let percent = gauge.push(millivolts, load);
+            let expected = 100 - elapsed_percent(seconds);
+
+            // The average lags a few samples behind, on top of the curve's own error
+            assert!(percent.abs_diff(expected) <= 5, "Reported {percent}% at {seconds} s, expected about {expected}%");
+        }
+    }
+
+    #[test]
+    fn follows_the_charge_drawn_out_of_recorded_cells() {
+        for (name, log) in recorded_discharges() {
+            let samples: Vec<_> = discharge_log(&log).collect();
+
+            // The charge drawn by the end of each sample, from the measured current
+            let mut drawn = Vec::with_capacity(samples.len());
+            let mut total = 0;
+            let mut last_seconds = 0;
+            for (seconds, _, _, milliamps) in &samples {
+                let milliamps = milliamps.unwrap_or_else(|| panic!("{name} doesn't have the current the cell gave"));
+                total += (seconds - last_seconds) as u64 * milliamps as u64;
+                drawn.push(total);
+                last_seconds = *seconds;
+            }
+
+            let mut gauge = Gauge::new();
+            let mut last = None;
+            for ((seconds, millivolts, load, _), drawn) in samples.iter().zip(drawn) {
+                let percent = gauge.push(*millivolts, *load);
+                let expected = (100 - drawn * 100 / total) as u8;
+
+                assert!(last.is_none_or(|last| percent <= last), "{name}: went up to {percent}% at {seconds} s");
+                // Looser than for the synthetic log, since the curve is only ever close to a real cell
+                assert!(percent.abs_diff(expected) <= 10, "{name}: reported {percent}% at {seconds} s, expected about {expected}%");
+                last = Some(percent);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_the_curve_between_its_points() {
+        assert_eq!(state_of_charge(4300), 100);
+        assert_eq!(state_of_charge(4200), 100);
+        assert_eq!(state_of_charge(3840), 50);
+        assert_eq!(state_of_charge(3650), 8);
+        assert_eq!(state_of_charge(3300), 0);
+        assert_eq!(state_of_charge(3000), 0);
+    }
+
+    #[test]
+    fn holds_the_report_while_the_estimate_wavers_across_a_breakpoint() {
+        let mut gauge = Gauge::new();
+        assert_eq!(settle(&mut gauge, 3840), 50);
+
+        // 3844 mV and 3836 mV sit either side of the 50% point, a percent off it
+        for millivolts in [3844, 3836].repeat(AVERAGED_SAMPLES * 2) {
+            assert_eq!(gauge.push(millivolts - Load::default().sag_millivolts(), Load::default()), 50);
+        }
+    }
+
+    #[test]
+    fn moves_the_report_once_the_estimate_is_far_enough_away() {
+        let mut gauge = Gauge::new();
+        settle(&mut gauge, 3840);
+
+        // The report follows the average down in steps, and stops short of 45% once it's within the hysteresis
+        let percent = settle(&mut gauge, 3820);
+        assert!(percent < 50 && percent.abs_diff(45) < HYSTERESIS_PERCENT, "Reported {percent}%");
+    }
+
+    #[test]
+    fn adds_back_the_sag_under_load() {
+        let load = Load { uwb: true, motors: 2 };
+        assert_eq!(load.sag_millivolts(), 43);
+
+        let mut resting = Gauge::new();
+        settle(&mut resting, 3840);
+
+        let mut loaded = Gauge::new();
+        for _ in 0..AVERAGED_SAMPLES {
+            loaded.push(3840 - load.sag_millivolts(), load);
+        }
+
+        assert_eq!(loaded.millivolts(), 3840);
+        assert_eq!(loaded.millivolts(), resting.millivolts());
+        assert_eq!(loaded.percent(), Some(50));
+
+        // Without taking the load into account the same sample would look well emptier
+        assert!(state_of_charge(3840 - load.sag_millivolts()) < loaded.percent().unwrap() - HYSTERESIS_PERCENT);
+    }
+
+    #[test]
+    fn estimates_the_time_left_at_a_steady_rate() {
+        let mut runtime = Runtime::new();
+
+        // A percent a minute, for long enough that the oldest samples are overwritten
+        for minute in 0..(3 * RUNTIME_SAMPLES as u8) {
+            let percent = 90 - minute;
+            runtime.push(minute as u64 * RUNTIME_INTERVAL_MS, percent);
+
+            let expected = (minute > 0).then_some(percent as u16);
+            assert_eq!(runtime.minutes_remaining(), expected, "At minute {minute}");
+        }
+    }
+
+    #[test]
+    fn keeps_one_sample_an_interval() {
+        let mut runtime = Runtime::new();
+        runtime.push(0, 80);
+        runtime.push(RUNTIME_INTERVAL_MS / 2, 10);
+        runtime.push(RUNTIME_INTERVAL_MS, 78);
+
+        // Going by the sample in between would have said there were seconds left
+        assert_eq!(runtime.minutes_remaining(), Some(39));
+    }
+
+    #[test]
+    fn cant_tell_the_time_left_without_a_fall() {
+        let mut runtime = Runtime::new();
+        assert_eq!(runtime.minutes_remaining(), None);
+
+        runtime.push(0, 60);
+        assert_eq!(runtime.minutes_remaining(), None);
+
+        runtime.push(RUNTIME_INTERVAL_MS, 60);
+        assert_eq!(runtime.minutes_remaining(), None);
+
+        runtime.push(2 * RUNTIME_INTERVAL_MS, 65);
+        assert_eq!(runtime.minutes_remaining(), None);
+
+        runtime.push(3 * RUNTIME_INTERVAL_MS, 50);
+        assert_eq!(runtime.minutes_remaining(), Some(15));
+
+        runtime.reset();
+        assert_eq!(runtime.minutes_remaining(), None);
+    }
+
+    #[test]
+    fn raises_each_level_once() {
+        let mut escalation = Escalation::new();
+        assert_eq!(escalation.update(50, None), None);
+
+        assert_eq!(escalation.update(20, None), Some(Level::Warning));
+        assert_eq!(escalation.update(19, None), None);
+        assert_eq!(escalation.update(20, Some(25)), None);
+
+        assert_eq!(escalation.update(12, Some(10)), Some(Level::Critical));
+        assert_eq!(escalation.update(10, None), None);
+
+        // A better estimate doesn't let a less urgent level through again
+        assert_eq!(escalation.update(15, None), None);
+        assert_eq!(escalation.level(), Some(Level::Critical));
+
+        assert_eq!(escalation.update(3, None), Some(Level::ShutdownImminent));
+        assert_eq!(escalation.update(1, Some(0)), None);
+        assert_eq!(escalation.level(), Some(Level::ShutdownImminent));
+    }
+
+    #[test]
+    fn raises_a_level_from_the_time_left_alone() {
+        let mut escalation = Escalation::new();
+
+        assert_eq!(escalation.update(60, Some(30)), Some(Level::Warning));
+        assert_eq!(escalation.update(60, Some(2)), Some(Level::ShutdownImminent));
+    }
+
+    #[test]
+    fn stands_down_only_once_charged_well_past_the_warning() {
+        let mut escalation = Escalation::new();
+        assert_eq!(escalation.update(8, None), Some(Level::Critical));
+
+        let (warning_percent, _) = Level::Warning.thresholds();
+        for percent in warning_percent + 1..warning_percent + RECOVERY_PERCENT {
+            assert_eq!(escalation.update(percent, None), None);
+            assert_eq!(escalation.level(), Some(Level::Critical), "Stood down at {percent}%");
+        }
+
+        // Dipping back into the warning doesn't raise it either, since critical was already raised
+        assert_eq!(escalation.update(warning_percent, None), None);
+
+        assert_eq!(escalation.update(warning_percent + RECOVERY_PERCENT, None), None);
+        assert_eq!(escalation.level(), None);
+
+        // Once stood down, the next run down raises every level afresh
+        assert_eq!(escalation.update(warning_percent, None), Some(Level::Warning));
+    }
+
+    /// Charges up to `millivolts` in rises the charger counts as still charging, and returns the state it ends in.
+    fn charge_to(charger: &mut Charger, from: u16, millivolts: u16) -> ChargeState {
+        (from..=millivolts).step_by(RISE_MILLIVOLTS as usize).map(|sample| charger.update(true, sample)).last().unwrap()
+    }
+
+    /// Holds the voltage flat for as many samples as it takes to count as full, less `short` of them.
+    fn hold(charger: &mut Charger, millivolts: u16, short: u16) -> ChargeState {
+        (0..PLATEAU_SAMPLES - short).map(|_| charger.update(true, millivolts)).last().unwrap()
+    }
+
+    #[test]
+    fn charges_while_there_is_usb_power() {
+        let mut charger = Charger::new();
+        assert_eq!(charger.update(false, 3800), ChargeState::Discharging);
+
+        assert_eq!(charger.update(true, 3850), ChargeState::Charging);
+        assert_eq!(charger.update(true, 3860), ChargeState::Charging);
+
+        assert_eq!(charger.update(false, 3790), ChargeState::Discharging);
+        assert_eq!(charger.state(), ChargeState::Discharging);
+
+        assert_eq!(charger.update(true, 3850), ChargeState::Charging);
+    }
+
+    #[test]
+    fn is_full_once_the_voltage_levels_off_near_the_top() {
+        let mut charger = Charger::new();
+        assert_eq!(charge_to(&mut charger, 4000, FULL_MILLIVOLTS), ChargeState::Charging);
+
+        assert_eq!(hold(&mut charger, FULL_MILLIVOLTS, 1), ChargeState::Charging);
+        assert_eq!(charger.update(true, FULL_MILLIVOLTS + RISE_MILLIVOLTS / 2), ChargeState::Full);
+    }
+
+    #[test]
+    fn keeps_charging_while_the_voltage_is_still_rising() {
+        let mut charger = Charger::new();
+        charge_to(&mut charger, 4000, FULL_MILLIVOLTS);
+
+        // Each rise starts the plateau over
+        hold(&mut charger, FULL_MILLIVOLTS, 1);
+        assert_eq!(charger.update(true, FULL_MILLIVOLTS + RISE_MILLIVOLTS), ChargeState::Charging);
+        assert_eq!(hold(&mut charger, FULL_MILLIVOLTS + RISE_MILLIVOLTS, 1), ChargeState::Charging);
+    }
+
+    #[test]
+    fn isnt_full_when_the_voltage_levels_off_low() {
+        let mut charger = Charger::new();
+        charger.update(true, 3900);
+
+        // Like a charger only just keeping up with the device
+        assert_eq!(hold(&mut charger, 3900, 0), ChargeState::Charging);
+        assert_eq!(hold(&mut charger, 3900, 0), ChargeState::Charging);
+    }
+
+    #[test]
+    fn charges_again_once_a_full_battery_sags() {
+        let mut charger = Charger::new();
+        charge_to(&mut charger, 4000, FULL_MILLIVOLTS);
+        assert_eq!(hold(&mut charger, FULL_MILLIVOLTS, 0), ChargeState::Full);
+
+        assert_eq!(charger.update(true, RECHARGE_MILLIVOLTS), ChargeState::Full);
+        assert_eq!(charger.update(true, RECHARGE_MILLIVOLTS - 1), ChargeState::Charging);
+
+        // And has to level off all over again to be full
+        assert_eq!(charge_to(&mut charger, RECHARGE_MILLIVOLTS, FULL_MILLIVOLTS), ChargeState::Charging);
+        assert_eq!(hold(&mut charger, FULL_MILLIVOLTS, 0), ChargeState::Full);
+    }
+
+    #[test]
+    fn discharges_as_soon_as_usb_power_is_lost() {
+        let mut charger = Charger::new();
+        charge_to(&mut charger, 4000, FULL_MILLIVOLTS);
+        hold(&mut charger, FULL_MILLIVOLTS, 0);
+
+        assert_eq!(charger.update(false, FULL_MILLIVOLTS), ChargeState::Discharging);
+
+        // Plugging back in doesn't pick up where it left off
+        assert_eq!(charger.update(true, FULL_MILLIVOLTS), ChargeState::Charging);
+    }
+}
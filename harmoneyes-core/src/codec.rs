@@ -0,0 +1,193 @@
+//! Helpers for the little-endian byte encodings shared between the devices and the console.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer ran out before the value was fully read or written.
+    BufferTooShort,
+    /// A tag byte did not correspond to any known variant.
+    UnknownTag(u8),
+    /// The bytes were well formed but described a value that doesn't make sense.
+    Invalid
+}
+
+/// Writes values one after another into a borrowed buffer.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// The number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.pos + data.len();
+        self.buf.get_mut(self.pos..end).ok_or(Error::BufferTooShort)?.copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.bytes(&[value])
+    }
+
+    pub fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> Result<(), Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> Result<(), Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn i16(&mut self, value: i16) -> Result<(), Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+}
+
+/// Reads values one after another out of a borrowed buffer.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Everything that hasn't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos + len;
+        let data = self.buf.get(self.pos..end).ok_or(Error::BufferTooShort)?;
+        self.pos = end;
+        Ok(data)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.bytes(N)?);
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i16(&mut self) -> Result<i16, Error> {
+        Ok(i16::from_le_bytes(self.array()?))
+    }
+}
+
+/// A string stored inline with a fixed capacity, for use where there's no allocator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FixedStr<const N: usize> {
+    bytes: [u8; N],
+    len: u8
+}
+
+impl<const N: usize> FixedStr<N> {
+    /// The longest string that fits, in bytes.
+    pub const CAPACITY: usize = N;
+
+    pub const fn empty() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+
+    /// Returns `None` if the string doesn't fit.
+    pub fn new(s: &str) -> Option<Self> {
+        if s.len() > N || s.len() > u8::MAX as usize {
+            return None;
+        }
+        let mut out = Self::empty();
+        out.bytes[..s.len()].copy_from_slice(s.as_bytes());
+        out.len = s.len() as u8;
+        Some(out)
+    }
+
+    /// Keeps as much of the string as fits without splitting a character.
+    pub fn truncated(s: &str) -> Self {
+        let mut end = s.len().min(N);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        Self::new(&s[..end]).unwrap_or(Self::empty())
+    }
+
+    /// Appends as much of the string as fits without splitting a character.
+    pub fn push_str(&mut self, s: &str) {
+        let mut end = s.len().min(N - self.len as usize);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len as usize..self.len as usize + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end as u8;
+    }
+
+    pub fn as_str(&self) -> &str {
+        // The bytes only ever come from a `&str` or from `Reader::str`, which validates them.
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for FixedStr<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Formatting into a string keeps as much as fits rather than failing.
+impl<const N: usize> core::fmt::Write for FixedStr<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+impl<const N: usize> core::fmt::Display for FixedStr<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Writer<'_> {
+    /// Writes a string prefixed by its length.
+    pub fn str<const N: usize>(&mut self, value: &FixedStr<N>) -> Result<(), Error> {
+        self.u8(value.len)?;
+        self.bytes(value.as_str().as_bytes())
+    }
+}
+
+impl Reader<'_> {
+    /// Reads a string prefixed by its length.
+    pub fn str<const N: usize>(&mut self) -> Result<FixedStr<N>, Error> {
+        let len = self.u8()? as usize;
+        let s = core::str::from_utf8(self.bytes(len)?).map_err(|_| Error::Invalid)?;
+        FixedStr::new(s).ok_or(Error::Invalid)
+    }
+}
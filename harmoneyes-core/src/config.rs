@@ -0,0 +1,393 @@
+//! Settings that can be changed on a device without reflashing it.
+//!
+//! Devices store each setting as a record of its key's ID followed by its value. Key IDs are never reused, and
+//! `SCHEMA_VERSION` is bumped whenever the meaning of a stored value changes so that settings written by older
+//! firmware can be migrated with `migrate`.
+
+use core::fmt::{self, Write};
+
+use crate::{codec::{Error, FixedStr, Reader, Writer}, guide::Spacing, haptics::{Direction, Wrist}, proximity::Limits};
+
+pub mod store;
+
+pub type Name = FixedStr<16>;
+
+/// The version of the layout the settings are stored with. Version 1 is the first, so nothing has been stored with an
+/// older one.
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// The longest a stored record can be, which is a name setting.
+pub const MAX_RECORD_LENGTH: usize = 1 + 1 + 1 + 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    /// The number the director uses to identify the performer wearing the device, or 0 if none has been given.
+    PerformerId,
+    /// A human readable name for the performer, like "Alto Sax 3".
+    PerformerName,
+    /// The section of the band the performer belongs to, or 0 if none has been given.
+    Section,
+    /// The transmit antenna delay of the ultra-wide band radio in DW3000 ticks, which takes effect the next time the
+    /// radio is set up, such as after the controller wakes up.
+    AntennaDelayTx,
+    /// The receive antenna delay of the ultra-wide band radio in DW3000 ticks, which takes effect like the transmit one.
+    AntennaDelayRx,
+    /// How strongly the cuff's motors should vibrate, as a percentage.
+    HapticIntensity,
+    /// How long a haptic cue lasts in milliseconds.
+    HapticDuration,
+    /// The two-wire address of the cuff on the left wrist, or 0 if there isn't one.
+    LeftCuffAddress,
+    /// The two-wire address of the cuff on the right wrist, or 0 if there isn't one.
+    RightCuffAddress,
+    /// The serial number of the wireless cuff on the left wrist, or empty if it's cabled.
+    LeftWirelessCuff,
+    /// The serial number of the wireless cuff on the right wrist, or empty if it's cabled.
+    RightWirelessCuff,
+    /// How near another performer can get in centimeters before the cuff warns of a collision, or 0 for no warnings.
+    CollisionRadius,
+    /// How fast in centimeters per second another performer can close in before the cuff warns of a collision, or 0
+    /// to only go by the radius.
+    CollisionClosingSpeed,
+    /// The short address of the guide to keep an interval from, in hex, or empty to not keep one.
+    GuidePeer,
+    /// Which way the guide is from the performer.
+    GuideSide,
+    /// The interval to keep from the guide in centimeters.
+    GuideSpacing,
+    /// How far off the interval the performer can be in centimeters before they're cued.
+    GuideTolerance
+}
+
+impl Key {
+    pub const ALL: [Key; 17] = [
+        Key::PerformerId,
+        Key::PerformerName,
+        Key::Section,
+        Key::AntennaDelayTx,
+        Key::AntennaDelayRx,
+        Key::HapticIntensity,
+        Key::HapticDuration,
+        Key::LeftCuffAddress,
+        Key::RightCuffAddress,
+        Key::LeftWirelessCuff,
+        Key::RightWirelessCuff,
+        Key::CollisionRadius,
+        Key::CollisionClosingSpeed,
+        Key::GuidePeer,
+        Key::GuideSide,
+        Key::GuideSpacing,
+        Key::GuideTolerance
+    ];
+
+    /// The identifier used for this key on the wire. These must never be reused, including 0x08, which was the serial
+    /// number of a paired cuff before cuffs were set per wrist. Records of it left in flash are skipped (see
+    /// [`migrate`]).
+    pub const fn id(self) -> u8 {
+        match self {
+            Key::PerformerId => 0x01,
+            Key::PerformerName => 0x02,
+            Key::Section => 0x03,
+            Key::AntennaDelayTx => 0x04,
+            Key::AntennaDelayRx => 0x05,
+            Key::HapticIntensity => 0x06,
+            Key::HapticDuration => 0x07,
+            Key::LeftCuffAddress => 0x09,
+            Key::RightCuffAddress => 0x0A,
+            Key::LeftWirelessCuff => 0x0B,
+            Key::RightWirelessCuff => 0x0C,
+            Key::CollisionRadius => 0x0D,
+            Key::CollisionClosingSpeed => 0x0E,
+            Key::GuidePeer => 0x0F,
+            Key::GuideSide => 0x10,
+            Key::GuideSpacing => 0x11,
+            Key::GuideTolerance => 0x12,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|key| key.id() == id)
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Key::PerformerId => "performer-id",
+            Key::PerformerName => "performer-name",
+            Key::Section => "section",
+            Key::AntennaDelayTx => "antenna-delay-tx",
+            Key::AntennaDelayRx => "antenna-delay-rx",
+            Key::HapticIntensity => "haptic-intensity",
+            Key::HapticDuration => "haptic-duration-ms",
+            Key::LeftCuffAddress => "left-cuff-address",
+            Key::RightCuffAddress => "right-cuff-address",
+            Key::LeftWirelessCuff => "left-wireless-cuff",
+            Key::RightWirelessCuff => "right-wireless-cuff",
+            Key::CollisionRadius => "collision-radius-cm",
+            Key::CollisionClosingSpeed => "collision-closing-speed-cm-s",
+            Key::GuidePeer => "guide-peer",
+            Key::GuideSide => "guide-side",
+            Key::GuideSpacing => "guide-spacing-cm",
+            Key::GuideTolerance => "guide-tolerance-cm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|key| key.name() == name)
+    }
+
+    /// Parses a value for this key from its textual representation.
+    pub fn parse(self, s: &str) -> Option<Value> {
+        match self {
+            Key::PerformerId | Key::AntennaDelayTx | Key::AntennaDelayRx | Key::HapticDuration | Key::CollisionRadius
+            | Key::CollisionClosingSpeed | Key::GuideSpacing | Key::GuideTolerance => s.parse().ok().map(Value::U16),
+            Key::Section => s.parse().ok().map(Value::U8),
+            Key::HapticIntensity => s.parse().ok().filter(|percent| *percent <= 100).map(Value::U8),
+            Key::PerformerName | Key::LeftWirelessCuff | Key::RightWirelessCuff => Name::new(s).map(Value::Name),
+            Key::LeftCuffAddress | Key::RightCuffAddress => parse_address(s).filter(|address| is_cuff_address(*address)).map(Value::U8),
+            Key::GuidePeer => parse_peer(s).map(|_| Name::truncated(s)).map(Value::Name),
+            Key::GuideSide => Direction::from_name(s).map(|_| Name::truncated(s)).map(Value::Name),
+        }
+    }
+}
+
+/// Parses an address written in decimal or in hex with a leading `0x`.
+fn parse_address(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses a peer's short address, which is written in hex the way the console shows it, or nothing for no peer.
+fn parse_peer(s: &str) -> Option<Option<u16>> {
+    if s.is_empty() {
+        return Some(None);
+    }
+
+    u16::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok().map(Some)
+}
+
+/// Whether a cuff can answer at `address`, which is either 0 for no cuff or a 7-bit address outside the ranges I2C
+/// reserves.
+const fn is_cuff_address(address: u8) -> bool {
+    address == 0 || (address >= 0x08 && address <= 0x77)
+}
+
+/// How the controller reaches the cuff on a wrist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CuffLink {
+    /// Over the two-wire interface, at this address.
+    Cable(u8),
+    /// Over Bluetooth LE, to the cuff with this serial number (see [`crate::wireless`]).
+    Wireless(Name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    U8(u8),
+    U16(u16),
+    Name(Name)
+}
+
+impl Value {
+    const TAG_U8: u8 = 0x01;
+    const TAG_U16: u8 = 0x02;
+    const TAG_NAME: u8 = 0x03;
+
+    pub fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        match self {
+            Value::U8(value) => { w.u8(Self::TAG_U8)?; w.u8(*value) },
+            Value::U16(value) => { w.u8(Self::TAG_U16)?; w.u16(*value) },
+            Value::Name(value) => { w.u8(Self::TAG_NAME)?; w.str(value) },
+        }
+    }
+
+    pub fn read(r: &mut Reader) -> Result<Self, Error> {
+        match r.u8()? {
+            Self::TAG_U8 => Ok(Value::U8(r.u8()?)),
+            Self::TAG_U16 => Ok(Value::U16(r.u16()?)),
+            Self::TAG_NAME => Ok(Value::Name(r.str()?)),
+            tag => Err(Error::UnknownTag(tag))
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::U8(value) => write!(f, "{value}"),
+            Value::U16(value) => write!(f, "{value}"),
+            Value::Name(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub performer_id: u16,
+    pub performer_name: Name,
+    pub section: u8,
+    pub antenna_delay_tx: u16,
+    pub antenna_delay_rx: u16,
+    pub haptic_intensity: u8,
+    pub haptic_duration_ms: u16,
+    pub left_cuff_address: u8,
+    pub right_cuff_address: u8,
+    pub left_wireless_cuff: Name,
+    pub right_wireless_cuff: Name,
+    pub collision_radius_cm: u16,
+    pub collision_closing_speed_cm_s: u16,
+    pub guide_peer: Option<u16>,
+    pub guide_side: Direction,
+    pub guide_spacing_cm: u16,
+    pub guide_tolerance_cm: u16
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        performer_id: 0,
+        performer_name: Name::empty(),
+        section: 0,
+        // The DW3000's power on default
+        antenna_delay_tx: 16385,
+        antenna_delay_rx: 16385,
+        haptic_intensity: 100,
+        haptic_duration_ms: 250,
+        // Wherever a cuff strapped for each wrist answers, so a second cuff only needs plugging in
+        left_cuff_address: Wrist::Left.default_address(),
+        right_cuff_address: Wrist::Right.default_address(),
+        left_wireless_cuff: Name::empty(),
+        right_wireless_cuff: Name::empty(),
+        // Off, since how close performers stand depends on the drill
+        collision_radius_cm: 0,
+        // About a brisk walk towards somebody who's standing still
+        collision_closing_speed_cm_s: 150,
+        guide_peer: None,
+        guide_side: Direction::Left,
+        // 22.5 inches, a common interval between marchers
+        guide_spacing_cm: 57,
+        guide_tolerance_cm: 10
+    };
+
+    pub fn get(&self, key: Key) -> Value {
+        match key {
+            Key::PerformerId => Value::U16(self.performer_id),
+            Key::PerformerName => Value::Name(self.performer_name),
+            Key::Section => Value::U8(self.section),
+            Key::AntennaDelayTx => Value::U16(self.antenna_delay_tx),
+            Key::AntennaDelayRx => Value::U16(self.antenna_delay_rx),
+            Key::HapticIntensity => Value::U8(self.haptic_intensity),
+            Key::HapticDuration => Value::U16(self.haptic_duration_ms),
+            Key::LeftCuffAddress => Value::U8(self.left_cuff_address),
+            Key::RightCuffAddress => Value::U8(self.right_cuff_address),
+            Key::LeftWirelessCuff => Value::Name(self.left_wireless_cuff),
+            Key::RightWirelessCuff => Value::Name(self.right_wireless_cuff),
+            Key::CollisionRadius => Value::U16(self.collision_radius_cm),
+            Key::CollisionClosingSpeed => Value::U16(self.collision_closing_speed_cm_s),
+            Key::GuidePeer => {
+                let mut peer = Name::empty();
+                if let Some(address) = self.guide_peer {
+                    let _ = write!(peer, "{address:04x}");
+                }
+                Value::Name(peer)
+            },
+            Key::GuideSide => Value::Name(Name::truncated(self.guide_side.name())),
+            Key::GuideSpacing => Value::U16(self.guide_spacing_cm),
+            Key::GuideTolerance => Value::U16(self.guide_tolerance_cm),
+        }
+    }
+
+    /// Fails with `Error::Invalid` if the value is the wrong type or out of range for the key.
+    pub fn set(&mut self, key: Key, value: Value) -> Result<(), Error> {
+        match (key, value) {
+            (Key::PerformerId, Value::U16(value)) => self.performer_id = value,
+            (Key::PerformerName, Value::Name(value)) => self.performer_name = value,
+            (Key::Section, Value::U8(value)) => self.section = value,
+            (Key::AntennaDelayTx, Value::U16(value)) => self.antenna_delay_tx = value,
+            (Key::AntennaDelayRx, Value::U16(value)) => self.antenna_delay_rx = value,
+            (Key::HapticIntensity, Value::U8(value)) if value <= 100 => self.haptic_intensity = value,
+            (Key::HapticDuration, Value::U16(value)) => self.haptic_duration_ms = value,
+            (Key::LeftCuffAddress, Value::U8(value)) if is_cuff_address(value) => self.left_cuff_address = value,
+            (Key::RightCuffAddress, Value::U8(value)) if is_cuff_address(value) => self.right_cuff_address = value,
+            (Key::LeftWirelessCuff, Value::Name(value)) => self.left_wireless_cuff = value,
+            (Key::RightWirelessCuff, Value::Name(value)) => self.right_wireless_cuff = value,
+            (Key::CollisionRadius, Value::U16(value)) => self.collision_radius_cm = value,
+            (Key::CollisionClosingSpeed, Value::U16(value)) => self.collision_closing_speed_cm_s = value,
+            (Key::GuidePeer, Value::Name(value)) => self.guide_peer = parse_peer(value.as_str()).ok_or(Error::Invalid)?,
+            (Key::GuideSide, Value::Name(value)) => self.guide_side = Direction::from_name(value.as_str()).ok_or(Error::Invalid)?,
+            (Key::GuideSpacing, Value::U16(value)) => self.guide_spacing_cm = value,
+            (Key::GuideTolerance, Value::U16(value)) => self.guide_tolerance_cm = value,
+            _ => return Err(Error::Invalid)
+        }
+        Ok(())
+    }
+
+    /// How to reach the cuff on `wrist`, or `None` if there isn't one. A wireless cuff takes the place of a cabled
+    /// one on the same wrist.
+    pub fn cuff_link(&self, wrist: Wrist) -> Option<CuffLink> {
+        let (address, serial) = match wrist {
+            Wrist::Left => (self.left_cuff_address, self.left_wireless_cuff),
+            Wrist::Right => (self.right_cuff_address, self.right_wireless_cuff),
+        };
+
+        if !serial.as_str().is_empty() {
+            Some(CuffLink::Wireless(serial))
+        } else if address != 0 {
+            Some(CuffLink::Cable(address))
+        } else {
+            None
+        }
+    }
+
+    /// When another performer counts as about to collide with this one, or `None` if collision warnings are off.
+    pub fn collision_limits(&self) -> Option<Limits> {
+        if self.collision_radius_cm == 0 {
+            return None;
+        }
+
+        Some(Limits {
+            radius_mm: self.collision_radius_cm as u32 * 10,
+            closing_mm_per_s: (self.collision_closing_speed_cm_s != 0).then_some(self.collision_closing_speed_cm_s as u32 * 10)
+        })
+    }
+
+    /// The interval to keep from the guide, or `None` if there's no guide set.
+    pub fn guide(&self) -> Option<Spacing> {
+        Some(Spacing {
+            peer: self.guide_peer?,
+            side: self.guide_side,
+            target_mm: self.guide_spacing_cm as u32 * 10,
+            tolerance_mm: self.guide_tolerance_cm as u32 * 10
+        })
+    }
+}
+
+/// Encodes a setting as it is stored, returning the length of the record.
+pub fn encode_record(key: Key, value: &Value, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut w = Writer::new(buf);
+    w.u8(key.id())?;
+    value.write(&mut w)?;
+    Ok(w.position())
+}
+
+/// Decodes a stored setting without looking up its key, since the ID might come from an older schema.
+pub fn decode_record(buf: &[u8]) -> Result<(u8, Value), Error> {
+    let mut r = Reader::new(buf);
+    Ok((r.u8()?, Value::read(&mut r)?))
+}
+
+/// Interprets a setting that was stored with the given schema version, returning `None` for settings that no longer
+/// exist.
+///
+/// Version 1 is the first schema, so the only setting that changes is one whose key has been retired since it was
+/// stored, which is dropped. When the meaning of a stored value changes, `SCHEMA_VERSION` is bumped and the setting
+/// is rewritten here for every `schema` before it.
+pub fn migrate(_schema: u8, id: u8, value: Value) -> Option<(Key, Value)> {
+    Some((Key::from_id(id)?, value))
+}
@@ -0,0 +1,444 @@
+//! The layout settings are kept in on flash, which only the reading and writing of is left to the device.
+//!
+//! Flash can only be erased a page at a time and wears out after enough erases, so the settings are kept as a log of
+//! records spread over several pages. Changing a setting appends a record to the current page, and once it fills up
+//! the settings that aren't at their defaults are copied over to the [`next_page`] in turn, which spreads the erases
+//! evenly. Each page starts with a [`Header`] holding a sequence number, so that the [`newest`] page can be found on
+//! boot, and the schema version its records were written with. The header is written last so that a page only counts
+//! once it has been completely filled in, and its magic word is written after the rest of it, since flash is written a
+//! word at a time and a reset partway through could otherwise leave the magic in front of an unwritten sequence number.
+//!
+//! Every record is a word holding its length and checksum followed by the record itself (see
+//! [`super::encode_record`]), padded out to a whole number of words. A reset partway through writing one leaves a
+//! record whose checksum doesn't match, which [`replay`] skips.
+
+use crate::crc::crc16;
+
+use super::{Config, Key, Value, MAX_RECORD_LENGTH, SCHEMA_VERSION};
+
+/// "HCFG", marking a page as holding settings.
+pub const MAGIC: u32 = 0x4746_4348;
+
+/// The magic, the sequence number and the schema version padded out to a whole word.
+pub const HEADER_SIZE: usize = 12;
+
+/// Every record starts with a word holding its length and checksum.
+pub const RECORD_HEADER_SIZE: usize = 4;
+
+/// Flash can only be written a whole word at a time.
+pub const WORD_SIZE: usize = 4;
+
+/// The most a record takes up, with its header and padding.
+pub const MAX_RECORD_SIZE: usize = RECORD_HEADER_SIZE + padded(MAX_RECORD_LENGTH);
+
+/// What a page starts with once it holds settings, which is the magic word followed by the rest of the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// One more than the page written before it, so the newest page is the furthest on, allowing for it wrapping
+    /// around. Never `u32::MAX`, which is what it reads as before it's been written.
+    pub sequence: u32,
+    /// The [`SCHEMA_VERSION`] the page's records were written with.
+    pub schema: u8
+}
+
+impl Header {
+    /// The header of a page that's being compacted onto after the page with `sequence`.
+    pub const fn after(sequence: u32) -> Self {
+        let sequence = match sequence.wrapping_add(1) {
+            UNWRITTEN => 0,
+            sequence => sequence,
+        };
+
+        Self { sequence, schema: SCHEMA_VERSION }
+    }
+
+    /// The header as it's written, with the magic in the first word. That word has to be written after the rest.
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0xFF; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        header[8] = self.schema;
+        header
+    }
+
+    /// Reads the header at the start of a page, or `None` if the page doesn't hold settings.
+    pub fn decode(header: &[u8; HEADER_SIZE]) -> Option<Self> {
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sequence = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        (magic == MAGIC && sequence != UNWRITTEN).then_some(Self { sequence, schema: header[8] })
+    }
+
+    /// Whether this page was written after `other`, counting sequence numbers on from each other so that they can
+    /// wrap around.
+    const fn is_after(&self, other: &Header) -> bool {
+        (self.sequence.wrapping_sub(other.sequence) as i32) > 0
+    }
+}
+
+/// What a sequence number reads as before it's been written.
+const UNWRITTEN: u32 = u32::MAX;
+
+/// The page with the furthest on sequence number, out of the headers of every page in order, or `None` if no page
+/// holds settings yet.
+pub fn newest(headers: &[Option<Header>]) -> Option<(usize, Header)> {
+    headers.iter()
+        .enumerate()
+        .filter_map(|(page, header)| header.map(|header| (page, header)))
+        .reduce(|newest, page| if page.1.is_after(&newest.1) { page } else { newest })
+}
+
+/// The page that's compacted onto after `page`, wrapping around to the first.
+pub const fn next_page(page: usize, pages: usize) -> usize {
+    (page + 1) % pages
+}
+
+/// The settings that are copied onto a fresh page, which is every one that isn't at its default.
+pub fn compacted(config: &Config) -> impl Iterator<Item = (Key, Value)> + '_ {
+    Key::ALL.into_iter()
+        .map(|key| (key, config.get(key)))
+        .filter(|(key, value)| *value != Config::DEFAULT.get(*key))
+}
+
+/// Encodes a record along with its length and checksum, padded out to a whole number of words. Returns the record
+/// and how much of it to write.
+pub fn encode_record(key: Key, value: &Value) -> ([u8; MAX_RECORD_SIZE], usize) {
+    let mut record = [0xFF; MAX_RECORD_SIZE];
+
+    // Every value fits within `MAX_RECORD_LENGTH`
+    let len = super::encode_record(key, value, &mut record[RECORD_HEADER_SIZE..]).unwrap();
+    let crc = crc16(&record[RECORD_HEADER_SIZE..][..len]);
+
+    record[0] = len as u8;
+    record[2..4].copy_from_slice(&crc.to_le_bytes());
+
+    (record, RECORD_HEADER_SIZE + padded(len))
+}
+
+/// What replaying a page found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Replayed {
+    /// Where on the page the next record goes, which is the end of the page if nothing more can be added to it.
+    pub offset: usize,
+    /// How many records were skipped because a reset cut them off partway through being written.
+    pub torn: usize,
+    /// Whether the log ran into something that isn't a record, after which nothing can be appended.
+    pub corrupt: bool
+}
+
+/// Applies the records on `page`, which starts with its header, to `config` in the order they were written.
+pub fn replay(page: &[u8], schema: u8, config: &mut Config) -> Replayed {
+    let mut replayed = Replayed { offset: HEADER_SIZE, torn: 0, corrupt: false };
+
+    while replayed.offset + RECORD_HEADER_SIZE <= page.len() {
+        let header = &page[replayed.offset..][..RECORD_HEADER_SIZE];
+
+        // Erased flash marks the end of the log
+        if header == [0xFF; RECORD_HEADER_SIZE] {
+            break;
+        }
+
+        let len = header[0] as usize;
+        let crc = u16::from_le_bytes([header[2], header[3]]);
+        let size = RECORD_HEADER_SIZE + padded(len);
+
+        if len > MAX_RECORD_LENGTH || replayed.offset + size > page.len() {
+            replayed.offset = page.len();
+            replayed.corrupt = true;
+            break;
+        }
+
+        let record = &page[replayed.offset + RECORD_HEADER_SIZE..][..len];
+        replayed.offset += size;
+
+        if crc16(record) != crc {
+            replayed.torn += 1;
+            continue;
+        }
+
+        if let Ok((id, value)) = super::decode_record(record)
+            && let Some((key, value)) = super::migrate(schema, id, value)
+        {
+            let _ = config.set(key, value);
+        }
+    }
+
+    replayed
+}
+
+/// Rounds a length up to a whole number of words.
+pub const fn padded(len: usize) -> usize {
+    len.div_ceil(WORD_SIZE) * WORD_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::config::Name;
+
+    /// Small pages, so that a few settings are enough to fill one.
+    const PAGE_SIZE: usize = 64;
+    const PAGES: usize = 3;
+
+    /// Flash holding the settings, kept the way the controller keeps them.
+    #[derive(Clone, Copy)]
+    struct Flash {
+        pages: [[u8; PAGE_SIZE]; PAGES],
+        page: usize,
+        sequence: u32,
+        offset: usize
+    }
+
+    impl Flash {
+        fn erased() -> Self {
+            Self { pages: [[0xFF; PAGE_SIZE]; PAGES], page: PAGES - 1, sequence: 0, offset: PAGE_SIZE }
+        }
+
+        /// Finds the newest page and replays it, as on boot.
+        fn load(&mut self) -> (Config, Replayed) {
+            let headers = self.pages.map(|page| Header::decode(page[..HEADER_SIZE].try_into().unwrap()));
+            let mut config = Config::DEFAULT;
+
+            let Some((page, header)) = newest(&headers) else {
+                return (config, Replayed { offset: PAGE_SIZE, torn: 0, corrupt: false });
+            };
+
+            let replayed = replay(&self.pages[page], header.schema, &mut config);
+            (self.page, self.sequence, self.offset) = (page, header.sequence, replayed.offset);
+            (config, replayed)
+        }
+
+        fn set(&mut self, config: &mut Config, key: Key, value: Value) {
+            config.set(key, value).unwrap();
+
+            let (record, size) = encode_record(key, &value);
+            if self.offset + size > PAGE_SIZE {
+                self.compact(config);
+                return;
+            }
+
+            self.pages[self.page][self.offset..][..size].copy_from_slice(&record[..size]);
+            self.offset += size;
+        }
+
+        fn compact(&mut self, config: &Config) {
+            let page = next_page(self.page, PAGES);
+            self.pages[page] = [0xFF; PAGE_SIZE];
+
+            let mut offset = HEADER_SIZE;
+            for (key, value) in compacted(config) {
+                let (record, size) = encode_record(key, &value);
+                self.pages[page][offset..][..size].copy_from_slice(&record[..size]);
+                offset += size;
+            }
+
+            let header = Header::after(self.sequence);
+            let encoded = header.encode();
+            self.pages[page][WORD_SIZE..HEADER_SIZE].copy_from_slice(&encoded[WORD_SIZE..]);
+            self.pages[page][..WORD_SIZE].copy_from_slice(&encoded[..WORD_SIZE]);
+            (self.page, self.sequence, self.offset) = (page, header.sequence, offset);
+        }
+    }
+
+    fn name(name: &str) -> Value {
+        Value::Name(Name::new(name).unwrap())
+    }
+
+    #[test]
+    fn starts_from_the_defaults_on_erased_flash() {
+        let mut flash = Flash::erased();
+        assert_eq!(flash.load().0, Config::DEFAULT);
+    }
+
+    #[test]
+    fn reads_back_what_was_set() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+        flash.set(&mut config, Key::PerformerName, name("Alto Sax 3"));
+        flash.set(&mut config, Key::HapticDuration, Value::U16(300));
+
+        let (loaded, replayed) = flash.load();
+        assert_eq!(loaded, config);
+        assert_eq!(loaded.haptic_duration_ms, 300);
+        assert_eq!(replayed.torn, 0);
+    }
+
+    #[test]
+    fn wraps_around_to_the_first_page() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::PerformerName, name("Drum Major"));
+
+        // Enough changes to fill every page more than once
+        let mut pages = Vec::new();
+        for duration_ms in 100..140 {
+            flash.set(&mut config, Key::HapticDuration, Value::U16(duration_ms));
+            if pages.last() != Some(&flash.page) {
+                pages.push(flash.page);
+            }
+
+            let mut reloaded = flash;
+            assert_eq!(reloaded.load().0, config, "Lost a setting on page {}", flash.page);
+            assert_eq!((reloaded.page, reloaded.offset), (flash.page, flash.offset));
+        }
+
+        assert!(pages.starts_with(&[0, 1, 2, 0, 1]), "Went through pages {pages:?}");
+    }
+
+    #[test]
+    fn finds_the_newest_page_past_the_wrap() {
+        let headers = [Some(Header { sequence: 7, schema: 1 }), None, Some(Header { sequence: 6, schema: 1 })];
+        assert_eq!(newest(&headers).map(|(page, _)| page), Some(0));
+
+        assert_eq!(next_page(2, 3), 0);
+        assert_eq!(newest(&[None, None]), None);
+    }
+
+    #[test]
+    fn finds_the_newest_page_past_the_sequence_wrapping_around() {
+        assert_eq!(Header::after(u32::MAX - 2).sequence, u32::MAX - 1);
+        assert_eq!(Header::after(u32::MAX - 1).sequence, 0);
+
+        let headers = [Some(Header { sequence: 0, schema: 1 }), None, Some(Header { sequence: u32::MAX - 1, schema: 1 })];
+        assert_eq!(newest(&headers).map(|(page, _)| page), Some(0));
+    }
+
+    #[test]
+    fn ignores_a_header_cut_off_after_the_magic() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+        let before = config;
+
+        // Firmware that wrote the header in one go could be reset with only the magic word written
+        let mut compacting = flash;
+        config.set(Key::PerformerName, name("Drum Major")).unwrap();
+        compacting.compact(&config);
+        flash.pages[compacting.page] = compacting.pages[compacting.page];
+        flash.pages[compacting.page][WORD_SIZE..HEADER_SIZE].fill(0xFF);
+
+        assert_eq!(flash.load().0, before);
+        assert_eq!(flash.sequence, 1);
+
+        // And the next compaction carries on from the page that counted
+        flash.compact(&config);
+        assert_eq!(flash.sequence, 2);
+        assert_eq!(flash.load().0, config);
+    }
+
+    #[test]
+    fn skips_a_record_cut_off_partway_through() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+        let before = config;
+
+        // The length and checksum made it, but the reset came before the whole name did
+        let (record, size) = encode_record(Key::PerformerName, &name("Alto Sax 3"));
+        let offset = flash.offset;
+        flash.pages[flash.page][offset..][..size / 2].copy_from_slice(&record[..size / 2]);
+        flash.offset += size;
+
+        let (loaded, replayed) = flash.load();
+        assert_eq!(loaded, before);
+        assert_eq!(replayed.torn, 1);
+
+        // The log carries on after it
+        assert_eq!(flash.offset, offset + size);
+        flash.set(&mut config, Key::PerformerName, name("Alto Sax 3"));
+        assert_eq!(flash.load().0, config);
+    }
+
+    #[test]
+    fn skips_a_record_whose_header_was_cut_off() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+
+        // Only the length made it
+        let (record, size) = encode_record(Key::HapticIntensity, &Value::U8(50));
+        flash.pages[flash.page][flash.offset] = record[0];
+        flash.offset += size;
+
+        let (loaded, replayed) = flash.load();
+        assert_eq!(loaded.haptic_duration_ms, 400);
+        assert_eq!(loaded.haptic_intensity, Config::DEFAULT.haptic_intensity);
+        assert_eq!(replayed.torn, 1);
+    }
+
+    #[test]
+    fn keeps_the_old_page_when_compacting_was_cut_off() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+        let before = config;
+
+        // The records made it onto the next page, but not the header that makes it count
+        let mut compacting = flash;
+        config.set(Key::PerformerName, name("Drum Major")).unwrap();
+        compacting.compact(&config);
+        flash.pages[compacting.page][HEADER_SIZE..].copy_from_slice(&compacting.pages[compacting.page][HEADER_SIZE..]);
+
+        assert_eq!(flash.load().0, before);
+    }
+
+    #[test]
+    fn stops_at_something_that_is_not_a_record() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+
+        // Longer than any record
+        flash.pages[flash.page][flash.offset] = MAX_RECORD_LENGTH as u8 + 1;
+
+        let (loaded, replayed) = flash.load();
+        assert_eq!(loaded, config);
+        assert!(replayed.corrupt);
+
+        // So the next change moves on to a fresh page
+        assert_eq!(replayed.offset, PAGE_SIZE);
+        let page = flash.page;
+        flash.set(&mut config, Key::HapticIntensity, Value::U8(50));
+        assert_eq!(flash.page, next_page(page, PAGES));
+        assert_eq!(flash.load().0, config);
+    }
+
+    #[test]
+    fn only_copies_settings_that_have_changed() {
+        let mut config = Config::DEFAULT;
+        assert_eq!(compacted(&config).count(), 0);
+
+        config.set(Key::HapticIntensity, Value::U8(50)).unwrap();
+        assert!(compacted(&config).eq([(Key::HapticIntensity, Value::U8(50))]));
+    }
+
+    #[test]
+    fn drops_settings_that_have_been_retired() {
+        let mut flash = Flash::erased();
+        let mut config = Config::DEFAULT;
+        flash.set(&mut config, Key::HapticDuration, Value::U16(400));
+
+        // A paired cuff's serial number, written by firmware from before it was retired
+        let mut record = [0xFF; MAX_RECORD_SIZE];
+        let len = {
+            let mut w = crate::codec::Writer::new(&mut record[RECORD_HEADER_SIZE..]);
+            w.u8(0x08).unwrap();
+            name("HCF0000000A1B2C").write(&mut w).unwrap();
+            w.position()
+        };
+        let crc = crc16(&record[RECORD_HEADER_SIZE..][..len]);
+        record[0] = len as u8;
+        record[2..4].copy_from_slice(&crc.to_le_bytes());
+        flash.pages[flash.page][flash.offset..][..RECORD_HEADER_SIZE + padded(len)].copy_from_slice(&record[..RECORD_HEADER_SIZE + padded(len)]);
+
+        let (loaded, replayed) = flash.load();
+        assert_eq!(loaded, config);
+        assert_eq!(replayed.torn, 0);
+    }
+}
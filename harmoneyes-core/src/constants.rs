@@ -4,16 +4,24 @@ pub const HARMONEYES: &str = "Harmoneyes";
 pub const MANUFACTURER: &str = concatcp!(HARMONEYES, " Group");
 pub const USB_VENDOR_ID: u16 = 0x1209; // Thank you https://pid.codes/
 
+/// The first interface of the CDC ACM class that carries the device's logs.
+pub const USB_LOGGER_INTERFACE: u8 = 0;
+/// The first interface of the CDC ACM class that carries the console protocol.
+pub const USB_SERIAL_INTERFACE: u8 = 2;
+
 pub mod cuff {
     use const_format::concatcp;
 
     pub const NAME: &str = concatcp!(super::HARMONEYES, " Cuff");
     pub const USB_PRODUCT_ID: u16 = 0x0001;
 
-    pub const SERIAL_ONE: &str = "HAU001";
-    pub const SERIAL_TWO: &str = "HAU002";
+    /// Starts every cuff's serial number, which is followed by its flash chip's unique ID.
+    pub const SERIAL_PREFIX: &str = "HAU";
 
-    pub const I2C_ADDRESS: u16 = 0x45; // Nice...
+    /// The address of a cuff on the left wrist, which is also where a cuff without its strap pin fitted answers.
+    pub const LEFT_I2C_ADDRESS: u8 = 0x45; // Nice...
+    /// The address of a cuff with its strap pin tied to ground, which marks it as being for the right wrist.
+    pub const RIGHT_I2C_ADDRESS: u8 = 0x46;
 }
 
 pub mod controller {
@@ -22,8 +30,6 @@ pub mod controller {
     pub const NAME: &str = concatcp!(super::HARMONEYES, " Controller");
     pub const USB_PRODUCT_ID: u16 = 0x0002;
 
-    pub const SERIALS: [&str; 2] = [SERIAL_ONE, SERIAL_TWO];
-
-    pub const SERIAL_ONE: &str = "HAO001";
-    pub const SERIAL_TWO: &str = "HAO002";
+    /// Starts every controller's serial number, which is followed by its chip's device ID.
+    pub const SERIAL_PREFIX: &str = "HAO";
 }
\ No newline at end of file
@@ -0,0 +1,211 @@
+//! What a controller does about each distance it measures to another controller, and when it sends keep alives.
+//!
+//! The firmware and the simulator both run this, so that the simulator behaves the same way the controllers do. They
+//! only differ in how they pass the decisions on to the cuffs and the console.
+
+use crate::{guide::{Correction, Guide, Spacing}, haptics::Direction, power::Mode, proximity::{Limits, Proximity, Warning}};
+
+/// How many keep alive periods go by between keep alives while idle.
+pub const IDLE_KEEP_ALIVE_EVERY: u32 = 5;
+
+/// A cue that moves the performer back to the interval from their guide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cue {
+    pub correction: Correction,
+    pub direction: Direction,
+    /// The interval the cue is keeping.
+    pub spacing: Spacing
+}
+
+/// What to do about a distance measurement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Decision {
+    /// The performer is about to be walked into.
+    pub warning: Option<Warning>,
+    /// The performer has drifted away from the interval from their guide.
+    pub cue: Option<Cue>
+}
+
+/// Keeps track of every other controller's distance for collision warnings and keeping an interval from the guide.
+#[derive(Default)]
+pub struct Coordinator {
+    proximity: Proximity,
+    guide: Guide,
+    /// Who the guide's measurements are of, so they're thrown away when the guide changes.
+    guide_peer: Option<u16>
+}
+
+impl Coordinator {
+    pub const fn new() -> Self {
+        Self { proximity: Proximity::new(), guide: Guide::new(), guide_peer: None }
+    }
+
+    /// Adds a measurement of the distance to `peer`, taken at `now_ms`, under the current collision `limits` and
+    /// guide `spacing` from the config.
+    pub fn update(
+        &mut self,
+        limits: Option<Limits>,
+        spacing: Option<Spacing>,
+        peer: u16,
+        distance_mm: u32,
+        now_ms: u64
+    ) -> Decision {
+        // Every measurement counts on its own, since somebody about to walk into the performer can't wait for an average
+        let warning = match limits {
+            Some(limits) => self.proximity.update(&limits, peer, distance_mm, now_ms),
+            None => {
+                // So turning warnings back on doesn't pick up from speeds worked out before they were turned off
+                self.proximity.clear();
+                None
+            },
+        };
+
+        // Measurements of somebody who's no longer the guide don't count
+        if spacing.map(|spacing| spacing.peer) != self.guide_peer {
+            self.guide.clear();
+            self.guide_peer = spacing.map(|spacing| spacing.peer);
+        }
+
+        let cue = spacing
+            .filter(|spacing| spacing.peer == peer)
+            .and_then(|spacing| {
+                let correction = self.guide.update(&spacing, distance_mm, now_ms)?;
+                Some(Cue { correction, direction: correction.direction(spacing.side), spacing })
+            });
+
+        Decision { warning, cue }
+    }
+
+    /// Forgets every measurement, for when ranging stops and whoever was nearby could be anywhere by the time it
+    /// starts again.
+    pub fn clear(&mut self) {
+        self.proximity.clear();
+        self.guide.clear();
+    }
+}
+
+/// Works out which keep alive periods a keep alive goes out in.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeepAlives {
+    /// How many periods have gone by.
+    tick: u32,
+    /// How many keep alives have gone out, which each one carries.
+    count: u32
+}
+
+impl KeepAlives {
+    pub const fn new() -> Self {
+        Self { tick: 0, count: 0 }
+    }
+
+    /// Moves on to the next period in `mode`, returning the count to send if a keep alive is due. Only every so often
+    /// while idle, and not at all while asleep, since nobody is ranging anyway.
+    pub fn tick(&mut self, mode: Mode) -> Option<u32> {
+        let due = match mode {
+            Mode::Active => true,
+            Mode::Idle => self.tick.is_multiple_of(IDLE_KEEP_ALIVE_EVERY),
+            Mode::Sleep => false,
+        };
+        self.tick = self.tick.wrapping_add(1);
+
+        due.then(|| {
+            let count = self.count;
+            self.count = self.count.wrapping_add(1);
+            count
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const GUIDE: u16 = 0x7424;
+    const OTHER: u16 = 0x1d0c;
+
+    const LIMITS: Limits = Limits { radius_mm: 500, closing_mm_per_s: None };
+    const SPACING: Spacing = Spacing { peer: GUIDE, side: Direction::Right, target_mm: 570, tolerance_mm: 100 };
+
+    #[test]
+    fn warns_of_a_collision_only_with_limits_set() {
+        let mut coordinator = Coordinator::new();
+
+        assert_eq!(coordinator.update(None, None, OTHER, 300, 0).warning, None);
+
+        let warning = coordinator.update(Some(LIMITS), None, OTHER, 300, 100).warning;
+        assert_eq!(warning.map(|warning| warning.peer), Some(OTHER));
+    }
+
+    #[test]
+    fn forgets_warnings_while_they_are_off() {
+        let mut coordinator = Coordinator::new();
+        assert!(coordinator.update(Some(LIMITS), None, OTHER, 300, 0).warning.is_some());
+
+        // Too soon after the last one for another warning
+        assert!(coordinator.update(Some(LIMITS), None, OTHER, 300, 100).warning.is_none());
+
+        coordinator.update(None, None, OTHER, 300, 200);
+        assert!(coordinator.update(Some(LIMITS), None, OTHER, 300, 300).warning.is_some());
+    }
+
+    #[test]
+    fn starts_afresh_once_ranging_stops() {
+        let mut coordinator = Coordinator::new();
+        assert!(coordinator.update(Some(LIMITS), None, OTHER, 300, 0).warning.is_some());
+        for at_ms in 0..4 {
+            coordinator.update(Some(LIMITS), Some(SPACING), GUIDE, 900, at_ms * 100);
+        }
+
+        coordinator.clear();
+
+        assert!(coordinator.update(Some(LIMITS), None, OTHER, 300, 500).warning.is_some());
+        // The guide's median starts over too
+        assert_eq!(coordinator.update(Some(LIMITS), Some(SPACING), GUIDE, 900, 600).cue, None);
+    }
+
+    #[test]
+    fn cues_towards_the_guide_once_they_drift_away() {
+        let mut coordinator = Coordinator::new();
+
+        // Somebody else being far away doesn't matter
+        for at_ms in 0..10 {
+            assert_eq!(coordinator.update(None, Some(SPACING), OTHER, 2000, at_ms * 100).cue, None);
+        }
+
+        let cues: Vec<_> = (0..5).filter_map(|at_ms| coordinator.update(None, Some(SPACING), GUIDE, 900, at_ms * 100).cue).collect();
+        assert_eq!(cues, [Cue { correction: Correction::Closer, direction: Direction::Right, spacing: SPACING }]);
+    }
+
+    #[test]
+    fn forgets_the_old_guide_when_it_changes() {
+        let mut coordinator = Coordinator::new();
+        for at_ms in 0..4 {
+            coordinator.update(None, Some(SPACING), GUIDE, 900, at_ms * 100);
+        }
+
+        // Another four measurements of the new guide aren't enough for a median on their own
+        let spacing = Spacing { peer: OTHER, ..SPACING };
+        for at_ms in 4..8 {
+            assert_eq!(coordinator.update(None, Some(spacing), OTHER, 900, at_ms * 100).cue, None);
+        }
+        assert!(coordinator.update(None, Some(spacing), OTHER, 900, 800).cue.is_some());
+    }
+
+    #[test]
+    fn sends_keep_alives_less_often_while_idle() {
+        let mut keep_alives = KeepAlives::new();
+
+        let active: Vec<_> = (0..3).filter_map(|_| keep_alives.tick(Mode::Active)).collect();
+        assert_eq!(active, [0, 1, 2]);
+
+        let idle = (0..IDLE_KEEP_ALIVE_EVERY * 2).filter(|_| keep_alives.tick(Mode::Idle).is_some()).count();
+        assert_eq!(idle, 2);
+
+        assert!((0..10).all(|_| keep_alives.tick(Mode::Sleep).is_none()));
+        assert_eq!(keep_alives.tick(Mode::Active), Some(5));
+    }
+}
@@ -0,0 +1,162 @@
+//! Reports of why a controller went down, kept across the reset that brings it back up.
+//!
+//! When the controller panics it writes down what it was doing in a [`Report`]: how long it had been up, the panic
+//! message and the last few [`Lines`] it noted. Once it has restarted it adds why the chip says it was reset (see
+//! [`ResetReason`]), and keeps the report until the console fetches and clears it.
+
+use core::fmt;
+
+use crate::codec::{Error, FixedStr, Reader, Writer};
+
+/// The longest panic message kept, which is usually enough for the location and the start of the message.
+pub const MESSAGE_LENGTH: usize = 80;
+
+/// The longest line kept.
+pub const LINE_LENGTH: usize = 40;
+
+/// How many of the lines noted before a crash are kept.
+pub const LINES: usize = 6;
+
+/// Why the chip was last reset, as the bits of the nRF52840's `RESETREAS` register. None of them set means it was
+/// powered on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResetReason(pub u32);
+
+impl ResetReason {
+    const NAMES: [(u32, &'static str); 9] = [
+        (1 << 0, "reset pin"),
+        (1 << 1, "watchdog"),
+        (1 << 2, "software reset"),
+        (1 << 3, "lockup"),
+        (1 << 16, "woken by a pin"),
+        (1 << 17, "woken by the comparator"),
+        (1 << 18, "debug interface"),
+        (1 << 19, "woken by NFC"),
+        (1 << 20, "woken by USB"),
+    ];
+
+    const WATCHDOG: u32 = 1 << 1;
+    const LOCKUP: u32 = 1 << 3;
+
+    /// Whether the chip reset itself because it stopped working, rather than being asked to or powered on.
+    pub const fn is_failure(self) -> bool {
+        self.0 & (Self::WATCHDOG | Self::LOCKUP) != 0
+    }
+
+    /// The name of every reason that's set.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES.into_iter().filter(move |(bit, _)| self.0 & bit != 0).map(|(_, name)| name)
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("power on");
+        }
+
+        for (i, name) in self.names().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The last few lines noted while running, oldest first, with the oldest dropped to make room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lines {
+    lines: [FixedStr<LINE_LENGTH>; LINES],
+    len: usize
+}
+
+impl Lines {
+    pub const fn new() -> Self {
+        Self { lines: [FixedStr::empty(); LINES], len: 0 }
+    }
+
+    /// Adds a line, cutting it short if it's too long.
+    pub fn push(&mut self, line: FixedStr<LINE_LENGTH>) {
+        if self.len == LINES {
+            self.lines.rotate_left(1);
+            self.len -= 1;
+        }
+
+        self.lines[self.len] = line;
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.lines[..self.len].iter().map(FixedStr::as_str)
+    }
+
+    fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u8(self.len as u8)?;
+        for line in &self.lines[..self.len] {
+            w.str(line)?;
+        }
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, Error> {
+        let len = r.u8()? as usize;
+        if len > LINES {
+            return Err(Error::Invalid);
+        }
+
+        let mut lines = Self::new();
+        for _ in 0..len {
+            lines.push(r.str()?);
+        }
+        Ok(lines)
+    }
+}
+
+impl Default for Lines {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a controller knew about a crash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// How long the controller had been up, or zero if it went down without getting to write the report itself.
+    pub uptime_ms: u64,
+    pub reset_reason: ResetReason,
+    pub message: FixedStr<MESSAGE_LENGTH>,
+    pub lines: Lines
+}
+
+impl Report {
+    pub const MAX_ENCODED_LENGTH: usize = 8 + 4 + 1 + MESSAGE_LENGTH + 1 + LINES * (1 + LINE_LENGTH);
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer::new(buf);
+        self.write(&mut w)?;
+        Ok(w.position())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        Self::read(&mut Reader::new(buf))
+    }
+
+    pub(crate) fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u64(self.uptime_ms)?;
+        w.u32(self.reset_reason.0)?;
+        w.str(&self.message)?;
+        self.lines.write(w)
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self {
+            uptime_ms: r.u64()?,
+            reset_reason: ResetReason(r.u32()?),
+            message: r.str()?,
+            lines: Lines::read(r)?
+        })
+    }
+}
@@ -0,0 +1,74 @@
+//! Checksums for data that has to survive being stored or sent over a noisy link.
+
+/// CRC-16/CCITT-FALSE, which is cheap enough to compute a byte at a time on either device.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    crc
+}
+
+/// CRC-32 as used by zlib, for checking a whole firmware image that arrives in pieces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 { (self.0 >> 1) ^ 0xEDB8_8320 } else { self.0 >> 1 };
+            }
+        }
+    }
+
+    pub const fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The input every CRC catalogue gives its check value for.
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn matches_the_catalogued_check_values() {
+        assert_eq!(crc16(CHECK), 0x29B1);
+        assert_eq!(crc32(CHECK), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn gives_the_same_crc_32_whatever_pieces_the_data_arrives_in() {
+        let mut crc = Crc32::new();
+        for piece in CHECK.chunks(4) {
+            crc.update(piece);
+        }
+
+        assert_eq!(crc.finish(), crc32(CHECK));
+    }
+}
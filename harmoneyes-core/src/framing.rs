@@ -0,0 +1,230 @@
+//! Splits a byte stream, like a USB serial port, into messages.
+//!
+//! Each message is COBS encoded so that it never contains a zero byte, and is then terminated by a
+//! zero byte. A reader that starts listening part way through a message only loses that message
+//! because the next zero is always the start of a fresh one.
+
+use crate::codec::Error;
+
+/// The largest number of bytes `len` bytes of data can take once framed, including the terminator.
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 2
+}
+
+/// Frames `data` into `out` and returns the number of bytes written, including the terminator.
+pub fn encode(data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if out.len() < max_encoded_len(data.len()) {
+        return Err(Error::BufferTooShort);
+    }
+
+    let mut code_pos = 0;
+    let mut pos = 1;
+    let mut code: u8 = 1;
+
+    for &byte in data {
+        if byte == 0 {
+            out[code_pos] = code;
+            code_pos = pos;
+            pos += 1;
+            code = 1;
+        } else {
+            out[pos] = byte;
+            pos += 1;
+            code += 1;
+
+            if code == 0xFF {
+                out[code_pos] = code;
+                code_pos = pos;
+                pos += 1;
+                code = 1;
+            }
+        }
+    }
+
+    out[code_pos] = code;
+    out[pos] = 0;
+
+    Ok(pos + 1)
+}
+
+/// Decodes a frame, without its terminator, in place and returns the length of the message.
+pub fn decode_in_place(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < buf.len() {
+        let code = buf[read];
+        if code == 0 {
+            return Err(Error::Invalid);
+        }
+        read += 1;
+
+        for _ in 1..code {
+            let byte = *buf.get(read).ok_or(Error::Invalid)?;
+            buf[write] = byte;
+            read += 1;
+            write += 1;
+        }
+
+        if code != 0xFF && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+
+    Ok(write)
+}
+
+/// Collects bytes as they arrive until a whole message has been received.
+pub struct Accumulator<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflowed: bool
+}
+
+impl<const N: usize> Default for Accumulator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Accumulator<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0, overflowed: false }
+    }
+
+    /// Adds one byte and, if it completed a frame, returns the decoded message.
+    ///
+    /// Frames that are too large for the buffer are dropped and reported as `BufferTooShort`.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], Error>> {
+        if byte != 0 {
+            if self.len < N {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(Error::BufferTooShort));
+        }
+        if len == 0 {
+            return None;
+        }
+
+        Some(decode_in_place(&mut self.buf[..len]).map(|n| &self.buf[..n]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; max_encoded_len(data.len())];
+        let len = encode(data, &mut out).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    fn round_trip(data: &[u8]) {
+        let mut framed = frame(data);
+
+        assert_eq!(framed.pop(), Some(0), "The frame wasn't terminated");
+        assert!(!framed.contains(&0), "The frame has a zero before its end");
+
+        let len = decode_in_place(&mut framed).unwrap();
+        assert_eq!(&framed[..len], data);
+    }
+
+    #[test]
+    fn frames_an_empty_message() {
+        assert_eq!(frame(&[]), [0x01, 0x00]);
+        round_trip(&[]);
+    }
+
+    #[test]
+    fn frames_a_message_with_zeros_in_it() {
+        assert_eq!(frame(&[0x11, 0x00, 0x00, 0x22]), [0x02, 0x11, 0x01, 0x02, 0x22, 0x00]);
+
+        round_trip(&[0x00]);
+        round_trip(&[0x00, 0x00]);
+        round_trip(&[0x11, 0x22, 0x00]);
+        round_trip(&[0x00, 0x11, 0x00, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn frames_long_runs_without_zeros() {
+        for len in [253, 254, 255, 508, 509, 600] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 255 + 1) as u8).collect();
+            assert!(frame(&data).len() <= max_encoded_len(len), "{len} bytes took more room than allowed");
+            round_trip(&data);
+        }
+
+        // A run of exactly 254 ends a block without a zero after it
+        let mut data = vec![0x33; 254];
+        data.push(0);
+        data.push(0x44);
+        round_trip(&data);
+    }
+
+    #[test]
+    fn refuses_to_frame_into_too_small_a_buffer() {
+        let mut out = [0; 4];
+        assert_eq!(encode(&[1, 2, 3], &mut out), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn rejects_a_frame_that_ends_part_way_through_a_block() {
+        let mut framed = [0x05, 0x11, 0x22];
+        assert_eq!(decode_in_place(&mut framed), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn collects_messages_split_across_reads() {
+        let mut accumulator = Accumulator::<16>::new();
+        let mut stream = frame(&[0x11, 0x00, 0x22]);
+        stream.extend(frame(&[0x33]));
+
+        let mut messages = Vec::new();
+        for byte in stream {
+            if let Some(message) = accumulator.push(byte) {
+                messages.push(message.unwrap().to_vec());
+            }
+        }
+
+        assert_eq!(messages, [vec![0x11, 0x00, 0x22], vec![0x33]]);
+    }
+
+    #[test]
+    fn ignores_empty_frames() {
+        let mut accumulator = Accumulator::<16>::new();
+
+        assert_eq!(accumulator.push(0), None);
+        assert_eq!(accumulator.push(0), None);
+    }
+
+    #[test]
+    fn drops_a_frame_too_large_for_its_buffer_and_picks_up_at_the_next() {
+        let mut accumulator = Accumulator::<4>::new();
+
+        for &byte in &frame(&[0x11; 8])[..9] {
+            assert_eq!(accumulator.push(byte), None);
+        }
+        assert_eq!(accumulator.push(0), Some(Err(Error::BufferTooShort)));
+
+        let mut last = None;
+        for byte in frame(&[0x22, 0x00]) {
+            last = accumulator.push(byte).map(|message| message.map(<[u8]>::to_vec));
+        }
+        assert_eq!(last, Some(Ok(vec![0x22, 0x00])));
+    }
+}
@@ -0,0 +1,381 @@
+//! The GATT service that lets a phone or laptop look after a controller over Bluetooth LE, without a USB cable.
+//!
+//! Alongside the standard Device Information and Battery services, a controller offers a console service that speaks
+//! the same messages as its USB serial port (see [`crate::protocol`]), one per GATT operation so they don't need
+//! framing. A client writes a [`Request`] to the request characteristic and is notified of the
+//! [`Response`](crate::protocol::Response) on the response characteristic, which also holds the last response for
+//! reading. While the client has notifications on for the telemetry characteristic every telemetry [`Event`] is
+//! notified there, the same as the console's stream.
+//!
+//! Only the requests in [`admits`] are taken, since the link isn't encrypted and a controller shouldn't have its
+//! firmware replaced by whoever happens to be nearby. Everything here is plain encoding, so a mock client only needs
+//! to pass bytes through [`decode_request`], [`fragments`], [`Reassembly`] and [`crate::protocol::Response::decode`]
+//! to stand in for the real thing.
+//!
+//! The longest notification is a telemetry event, so a client needs to raise the ATT MTU to at least [`MIN_MTU`] to
+//! get all of them.
+
+use crate::{codec::Error, protocol::{Failure, Request}, telemetry::Event};
+
+/// The UUID of the console service.
+pub const SERVICE_UUID: u128 = 0x6e4a1001_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The UUID of the characteristic a client writes requests to.
+pub const REQUEST_UUID: u128 = 0x6e4a1002_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The UUID of the characteristic that responses are notified on.
+pub const RESPONSE_UUID: u128 = 0x6e4a1003_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The UUID of the characteristic that telemetry events are notified on.
+pub const TELEMETRY_UUID: u128 = 0x6e4a1004_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The longest request that's admitted, which is setting a name.
+pub const MAX_REQUEST_LENGTH: usize = 32;
+
+/// The longest response to an admitted request, which is the device info.
+pub const MAX_RESPONSE_LENGTH: usize = 64;
+
+/// The longest telemetry notification.
+pub const MAX_TELEMETRY_LENGTH: usize = Event::MAX_ENCODED_LENGTH;
+
+/// The smallest ATT MTU that every notification fits in, after the three bytes of its header.
+pub const MIN_MTU: usize = MAX_TELEMETRY_LENGTH + 3;
+
+/// The ATT MTU every client starts out with, which responses are fragmented to fit.
+pub const DEFAULT_MTU: usize = 23;
+
+/// The bytes of an ATT notification taken up by its opcode and handle.
+const NOTIFICATION_HEADER_LENGTH: usize = 3;
+
+/// Set in the first byte of a fragment when there are more to come. The rest of the byte counts the fragments from
+/// zero.
+const MORE_FRAGMENTS: u8 = 0x80;
+
+/// Whether a request is taken over the air. Firmware updates and restarts need a cable, and crash reports don't fit in
+/// a notification.
+pub const fn admits(request: &Request) -> bool {
+    match request {
+        Request::Info
+        | Request::ConfigGet { .. }
+        | Request::ConfigSet { .. }
+        | Request::ConfigReset
+        | Request::HapticTest { .. }
+        | Request::SetPowerMode { .. } => true,
+        // Telemetry follows whether the client has notifications on instead
+        Request::Stream { .. } => false,
+        Request::UpdateBegin { .. }
+        | Request::UpdateWrite { .. }
+        | Request::UpdateFinish { .. }
+        | Request::RebootToBootloader
+        | Request::CrashGet { .. }
+        | Request::CrashClear => false,
+    }
+}
+
+/// Decodes a request written to the request characteristic, or the failure to answer it with.
+pub fn decode_request(data: &[u8]) -> Result<Request, Failure> {
+    // Longer than any admitted request, so something's been tacked on
+    if data.len() > MAX_REQUEST_LENGTH {
+        return Err(Failure::Malformed);
+    }
+
+    let request = Request::decode(data).map_err(|_| Failure::Malformed)?;
+
+    if admits(&request) {
+        Ok(request)
+    } else {
+        Err(Failure::Unsupported)
+    }
+}
+
+/// One notification's worth of a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fragment<'a> {
+    pub index: u8,
+    pub more: bool,
+    pub data: &'a [u8]
+}
+
+impl Fragment<'_> {
+    /// Writes the fragment into `buf`, returning how many bytes it took.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = 1 + self.data.len();
+        let buf = buf.get_mut(..len).ok_or(Error::BufferTooShort)?;
+
+        buf[0] = self.index | if self.more { MORE_FRAGMENTS } else { 0 };
+        buf[1..].copy_from_slice(self.data);
+
+        Ok(len)
+    }
+}
+
+/// Splits an encoded response into fragments that each fit in a notification at `mtu`.
+pub fn fragments(message: &[u8], mtu: usize) -> impl Iterator<Item = Fragment<'_>> {
+    let size = mtu.saturating_sub(NOTIFICATION_HEADER_LENGTH + 1).max(1);
+    let count = message.len().div_ceil(size).max(1);
+
+    (0..count).map(move |index| {
+        let start = index * size;
+        Fragment {
+            index: index as u8,
+            more: index + 1 < count,
+            data: &message[start..(start + size).min(message.len())]
+        }
+    })
+}
+
+/// Puts the fragments of a response back together on the client.
+pub struct Reassembly {
+    buf: [u8; MAX_RESPONSE_LENGTH],
+    len: usize,
+    /// The index of the fragment expected next, or `None` between responses.
+    next: Option<u8>
+}
+
+impl Reassembly {
+    pub const fn new() -> Self {
+        Self { buf: [0; MAX_RESPONSE_LENGTH], len: 0, next: None }
+    }
+
+    /// Adds a notification from the response characteristic, returning the whole response once its last fragment is
+    /// in. A first fragment always starts a new response, throwing away what's left of one that never finished, and
+    /// any other out of order fragment is an error.
+    pub fn push(&mut self, notification: &[u8]) -> Result<Option<&[u8]>, Error> {
+        let (&header, data) = notification.split_first().ok_or(Error::BufferTooShort)?;
+        let index = header & !MORE_FRAGMENTS;
+
+        if index == 0 {
+            self.len = 0;
+        } else if self.next != Some(index) {
+            self.next = None;
+            return Err(Error::Invalid);
+        }
+
+        let Some(buf) = self.buf.get_mut(self.len..self.len + data.len()) else {
+            self.next = None;
+            return Err(Error::BufferTooShort);
+        };
+        buf.copy_from_slice(data);
+        self.len += data.len();
+
+        if header & MORE_FRAGMENTS != 0 {
+            self.next = Some(index + 1);
+            Ok(None)
+        } else {
+            self.next = None;
+            Ok(Some(&self.buf[..self.len]))
+        }
+    }
+}
+
+impl Default for Reassembly {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{codec::FixedStr, config::{Config, Key, Name, Value}, protocol::{DeviceInfo, DeviceKind, Response}};
+
+    /// A client connected to a controller at some ATT MTU, with the controller's side played the way the firmware
+    /// does it: decode the write, answer it, then notify the encoded response in fragments.
+    struct MockClient {
+        mtu: usize,
+        reassembly: Reassembly,
+        /// Every notification the controller sent, for checking they fit.
+        notifications: Vec<Vec<u8>>
+    }
+
+    impl MockClient {
+        fn new(mtu: usize) -> Self {
+            Self { mtu, reassembly: Reassembly::new(), notifications: Vec::new() }
+        }
+
+        fn request(&mut self, request: &Request) -> Response {
+            let mut data = [0; 64];
+            let len = request.encode(&mut data).expect("Request did not encode");
+            self.write(&data[..len])
+        }
+
+        /// Writes raw bytes to the request characteristic and waits for the response.
+        fn write(&mut self, data: &[u8]) -> Response {
+            let response = match decode_request(data) {
+                Ok(request) => answer(request),
+                Err(failure) => Response::Failed(failure),
+            };
+
+            let mut message = [0; MAX_RESPONSE_LENGTH];
+            let len = response.encode(&mut message).expect("Response did not fit in the characteristic");
+
+            let mut whole = None;
+            for fragment in fragments(&message[..len], self.mtu) {
+                let mut notification = [0; MAX_RESPONSE_LENGTH + 1];
+                let len = fragment.encode(&mut notification).expect("Fragment did not encode");
+                self.notifications.push(notification[..len].to_vec());
+
+                assert!(whole.is_none(), "A fragment came after the last one");
+                whole = self.reassembly.push(&notification[..len]).expect("Fragment was rejected").map(<[u8]>::to_vec);
+            }
+
+            Response::decode(&whole.expect("The last fragment never came")).expect("Response did not decode")
+        }
+    }
+
+    /// A controller with the longest strings there can be, so its device info is the longest response.
+    fn answer(request: Request) -> Response {
+        match request {
+            Request::Info => Response::Info(DeviceInfo {
+                kind: DeviceKind::Controller,
+                serial: FixedStr::new("HAC0123456789ABC").unwrap(),
+                name: Name::new("Sousaphone Three").unwrap(),
+                firmware_version: FixedStr::new("0.1.0-rc.1+ab12c").unwrap(),
+                uptime_ms: u64::MAX,
+                performer_id: u16::MAX,
+                section: u8::MAX
+            }),
+            Request::ConfigGet { key } => Response::Config { key, value: Config::DEFAULT.get(key) },
+            _ => Response::Done,
+        }
+    }
+
+    fn config_set(name: &str) -> Request {
+        Request::ConfigSet { key: Key::PerformerName, value: Value::Name(Name::new(name).unwrap()) }
+    }
+
+    #[test]
+    fn answers_a_request() {
+        let mut client = MockClient::new(MIN_MTU);
+
+        assert_eq!(client.request(&Request::ConfigGet { key: Key::GuideSpacing }), answer(Request::ConfigGet { key: Key::GuideSpacing }));
+        assert_eq!(client.request(&config_set("Drum Major")), Response::Done);
+        assert_eq!(client.notifications.len(), 2);
+    }
+
+    #[test]
+    fn rejects_malformed_writes() {
+        let mut client = MockClient::new(MIN_MTU);
+
+        for data in [
+            &[][..],
+            // An unknown request
+            &[0x7F],
+            // A config key that doesn't exist
+            &[0x02, 0xEE],
+            // Cut off part way through a name
+            &[0x03, 0x02, 0x03, 5, b'D', b'r'],
+            // A name that isn't UTF-8
+            &[0x03, 0x02, 0x03, 2, 0xC3, 0x28],
+            // A motor that doesn't exist
+            &[0x05, 0x7F, 0, 0, 0, 0, 0, 0, 0, 0],
+        ] {
+            assert_eq!(client.write(data), Response::Failed(Failure::Malformed), "Took {data:02x?}");
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_writes() {
+        let mut client = MockClient::new(MIN_MTU);
+
+        // The longest name fits
+        let mut data = [0; 64];
+        let len = config_set("Sousaphone Three").encode(&mut data).unwrap();
+        assert!(len <= MAX_REQUEST_LENGTH);
+        assert_eq!(client.write(&data[..len]), Response::Done);
+
+        // Anything past the end of the characteristic is turned away, even if the request in front of it is fine
+        assert_eq!(client.write(&data[..MAX_REQUEST_LENGTH + 1]), Response::Failed(Failure::Malformed));
+
+        // As is a name longer than a name can be
+        let mut data = [0x03, 0x02, 0x03, 20].to_vec();
+        data.extend_from_slice(b"Sousaphone Number 3!");
+        assert!(data.len() <= MAX_REQUEST_LENGTH);
+        assert_eq!(client.write(&data), Response::Failed(Failure::Malformed));
+    }
+
+    #[test]
+    fn refuses_requests_that_need_a_cable() {
+        let mut client = MockClient::new(MIN_MTU);
+
+        assert_eq!(client.request(&Request::RebootToBootloader), Response::Failed(Failure::Unsupported));
+        assert_eq!(client.request(&Request::CrashClear), Response::Failed(Failure::Unsupported));
+        assert_eq!(client.request(&Request::Stream { enabled: true }), Response::Failed(Failure::Unsupported));
+    }
+
+    #[test]
+    fn fragments_a_long_response_at_the_default_mtu() {
+        let mut client = MockClient::new(DEFAULT_MTU);
+
+        assert_eq!(client.request(&Request::Info), answer(Request::Info));
+
+        assert!(client.notifications.len() > 1, "The device info should need more than one notification");
+        for notification in &client.notifications {
+            assert!(notification.len() <= DEFAULT_MTU - NOTIFICATION_HEADER_LENGTH);
+        }
+
+        // And the next response comes through on its own
+        assert_eq!(client.request(&config_set("Drum Major")), Response::Done);
+    }
+
+    #[test]
+    fn sends_a_long_response_whole_once_the_mtu_is_raised() {
+        let mut client = MockClient::new(MIN_MTU);
+
+        assert_eq!(client.request(&Request::Info), answer(Request::Info));
+        assert_eq!(client.notifications.len(), 1);
+    }
+
+    #[test]
+    fn starts_over_when_a_response_is_cut_short() {
+        let message: Vec<u8> = (0..MAX_RESPONSE_LENGTH as u8).collect();
+        let mut notification = [0; DEFAULT_MTU];
+        let mut reassembly = Reassembly::new();
+
+        let first = fragments(&message, DEFAULT_MTU).next().unwrap();
+        let len = first.encode(&mut notification).unwrap();
+        assert_eq!(reassembly.push(&notification[..len]), Ok(None));
+
+        // The rest never came, and the next response is a short one
+        let len = Fragment { index: 0, more: false, data: &[0x81] }.encode(&mut notification).unwrap();
+        assert_eq!(reassembly.push(&notification[..len]), Ok(Some(&[0x81][..])));
+    }
+
+    #[test]
+    fn rejects_fragments_out_of_order() {
+        let message: Vec<u8> = (0..MAX_RESPONSE_LENGTH as u8).collect();
+        let mut notification = [0; DEFAULT_MTU];
+        let mut reassembly = Reassembly::new();
+
+        let mut fragments = fragments(&message, DEFAULT_MTU);
+        let len = fragments.next().unwrap().encode(&mut notification).unwrap();
+        assert_eq!(reassembly.push(&notification[..len]), Ok(None));
+
+        // The second fragment went missing
+        fragments.next();
+        let len = fragments.next().unwrap().encode(&mut notification).unwrap();
+        assert_eq!(reassembly.push(&notification[..len]), Err(Error::Invalid));
+
+        assert_eq!(reassembly.push(&[]), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn rejects_a_response_longer_than_the_characteristic() {
+        let message = [0; MAX_RESPONSE_LENGTH + 1];
+        let mut notification = [0; DEFAULT_MTU];
+        let mut reassembly = Reassembly::new();
+
+        let results: Vec<_> = fragments(&message, DEFAULT_MTU)
+            .map(|fragment| {
+                let len = fragment.encode(&mut notification).unwrap();
+                reassembly.push(&notification[..len]).map(|whole| whole.is_some())
+            })
+            .collect();
+
+        assert_eq!(results.last(), Some(&Err(Error::BufferTooShort)));
+    }
+}
@@ -0,0 +1,123 @@
+//! Keeping a set interval from a guide, like the two steps a marcher keeps from the guide beside them.
+//!
+//! This only needs the distance to the guide that ranging already measures. The distance is filtered by taking the
+//! median of the last [`WINDOW`] measurements, which throws out the odd one that's wildly off, and once it's further
+//! from the target than the tolerance the performer is cued to move closer to the guide or further away from them.
+//! Ranging can't tell which way the guide is, so that's set along with the spacing (see [`Spacing::side`]).
+
+use crate::haptics::Direction;
+
+/// How many of the latest measurements the median is taken over.
+pub const WINDOW: usize = 5;
+
+/// How long the guide can go without being measured before the measurements from before are thrown away.
+pub const STALE_MS: u64 = 2000;
+
+/// How often the performer is cued while they're out of the tolerance.
+pub const CUE_EVERY_MS: u64 = 1500;
+
+/// The interval to keep from the guide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Spacing {
+    /// The short address of the guide's controller.
+    pub peer: u16,
+    /// Which way the guide is from the performer.
+    pub side: Direction,
+    pub target_mm: u32,
+    /// How far off the target the performer can be before they're cued.
+    pub tolerance_mm: u32
+}
+
+/// Which way the performer needs to move to get back to the target spacing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Correction {
+    Closer,
+    Further
+}
+
+impl Correction {
+    /// The direction to cue, towards the guide on `side` or away from them.
+    pub const fn direction(self, side: Direction) -> Direction {
+        match self {
+            Correction::Closer => side,
+            Correction::Further => side.opposite(),
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Correction::Closer => "closer",
+            Correction::Further => "further",
+        }
+    }
+}
+
+/// The filtered distance to the guide, and when the performer was last cued.
+pub struct Guide {
+    window: [u32; WINDOW],
+    len: usize,
+    next: usize,
+    at_ms: u64,
+    cued_at_ms: Option<u64>
+}
+
+impl Guide {
+    pub const fn new() -> Self {
+        Self { window: [0; WINDOW], len: 0, next: 0, at_ms: 0, cued_at_ms: None }
+    }
+
+    /// Adds a measurement of the distance to the guide, taken at `now_ms`. Returns the correction to cue if the
+    /// filtered distance is out of the tolerance, unless the performer was already cued in the last [`CUE_EVERY_MS`].
+    pub fn update(&mut self, spacing: &Spacing, distance_mm: u32, now_ms: u64) -> Option<Correction> {
+        if now_ms.saturating_sub(self.at_ms) > STALE_MS {
+            self.clear();
+        }
+
+        self.window[self.next] = distance_mm;
+        self.next = (self.next + 1) % WINDOW;
+        self.len = (self.len + 1).min(WINDOW);
+        self.at_ms = now_ms;
+
+        let correction = correction(spacing, self.distance_mm()?)?;
+
+        if self.cued_at_ms.is_some_and(|cued_at_ms| now_ms.saturating_sub(cued_at_ms) < CUE_EVERY_MS) {
+            return None;
+        }
+        self.cued_at_ms = Some(now_ms);
+
+        Some(correction)
+    }
+
+    /// The median of the latest measurements, once there are enough of them.
+    pub fn distance_mm(&self) -> Option<u32> {
+        if self.len < WINDOW {
+            return None;
+        }
+
+        let mut sorted = self.window;
+        sorted.sort_unstable();
+        Some(sorted[WINDOW / 2])
+    }
+
+    /// Forgets the measurements, for when the guide changes or goes quiet.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Guide {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which way to move to get back to the target spacing from `distance_mm`, or `None` inside the tolerance.
+pub fn correction(spacing: &Spacing, distance_mm: u32) -> Option<Correction> {
+    if distance_mm > spacing.target_mm.saturating_add(spacing.tolerance_mm) {
+        Some(Correction::Closer)
+    } else if distance_mm < spacing.target_mm.saturating_sub(spacing.tolerance_mm) {
+        Some(Correction::Further)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,274 @@
+//! The cuffs a performer wears and the motors in them.
+//!
+//! A performer can wear a cuff on either wrist or both, each at its own address on the controller's two-wire
+//! interface. Directions are [`route`]d to whichever motors make the most sense for the cuffs that are there, so
+//! that a left cue lands on the left wrist when there's a cuff on it.
+
+use crate::{codec::{Error, Reader}, constants::cuff::{LEFT_I2C_ADDRESS, RIGHT_I2C_ADDRESS}};
+
+/// One of the four vibration motors sewn into the cuff.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motor {
+    Front,
+    Back,
+    Left,
+    Right
+}
+
+impl Motor {
+    pub const ALL: [Motor; 4] = [Motor::Front, Motor::Back, Motor::Left, Motor::Right];
+
+    /// The command code the cuff listens for on the two-wire interface for this motor.
+    pub const fn code(self) -> u8 {
+        match self {
+            Motor::Front => 0x10,
+            Motor::Back => 0x11,
+            Motor::Left => 0x12,
+            Motor::Right => 0x13,
+        }
+    }
+
+    pub const fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x10 => Some(Motor::Front),
+            0x11 => Some(Motor::Back),
+            0x12 => Some(Motor::Left),
+            0x13 => Some(Motor::Right),
+            _ => None
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Motor::Front => "front",
+            Motor::Back => "back",
+            Motor::Left => "left",
+            Motor::Right => "right",
+        }
+    }
+}
+
+/// How long one cycle of switching a motor on and off takes while it runs at less than full strength. Short enough
+/// that the motor doesn't spin down in between, so it feels like a weaker vibration rather than a buzzing one.
+pub const DUTY_PERIOD_MS: u64 = 20;
+
+/// Tells the cuff to run one of its motors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Run {
+    pub motor: Motor,
+    pub duration_ms: u64,
+    /// How strongly to run the motor as a percentage, which the cuff gets by switching it on for that share of every
+    /// [`DUTY_PERIOD_MS`].
+    pub intensity: u8
+}
+
+impl Run {
+    /// The longest command, which is the motor's code, the duration and the intensity.
+    pub const LENGTH: usize = 1 + 8 + 1;
+
+    pub const fn command(&self) -> [u8; Self::LENGTH] {
+        let duration = self.duration_ms.to_le_bytes();
+        [
+            self.motor.code(),
+            duration[0], duration[1], duration[2], duration[3], duration[4], duration[5], duration[6], duration[7],
+            self.intensity
+        ]
+    }
+
+    /// Reads a command sent to the cuff, if that's what it is. Controllers from before the intensity could be set
+    /// leave it off, which runs the motor at full strength.
+    pub fn from_command(command: &[u8]) -> Option<Self> {
+        let (&code, rest) = command.split_first()?;
+        let motor = Motor::from_code(code)?;
+
+        let (duration, intensity) = match rest.len() {
+            8 => (rest, 100),
+            9 => (&rest[..8], rest[8].min(100)),
+            _ => return None,
+        };
+
+        Some(Self { motor, duration_ms: u64::from_le_bytes(duration.try_into().ok()?), intensity })
+    }
+
+    /// How long the motor is on and then off in every [`DUTY_PERIOD_MS`], or `None` to leave it on throughout.
+    pub const fn duty_ms(&self) -> Option<(u64, u64)> {
+        if self.intensity >= 100 {
+            return None;
+        }
+
+        let on_ms = DUTY_PERIOD_MS * self.intensity as u64 / 100;
+        Some((on_ms, DUTY_PERIOD_MS - on_ms))
+    }
+}
+
+/// The wrist a cuff is worn on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrist {
+    Left,
+    Right
+}
+
+impl Wrist {
+    pub const ALL: [Wrist; 2] = [Wrist::Left, Wrist::Right];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Wrist::Left => "left",
+            Wrist::Right => "right",
+        }
+    }
+
+    pub const fn code(self) -> u8 {
+        match self {
+            Wrist::Left => 0x01,
+            Wrist::Right => 0x02,
+        }
+    }
+
+    pub const fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x01 => Some(Wrist::Left),
+            0x02 => Some(Wrist::Right),
+            _ => None
+        }
+    }
+
+    /// The address a cuff answers to on the two-wire interface when its strap pin says it's on this wrist.
+    pub const fn default_address(self) -> u8 {
+        match self {
+            Wrist::Left => LEFT_I2C_ADDRESS,
+            Wrist::Right => RIGHT_I2C_ADDRESS,
+        }
+    }
+
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Reads a wrist that was added to the end of a message, which means the left wrist when it isn't there since
+    /// that's where a lone cuff answers.
+    pub(crate) fn read_or_left(r: &mut Reader) -> Result<Self, Error> {
+        if r.remaining().is_empty() {
+            return Ok(Wrist::Left);
+        }
+
+        Self::from_code(r.u8()?).ok_or(Error::Invalid)
+    }
+}
+
+/// A direction to cue the performer in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Back,
+    Left,
+    Right
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [Direction::Forward, Direction::Back, Direction::Left, Direction::Right];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Direction::Forward => "forward",
+            Direction::Back => "back",
+            Direction::Left => "left",
+            Direction::Right => "right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|direction| direction.name() == name)
+    }
+
+    /// The direction pointing the other way.
+    pub const fn opposite(self) -> Self {
+        match self {
+            Direction::Forward => Direction::Back,
+            Direction::Back => Direction::Forward,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+
+    /// The motor on a single cuff that points this way.
+    pub const fn motor(self) -> Motor {
+        match self {
+            Direction::Forward => Motor::Front,
+            Direction::Back => Motor::Back,
+            Direction::Left => Motor::Left,
+            Direction::Right => Motor::Right,
+        }
+    }
+}
+
+/// Pulses on every motor of every cuff at once, for warnings that aren't about a direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pulses {
+    pub count: u8,
+    pub on_ms: u16,
+    pub off_ms: u16
+}
+
+impl Pulses {
+    /// Short, quick pulses that feel nothing like a cue, for when somebody is about to walk into the performer.
+    pub const URGENT: Pulses = Pulses { count: 4, on_ms: 100, off_ms: 60 };
+
+    /// How long the whole pattern takes.
+    pub const fn duration_ms(&self) -> u32 {
+        self.count as u32 * (self.on_ms as u32 + self.off_ms as u32)
+    }
+}
+
+/// The motors to run to cue `direction`, given which wrists have a cuff, indexed by [`Wrist::index`].
+///
+/// Forward and back go to every cuff, so they feel the same with one cuff or two. Left and right go to the cuff on
+/// that wrist alone, or if there isn't one, to the matching motor on the other cuff.
+pub fn route(direction: Direction, fitted: [bool; Wrist::ALL.len()]) -> [Option<(Wrist, Motor)>; Wrist::ALL.len()] {
+    let motor = direction.motor();
+    let on = |wrist: Wrist| fitted[wrist.index()].then_some((wrist, motor));
+
+    match direction {
+        Direction::Forward | Direction::Back => [on(Wrist::Left), on(Wrist::Right)],
+        Direction::Left => [on(Wrist::Left).or(on(Wrist::Right)), None],
+        Direction::Right => [on(Wrist::Right).or(on(Wrist::Left)), None],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_a_run_command() {
+        let run = Run { motor: Motor::Left, duration_ms: 250, intensity: 60 };
+        assert_eq!(Run::from_command(&run.command()), Some(run));
+    }
+
+    #[test]
+    fn runs_at_full_strength_for_controllers_without_an_intensity() {
+        let command = Run { motor: Motor::Back, duration_ms: 250, intensity: 40 }.command();
+
+        let run = Run::from_command(&command[..9]).unwrap();
+        assert_eq!(run.intensity, 100);
+        assert_eq!(run.duty_ms(), None);
+    }
+
+    #[test]
+    fn ignores_commands_that_are_not_a_run() {
+        let command = Run { motor: Motor::Front, duration_ms: 250, intensity: 100 }.command();
+
+        assert_eq!(Run::from_command(&command[..5]), None);
+        assert_eq!(Run::from_command(&[0x7F; Run::LENGTH]), None);
+        assert_eq!(Run::from_command(&[]), None);
+    }
+
+    #[test]
+    fn switches_the_motor_for_its_share_of_the_period() {
+        let run = |intensity| Run { motor: Motor::Front, duration_ms: 250, intensity };
+
+        assert_eq!(run(100).duty_ms(), None);
+        assert_eq!(run(75).duty_ms(), Some((15, 5)));
+        assert_eq!(run(0).duty_ms(), Some((0, DUTY_PERIOD_MS)));
+    }
+}
@@ -0,0 +1,91 @@
+//! Keeping track of whether each of a controller's tasks is still doing its job.
+//!
+//! A task that hangs waiting on something that never comes doesn't crash, it just quietly stops. So each task
+//! checks in as it goes, and is counted as stalled once it hasn't for longer than its [`Task::timeout_ms`]. A task
+//! that's waiting on something outside its control, such as a request from the console, parks instead, and isn't
+//! expected to check in until it's busy again.
+
+/// A task that has to keep checking in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    Uwb,
+    Coord,
+    Ble,
+    Bat,
+    Usb,
+    Cuff,
+    Wireless
+}
+
+impl Task {
+    pub const ALL: [Task; 7] = [Task::Uwb, Task::Coord, Task::Ble, Task::Bat, Task::Usb, Task::Cuff, Task::Wireless];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Task::Uwb => "uwb",
+            Task::Coord => "coord",
+            Task::Ble => "ble",
+            Task::Bat => "bat",
+            Task::Usb => "usb",
+            Task::Cuff => "cuff",
+            Task::Wireless => "wireless",
+        }
+    }
+
+    /// How long the task can go without checking in, which leaves room for the longest thing it waits on while busy.
+    pub const fn timeout_ms(self) -> u64 {
+        match self {
+            // Ranging steps take tens of milliseconds, and resetting the radio well under a second
+            Task::Uwb => 10_000,
+            // Ticks every second
+            Task::Coord => 10_000,
+            Task::Ble => 30_000,
+            // Samples every 2.5 s
+            Task::Bat => 15_000,
+            // Starting an update erases the whole update partition, which takes several seconds
+            Task::Usb => 30_000,
+            // Reads the cuff every 10 s
+            Task::Cuff => 30_000,
+            // Ticks every second, and gives up on connecting to a cuff after a few
+            Task::Wireless => 30_000,
+        }
+    }
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// When each task last checked in, or `None` while it's parked.
+#[derive(Clone, Copy, Debug)]
+pub struct Health {
+    checked_in: [Option<u64>; Task::ALL.len()]
+}
+
+impl Health {
+    /// Every task starts out as having checked in at boot, so one that never gets going is noticed too.
+    pub const fn new() -> Self {
+        Self { checked_in: [Some(0); Task::ALL.len()] }
+    }
+
+    pub fn check_in(&mut self, task: Task, now_ms: u64) {
+        self.checked_in[task.index()] = Some(now_ms);
+    }
+
+    pub fn park(&mut self, task: Task) {
+        self.checked_in[task.index()] = None;
+    }
+
+    /// The first task that has gone too long without checking in, if any.
+    pub fn stalled(&self, now_ms: u64) -> Option<Task> {
+        Task::ALL.into_iter().find(|task| {
+            self.checked_in[task.index()].is_some_and(|at| now_ms.saturating_sub(at) > task.timeout_ms())
+        })
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
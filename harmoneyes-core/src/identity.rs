@@ -0,0 +1,34 @@
+//! Turning the unique ID burned into each device's chip into the identifiers the rest of the system uses.
+
+use crate::codec::FixedStr;
+
+pub type Serial = FixedStr<16>;
+
+/// The USB serial number of a device, the prefix for its kind followed by the low 48 bits of its ID in hex.
+pub fn serial(prefix: &str, id: u64) -> Serial {
+    const DIGITS: usize = 12;
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut digits = [0u8; DIGITS];
+    for (i, digit) in digits.iter_mut().enumerate() {
+        *digit = HEX[(id >> (4 * (DIGITS - 1 - i)) & 0xF) as usize];
+    }
+
+    let mut serial = Serial::truncated(prefix);
+    // The digits are all ASCII
+    let digits = core::str::from_utf8(&digits).unwrap();
+    serial.push_str(digits);
+    serial
+}
+
+/// The 16 bit address a controller uses on the mesh and as its ultra-wide band short address. Two IDs can fold to the
+/// same address, which ranging picks up on (see [`crate::uwb::Outcome::Clashed`]).
+pub fn short_address(id: u64) -> u16 {
+    let folded = (id ^ (id >> 16) ^ (id >> 32) ^ (id >> 48)) as u16;
+
+    // 0xFFFF is the broadcast address and 0xFFFE means "no short address" in 802.15.4
+    match folded {
+        0xFFFE | 0xFFFF => folded & 0x7FFF,
+        address => address,
+    }
+}
@@ -1,3 +1,28 @@
 #![no_std]
 
-pub mod constants;
\ No newline at end of file
+pub mod battery;
+pub mod codec;
+pub mod config;
+pub mod constants;
+pub mod coord;
+pub mod crash;
+pub mod crc;
+pub mod framing;
+pub mod gatt;
+pub mod guide;
+pub mod haptics;
+pub mod health;
+pub mod identity;
+pub mod link;
+pub mod mesh;
+pub mod power;
+pub mod protocol;
+pub mod proximity;
+pub mod ranging;
+pub mod registers;
+pub mod status;
+pub mod telemetry;
+pub mod transfer;
+pub mod update;
+pub mod uwb;
+pub mod wireless;
@@ -0,0 +1,305 @@
+//! Supervising the link between a controller and its cuff, whether over the two-wire interface or wireless (see
+//! [`crate::wireless`]).
+//!
+//! The cuff keeps its motors doing whatever the last command told them to, so if the controller dies or the cable
+//! comes loose partway through a cue the performer would be left buzzing, or waiting for cues that never come. The
+//! controller sends a keep alive every [`INTERVAL_MS`], and anything it sends counts as hearing from it. Once the cuff
+//! hasn't heard from it for [`TIMEOUT_MS`] the link counts as lost: the cuff stops its motors and lets the performer
+//! know, until the controller is heard from again.
+//!
+//! The controller keeps track of the cuff the same way from its side, going by whether its keep alives are answered
+//! (see [`Tracker`]), and tells the rest of the band when its performer has lost their cuff. How long the keep alives
+//! take to be answered and how many go missing is also tallied (see [`Quality`]), which matters most for a wireless
+//! cuff where the odd one going missing is normal but a lot of them means the performer is about to lose cues.
+
+use crate::codec::{Error, Reader, Writer};
+
+/// The command code of a keep alive. It doesn't overlap with the codes in [`crate::haptics`], [`crate::power`],
+/// [`crate::transfer`] or [`crate::registers`].
+pub const KEEP_ALIVE: u8 = 0x50;
+
+/// How often the controller sends a keep alive.
+pub const INTERVAL_MS: u64 = 1000;
+
+/// How long the cuff goes without hearing from the controller before the link counts as lost, which allows for a
+/// couple of keep alives going missing.
+pub const TIMEOUT_MS: u64 = 3500;
+
+/// How many times every motor is pulsed when the link is lost, so it can't be mistaken for a cue.
+pub const LOST_PULSES: u32 = 3;
+
+/// How long each of those pulses, and the gaps between them, last.
+pub const LOST_PULSE_MS: u64 = 150;
+
+/// How many keep alives in a row can go unanswered before a cuff that was answering counts as lost.
+pub const LOST_AFTER: u32 = 3;
+
+/// How many keep alives in a row go unanswered between each attempt at freeing up the bus.
+pub const RECOVER_EVERY: u32 = 2;
+
+/// How many keep alives the quality of the link is reported over.
+pub const QUALITY_EVERY: u16 = 10;
+
+/// The share of keep alives, as a percentage, that can go unanswered before the link counts as poor.
+pub const POOR_LOSS_PERCENT: u8 = 20;
+
+/// The command that keeps the link alive.
+pub const fn keep_alive() -> [u8; 1] {
+    [KEEP_ALIVE]
+}
+
+pub fn is_keep_alive(command: &[u8]) -> bool {
+    command == [KEEP_ALIVE]
+}
+
+/// A change in whether the link is up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Lost,
+    Restored
+}
+
+/// Keeps track of when the controller was last heard from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Supervisor {
+    /// `None` until the controller is first heard from, since there's no link to lose before then.
+    last_heard: Option<u64>,
+    lost: bool
+}
+
+impl Supervisor {
+    pub const fn new() -> Self {
+        Self { last_heard: None, lost: false }
+    }
+
+    pub fn heard(&mut self, now_ms: u64) -> Option<Change> {
+        self.last_heard = Some(now_ms);
+
+        if self.lost {
+            self.lost = false;
+            Some(Change::Restored)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the link has just been lost.
+    pub fn check(&mut self, now_ms: u64) -> Option<Change> {
+        let silent = self.last_heard.is_some_and(|at| now_ms.saturating_sub(at) >= TIMEOUT_MS);
+
+        if silent && !self.lost {
+            self.lost = true;
+            Some(Change::Lost)
+        } else {
+            None
+        }
+    }
+
+    pub const fn is_lost(&self) -> bool {
+        self.lost
+    }
+}
+
+/// Whether a controller can reach its cuff.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Presence {
+    /// The controller has only just started and hasn't heard from the cuff yet.
+    #[default]
+    Probing,
+    Present,
+    /// The cuff has stopped answering, so the performer isn't getting any cues.
+    Lost
+}
+
+impl Presence {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Presence::Probing => "probing",
+            Presence::Present => "present",
+            Presence::Lost => "lost",
+        }
+    }
+
+    pub const fn code(self) -> u8 {
+        match self {
+            Presence::Probing => 0x00,
+            Presence::Present => 0x01,
+            Presence::Lost => 0x02,
+        }
+    }
+
+    pub const fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x00 => Some(Presence::Probing),
+            0x01 => Some(Presence::Present),
+            0x02 => Some(Presence::Lost),
+            _ => None
+        }
+    }
+}
+
+/// Keeps track of whether the cuff is answering the controller's keep alives.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tracker {
+    presence: Presence,
+    /// How many keep alives in a row have gone unanswered.
+    failures: u32
+}
+
+impl Tracker {
+    pub const fn new() -> Self {
+        Self { presence: Presence::Probing, failures: 0 }
+    }
+
+    pub const fn presence(&self) -> Presence {
+        self.presence
+    }
+
+    /// The cuff answered. Returns the new presence if it changed.
+    pub fn answered(&mut self) -> Option<Presence> {
+        self.failures = 0;
+        self.change_to(Presence::Present)
+    }
+
+    /// The cuff didn't answer. Returns the new presence if it changed. A cuff that has never answered is still being
+    /// probed for rather than lost, since every controller looks for a cuff on each wrist and most performers only
+    /// wear one.
+    pub fn failed(&mut self) -> Option<Presence> {
+        self.failures = self.failures.saturating_add(1);
+
+        if self.failures >= LOST_AFTER && self.presence != Presence::Probing {
+            self.change_to(Presence::Lost)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the bus should be freed up before the next keep alive, in case the cuff is holding it.
+    pub const fn should_recover_bus(&self) -> bool {
+        self.failures > 0 && self.failures.is_multiple_of(RECOVER_EVERY)
+    }
+
+    fn change_to(&mut self, presence: Presence) -> Option<Presence> {
+        if self.presence == presence {
+            return None;
+        }
+
+        self.presence = presence;
+        Some(presence)
+    }
+}
+
+/// How the link to a cuff did over the last few keep alives.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QualityReport {
+    pub sent: u16,
+    /// How many of them went unanswered.
+    pub lost: u16,
+    /// How long the answered ones took on average, or `None` if none were.
+    pub latency_ms: Option<u16>
+}
+
+impl QualityReport {
+    pub fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u16(self.sent)?;
+        w.u16(self.lost)?;
+        w.u16(self.latency_ms.unwrap_or(u16::MAX))
+    }
+
+    pub fn read(r: &mut Reader) -> Result<Self, Error> {
+        let sent = r.u16()?;
+        let lost = r.u16()?;
+        let latency_ms = match r.u16()? {
+            u16::MAX => None,
+            latency_ms => Some(latency_ms),
+        };
+
+        Ok(Self { sent, lost, latency_ms })
+    }
+
+    /// The share of keep alives that went unanswered, as a percentage.
+    pub fn loss_percent(&self) -> u8 {
+        if self.sent == 0 {
+            return 0;
+        }
+
+        (100 * self.lost as u32 / self.sent as u32) as u8
+    }
+
+    /// Whether enough keep alives went unanswered that cues are likely going missing too.
+    pub fn is_poor(&self) -> bool {
+        self.loss_percent() >= POOR_LOSS_PERCENT
+    }
+}
+
+/// Tallies how the keep alives to a cuff are getting on, for a [`QualityReport`] every [`QUALITY_EVERY`] of them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quality {
+    sent: u16,
+    lost: u16,
+    total_latency_ms: u32
+}
+
+impl Quality {
+    pub const fn new() -> Self {
+        Self { sent: 0, lost: 0, total_latency_ms: 0 }
+    }
+
+    /// A keep alive was answered after `latency_ms`. Returns a report if one is due.
+    pub fn answered(&mut self, latency_ms: u32) -> Option<QualityReport> {
+        self.sent += 1;
+        self.total_latency_ms = self.total_latency_ms.saturating_add(latency_ms);
+        self.report_if_due()
+    }
+
+    /// A keep alive went unanswered. Returns a report if one is due.
+    pub fn failed(&mut self) -> Option<QualityReport> {
+        self.sent += 1;
+        self.lost += 1;
+        self.report_if_due()
+    }
+
+    fn report_if_due(&mut self) -> Option<QualityReport> {
+        if self.sent < QUALITY_EVERY {
+            return None;
+        }
+
+        let answered = (self.sent - self.lost) as u32;
+        let latency_ms = (answered > 0).then(|| (self.total_latency_ms / answered).min(u16::MAX as u32 - 1) as u16);
+        let report = QualityReport { sent: self.sent, lost: self.lost, latency_ms };
+
+        *self = Self::new();
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_probing_for_a_cuff_that_never_answers() {
+        let mut tracker = Tracker::new();
+
+        for _ in 0..LOST_AFTER * 4 {
+            assert_eq!(tracker.failed(), None);
+        }
+        assert_eq!(tracker.presence(), Presence::Probing);
+
+        assert_eq!(tracker.answered(), Some(Presence::Present));
+    }
+
+    #[test]
+    fn loses_a_cuff_that_stops_answering() {
+        let mut tracker = Tracker::new();
+        tracker.answered();
+
+        for _ in 1..LOST_AFTER {
+            assert_eq!(tracker.failed(), None);
+        }
+        assert_eq!(tracker.failed(), Some(Presence::Lost));
+        assert_eq!(tracker.failed(), None);
+
+        assert_eq!(tracker.answered(), Some(Presence::Present));
+    }
+}
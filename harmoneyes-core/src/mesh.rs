@@ -0,0 +1,201 @@
+//! The layout of the bluetooth mesh advertisements that controllers broadcast to each other.
+//!
+//! Every advertisement carries a single AD structure with the mesh AD type whose data is the
+//! magic string "Harmoneyes", the short address of the controller that sent it and then the message itself.
+
+use crate::{battery::{Alert, Report}, codec::{Reader, Writer}, haptics::Wrist, link::Presence, power::Mode, telemetry::MESH_PAYLOAD_LENGTH};
+
+/// The "Mesh Message" AD type from the Bluetooth assigned numbers.
+pub const AD_TYPE: u8 = 0x2A;
+
+/// Marks an advertisement as coming from another Harmoneyes controller.
+pub const MAGIC: &[u8; 10] = b"Harmoneyes";
+
+/// The length of the sender's short address.
+pub const SOURCE_LENGTH: usize = 2;
+
+/// The length of a message, which shares the mesh payload with the sender's address.
+pub const MESSAGE_LENGTH: usize = MESH_PAYLOAD_LENGTH - SOURCE_LENGTH;
+
+/// The length of the AD structure data, that is the magic string, the sender's address and the message.
+pub const DATA_LENGTH: usize = MAGIC.len() + MESH_PAYLOAD_LENGTH;
+
+/// A message received from another controller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    /// The short address of the controller that sent the message.
+    pub source: u16,
+    pub message: &'a [u8]
+}
+
+/// Builds the AD structure data for a message from the controller with the given short address.
+pub fn encode(source: u16, message: &[u8; MESSAGE_LENGTH]) -> [u8; DATA_LENGTH] {
+    let mut raw = [0; DATA_LENGTH];
+
+    raw[..MAGIC.len()].copy_from_slice(MAGIC);
+    raw[MAGIC.len()..MAGIC.len() + SOURCE_LENGTH].copy_from_slice(&source.to_le_bytes());
+    raw[MAGIC.len() + SOURCE_LENGTH..].copy_from_slice(message);
+
+    raw
+}
+
+/// Finds the message in received advertising data, which starts with the length and type of the AD structure.
+pub fn parse(data: &[u8]) -> Option<Packet<'_>> {
+    let header = 2 + MAGIC.len();
+
+    if data.len() > header + SOURCE_LENGTH && data[1] == AD_TYPE && data[2..header] == *MAGIC {
+        Some(Packet {
+            source: u16::from_le_bytes([data[header], data[header + 1]]),
+            message: &data[header + SOURCE_LENGTH..]
+        })
+    } else {
+        None
+    }
+}
+
+/// Marks a message as a keep alive.
+const KEEP_ALIVE_PREFIX: &[u8] = b"Signal ";
+
+/// The keep alive a controller sends every second so the others know it's still around, along with how its own
+/// battery and its cuffs are holding up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAlive {
+    pub count: u32,
+    /// The controller's battery, or `None` until it has taken its first sample.
+    pub battery: Option<Report>,
+    /// How the cuff on each wrist is doing, indexed by [`Wrist::index`], or `None` if there's no cuff on it.
+    pub cuffs: [Option<Cuff>; Wrist::ALL.len()],
+    /// The number the director uses for the performer, or 0 if none has been set.
+    pub performer_id: u16,
+    /// The section of the band the performer belongs to, or 0 if none has been set.
+    pub section: u8
+}
+
+/// How one of a performer's cuffs is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cuff {
+    /// Whether the controller can reach the cuff, so the director knows when a performer isn't getting cues.
+    pub presence: Presence,
+    /// The cuff's battery, or `None` if the cuff hasn't reported one.
+    pub battery: Option<Report>
+}
+
+/// Stands in for the presence of a cuff on a wrist that doesn't have one.
+const NO_CUFF: u8 = 0xFF;
+
+pub fn keep_alive(keep_alive: &KeepAlive) -> [u8; MESSAGE_LENGTH] {
+    let mut buf = [0; MESSAGE_LENGTH];
+
+    buf[..KEEP_ALIVE_PREFIX.len()].copy_from_slice(KEEP_ALIVE_PREFIX);
+
+    let mut w = Writer::new(&mut buf[KEEP_ALIVE_PREFIX.len()..]);
+    // The message is much longer than a keep alive, so none of this can fail
+    w.u32(keep_alive.count).expect("Keep alive did not fit in a message");
+    Report::write_optional(keep_alive.battery, &mut w).expect("Keep alive did not fit in a message");
+    for cuff in keep_alive.cuffs {
+        match cuff {
+            Some(cuff) => {
+                w.u8(cuff.presence.code()).expect("Keep alive did not fit in a message");
+                Report::write_optional(cuff.battery, &mut w).expect("Keep alive did not fit in a message");
+            },
+            None => w.u8(NO_CUFF).expect("Keep alive did not fit in a message"),
+        }
+    }
+    w.u16(keep_alive.performer_id).expect("Keep alive did not fit in a message");
+    w.u8(keep_alive.section).expect("Keep alive did not fit in a message");
+
+    buf
+}
+
+/// Reads a keep alive out of a message, if that's what it is.
+pub fn parse_keep_alive(message: &[u8]) -> Option<KeepAlive> {
+    let mut r = Reader::new(message.strip_prefix(KEEP_ALIVE_PREFIX)?);
+
+    let count = r.u32().ok()?;
+    let battery = Report::read_optional(&mut r).ok()?;
+
+    let mut cuffs = [None; Wrist::ALL.len()];
+    for cuff in &mut cuffs {
+        *cuff = match r.u8().ok()? {
+            NO_CUFF => None,
+            code => Some(Cuff { presence: Presence::from_code(code)?, battery: Report::read_optional(&mut r).ok()? }),
+        };
+    }
+
+    // Keep alives from before these were sent are padded with zeros, which reads as neither being set
+    let performer_id = r.u16().ok()?;
+    let section = r.u8().ok()?;
+
+    Some(KeepAlive { count, battery, cuffs, performer_id, section })
+}
+
+/// Marks a message as a low battery alert.
+const BATTERY_ALERT_PREFIX: &[u8] = b"Battery ";
+
+/// The message a controller broadcasts when its battery is running low, so that whoever is watching the show from
+/// another controller's console can see which performer needs a new battery.
+pub fn battery_alert(alert: &Alert) -> [u8; MESSAGE_LENGTH] {
+    let mut buf = [0; MESSAGE_LENGTH];
+
+    buf[..BATTERY_ALERT_PREFIX.len()].copy_from_slice(BATTERY_ALERT_PREFIX);
+    // The message is much longer than an alert, so this can't fail
+    alert.write(&mut Writer::new(&mut buf[BATTERY_ALERT_PREFIX.len()..])).expect("Battery alert did not fit in a message");
+
+    buf
+}
+
+/// Reads a low battery alert out of a message, if that's what it is.
+pub fn parse_battery_alert(message: &[u8]) -> Option<Alert> {
+    let rest = message.strip_prefix(BATTERY_ALERT_PREFIX)?;
+
+    Alert::read(&mut Reader::new(rest)).ok()
+}
+
+/// Marks a message as a power mode command.
+const POWER_MODE_PREFIX: &[u8] = b"Power ";
+
+/// The message the controller a director is using broadcasts to put the whole band into a power mode, such as to
+/// sleep between reps and wake again for the next one.
+pub fn power_mode(mode: Mode) -> [u8; MESSAGE_LENGTH] {
+    let mut buf = [0; MESSAGE_LENGTH];
+
+    buf[..POWER_MODE_PREFIX.len()].copy_from_slice(POWER_MODE_PREFIX);
+    buf[POWER_MODE_PREFIX.len()] = mode.code();
+
+    buf
+}
+
+/// Reads a power mode command out of a message, if that's what it is.
+pub fn parse_power_mode(message: &[u8]) -> Option<Mode> {
+    let rest = message.strip_prefix(POWER_MODE_PREFIX)?;
+
+    Mode::from_code(*rest.first()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEEP_ALIVE: KeepAlive = KeepAlive {
+        count: 42,
+        battery: None,
+        cuffs: [Some(Cuff { presence: Presence::Present, battery: None }), None],
+        performer_id: 17,
+        section: 3
+    };
+
+    #[test]
+    fn reads_back_a_keep_alive() {
+        assert_eq!(parse_keep_alive(&keep_alive(&KEEP_ALIVE)), Some(KEEP_ALIVE));
+    }
+
+    #[test]
+    fn reads_a_keep_alive_from_before_the_performer_was_sent() {
+        let mut message = keep_alive(&KEEP_ALIVE);
+        // The performer ID and section follow the prefix, the count, the battery and the two cuffs
+        let end = KEEP_ALIVE_PREFIX.len() + 4 + (1 + Report::ENCODED_LENGTH) + (2 + Report::ENCODED_LENGTH) + 1;
+        message[end..].fill(0);
+
+        assert_eq!(parse_keep_alive(&message), Some(KeepAlive { performer_id: 0, section: 0, ..KEEP_ALIVE }));
+    }
+}
@@ -0,0 +1,64 @@
+//! The power modes a controller and its cuff move between, so that a band on a break between reps isn't ranging,
+//! advertising and animating its LEDs flat out the whole time.
+//!
+//! A director puts the whole band into a mode from the console, which tells the controller it's plugged into and has
+//! it broadcast the mode over the mesh. Each controller passes its mode on to its cuff over the two-wire interface.
+
+/// How much of the device is running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Everything is running as normal.
+    #[default]
+    Active,
+    /// Ranging is paused and the mesh is only listened to part of the time, but the device can pick back up straight
+    /// away.
+    Idle,
+    /// Everything but an occasional listen on the mesh is off, until a mesh command or the button wakes it.
+    Sleep
+}
+
+impl Mode {
+    pub const ALL: [Mode; 3] = [Mode::Active, Mode::Idle, Mode::Sleep];
+
+    /// The command code the cuff listens for on the two-wire interface, followed by the code of the mode. It doesn't
+    /// overlap with the codes in [`crate::haptics`], [`crate::link`], [`crate::transfer`] or [`crate::registers`].
+    pub const COMMAND: u8 = 0x40;
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Mode::Active => "active",
+            Mode::Idle => "idle",
+            Mode::Sleep => "sleep",
+        }
+    }
+
+    pub const fn code(self) -> u8 {
+        match self {
+            Mode::Active => 0x01,
+            Mode::Idle => 0x02,
+            Mode::Sleep => 0x03,
+        }
+    }
+
+    pub const fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x01 => Some(Mode::Active),
+            0x02 => Some(Mode::Idle),
+            0x03 => Some(Mode::Sleep),
+            _ => None
+        }
+    }
+
+    /// The command that puts the cuff into this mode.
+    pub const fn command(self) -> [u8; 2] {
+        [Self::COMMAND, self.code()]
+    }
+
+    /// Reads the mode out of a command sent to the cuff, if that's what it is.
+    pub fn from_command(command: &[u8]) -> Option<Self> {
+        match command {
+            [Self::COMMAND, code] => Self::from_code(*code),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! The messages exchanged between the console and a device over the device's USB serial port.
+//!
+//! The console sends a [`Request`] and the device answers each one with exactly one [`Response`].
+//! While telemetry streaming is enabled the device also sends [`Response::Telemetry`] whenever
+//! something happens, so the console must be prepared to receive those between a request and its
+//! answer. Every message is sent as a single frame, see [`crate::framing`].
+
+use crate::{codec::{Error, FixedStr, Reader, Writer}, config::{Key, Name, Value}, crash::Report, haptics::{Motor, Wrist}, power::Mode, telemetry::Event, update::{Chunk, Signature, SIGNATURE_LENGTH}};
+
+/// The size of the buffer needed to hold any encoded message, which is either a telemetry event or a crash report.
+pub const MAX_MESSAGE_LENGTH: usize = if 1 + Event::MAX_ENCODED_LENGTH > 2 + Report::MAX_ENCODED_LENGTH {
+    1 + Event::MAX_ENCODED_LENGTH
+} else {
+    2 + Report::MAX_ENCODED_LENGTH
+};
+
+/// The size of the buffer needed to hold any framed message.
+pub const MAX_FRAME_LENGTH: usize = crate::framing::max_encoded_len(MAX_MESSAGE_LENGTH);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Asks the device to describe itself.
+    Info,
+    ConfigGet { key: Key },
+    ConfigSet { key: Key, value: Value },
+    /// Restores every setting to its default.
+    ConfigReset,
+    /// Runs one of the motors in the cuff on `wrist` for a while.
+    HapticTest { wrist: Wrist, motor: Motor, duration_ms: u64 },
+    /// Turns the stream of telemetry events on or off.
+    Stream { enabled: bool },
+    /// Starts a firmware update with an image of the given size, erasing any earlier partial update. Updates for a
+    /// cuff are sent to the controller it's cabled to, which passes them on.
+    UpdateBegin { target: DeviceKind, size: u32 },
+    /// Writes the next piece of the image, which has to follow on from the last one.
+    UpdateWrite { target: DeviceKind, offset: u32, chunk: Chunk },
+    /// Checks the signature of the image and, if it's valid, restarts the device into it.
+    UpdateFinish { target: DeviceKind, signature: Signature },
+    /// Restarts the device into its built in USB bootloader so that a new image can be copied onto it.
+    RebootToBootloader,
+    /// Puts the device and its cuff into a power mode, and with `band` has a controller broadcast the mode over the
+    /// mesh so every other controller follows.
+    SetPowerMode { mode: Mode, band: bool },
+    /// Fetches a crash report, with the most recent at index 0.
+    CrashGet { index: u8 },
+    /// Throws away every crash report.
+    CrashClear
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Controller,
+    Cuff
+}
+
+impl DeviceKind {
+    pub const fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Controller => "controller",
+            DeviceKind::Cuff => "cuff",
+        }
+    }
+
+    const fn code(self) -> u8 {
+        match self {
+            DeviceKind::Controller => 0x01,
+            DeviceKind::Cuff => 0x02,
+        }
+    }
+
+    const fn from_code(code: u8) -> Result<Self, Error> {
+        match code {
+            0x01 => Ok(DeviceKind::Controller),
+            0x02 => Ok(DeviceKind::Cuff),
+            code => Err(Error::UnknownTag(code))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub kind: DeviceKind,
+    pub serial: FixedStr<16>,
+    /// The name of the performer wearing the device, or empty if none has been set.
+    pub name: Name,
+    pub firmware_version: FixedStr<16>,
+    pub uptime_ms: u64,
+    /// The number the director uses for the performer wearing the device, or 0 if none has been set.
+    pub performer_id: u16,
+    /// The section of the band the performer belongs to, or 0 if none has been set.
+    pub section: u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The device doesn't know how to do what was asked.
+    Unsupported,
+    /// The request couldn't be decoded.
+    Malformed,
+    /// The value is the wrong type or out of range for the setting.
+    InvalidValue,
+    /// The cuff didn't acknowledge the command.
+    CuffUnavailable,
+    /// The device couldn't save the change to its flash.
+    StorageFailed,
+    /// The firmware image is too large, out of order, or failed its signature check.
+    UpdateRejected,
+    /// One cuff restarted into the new firmware but the other didn't take it, so the pair no longer match.
+    UpdatePartial
+}
+
+impl Failure {
+    pub const fn description(self) -> &'static str {
+        match self {
+            Failure::Unsupported => "the device does not support this request",
+            Failure::Malformed => "the device could not decode the request",
+            Failure::InvalidValue => "the value is not valid for this setting",
+            Failure::CuffUnavailable => "the cuff did not respond",
+            Failure::StorageFailed => "the device could not save the change",
+            Failure::UpdateRejected => "the device rejected the firmware image",
+            Failure::UpdatePartial => "only some of the cuffs took the firmware image, so update them again to match",
+        }
+    }
+
+    const fn code(self) -> u8 {
+        match self {
+            Failure::Unsupported => 0x01,
+            Failure::Malformed => 0x02,
+            Failure::InvalidValue => 0x03,
+            Failure::CuffUnavailable => 0x04,
+            Failure::StorageFailed => 0x05,
+            Failure::UpdateRejected => 0x06,
+            Failure::UpdatePartial => 0x07,
+        }
+    }
+
+    const fn from_code(code: u8) -> Result<Self, Error> {
+        match code {
+            0x01 => Ok(Failure::Unsupported),
+            0x02 => Ok(Failure::Malformed),
+            0x03 => Ok(Failure::InvalidValue),
+            0x04 => Ok(Failure::CuffUnavailable),
+            0x05 => Ok(Failure::StorageFailed),
+            0x06 => Ok(Failure::UpdateRejected),
+            0x07 => Ok(Failure::UpdatePartial),
+            code => Err(Error::UnknownTag(code))
+        }
+    }
+}
+
+// See the note on `Telemetry` about the size of this enum.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The request was carried out.
+    Done,
+    Failed(Failure),
+    Info(DeviceInfo),
+    Config { key: Key, value: Value },
+    Telemetry(Event),
+    /// The crash report that was asked for, or `None` if there aren't that many.
+    Crash(Option<Report>)
+}
+
+impl Request {
+    const INFO: u8 = 0x01;
+    const CONFIG_GET: u8 = 0x02;
+    const CONFIG_SET: u8 = 0x03;
+    const CONFIG_RESET: u8 = 0x04;
+    const HAPTIC_TEST: u8 = 0x05;
+    const STREAM: u8 = 0x06;
+    const UPDATE_BEGIN: u8 = 0x07;
+    const UPDATE_WRITE: u8 = 0x08;
+    const UPDATE_FINISH: u8 = 0x09;
+    const REBOOT_TO_BOOTLOADER: u8 = 0x0A;
+    const SET_POWER_MODE: u8 = 0x0B;
+    const CRASH_GET: u8 = 0x0C;
+    const CRASH_CLEAR: u8 = 0x0D;
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer::new(buf);
+
+        match self {
+            Request::Info => w.u8(Self::INFO)?,
+            Request::ConfigGet { key } => {
+                w.u8(Self::CONFIG_GET)?;
+                w.u8(key.id())?;
+            },
+            Request::ConfigSet { key, value } => {
+                w.u8(Self::CONFIG_SET)?;
+                w.u8(key.id())?;
+                value.write(&mut w)?;
+            },
+            Request::ConfigReset => w.u8(Self::CONFIG_RESET)?,
+            Request::HapticTest { wrist, motor, duration_ms } => {
+                w.u8(Self::HAPTIC_TEST)?;
+                w.u8(motor.code())?;
+                w.u64(*duration_ms)?;
+                // At the end, since consoles from before there could be two cuffs don't send it
+                w.u8(wrist.code())?;
+            },
+            Request::Stream { enabled } => {
+                w.u8(Self::STREAM)?;
+                w.u8(*enabled as u8)?;
+            },
+            Request::UpdateBegin { target, size } => {
+                w.u8(Self::UPDATE_BEGIN)?;
+                w.u8(target.code())?;
+                w.u32(*size)?;
+            },
+            Request::UpdateWrite { target, offset, chunk } => {
+                w.u8(Self::UPDATE_WRITE)?;
+                w.u8(target.code())?;
+                w.u32(*offset)?;
+                chunk.write(&mut w)?;
+            },
+            Request::UpdateFinish { target, signature } => {
+                w.u8(Self::UPDATE_FINISH)?;
+                w.u8(target.code())?;
+                w.bytes(signature)?;
+            },
+            Request::RebootToBootloader => w.u8(Self::REBOOT_TO_BOOTLOADER)?,
+            Request::SetPowerMode { mode, band } => {
+                w.u8(Self::SET_POWER_MODE)?;
+                w.u8(mode.code())?;
+                w.u8(*band as u8)?;
+            },
+            Request::CrashGet { index } => {
+                w.u8(Self::CRASH_GET)?;
+                w.u8(*index)?;
+            },
+            Request::CrashClear => w.u8(Self::CRASH_CLEAR)?,
+        }
+
+        Ok(w.position())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader::new(buf);
+
+        Ok(match r.u8()? {
+            Self::INFO => Request::Info,
+            Self::CONFIG_GET => Request::ConfigGet { key: read_key(&mut r)? },
+            Self::CONFIG_SET => Request::ConfigSet { key: read_key(&mut r)?, value: Value::read(&mut r)? },
+            Self::CONFIG_RESET => Request::ConfigReset,
+            Self::HAPTIC_TEST => {
+                let motor = Motor::from_code(r.u8()?).ok_or(Error::Invalid)?;
+                let duration_ms = r.u64()?;
+                Request::HapticTest { wrist: Wrist::read_or_left(&mut r)?, motor, duration_ms }
+            },
+            Self::STREAM => Request::Stream { enabled: r.u8()? != 0 },
+            Self::UPDATE_BEGIN => Request::UpdateBegin { target: DeviceKind::from_code(r.u8()?)?, size: r.u32()? },
+            Self::UPDATE_WRITE => Request::UpdateWrite {
+                target: DeviceKind::from_code(r.u8()?)?,
+                offset: r.u32()?,
+                chunk: Chunk::read(&mut r)?
+            },
+            Self::UPDATE_FINISH => Request::UpdateFinish {
+                target: DeviceKind::from_code(r.u8()?)?,
+                // The length was asked for, so the conversion can't fail
+                signature: r.bytes(SIGNATURE_LENGTH)?.try_into().unwrap()
+            },
+            Self::REBOOT_TO_BOOTLOADER => Request::RebootToBootloader,
+            Self::SET_POWER_MODE => Request::SetPowerMode {
+                mode: Mode::from_code(r.u8()?).ok_or(Error::Invalid)?,
+                band: r.u8()? != 0
+            },
+            Self::CRASH_GET => Request::CrashGet { index: r.u8()? },
+            Self::CRASH_CLEAR => Request::CrashClear,
+            tag => return Err(Error::UnknownTag(tag))
+        })
+    }
+}
+
+impl Response {
+    const DONE: u8 = 0x81;
+    const FAILED: u8 = 0x82;
+    const INFO: u8 = 0x83;
+    const CONFIG: u8 = 0x84;
+    const TELEMETRY: u8 = 0x85;
+    const CRASH: u8 = 0x86;
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer::new(buf);
+
+        match self {
+            Response::Done => w.u8(Self::DONE)?,
+            Response::Failed(failure) => {
+                w.u8(Self::FAILED)?;
+                w.u8(failure.code())?;
+            },
+            Response::Info(info) => {
+                w.u8(Self::INFO)?;
+                w.u8(info.kind.code())?;
+                w.str(&info.serial)?;
+                w.str(&info.name)?;
+                w.str(&info.firmware_version)?;
+                w.u64(info.uptime_ms)?;
+                // At the end, since devices from before they were reported don't send them
+                w.u16(info.performer_id)?;
+                w.u8(info.section)?;
+            },
+            Response::Config { key, value } => {
+                w.u8(Self::CONFIG)?;
+                w.u8(key.id())?;
+                value.write(&mut w)?;
+            },
+            Response::Telemetry(event) => {
+                w.u8(Self::TELEMETRY)?;
+                let position = w.position();
+                return Ok(position + event.encode(&mut buf[position..])?);
+            },
+            Response::Crash(report) => {
+                w.u8(Self::CRASH)?;
+                w.u8(report.is_some() as u8)?;
+                if let Some(report) = report {
+                    report.write(&mut w)?;
+                }
+            },
+        }
+
+        Ok(w.position())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader::new(buf);
+
+        Ok(match r.u8()? {
+            Self::DONE => Response::Done,
+            Self::FAILED => Response::Failed(Failure::from_code(r.u8()?)?),
+            Self::INFO => {
+                let kind = DeviceKind::from_code(r.u8()?)?;
+                let serial = r.str()?;
+                let name = r.str()?;
+                let firmware_version = r.str()?;
+                let uptime_ms = r.u64()?;
+                let (performer_id, section) = if r.remaining().is_empty() { (0, 0) } else { (r.u16()?, r.u8()?) };
+                Response::Info(DeviceInfo { kind, serial, name, firmware_version, uptime_ms, performer_id, section })
+            },
+            Self::CONFIG => Response::Config { key: read_key(&mut r)?, value: Value::read(&mut r)? },
+            Self::TELEMETRY => Response::Telemetry(Event::decode(r.remaining())?),
+            Self::CRASH => Response::Crash(if r.u8()? != 0 { Some(Report::read(&mut r)?) } else { None }),
+            tag => return Err(Error::UnknownTag(tag))
+        })
+    }
+}
+
+fn read_key(r: &mut Reader) -> Result<Key, Error> {
+    let id = r.u8()?;
+    Key::from_id(id).ok_or(Error::UnknownTag(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crash::{Lines, ResetReason, LINE_LENGTH, MESSAGE_LENGTH}, telemetry::{Telemetry, MESH_PAYLOAD_LENGTH}, update::CHUNK_LENGTH};
+
+    fn round_trip_request(request: Request) {
+        let mut buf = [0; MAX_MESSAGE_LENGTH];
+        let len = request.encode(&mut buf).unwrap();
+
+        assert_eq!(Request::decode(&buf[..len]), Ok(request));
+    }
+
+    fn round_trip_response(response: Response) {
+        let mut buf = [0; MAX_MESSAGE_LENGTH];
+        let len = response.encode(&mut buf).unwrap();
+
+        assert_eq!(Response::decode(&buf[..len]), Ok(response));
+    }
+
+    /// A crash report with every string as long as it can be.
+    fn longest_report() -> Report {
+        let mut lines = Lines::new();
+        for _ in 0..crate::crash::LINES {
+            lines.push(FixedStr::new(&"l".repeat(LINE_LENGTH)).unwrap());
+        }
+
+        Report { uptime_ms: u64::MAX, reset_reason: ResetReason(0x0000_0004), message: FixedStr::new(&"m".repeat(MESSAGE_LENGTH)).unwrap(), lines }
+    }
+
+    #[test]
+    fn reads_back_every_request() {
+        let requests = [
+            Request::Info,
+            Request::ConfigGet { key: Key::PerformerName },
+            Request::ConfigSet { key: Key::PerformerName, value: Value::Name(Name::new("Alto Sax 3").unwrap()) },
+            Request::ConfigSet { key: Key::PerformerId, value: Value::U16(0xBEEF) },
+            Request::ConfigSet { key: Key::Section, value: Value::U8(7) },
+            Request::ConfigReset,
+            Request::HapticTest { wrist: Wrist::Right, motor: Motor::Back, duration_ms: 250 },
+            Request::Stream { enabled: true },
+            Request::Stream { enabled: false },
+            Request::UpdateBegin { target: DeviceKind::Cuff, size: 0x0004_0000 },
+            Request::UpdateWrite { target: DeviceKind::Controller, offset: 0x0100, chunk: Chunk::new(&[0xA5; CHUNK_LENGTH]).unwrap() },
+            Request::UpdateWrite { target: DeviceKind::Cuff, offset: 0, chunk: Chunk::new(&[]).unwrap() },
+            Request::UpdateFinish { target: DeviceKind::Cuff, signature: [0x5A; SIGNATURE_LENGTH] },
+            Request::RebootToBootloader,
+            Request::SetPowerMode { mode: Mode::Sleep, band: true },
+            Request::SetPowerMode { mode: Mode::Idle, band: false },
+            Request::CrashGet { index: 3 },
+            Request::CrashClear
+        ];
+
+        for request in requests {
+            round_trip_request(request);
+        }
+        for key in Key::ALL {
+            round_trip_request(Request::ConfigGet { key });
+        }
+    }
+
+    #[test]
+    fn reads_back_every_response() {
+        let info = DeviceInfo {
+            kind: DeviceKind::Cuff,
+            serial: FixedStr::new("HAC0123456789ABC").unwrap(),
+            name: Name::new("Sousaphone Three").unwrap(),
+            firmware_version: FixedStr::new("0.1.0").unwrap(),
+            uptime_ms: 123_456,
+            performer_id: 42,
+            section: 3
+        };
+        let failures = [
+            Failure::Unsupported,
+            Failure::Malformed,
+            Failure::InvalidValue,
+            Failure::CuffUnavailable,
+            Failure::StorageFailed,
+            Failure::UpdateRejected,
+            Failure::UpdatePartial
+        ];
+
+        round_trip_response(Response::Done);
+        for failure in failures {
+            round_trip_response(Response::Failed(failure));
+        }
+        round_trip_response(Response::Info(info));
+        round_trip_response(Response::Config { key: Key::HapticIntensity, value: Value::U8(80) });
+        round_trip_response(Response::Telemetry(Event { uptime_ms: 99, telemetry: Telemetry::Distance { peer: 0x0102, tof: 12_345 } }));
+        round_trip_response(Response::Crash(None));
+        round_trip_response(Response::Crash(Some(longest_report())));
+    }
+
+    #[test]
+    fn fits_the_longest_messages_in_the_buffer() {
+        let event = Event { uptime_ms: u64::MAX, telemetry: Telemetry::mesh(true, &[0xFF; MESH_PAYLOAD_LENGTH]) };
+
+        round_trip_response(Response::Telemetry(event));
+        round_trip_response(Response::Crash(Some(longest_report())));
+    }
+
+    #[test]
+    fn reads_device_info_from_before_the_performer_was_reported() {
+        let mut buf = [0; MAX_MESSAGE_LENGTH];
+        let info = DeviceInfo {
+            kind: DeviceKind::Controller,
+            serial: FixedStr::new("HAC1").unwrap(),
+            name: Name::empty(),
+            firmware_version: FixedStr::new("0.0.9").unwrap(),
+            uptime_ms: 5,
+            performer_id: 42,
+            section: 3
+        };
+        let len = Response::Info(info).encode(&mut buf).unwrap();
+
+        // Drop the performer ID and section from the end
+        assert_eq!(Response::decode(&buf[..len - 3]), Ok(Response::Info(DeviceInfo { performer_id: 0, section: 0, ..info })));
+    }
+
+    #[test]
+    fn reads_a_haptic_test_from_before_there_could_be_two_cuffs_as_the_left_wrist() {
+        let mut buf = [0; MAX_MESSAGE_LENGTH];
+        let len = Request::HapticTest { wrist: Wrist::Right, motor: Motor::Front, duration_ms: 100 }.encode(&mut buf).unwrap();
+
+        assert_eq!(Request::decode(&buf[..len - 1]), Ok(Request::HapticTest { wrist: Wrist::Left, motor: Motor::Front, duration_ms: 100 }));
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        assert_eq!(Request::decode(&[0x7F]), Err(Error::UnknownTag(0x7F)));
+        assert_eq!(Response::decode(&[0x01]), Err(Error::UnknownTag(0x01)));
+        assert_eq!(Request::decode(&[Request::UPDATE_BEGIN, 0x09, 0, 0, 0, 0]), Err(Error::UnknownTag(0x09)));
+        assert_eq!(Request::decode(&[Request::CONFIG_GET, 0xEE]), Err(Error::UnknownTag(0xEE)));
+        assert_eq!(Response::decode(&[Response::FAILED, 0x00]), Err(Error::UnknownTag(0x00)));
+    }
+
+    #[test]
+    fn rejects_a_message_that_ends_early() {
+        assert_eq!(Request::decode(&[]), Err(Error::BufferTooShort));
+        assert_eq!(Request::decode(&[Request::UPDATE_BEGIN, 0x01, 0x00]), Err(Error::BufferTooShort));
+    }
+}
@@ -0,0 +1,273 @@
+//! Warning a performer that somebody is about to walk into them.
+//!
+//! This only needs the distance to each peer that ranging measures, so it works without positions or a drill chart.
+//! Every peer is tracked on its own, along with how fast it's closing in, worked out from the change in its distance
+//! between measurements. A peer gets a [`Warning`] when it's inside the radius in [`Limits`], or when it's closing
+//! faster than the limit, however far away it still is.
+//!
+//! Ranging now and then measures something wildly off, so a change in distance faster than [`MAX_SPEED_MM_PER_S`]
+//! is left out of the closing speed.
+
+use crate::codec::{Error, Reader, Writer};
+
+/// How many peers are tracked at once. The ones heard from least recently make way for new ones.
+pub const MAX_PEERS: usize = 16;
+
+/// How long a peer can go without being measured before its closing speed is worked out afresh.
+pub const STALE_MS: u64 = 2000;
+
+/// How long apart two measurements have to be for the change between them to count towards the closing speed, so
+/// that the noise in each one doesn't swamp it.
+pub const MIN_INTERVAL_MS: u64 = 100;
+
+/// Faster than anybody in a band moves, so a change in distance any quicker than this is a bad measurement.
+pub const MAX_SPEED_MM_PER_S: i64 = 10_000;
+
+/// How long after a warning the same peer can set off another one, so the cuff isn't restarted on every measurement.
+pub const REPEAT_MS: u64 = 1000;
+
+/// When a peer counts as too close.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Any peer nearer than this gets a warning.
+    pub radius_mm: u32,
+    /// Any peer closing in at least this fast gets a warning wherever it is, or `None` to only go by the radius.
+    pub closing_mm_per_s: Option<u32>
+}
+
+/// A peer that's too close, or closing in too fast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub peer: u16,
+    pub distance_mm: u32,
+    /// How fast the peer is closing in, which is negative when it's moving away.
+    pub closing_mm_per_s: i16
+}
+
+impl Warning {
+    pub fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u16(self.peer)?;
+        w.u32(self.distance_mm)?;
+        w.i16(self.closing_mm_per_s)
+    }
+
+    pub fn read(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self { peer: r.u16()?, distance_mm: r.u32()?, closing_mm_per_s: r.i16()? })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Track {
+    peer: u16,
+    /// The measurement the closing speed was last worked out from.
+    distance_mm: u32,
+    at_ms: u64,
+    /// Smoothed over the last few measurements, or `None` until there have been two.
+    closing_mm_per_s: Option<i32>,
+    warned_at_ms: Option<u64>
+}
+
+/// The distance to, and closing speed of, each peer nearby.
+pub struct Proximity {
+    tracks: [Option<Track>; MAX_PEERS]
+}
+
+impl Proximity {
+    pub const fn new() -> Self {
+        Self { tracks: [None; MAX_PEERS] }
+    }
+
+    /// Adds a measurement of the distance to `peer`, taken at `now_ms`. Returns a warning if the peer is too close,
+    /// unless it already had one in the last [`REPEAT_MS`].
+    pub fn update(&mut self, limits: &Limits, peer: u16, distance_mm: u32, now_ms: u64) -> Option<Warning> {
+        let track = self.track(peer, distance_mm, now_ms);
+
+        let elapsed_ms = now_ms.saturating_sub(track.at_ms);
+        if elapsed_ms > STALE_MS {
+            track.closing_mm_per_s = None;
+            track.distance_mm = distance_mm;
+            track.at_ms = now_ms;
+        } else if elapsed_ms >= MIN_INTERVAL_MS {
+            let speed = (track.distance_mm as i64 - distance_mm as i64) * 1000 / elapsed_ms as i64;
+
+            // Any faster and this measurement or the last one is off, which carrying on from this one gets rid of
+            if speed.abs() <= MAX_SPEED_MM_PER_S {
+                // Weighs the newest change a quarter, which is enough to follow somebody breaking into a run
+                track.closing_mm_per_s = Some(match track.closing_mm_per_s {
+                    Some(closing) => closing + (speed as i32 - closing) / 4,
+                    None => speed as i32,
+                });
+            }
+            track.distance_mm = distance_mm;
+            track.at_ms = now_ms;
+        }
+
+        let closing_mm_per_s = track.closing_mm_per_s.unwrap_or(0);
+        if !is_too_close(limits, distance_mm, closing_mm_per_s) {
+            return None;
+        }
+
+        if track.warned_at_ms.is_some_and(|warned_at_ms| now_ms.saturating_sub(warned_at_ms) < REPEAT_MS) {
+            return None;
+        }
+        track.warned_at_ms = Some(now_ms);
+
+        Some(Warning {
+            peer,
+            distance_mm,
+            closing_mm_per_s: closing_mm_per_s.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        })
+    }
+
+    /// Forgets every peer, for when ranging stops.
+    pub fn clear(&mut self) {
+        self.tracks = [None; MAX_PEERS];
+    }
+
+    /// The track for `peer`, starting one at `distance_mm` if there isn't one yet.
+    fn track(&mut self, peer: u16, distance_mm: u32, now_ms: u64) -> &mut Track {
+        let index = match self.tracks.iter().position(|track| track.is_some_and(|track| track.peer == peer)) {
+            Some(index) => index,
+            None => {
+                // An empty slot, or else the peer heard from least recently
+                let index = self.tracks.iter()
+                    .enumerate()
+                    .min_by_key(|(_, track)| track.map(|track| track.at_ms))
+                    .map_or(0, |(index, _)| index);
+
+                self.tracks[index] = Some(Track { peer, distance_mm, at_ms: now_ms, closing_mm_per_s: None, warned_at_ms: None });
+                index
+            },
+        };
+
+        self.tracks[index].as_mut().expect("The track was just found or made")
+    }
+}
+
+impl Default for Proximity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether a peer `distance_mm` away and closing at `closing_mm_per_s` is inside the radius or closing too fast.
+pub fn is_too_close(limits: &Limits, distance_mm: u32, closing_mm_per_s: i32) -> bool {
+    let closing_too_fast = limits.closing_mm_per_s.is_some_and(|limit| closing_mm_per_s > 0 && closing_mm_per_s as u32 >= limit);
+
+    distance_mm <= limits.radius_mm || closing_too_fast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: u16 = 0x1d0c;
+
+    const LIMITS: Limits = Limits { radius_mm: 500, closing_mm_per_s: Some(1500) };
+
+    /// Measures `PEER` every `MIN_INTERVAL_MS` from `start_ms`, returning every warning.
+    fn approach(proximity: &mut Proximity, distances_mm: &[u32], start_ms: u64) -> [Option<Warning>; 8] {
+        let mut warnings = [None; 8];
+        for (i, distance_mm) in distances_mm.iter().enumerate() {
+            warnings[i] = proximity.update(&LIMITS, PEER, *distance_mm, start_ms + i as u64 * MIN_INTERVAL_MS);
+        }
+        warnings
+    }
+
+    #[test]
+    fn warns_of_a_peer_inside_the_radius() {
+        let mut proximity = Proximity::new();
+
+        assert_eq!(proximity.update(&LIMITS, PEER, 800, 0), None);
+        assert_eq!(proximity.update(&LIMITS, PEER, 450, 5000), Some(Warning { peer: PEER, distance_mm: 450, closing_mm_per_s: 0 }));
+    }
+
+    #[test]
+    fn warns_of_a_peer_closing_fast_from_far_away() {
+        let mut proximity = Proximity::new();
+
+        // 2 m/s from ten meters off, nowhere near the radius
+        let warnings = approach(&mut proximity, &[10_000, 9800], 0);
+        assert_eq!(warnings[0], None);
+        assert_eq!(warnings[1], Some(Warning { peer: PEER, distance_mm: 9800, closing_mm_per_s: 2000 }));
+    }
+
+    #[test]
+    fn leaves_a_peer_closing_slowly_or_moving_away_alone() {
+        let mut proximity = Proximity::new();
+        assert!(approach(&mut proximity, &[3000, 2900, 2800, 2700, 2600, 2500], 0).iter().all(Option::is_none));
+
+        let mut proximity = Proximity::new();
+        assert!(approach(&mut proximity, &[3000, 3300, 3600, 3900], 0).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn only_goes_by_the_radius_without_a_closing_speed() {
+        let limits = Limits { closing_mm_per_s: None, ..LIMITS };
+        let mut proximity = Proximity::new();
+
+        assert_eq!(proximity.update(&limits, PEER, 10_000, 0), None);
+        assert_eq!(proximity.update(&limits, PEER, 9000, 100), None);
+        assert!(proximity.update(&limits, PEER, 400, 200).is_some());
+    }
+
+    #[test]
+    fn leaves_out_a_change_faster_than_anybody_moves() {
+        let mut proximity = Proximity::new();
+
+        // Twenty meters a second is a bad measurement rather than somebody closing in, but the closing speed carries
+        // on from it
+        let warnings = approach(&mut proximity, &[5000, 3000, 2800], 0);
+        assert_eq!(warnings[..3], [None, None, Some(Warning { peer: PEER, distance_mm: 2800, closing_mm_per_s: 2000 })]);
+    }
+
+    #[test]
+    fn warns_the_same_peer_again_only_after_a_while() {
+        let mut proximity = Proximity::new();
+        assert!(proximity.update(&LIMITS, PEER, 400, 0).is_some());
+
+        for at_ms in (100..REPEAT_MS).step_by(100) {
+            assert_eq!(proximity.update(&LIMITS, PEER, 400, at_ms), None, "Warned again at {at_ms} ms");
+        }
+        assert!(proximity.update(&LIMITS, PEER, 400, REPEAT_MS).is_some());
+
+        // Another peer doesn't have to wait on this one
+        assert!(proximity.update(&LIMITS, PEER + 1, 400, REPEAT_MS).is_some());
+    }
+
+    #[test]
+    fn works_the_speed_out_afresh_after_a_gap() {
+        let mut proximity = Proximity::new();
+        approach(&mut proximity, &[6000, 5900], 0);
+
+        // Two meters in over the gap would be closing fast, if the gap didn't mean it's anybody's guess
+        assert_eq!(proximity.update(&LIMITS, PEER, 3900, 100 + STALE_MS + 1), None);
+    }
+
+    #[test]
+    fn makes_way_for_new_peers_by_forgetting_the_one_heard_from_least_recently() {
+        let mut proximity = Proximity::new();
+        assert!(proximity.update(&LIMITS, PEER, 400, 0).is_some());
+
+        // One peer short of pushing it out, so it's still too soon to warn of it again
+        for peer in 1..MAX_PEERS as u16 {
+            proximity.update(&LIMITS, PEER + peer, 3000, peer as u64);
+        }
+        assert_eq!(proximity.update(&LIMITS, PEER, 400, 100), None);
+
+        // Once every slot has somebody heard from since, it starts over as a new peer
+        for peer in 1..=MAX_PEERS as u16 {
+            proximity.update(&LIMITS, PEER + 0x100 + peer, 3000, 200 + peer as u64);
+        }
+        assert!(proximity.update(&LIMITS, PEER, 400, 300).is_some());
+    }
+
+    #[test]
+    fn forgets_every_peer_once_cleared() {
+        let mut proximity = Proximity::new();
+        assert!(proximity.update(&LIMITS, PEER, 400, 0).is_some());
+
+        proximity.clear();
+        assert!(proximity.update(&LIMITS, PEER, 400, 100).is_some());
+    }
+}
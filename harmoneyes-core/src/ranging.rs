@@ -0,0 +1,76 @@
+//! Conversions for the time of flight values measured by the ultra-wide band radio.
+
+/// The DW3000 counts time in units of 1 / (128 * 499.2 MHz), or roughly 15.65 picoseconds.
+pub const TICK_SECONDS: f64 = 1.0 / (128.0 * 499.2e6);
+
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+/// Converts a one way time of flight in DW3000 ticks to a distance in meters.
+pub fn tof_to_meters(tof: u64) -> f64 {
+    tof as f64 * TICK_SECONDS * SPEED_OF_LIGHT
+}
+
+/// Converts a one way time of flight in DW3000 ticks to a whole number of millimeters.
+pub fn tof_to_millimeters(tof: u64) -> u32 {
+    (tof_to_meters(tof) * 1000.0) as u32
+}
+
+/// Converts a distance in meters to a one way time of flight in DW3000 ticks.
+pub fn meters_to_tof(meters: f64) -> u64 {
+    (meters / (TICK_SECONDS * SPEED_OF_LIGHT)) as u64
+}
+
+/// The length of the reply delay that every ranging response carries.
+pub const REPLY_DELAY_LENGTH: usize = 8;
+
+/// Encodes how long a controller took between receiving its last frame and answering it, big-endian.
+pub fn encode_reply_delay(ticks: u64) -> [u8; REPLY_DELAY_LENGTH] {
+    ticks.to_be_bytes()
+}
+
+pub fn decode_reply_delay(bytes: [u8; REPLY_DELAY_LENGTH]) -> u64 {
+    u64::from_be_bytes(bytes)
+}
+
+/// Works out the one way time of flight from the time between sending a frame and receiving the answer to it,
+/// and the time the other controller says it took to answer. Returns `None` if the other controller didn't know
+/// its reply delay yet or the numbers don't make sense.
+pub fn time_of_flight(round_trip: u64, reply_delay: u64) -> Option<u64> {
+    if reply_delay != 0 && round_trip > reply_delay {
+        Some((round_trip - reply_delay) / 2)
+    } else {
+        None
+    }
+}
+
+/// Averages measurements in separate blocks of `N`, which smooths out the noise at the cost of only producing
+/// an average every `N` measurements.
+pub struct BlockAverage<const N: usize> {
+    buf: [u64; N],
+    next: usize
+}
+
+impl<const N: usize> BlockAverage<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], next: 0 }
+    }
+
+    /// Adds a measurement, returning the average once the block is full.
+    pub fn push(&mut self, value: u64) -> Option<u64> {
+        self.buf[self.next] = value;
+        self.next += 1;
+
+        if self.next < N {
+            return None;
+        }
+
+        self.next = 0;
+        Some(self.buf.iter().sum::<u64>() / N as u64)
+    }
+}
+
+impl<const N: usize> Default for BlockAverage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,85 @@
+//! The status registers the controller reads from the cuff over the two-wire interface.
+//!
+//! The controller writes the code of a [`Register`] and reads its [`Contents`] back in the same transaction. The
+//! contents start with the register's code and end with a CRC-16 of the rest, like a firmware transfer frame, so a
+//! read that was corrupted on the way is thrown away rather than believed.
+//!
+//! The codes don't overlap with the motor codes in [`crate::haptics`] or the frame codes in [`crate::transfer`].
+
+use crate::{battery::Report, codec::{Error, Reader, Writer}, crc::crc16, transfer::checked};
+
+/// The size of the buffer needed to hold the contents of any register.
+pub const MAX_CONTENTS_LENGTH: usize = 1 + 1 + Report::ENCODED_LENGTH + 2;
+
+/// A register the controller can read from the cuff.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// The cuff's own battery.
+    Battery
+}
+
+impl Register {
+    const BATTERY: u8 = 0x30;
+
+    pub const fn code(self) -> u8 {
+        match self {
+            Register::Battery => Self::BATTERY,
+        }
+    }
+
+    pub const fn from_code(code: u8) -> Option<Self> {
+        match code {
+            Self::BATTERY => Some(Register::Battery),
+            _ => None
+        }
+    }
+
+    /// The length of the register's encoded contents, which is how much the controller reads back.
+    pub const fn length(self) -> usize {
+        match self {
+            Register::Battery => 1 + 1 + Report::ENCODED_LENGTH + 2,
+        }
+    }
+}
+
+/// What the cuff answers with when a register is read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Contents {
+    /// The state of the cuff's battery, or `None` until it has taken its first sample.
+    Battery(Option<Report>)
+}
+
+impl Contents {
+    pub const fn register(&self) -> Register {
+        match self {
+            Contents::Battery(_) => Register::Battery,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer::new(buf);
+        w.u8(self.register().code())?;
+
+        match self {
+            Contents::Battery(report) => Report::write_optional(*report, &mut w)?,
+        }
+
+        let len = w.position();
+        let crc = crc16(&buf[..len]);
+        Writer::new(&mut buf[len..]).u16(crc)?;
+
+        Ok(len + 2)
+    }
+
+    /// Fails with [`Error::Invalid`] if the contents' CRC doesn't match.
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader::new(checked(buf)?);
+
+        let contents = match r.u8()? {
+            Register::BATTERY => Contents::Battery(Report::read_optional(&mut r)?),
+            tag => return Err(Error::UnknownTag(tag))
+        };
+
+        Ok(contents)
+    }
+}
@@ -0,0 +1,212 @@
+//! What the status LED on a controller or cuff is showing.
+//!
+//! Tasks set and clear [`State`]s as things happen, and the LED shows whichever of the states that are set is the
+//! most important, as the colour and pattern of its [`Look`]. Some states only mean something while they keep being
+//! set, such as being paired with a device that might have gone away, so they lapse on their own after a while.
+//! With nothing set the LED shows [`State::NoPeers`], since a device that hasn't heard from anyone has nothing else
+//! to say.
+
+use crate::battery::Level;
+
+/// A colour as red, green and blue.
+pub type Colour = (u8, u8, u8);
+
+/// How the colour of a [`Look`] changes over time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// The LED is off.
+    Off,
+    Solid,
+    /// On for `on_ms` then off for `off_ms`, over and over.
+    Blink { on_ms: u32, off_ms: u32 },
+    /// Fades up to the full colour and back down again over `period_ms`.
+    Breathe { period_ms: u32 }
+}
+
+/// The colour and pattern a state is shown with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Look {
+    pub colour: Colour,
+    pub pattern: Pattern
+}
+
+impl Look {
+    const fn new(colour: Colour, pattern: Pattern) -> Self {
+        Self { colour, pattern }
+    }
+
+    /// The colour to show `ms` milliseconds into the pattern.
+    pub fn colour_at(&self, ms: u64) -> Colour {
+        let (red, green, blue) = self.colour;
+
+        // How far up to the full colour the LED is, out of 255
+        let level = match self.pattern {
+            Pattern::Off => 0,
+            Pattern::Solid => 255,
+            Pattern::Blink { on_ms, off_ms } => {
+                let phase = ms % (on_ms + off_ms) as u64;
+                if phase < on_ms as u64 { 255 } else { 0 }
+            },
+            Pattern::Breathe { period_ms } => {
+                let half = (period_ms / 2).max(1) as u64;
+                let phase = ms % (2 * half);
+                let rising = if phase < half { phase } else { 2 * half - phase };
+                (rising * 255 / half) as u32
+            },
+        };
+
+        let scale = |channel: u8| (channel as u32 * level / 255) as u8;
+
+        (scale(red), scale(green), scale(blue))
+    }
+
+    /// Whether the look never changes, so the LED only needs writing when the state does.
+    pub const fn is_steady(&self) -> bool {
+        matches!(self.pattern, Pattern::Off | Pattern::Solid)
+    }
+}
+
+/// Something the status LED can show, from the most important to the least.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// A firmware update is being received.
+    Updating,
+    /// Something has gone wrong that the device is trying to recover from.
+    Fault,
+    /// The cuff has stopped hearing from its controller, so its motors are stopped.
+    LinkLost,
+    /// The device is in the sleep power mode, so the LED is off.
+    Asleep,
+    /// The battery is running low.
+    LowBattery(Level),
+    /// The device is still starting up.
+    Booting,
+    Charging,
+    Charged,
+    /// The device is in the idle power mode.
+    Idle,
+    /// The controller is ranging with at least one other controller.
+    Ranging,
+    /// There's nobody to range with, or for a cuff, no controller talking to it.
+    NoPeers,
+    /// The controller and its cuff are talking to each other.
+    Paired
+}
+
+impl State {
+    /// How many states there are, with every low battery level counting as one.
+    const COUNT: usize = 12;
+
+    /// Where the state sits in the order of importance, with the most important first. Setting a state replaces
+    /// any other in the same place, which only matters for the levels of [`State::LowBattery`].
+    const fn rank(self) -> usize {
+        match self {
+            State::Updating => 0,
+            State::Fault => 1,
+            State::LinkLost => 2,
+            State::Asleep => 3,
+            State::LowBattery(_) => 4,
+            State::Booting => 5,
+            State::Charging => 6,
+            State::Charged => 7,
+            State::Idle => 8,
+            State::Ranging => 9,
+            State::NoPeers => 10,
+            State::Paired => 11,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            State::Updating => "updating",
+            State::Fault => "fault",
+            State::LinkLost => "link lost",
+            State::Asleep => "asleep",
+            State::LowBattery(level) => level.name(),
+            State::Booting => "booting",
+            State::Charging => "charging",
+            State::Charged => "charged",
+            State::Idle => "idle",
+            State::Ranging => "ranging",
+            State::NoPeers => "no peers",
+            State::Paired => "paired",
+        }
+    }
+
+    pub const fn look(self) -> Look {
+        match self {
+            State::Updating => Look::new((0, 255, 255), Pattern::Blink { on_ms: 100, off_ms: 100 }),
+            State::Fault => Look::new((255, 0, 255), Pattern::Blink { on_ms: 250, off_ms: 250 }),
+            State::LinkLost => Look::new((255, 255, 0), Pattern::Blink { on_ms: 100, off_ms: 400 }),
+            State::Asleep => Look::new((0, 0, 0), Pattern::Off),
+            State::LowBattery(Level::Warning) => Look::new((255, 96, 0), Pattern::Solid),
+            State::LowBattery(Level::Critical) => Look::new((255, 0, 0), Pattern::Solid),
+            State::LowBattery(Level::ShutdownImminent) => Look::new((255, 0, 0), Pattern::Blink { on_ms: 500, off_ms: 500 }),
+            State::Booting => Look::new((128, 128, 128), Pattern::Solid),
+            State::Charging => Look::new((0, 255, 0), Pattern::Breathe { period_ms: 2000 }),
+            State::Charged => Look::new((0, 255, 0), Pattern::Solid),
+            State::Idle => Look::new((0, 0, 64), Pattern::Breathe { period_ms: 4000 }),
+            State::Ranging => Look::new((0, 0, 255), Pattern::Breathe { period_ms: 2000 }),
+            State::NoPeers => Look::new((64, 64, 64), Pattern::Blink { on_ms: 200, off_ms: 1800 }),
+            State::Paired => Look::new((0, 0, 255), Pattern::Solid),
+        }
+    }
+
+    /// How long the state lasts without being set again, or `None` if it lasts until it's cleared.
+    pub const fn lifetime_ms(self) -> Option<u64> {
+        match self {
+            // So that an update the console gave up on doesn't leave the LED flashing
+            State::Updating => Some(30_000),
+            State::Paired => Some(30_000),
+            _ => None
+        }
+    }
+}
+
+/// A change to the states the LED could show, sent by the task that noticed it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Set(State),
+    /// Clears the state, or for [`State::LowBattery`] whichever level is set.
+    Clear(State)
+}
+
+/// The states that are set, and when any that lapse will.
+#[derive(Clone, Copy, Debug)]
+pub struct Statuses {
+    /// Each state at its rank, with the uptime it lapses at.
+    states: [Option<(State, Option<u64>)>; State::COUNT]
+}
+
+impl Statuses {
+    pub const fn new() -> Self {
+        Self { states: [None; State::COUNT] }
+    }
+
+    pub fn apply(&mut self, command: Command, now_ms: u64) {
+        match command {
+            Command::Set(state) => {
+                let lapses = state.lifetime_ms().map(|lifetime| now_ms + lifetime);
+                self.states[state.rank()] = Some((state, lapses));
+            },
+            Command::Clear(state) => self.states[state.rank()] = None,
+        }
+    }
+
+    /// The most important state that's set, after dropping any that have lapsed.
+    pub fn top(&mut self, now_ms: u64) -> State {
+        for slot in &mut self.states {
+            if let Some((_, Some(lapses))) = slot && *lapses <= now_ms {
+                *slot = None;
+            }
+        }
+
+        self.states.iter().flatten().map(|(state, _)| *state).next().unwrap_or(State::NoPeers)
+    }
+}
+
+impl Default for Statuses {
+    fn default() -> Self {
+        Self::new()
+    }
+}
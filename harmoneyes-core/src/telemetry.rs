@@ -0,0 +1,193 @@
+//! Telemetry that a device streams to the console over its USB serial port.
+//!
+//! Each event is encoded as the device uptime followed by a tag byte and the fields of the
+//! event, all in little-endian order.
+
+use crate::{battery::{Alert, ChargeState, Report}, codec::{Error, Reader, Writer}, haptics::{Motor, Wrist}, link::{Presence, QualityReport}, proximity::Warning, uwb::Faults};
+
+/// The size of the application data carried by a single mesh advertisement.
+pub const MESH_PAYLOAD_LENGTH: usize = 242;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Milliseconds since the device booted.
+    pub uptime_ms: u64,
+    pub telemetry: Telemetry
+}
+
+// There's no allocator on the devices to box the mesh payload with, and events are short lived anyways.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Telemetry {
+    /// A time of flight measurement to a peer in DW3000 ticks.
+    Distance { peer: u16, tof: u64 },
+    /// A battery sample, and whether the battery was on charge.
+    Battery { millivolts: u16, percent: u8, charge: ChargeState },
+    /// A message that was sent or received over the bluetooth mesh.
+    Mesh { sent: bool, length: u8, payload: [u8; MESH_PAYLOAD_LENGTH] },
+    /// A command that was given to one of the motors in the cuff on `wrist`.
+    Haptic { wrist: Wrist, motor: Motor, duration_ms: u64 },
+    /// A low battery alert raised by the controller with the given short address, either this one or one heard over
+    /// the mesh.
+    BatteryAlert { source: u16, alert: Alert },
+    /// The battery in a cuff of the controller with the given short address, either this one or one heard over the
+    /// mesh.
+    CuffBattery { source: u16, wrist: Wrist, report: Report },
+    /// The ultra-wide band radio's fault counters, sent whenever they change.
+    UwbFaults { faults: Faults },
+    /// Whether the controller with the given short address can reach a cuff, either this one when it changes or one
+    /// heard over the mesh.
+    CuffPresence { source: u16, wrist: Wrist, presence: Presence },
+    /// How the keep alives to the cuff on `wrist` have been getting on, sent every [`crate::link::QUALITY_EVERY`] of
+    /// them.
+    CuffLink { wrist: Wrist, quality: QualityReport },
+    /// Another performer was too close or closing in too fast, and the cuffs were told to warn of a collision.
+    Collision { warning: Warning },
+    /// Another controller is ranging with the same short address as this one, sent for every frame heard from it.
+    AddressClash { address: u16 }
+}
+
+impl Telemetry {
+    const DISTANCE: u8 = 0x01;
+    const BATTERY: u8 = 0x02;
+    const MESH: u8 = 0x03;
+    const HAPTIC: u8 = 0x04;
+    const BATTERY_ALERT: u8 = 0x05;
+    const CUFF_BATTERY: u8 = 0x06;
+    const UWB_FAULTS: u8 = 0x07;
+    const CUFF_PRESENCE: u8 = 0x08;
+    const CUFF_LINK: u8 = 0x09;
+    const COLLISION: u8 = 0x0A;
+    const ADDRESS_CLASH: u8 = 0x0B;
+
+    pub fn mesh(sent: bool, data: &[u8]) -> Self {
+        let length = data.len().min(MESH_PAYLOAD_LENGTH);
+        let mut payload = [0; MESH_PAYLOAD_LENGTH];
+        payload[..length].copy_from_slice(&data[..length]);
+
+        Telemetry::Mesh { sent, length: length as u8, payload }
+    }
+}
+
+impl Event {
+    pub const MAX_ENCODED_LENGTH: usize = 8 + 1 + 2 + MESH_PAYLOAD_LENGTH;
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer::new(buf);
+
+        w.u64(self.uptime_ms)?;
+
+        match &self.telemetry {
+            Telemetry::Distance { peer, tof } => {
+                w.u8(Telemetry::DISTANCE)?;
+                w.u16(*peer)?;
+                w.u64(*tof)?;
+            },
+            Telemetry::Battery { millivolts, percent, charge } => {
+                w.u8(Telemetry::BATTERY)?;
+                w.u16(*millivolts)?;
+                w.u8(*percent)?;
+                w.u8(charge.code())?;
+            },
+            Telemetry::Mesh { sent, length, payload } => {
+                w.u8(Telemetry::MESH)?;
+                w.u8(*sent as u8)?;
+                w.u8(*length)?;
+                w.bytes(&payload[..*length as usize])?;
+            },
+            Telemetry::Haptic { wrist, motor, duration_ms } => {
+                w.u8(Telemetry::HAPTIC)?;
+                w.u8(motor.code())?;
+                w.u64(*duration_ms)?;
+                // At the end, since events from before there could be two cuffs don't have it
+                w.u8(wrist.code())?;
+            },
+            Telemetry::BatteryAlert { source, alert } => {
+                w.u8(Telemetry::BATTERY_ALERT)?;
+                w.u16(*source)?;
+                alert.write(&mut w)?;
+            },
+            Telemetry::CuffBattery { source, wrist, report } => {
+                w.u8(Telemetry::CUFF_BATTERY)?;
+                w.u16(*source)?;
+                report.write(&mut w)?;
+                // At the end, since events from before there could be two cuffs don't have it
+                w.u8(wrist.code())?;
+            },
+            Telemetry::UwbFaults { faults } => {
+                w.u8(Telemetry::UWB_FAULTS)?;
+                faults.write(&mut w)?;
+            },
+            Telemetry::CuffPresence { source, wrist, presence } => {
+                w.u8(Telemetry::CUFF_PRESENCE)?;
+                w.u16(*source)?;
+                w.u8(wrist.code())?;
+                w.u8(presence.code())?;
+            },
+            Telemetry::CuffLink { wrist, quality } => {
+                w.u8(Telemetry::CUFF_LINK)?;
+                w.u8(wrist.code())?;
+                quality.write(&mut w)?;
+            },
+            Telemetry::Collision { warning } => {
+                w.u8(Telemetry::COLLISION)?;
+                warning.write(&mut w)?;
+            },
+            Telemetry::AddressClash { address } => {
+                w.u8(Telemetry::ADDRESS_CLASH)?;
+                w.u16(*address)?;
+            },
+        }
+
+        Ok(w.position())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader::new(buf);
+
+        let uptime_ms = r.u64()?;
+
+        let telemetry = match r.u8()? {
+            Telemetry::DISTANCE => Telemetry::Distance { peer: r.u16()?, tof: r.u64()? },
+            Telemetry::BATTERY => Telemetry::Battery {
+                millivolts: r.u16()?,
+                percent: r.u8()?,
+                charge: ChargeState::from_code(r.u8()?)?
+            },
+            Telemetry::MESH => {
+                let sent = r.u8()? != 0;
+                let length = r.u8()? as usize;
+                if length > MESH_PAYLOAD_LENGTH {
+                    return Err(Error::Invalid);
+                }
+                Telemetry::mesh(sent, r.bytes(length)?)
+            },
+            Telemetry::HAPTIC => {
+                let motor = Motor::from_code(r.u8()?).ok_or(Error::Invalid)?;
+                let duration_ms = r.u64()?;
+                Telemetry::Haptic { wrist: Wrist::read_or_left(&mut r)?, motor, duration_ms }
+            },
+            Telemetry::BATTERY_ALERT => Telemetry::BatteryAlert { source: r.u16()?, alert: Alert::read(&mut r)? },
+            Telemetry::CUFF_BATTERY => {
+                let source = r.u16()?;
+                let report = Report::read(&mut r)?;
+                Telemetry::CuffBattery { source, wrist: Wrist::read_or_left(&mut r)?, report }
+            },
+            Telemetry::UWB_FAULTS => Telemetry::UwbFaults { faults: Faults::read(&mut r)? },
+            Telemetry::CUFF_PRESENCE => Telemetry::CuffPresence {
+                source: r.u16()?,
+                wrist: Wrist::from_code(r.u8()?).ok_or(Error::Invalid)?,
+                presence: Presence::from_code(r.u8()?).ok_or(Error::Invalid)?
+            },
+            Telemetry::CUFF_LINK => Telemetry::CuffLink {
+                wrist: Wrist::from_code(r.u8()?).ok_or(Error::Invalid)?,
+                quality: QualityReport::read(&mut r)?
+            },
+            Telemetry::COLLISION => Telemetry::Collision { warning: Warning::read(&mut r)? },
+            Telemetry::ADDRESS_CLASH => Telemetry::AddressClash { address: r.u16()? },
+            tag => return Err(Error::UnknownTag(tag))
+        };
+
+        Ok(Self { uptime_ms, telemetry })
+    }
+}
@@ -0,0 +1,302 @@
+//! Firmware images passed on from the controller to the cuff over the two-wire interface.
+//!
+//! The controller starts a transfer with the size of the image, sends the image from the start as a run of
+//! [`Message::Data`] frames, then finishes with a CRC-32 of the whole image, which the cuff checks against what it
+//! wrote to flash before asking its bootloader to swap the image in. Every frame ends with a CRC-16 of the rest of
+//! it, since the bus runs down a cable alongside the motors.
+//!
+//! After each frame the controller reads back the cuff's [`Status`], which says how much of the image has arrived.
+//! A frame that was corrupted or lost is simply sent again from wherever the cuff got up to.
+//!
+//! The command codes don't overlap with the motor codes in [`crate::haptics`], so the cuff can tell a frame from a
+//! motor command by its first byte.
+
+use crate::{codec::{Error, Reader, Writer}, crc::crc16};
+
+/// The most image data a single frame carries.
+pub const DATA_LENGTH: usize = 32;
+
+/// The size of the buffer needed to hold any encoded frame.
+pub const MAX_FRAME_LENGTH: usize = 1 + 4 + 1 + DATA_LENGTH + 2;
+
+/// The length of an encoded status.
+pub const STATUS_LENGTH: usize = 1 + 4 + 2;
+
+/// A frame sent from the controller to the cuff.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    /// Starts a transfer of an image of the given size, throwing away any earlier one that didn't finish.
+    Begin { size: u32 },
+    /// The next piece of the image, at most `DATA_LENGTH` bytes.
+    Data { offset: u32, data: &'a [u8] },
+    /// Ends the transfer with the CRC-32 of the whole image.
+    Finish { crc: u32 }
+}
+
+impl<'a> Message<'a> {
+    const BEGIN: u8 = 0x20;
+    const DATA: u8 = 0x21;
+    const FINISH: u8 = 0x22;
+
+    /// Whether a frame starting with this byte is part of a transfer rather than a motor command.
+    pub const fn is_transfer(code: u8) -> bool {
+        matches!(code, Self::BEGIN | Self::DATA | Self::FINISH)
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer::new(buf);
+
+        match self {
+            Message::Begin { size } => {
+                w.u8(Self::BEGIN)?;
+                w.u32(*size)?;
+            },
+            Message::Data { offset, data } => {
+                if data.len() > DATA_LENGTH {
+                    return Err(Error::Invalid);
+                }
+                w.u8(Self::DATA)?;
+                w.u32(*offset)?;
+                w.u8(data.len() as u8)?;
+                w.bytes(data)?;
+            },
+            Message::Finish { crc } => {
+                w.u8(Self::FINISH)?;
+                w.u32(*crc)?;
+            },
+        }
+
+        let len = w.position();
+        let crc = crc16(&buf[..len]);
+        Writer::new(&mut buf[len..]).u16(crc)?;
+
+        Ok(len + 2)
+    }
+
+    /// Fails with [`Error::Invalid`] if the frame's CRC doesn't match.
+    pub fn decode(buf: &'a [u8]) -> Result<Self, Error> {
+        let body = checked(buf)?;
+        let mut r = Reader::new(body);
+
+        let message = match r.u8()? {
+            Self::BEGIN => Message::Begin { size: r.u32()? },
+            Self::DATA => {
+                let offset = r.u32()?;
+                let len = r.u8()? as usize;
+                if len > DATA_LENGTH {
+                    return Err(Error::Invalid);
+                }
+                Message::Data { offset, data: r.bytes(len)? }
+            },
+            Self::FINISH => Message::Finish { crc: r.u32()? },
+            tag => return Err(Error::UnknownTag(tag))
+        };
+
+        Ok(message)
+    }
+}
+
+/// Where the cuff has got to with a transfer, which the controller reads back after every frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// No transfer has been started since the cuff last restarted.
+    Idle,
+    /// A transfer is underway and the first `received` bytes of the image have been written.
+    Receiving { received: u32 },
+    /// The image checked out and the cuff is about to restart into it.
+    Complete,
+    /// The transfer was abandoned and has to be started again.
+    Failed(Fault)
+}
+
+/// Why the cuff abandoned a transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The image doesn't fit in the cuff's update partition.
+    TooLarge,
+    /// The running image is still on trial, so the bootloader won't accept another one yet.
+    Unconfirmed,
+    /// The image couldn't be written to flash.
+    Flash,
+    /// The image in flash doesn't match the CRC it was finished with.
+    Checksum
+}
+
+impl Fault {
+    pub const fn description(self) -> &'static str {
+        match self {
+            Fault::TooLarge => "the image is too large",
+            Fault::Unconfirmed => "the running firmware has not confirmed itself yet",
+            Fault::Flash => "the image could not be written to flash",
+            Fault::Checksum => "the image was corrupted on the way",
+        }
+    }
+
+    const fn code(self) -> u32 {
+        match self {
+            Fault::TooLarge => 0x01,
+            Fault::Unconfirmed => 0x02,
+            Fault::Flash => 0x03,
+            Fault::Checksum => 0x04,
+        }
+    }
+
+    const fn from_code(code: u32) -> Result<Self, Error> {
+        match code {
+            0x01 => Ok(Fault::TooLarge),
+            0x02 => Ok(Fault::Unconfirmed),
+            0x03 => Ok(Fault::Flash),
+            0x04 => Ok(Fault::Checksum),
+            _ => Err(Error::Invalid)
+        }
+    }
+}
+
+impl Status {
+    const IDLE: u8 = 0x01;
+    const RECEIVING: u8 = 0x02;
+    const COMPLETE: u8 = 0x03;
+    const FAILED: u8 = 0x04;
+
+    pub fn encode(&self) -> [u8; STATUS_LENGTH] {
+        let (tag, value) = match self {
+            Status::Idle => (Self::IDLE, 0),
+            Status::Receiving { received } => (Self::RECEIVING, *received),
+            Status::Complete => (Self::COMPLETE, 0),
+            Status::Failed(fault) => (Self::FAILED, fault.code()),
+        };
+
+        let mut buf = [0; STATUS_LENGTH];
+        buf[0] = tag;
+        buf[1..5].copy_from_slice(&value.to_le_bytes());
+        let crc = crc16(&buf[..5]);
+        buf[5..].copy_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    /// Fails with [`Error::Invalid`] if the status's CRC doesn't match.
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader::new(checked(buf)?);
+
+        let tag = r.u8()?;
+        let value = r.u32()?;
+
+        Ok(match tag {
+            Self::IDLE => Status::Idle,
+            Self::RECEIVING => Status::Receiving { received: value },
+            Self::COMPLETE => Status::Complete,
+            Self::FAILED => Status::Failed(Fault::from_code(value)?),
+            tag => return Err(Error::UnknownTag(tag))
+        })
+    }
+}
+
+/// Checks the CRC at the end of a frame, returning the rest of it.
+pub(crate) fn checked(buf: &[u8]) -> Result<&[u8], Error> {
+    let Some(split) = buf.len().checked_sub(2) else {
+        return Err(Error::BufferTooShort);
+    };
+
+    let (body, crc) = buf.split_at(split);
+    if crc16(body).to_le_bytes() != crc {
+        return Err(Error::Invalid);
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        let mut buf = [0; MAX_FRAME_LENGTH];
+        let len = message.encode(&mut buf).unwrap();
+
+        assert_eq!(Message::decode(&buf[..len]), Ok(message));
+    }
+
+    #[test]
+    fn reads_back_every_message() {
+        round_trip(Message::Begin { size: 0x0001_2345 });
+        round_trip(Message::Data { offset: 64, data: &[0xA5; DATA_LENGTH] });
+        round_trip(Message::Data { offset: 96, data: &[] });
+        round_trip(Message::Finish { crc: 0xCBF4_3926 });
+    }
+
+    #[test]
+    fn tells_transfer_frames_from_motor_commands() {
+        let mut buf = [0; MAX_FRAME_LENGTH];
+        for message in [Message::Begin { size: 1 }, Message::Data { offset: 0, data: &[1] }, Message::Finish { crc: 0 }] {
+            message.encode(&mut buf).unwrap();
+            assert!(Message::is_transfer(buf[0]));
+        }
+
+        assert!(!Message::is_transfer(0x01));
+    }
+
+    #[test]
+    fn rejects_a_frame_with_a_flipped_byte() {
+        let mut buf = [0; MAX_FRAME_LENGTH];
+        let len = Message::Data { offset: 32, data: &[1, 2, 3, 4] }.encode(&mut buf).unwrap();
+
+        // Either in the frame or in its CRC
+        for at in [3, len - 1] {
+            let mut corrupted = buf;
+            corrupted[at] ^= 0x01;
+            assert_eq!(Message::decode(&corrupted[..len]), Err(Error::Invalid), "Took a frame flipped at {at}");
+        }
+    }
+
+    #[test]
+    fn rejects_a_frame_too_short_to_hold_a_crc() {
+        assert_eq!(Message::decode(&[]), Err(Error::BufferTooShort));
+        assert_eq!(Message::decode(&[0x20]), Err(Error::BufferTooShort));
+        assert_eq!(Status::decode(&[0x01]), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn rejects_a_frame_that_ends_before_its_fields_do() {
+        // A begin frame with only half its size, but a good CRC over what's there
+        let mut buf = [0x20, 0x01, 0x02, 0, 0];
+        let crc = crc16(&buf[..3]);
+        buf[3..].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(Message::decode(&buf), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn refuses_to_send_more_data_than_a_frame_carries() {
+        let mut buf = [0; MAX_FRAME_LENGTH + 1];
+        assert_eq!(Message::Data { offset: 0, data: &[0; DATA_LENGTH + 1] }.encode(&mut buf), Err(Error::Invalid));
+
+        let mut short = [0; 4];
+        assert_eq!(Message::Begin { size: 1 }.encode(&mut short), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn reads_back_every_status() {
+        let statuses = [
+            Status::Idle,
+            Status::Receiving { received: 4096 },
+            Status::Complete,
+            Status::Failed(Fault::TooLarge),
+            Status::Failed(Fault::Unconfirmed),
+            Status::Failed(Fault::Flash),
+            Status::Failed(Fault::Checksum)
+        ];
+
+        for status in statuses {
+            assert_eq!(Status::decode(&status.encode()), Ok(status));
+        }
+    }
+
+    #[test]
+    fn rejects_a_status_with_a_flipped_byte() {
+        let mut status = Status::Receiving { received: 4096 }.encode();
+        status[2] ^= 0x10;
+
+        assert_eq!(Status::decode(&status), Err(Error::Invalid));
+    }
+}
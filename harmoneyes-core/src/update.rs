@@ -0,0 +1,55 @@
+//! Firmware updates delivered over the console protocol.
+//!
+//! The console starts an update with the size of the image, writes it in chunks from the start, then finishes with a
+//! signature. The signature is an Ed25519 signature over the SHA-512 digest of the image, which the device checks
+//! against its public key before asking the bootloader to swap the image in. The new image runs on trial until it
+//! confirms itself, and the bootloader swaps the old one back if the device resets before then.
+//!
+//! A cuff's update is sent to the controller it's cabled to. The controller checks the signature itself as the image
+//! passes through and hands it on to the cuff over the two-wire interface, see [`crate::transfer`].
+
+use crate::codec::{Error, Reader, Writer};
+
+/// The most image data a single request carries.
+pub const CHUNK_LENGTH: usize = 128;
+
+/// The length of an Ed25519 signature.
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// The length of an Ed25519 public key.
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+
+pub type Signature = [u8; SIGNATURE_LENGTH];
+
+/// A piece of a firmware image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    bytes: [u8; CHUNK_LENGTH],
+    len: u8
+}
+
+impl Chunk {
+    /// Returns `None` if the data is longer than `CHUNK_LENGTH`.
+    pub fn new(data: &[u8]) -> Option<Self> {
+        if data.len() > CHUNK_LENGTH {
+            return None;
+        }
+        let mut bytes = [0; CHUNK_LENGTH];
+        bytes[..data.len()].copy_from_slice(data);
+        Some(Self { bytes, len: data.len() as u8 })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub(crate) fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u8(self.len)?;
+        w.bytes(self.as_slice())
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, Error> {
+        let len = r.u8()? as usize;
+        Self::new(r.bytes(len)?).ok_or(Error::Invalid)
+    }
+}
@@ -0,0 +1,496 @@
+//! The ranging state machine that controllers run on their ultra-wide band radios.
+//!
+//! Controllers range with whoever they hear by answering every frame they receive, each answer carrying how long
+//! the controller took to send it. Timing a frame's round trip and subtracting the other side's reply delay leaves
+//! twice the time of flight. When a controller doesn't hear anything for a while it sends a frame without a reply
+//! delay to get things going again.
+//!
+//! Every frame's payload is the sender's short address in little endian followed by the reply delay in big endian,
+//! which is all zeroes if the frame isn't an answer.
+
+use core::time::Duration;
+
+use crate::{codec::{Error, Reader, Writer}, ranging::{self, REPLY_DELAY_LENGTH}};
+
+/// The length of the sender's short address at the start of every frame.
+pub const ADDRESS_LENGTH: usize = 2;
+
+/// The length of a ranging frame's payload.
+pub const RANGING_PAYLOAD_LENGTH: usize = ADDRESS_LENGTH + REPLY_DELAY_LENGTH;
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+/// How long to listen for a frame before sending one ourselves. From testing with a basic ping pong a 10 ms timeout
+/// with no artificial turn-around delay achieved about a 0.15% timeout to response rate.
+pub const LISTEN_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How long to wait after receiving a frame before answering it, giving the sender time to start listening.
+pub const TURNAROUND: Duration = Duration::from_millis(3);
+
+/// How many times to try sending a frame before giving up on the radio.
+pub const TRANSMIT_ATTEMPTS: usize = 10;
+
+/// The largest frame payload the state machine expects to receive.
+pub const MAX_PAYLOAD_LENGTH: usize = 128;
+
+/// How many errors in a row are met by just trying again, since a one off glitch on the SPI bus doesn't need any more.
+pub const RETRIES: u32 = 2;
+
+/// How many errors in a row after reconfiguring the radio didn't help are met by resetting it straight away.
+pub const RESETS: u32 = 2;
+
+/// How long to wait before the first reset that's backed off from, doubled for every error in a row after that.
+pub const FIRST_BACK_OFF: Duration = Duration::from_secs(1);
+
+/// The longest wait between resets, so a radio that comes back is picked up again within a minute.
+pub const MAX_BACK_OFF: Duration = Duration::from_secs(60);
+
+/// A frame that the radio received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Received {
+    /// The length of the payload that was copied into the buffer.
+    pub len: usize,
+    /// When the frame arrived in DW3000 ticks.
+    pub timestamp: u64
+}
+
+/// An ultra-wide band radio that can timestamp the frames it sends and receives.
+// Everything runs on a single executor, so the futures don't need to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait UwbRadio {
+    type Error;
+
+    /// Sends a frame once `delay` has passed, returning when it left the antenna in DW3000 ticks.
+    async fn transmit(&mut self, payload: &[u8], delay: Duration) -> Result<u64, Self::Error>;
+
+    /// Listens for a frame for up to `timeout`, copying its payload into `buf`. Returns `None` if nothing arrived.
+    async fn receive(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<Received>, Self::Error>;
+
+    /// Brings the radio back into a working state after an error.
+    async fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// Sets the radio up again without resetting it, which is quicker but doesn't help if the radio itself is stuck.
+    /// Radios that can't do that just reset.
+    async fn reconfigure(&mut self) -> Result<(), Self::Error> {
+        self.reset().await
+    }
+}
+
+/// What happened during a step of the state machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Nothing was heard, so the next step will start an exchange.
+    TimedOut,
+    /// A frame without a reply delay was sent to get things started.
+    Initiated,
+    /// Something was received that wasn't a ranging frame, so it was left unanswered.
+    Ignored,
+    /// A ranging frame came from another controller with our own short address, so it was left unanswered. Everybody
+    /// else sees the two as one peer until one of them is swapped out.
+    Clashed,
+    /// A frame was received and answered. The time of flight to its sender is known if the frame was an answer to
+    /// our own last frame.
+    Exchanged { peer: u16, tof: Option<u64> }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Driver,
+    Listener
+}
+
+/// Switches between listening and driving the exchange, and keeps track of the timestamps needed to work out the
+/// time of flight.
+#[derive(Debug)]
+pub struct Ranging {
+    /// Our own short address, sent in every frame.
+    address: u16,
+    mode: Mode,
+    last_tx: Option<u64>,
+    last_rx: Option<u64>
+}
+
+impl Ranging {
+    pub const fn new(address: u16) -> Self {
+        Self { address, mode: Mode::Listener, last_tx: None, last_rx: None }
+    }
+
+    /// Listens for a frame and answers it, or starts an exchange if the listening timed out last time.
+    pub async fn step<R: UwbRadio>(&mut self, radio: &mut R) -> Result<Outcome, R::Error> {
+        if self.mode == Mode::Driver {
+            // Send one message to get things started
+            self.mode = Mode::Listener;
+            transmit(radio, &self.payload([0; REPLY_DELAY_LENGTH]), Duration::ZERO).await?;
+            return Ok(Outcome::Initiated);
+        }
+
+        let mut buf = [0u8; MAX_PAYLOAD_LENGTH];
+
+        let Some(received) = radio.receive(&mut buf, LISTEN_TIMEOUT).await? else {
+            // If the timeout was triggered swap to driver mode.
+            self.mode = Mode::Driver;
+            return Ok(Outcome::TimedOut);
+        };
+
+        // Frames from anything other than another controller are ignored
+        if received.len != RANGING_PAYLOAD_LENGTH {
+            return Ok(Outcome::Ignored);
+        }
+
+        let peer = u16::from_le_bytes([buf[0], buf[1]]);
+        if peer == self.address {
+            return Ok(Outcome::Clashed);
+        }
+
+        let mut tof = None;
+
+        if let Some(last_tx) = self.last_tx
+            && received.timestamp > last_tx
+        {
+            let mut reply_delay = [0u8; REPLY_DELAY_LENGTH];
+            reply_delay.copy_from_slice(&buf[ADDRESS_LENGTH..RANGING_PAYLOAD_LENGTH]);
+
+            let reply_delay = ranging::decode_reply_delay(reply_delay);
+
+            // A frame with no reply delay wasn't an answer to anything
+            if reply_delay != 0 {
+                tof = ranging::time_of_flight(received.timestamp - last_tx, reply_delay);
+            }
+        }
+
+        let mut reply_delay = [0u8; REPLY_DELAY_LENGTH];
+
+        if let (Some(last_rx), Some(last_tx)) = (self.last_rx, self.last_tx)
+            && last_tx > last_rx
+        {
+            reply_delay = ranging::encode_reply_delay(last_tx - last_rx);
+        }
+
+        // We got a packet! Time to respond.
+        let tx = transmit(radio, &self.payload(reply_delay), TURNAROUND).await?;
+
+        self.last_tx = Some(tx);
+        self.last_rx = Some(received.timestamp);
+
+        Ok(Outcome::Exchanged { peer, tof })
+    }
+
+    /// Applies `remedy` to the radio, forgetting the timestamps if it's set up again since they mean nothing once
+    /// the radio's clock has been reset. Any wait for a [`Remedy::BackOff`] is up to the caller.
+    pub async fn recover<R: UwbRadio>(&mut self, radio: &mut R, remedy: Remedy) -> Result<(), R::Error> {
+        match remedy {
+            Remedy::Retry => Ok(()),
+            Remedy::Reconfigure => {
+                *self = Self::new(self.address);
+                radio.reconfigure().await
+            },
+            Remedy::Reset | Remedy::BackOff(_) => {
+                *self = Self::new(self.address);
+                radio.reset().await
+            },
+        }
+    }
+
+    fn payload(&self, reply_delay: [u8; REPLY_DELAY_LENGTH]) -> [u8; RANGING_PAYLOAD_LENGTH] {
+        let mut payload = [0u8; RANGING_PAYLOAD_LENGTH];
+        payload[..ADDRESS_LENGTH].copy_from_slice(&self.address.to_le_bytes());
+        payload[ADDRESS_LENGTH..].copy_from_slice(&reply_delay);
+        payload
+    }
+}
+
+/// What to do about an error from the radio, from the least disruptive to the most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Remedy {
+    /// Carry on, and let the next step try again.
+    Retry,
+    /// Set the radio up again.
+    Reconfigure,
+    /// Reset the radio.
+    Reset,
+    /// Wait this long, then reset the radio.
+    BackOff(Duration)
+}
+
+impl Remedy {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Remedy::Retry => "retry",
+            Remedy::Reconfigure => "reconfigure",
+            Remedy::Reset => "reset",
+            Remedy::BackOff(_) => "back off",
+        }
+    }
+}
+
+/// How many times the radio has gone wrong since the controller started, and what it took to bring it back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+    pub errors: u32,
+    pub retries: u32,
+    pub reconfigures: u32,
+    pub resets: u32,
+    /// Resets that were waited for, because the ones before didn't help.
+    pub back_offs: u32
+}
+
+impl Faults {
+    pub const ENCODED_LENGTH: usize = 20;
+
+    pub(crate) fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u32(self.errors)?;
+        w.u32(self.retries)?;
+        w.u32(self.reconfigures)?;
+        w.u32(self.resets)?;
+        w.u32(self.back_offs)
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Self { errors: r.u32()?, retries: r.u32()?, reconfigures: r.u32()?, resets: r.u32()?, back_offs: r.u32()? })
+    }
+}
+
+/// Picks a [`Remedy`] for each error, going further the more errors there have been in a row, and counts them up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Recovery {
+    in_a_row: u32,
+    faults: Faults
+}
+
+impl Recovery {
+    pub const fn new() -> Self {
+        Self {
+            in_a_row: 0,
+            faults: Faults { errors: 0, retries: 0, reconfigures: 0, resets: 0, back_offs: 0 }
+        }
+    }
+
+    /// Picks the remedy for an error. Trying again is no use if the error left the radio needing to be set up again,
+    /// which `ready` says it didn't.
+    pub fn failed(&mut self, ready: bool) -> Remedy {
+        self.faults.errors = self.faults.errors.saturating_add(1);
+        self.in_a_row = self.in_a_row.saturating_add(1);
+
+        if !ready && self.in_a_row <= RETRIES {
+            self.in_a_row = RETRIES + 1;
+        }
+
+        let remedy = match self.in_a_row {
+            n if n <= RETRIES => Remedy::Retry,
+            n if n == RETRIES + 1 => Remedy::Reconfigure,
+            n if n <= RETRIES + 1 + RESETS => Remedy::Reset,
+            n => {
+                let doublings = (n - RETRIES - RESETS - 2).min(16);
+                Remedy::BackOff(FIRST_BACK_OFF.saturating_mul(1 << doublings).min(MAX_BACK_OFF))
+            },
+        };
+
+        let count = match remedy {
+            Remedy::Retry => &mut self.faults.retries,
+            Remedy::Reconfigure => &mut self.faults.reconfigures,
+            Remedy::Reset => &mut self.faults.resets,
+            Remedy::BackOff(_) => &mut self.faults.back_offs,
+        };
+        *count = count.saturating_add(1);
+
+        remedy
+    }
+
+    /// The radio is working again, so the next error starts from trying again.
+    pub fn succeeded(&mut self) {
+        self.in_a_row = 0;
+    }
+
+    pub fn faults(&self) -> Faults {
+        self.faults
+    }
+}
+
+/// Transmission errors are pretty rare, so retrying a few times is usually enough. Any errors that keep happening
+/// are probably a much more serious issue.
+async fn transmit<R: UwbRadio>(radio: &mut R, payload: &[u8], delay: Duration) -> Result<u64, R::Error> {
+    let mut attempts = 1;
+
+    loop {
+        match radio.transmit(payload, delay).await {
+            Ok(timestamp) => return Ok(timestamp),
+            Err(e) if attempts >= TRANSMIT_ATTEMPTS => return Err(e),
+            Err(_) => attempts += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{pin::pin, task::{Context, Poll, Waker}};
+
+    use super::*;
+    use super::mock::{MockError, ScriptedFrame, ScriptedRadio, Step};
+
+    const ADDRESS: u16 = 0x1234;
+    const PEER: u16 = 0xBEEF;
+
+    /// The scripted radio answers straight away, so its futures are ready the first time they're polled.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("The scripted radio never waits"),
+        }
+    }
+
+    fn frame(peer: u16, reply_delay: u64) -> [u8; RANGING_PAYLOAD_LENGTH] {
+        let mut payload = [0; RANGING_PAYLOAD_LENGTH];
+        payload[..ADDRESS_LENGTH].copy_from_slice(&peer.to_le_bytes());
+        payload[ADDRESS_LENGTH..].copy_from_slice(&ranging::encode_reply_delay(reply_delay));
+        payload
+    }
+
+    fn reply_delay(payload: &[u8]) -> u64 {
+        ranging::decode_reply_delay(payload[ADDRESS_LENGTH..].try_into().unwrap())
+    }
+
+    #[test]
+    fn answers_and_measures_the_time_of_flight() {
+        let start = frame(PEER, 0);
+        let answer = frame(PEER, 1000);
+        let script = [
+            Step::Receive(Some(ScriptedFrame { payload: &start, timestamp: 10_000 })),
+            Step::Transmit { timestamp: 12_000 },
+            // Sent back 1000 ticks after ours arrived, and the frames spent 500 ticks in the air each way
+            Step::Receive(Some(ScriptedFrame { payload: &answer, timestamp: 14_000 })),
+            Step::Transmit { timestamp: 16_000 }
+        ];
+        let mut radio = ScriptedRadio::new(&script);
+        let mut ranging = Ranging::new(ADDRESS);
+
+        assert_eq!(block_on(ranging.step(&mut radio)), Ok(Outcome::Exchanged { peer: PEER, tof: None }));
+        assert_eq!(radio.last_delay, TURNAROUND);
+        assert_eq!(reply_delay(radio.last_sent()), 0);
+
+        assert_eq!(block_on(ranging.step(&mut radio)), Ok(Outcome::Exchanged { peer: PEER, tof: Some(500) }));
+        assert_eq!(&radio.last_sent()[..ADDRESS_LENGTH], &ADDRESS.to_le_bytes());
+        assert_eq!(reply_delay(radio.last_sent()), 2000);
+        assert!(radio.finished());
+    }
+
+    #[test]
+    fn starts_an_exchange_after_hearing_nothing() {
+        let script = [Step::Receive(None), Step::Transmit { timestamp: 1 }, Step::Receive(Some(ScriptedFrame { payload: &[1, 2, 3], timestamp: 2 }))];
+        let mut radio = ScriptedRadio::new(&script);
+        let mut ranging = Ranging::new(ADDRESS);
+
+        assert_eq!(block_on(ranging.step(&mut radio)), Ok(Outcome::TimedOut));
+        assert_eq!(block_on(ranging.step(&mut radio)), Ok(Outcome::Initiated));
+        assert_eq!(radio.last_delay, Duration::ZERO);
+        assert_eq!(reply_delay(radio.last_sent()), 0);
+        assert_eq!(block_on(ranging.step(&mut radio)), Ok(Outcome::Ignored));
+        assert!(radio.finished());
+    }
+
+    #[test]
+    fn leaves_a_frame_with_its_own_address_unanswered() {
+        let clash = frame(ADDRESS, 0);
+        let script = [Step::Receive(Some(ScriptedFrame { payload: &clash, timestamp: 1 }))];
+        let mut radio = ScriptedRadio::new(&script);
+
+        assert_eq!(block_on(Ranging::new(ADDRESS).step(&mut radio)), Ok(Outcome::Clashed));
+        assert!(radio.finished());
+    }
+
+    #[test]
+    fn retries_transmissions_before_giving_up() {
+        let start = frame(PEER, 0);
+        let mut script = [Step::Fail; 1 + TRANSMIT_ATTEMPTS];
+        script[0] = Step::Receive(Some(ScriptedFrame { payload: &start, timestamp: 1 }));
+        let mut radio = ScriptedRadio::new(&script);
+
+        assert_eq!(block_on(Ranging::new(ADDRESS).step(&mut radio)), Err(MockError::Scripted));
+        assert!(radio.finished());
+    }
+
+    #[test]
+    fn escalates_the_remedy_for_errors_in_a_row() {
+        let mut recovery = Recovery::new();
+        let remedies: [Remedy; 12] = core::array::from_fn(|_| recovery.failed(true));
+
+        assert_eq!(remedies[..5], [Remedy::Retry, Remedy::Retry, Remedy::Reconfigure, Remedy::Reset, Remedy::Reset]);
+
+        let back_offs = remedies[5..].iter().map(|remedy| match remedy {
+            Remedy::BackOff(wait) => wait.as_secs(),
+            remedy => panic!("Expected a back off, got {remedy:?}"),
+        });
+        // Doubling from a second until it's capped at a minute
+        assert!(back_offs.eq([1, 2, 4, 8, 16, 32, 60]));
+
+        assert_eq!(recovery.faults(), Faults { errors: 12, retries: 2, reconfigures: 1, resets: 2, back_offs: 7 });
+    }
+
+    #[test]
+    fn caps_the_back_off_however_long_the_radio_stays_down() {
+        let mut recovery = Recovery::new();
+        for _ in 0..1000 {
+            recovery.failed(true);
+        }
+
+        assert_eq!(recovery.failed(true), Remedy::BackOff(MAX_BACK_OFF));
+    }
+
+    #[test]
+    fn skips_retrying_when_the_radio_needs_setting_up() {
+        let mut recovery = Recovery::new();
+
+        assert_eq!(recovery.failed(false), Remedy::Reconfigure);
+        assert_eq!(recovery.failed(true), Remedy::Reset);
+        assert_eq!(recovery.faults().retries, 0);
+    }
+
+    #[test]
+    fn starts_over_once_the_radio_works_again() {
+        let mut recovery = Recovery::new();
+        for _ in 0..6 {
+            recovery.failed(true);
+        }
+        recovery.succeeded();
+
+        assert_eq!(recovery.failed(true), Remedy::Retry);
+        assert_eq!(recovery.faults().errors, 7);
+    }
+
+    #[test]
+    fn recovers_the_radio_and_forgets_its_timestamps() {
+        let start = frame(PEER, 0);
+        let script = [
+            Step::Receive(Some(ScriptedFrame { payload: &start, timestamp: 10_000 })),
+            Step::Transmit { timestamp: 12_000 },
+            Step::Fail,
+            Step::Fail,
+            Step::Fail,
+            Step::Fail,
+            Step::Receive(Some(ScriptedFrame { payload: &start, timestamp: 20_000 })),
+            Step::Transmit { timestamp: 22_000 }
+        ];
+        let mut radio = ScriptedRadio::new(&script);
+        let mut ranging = Ranging::new(ADDRESS);
+        let mut recovery = Recovery::new();
+
+        assert!(block_on(ranging.step(&mut radio)).is_ok());
+
+        // Retry, retry, reconfigure and reset, where the radio without a quicker way to reconfigure resets both times
+        for expected in [Remedy::Retry, Remedy::Retry, Remedy::Reconfigure, Remedy::Reset] {
+            assert_eq!(block_on(ranging.step(&mut radio)), Err(MockError::Scripted));
+            let remedy = recovery.failed(true);
+            assert_eq!(remedy, expected);
+            assert_eq!(block_on(ranging.recover(&mut radio, remedy)), Ok(()));
+        }
+        assert_eq!(radio.resets, 2);
+
+        // A reply delay from before the reset would mean nothing to the peer
+        assert_eq!(block_on(ranging.step(&mut radio)), Ok(Outcome::Exchanged { peer: PEER, tof: None }));
+        assert_eq!(reply_delay(radio.last_sent()), 0);
+        recovery.succeeded();
+
+        assert_eq!(recovery.faults(), Faults { errors: 4, retries: 2, reconfigures: 1, resets: 1, back_offs: 0 });
+        assert!(radio.finished());
+    }
+}
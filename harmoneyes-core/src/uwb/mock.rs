@@ -0,0 +1,108 @@
+//! A radio that plays back a script, for exercising the ranging state machine without a DW3000.
+
+use core::time::Duration;
+
+use super::{Received, UwbRadio, MAX_PAYLOAD_LENGTH};
+
+/// One call that the script expects the state machine to make.
+#[derive(Clone, Copy, Debug)]
+pub enum Step<'a> {
+    /// Expect a transmission and report that it left the antenna at `timestamp`.
+    Transmit { timestamp: u64 },
+    /// Expect the radio to listen, and hand it this frame or time out.
+    Receive(Option<ScriptedFrame<'a>>),
+    /// Fail whatever the next call is.
+    Fail
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScriptedFrame<'a> {
+    pub payload: &'a [u8],
+    pub timestamp: u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockError {
+    /// The script asked for this call to fail.
+    Scripted,
+    /// The state machine made a different call to the one the script expected at this step.
+    Unexpected { step: usize },
+    /// The state machine kept going after the end of the script.
+    Finished
+}
+
+pub struct ScriptedRadio<'a> {
+    script: &'a [Step<'a>],
+    next: usize,
+    sent: [u8; MAX_PAYLOAD_LENGTH],
+    sent_len: usize,
+    /// The delay asked for by the last transmission.
+    pub last_delay: Duration,
+    /// How many times the radio has been reset.
+    pub resets: usize
+}
+
+impl<'a> ScriptedRadio<'a> {
+    pub fn new(script: &'a [Step<'a>]) -> Self {
+        Self {
+            script,
+            next: 0,
+            sent: [0; MAX_PAYLOAD_LENGTH],
+            sent_len: 0,
+            last_delay: Duration::ZERO,
+            resets: 0
+        }
+    }
+
+    /// Whether every step of the script has been played.
+    pub fn finished(&self) -> bool {
+        self.next == self.script.len()
+    }
+
+    /// The payload of the last transmission.
+    pub fn last_sent(&self) -> &[u8] {
+        &self.sent[..self.sent_len]
+    }
+
+    fn advance(&mut self) -> Result<(usize, Step<'a>), MockError> {
+        let step = *self.script.get(self.next).ok_or(MockError::Finished)?;
+        self.next += 1;
+
+        match step {
+            Step::Fail => Err(MockError::Scripted),
+            step => Ok((self.next - 1, step)),
+        }
+    }
+}
+
+impl UwbRadio for ScriptedRadio<'_> {
+    type Error = MockError;
+
+    async fn transmit(&mut self, payload: &[u8], delay: Duration) -> Result<u64, Self::Error> {
+        match self.advance()? {
+            (_, Step::Transmit { timestamp }) => {
+                self.sent[..payload.len()].copy_from_slice(payload);
+                self.sent_len = payload.len();
+                self.last_delay = delay;
+                Ok(timestamp)
+            },
+            (step, _) => Err(MockError::Unexpected { step }),
+        }
+    }
+
+    async fn receive(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<Option<Received>, Self::Error> {
+        match self.advance()? {
+            (_, Step::Receive(None)) => Ok(None),
+            (_, Step::Receive(Some(frame))) => {
+                buf[..frame.payload.len()].copy_from_slice(frame.payload);
+                Ok(Some(Received { len: frame.payload.len(), timestamp: frame.timestamp }))
+            },
+            (step, _) => Err(MockError::Unexpected { step }),
+        }
+    }
+
+    async fn reset(&mut self) -> Result<(), Self::Error> {
+        self.resets += 1;
+        Ok(())
+    }
+}
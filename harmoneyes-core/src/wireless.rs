@@ -0,0 +1,62 @@
+//! The wireless link between a controller and a cuff, for performers whose uniform or instrument snags on a cable.
+//!
+//! A wireless cuff is a Bluetooth LE peripheral and the controller connects to it as a central. The cuff offers a
+//! single GATT service, and the commands that would otherwise go over the two-wire interface are written to its
+//! command characteristic byte for byte: motor commands ([`crate::haptics`]), power modes ([`crate::power`]) and keep
+//! alives ([`crate::link`]). A status register ([`crate::registers`]) is read by writing its code to the command
+//! characteristic and then reading the response characteristic, which holds the contents of the register that was
+//! asked for last.
+//!
+//! A controller only connects to the cuff it's paired with, which is the one whose serial number is set for that
+//! wrist in the config. The cuff puts its serial number in its advertisement, after [`MAGIC`], so the controller can
+//! tell it apart from every other cuff in the band without connecting to them.
+
+use crate::{haptics::Run, identity::Serial, mesh::AD_TYPE, registers::MAX_CONTENTS_LENGTH};
+
+/// The UUID of the cuff's GATT service.
+pub const SERVICE_UUID: u128 = 0x6e4a0001_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The UUID of the characteristic the controller writes commands to.
+pub const COMMAND_UUID: u128 = 0x6e4a0002_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The UUID of the characteristic the controller reads the register it asked for from.
+pub const RESPONSE_UUID: u128 = 0x6e4a0003_8b5d_4c3f_9a52_4861726d6f6e;
+
+/// The longest command written to the cuff, which is a motor command.
+pub const MAX_COMMAND_LENGTH: usize = Run::LENGTH;
+
+/// The longest response read from the cuff.
+pub const MAX_RESPONSE_LENGTH: usize = MAX_CONTENTS_LENGTH;
+
+/// Marks an advertisement as coming from a wireless cuff.
+pub const MAGIC: &[u8; 14] = b"HarmoneyesCuff";
+
+/// The length of a cuff's advertising data.
+pub const DATA_LENGTH: usize = 2 + MAGIC.len() + Serial::CAPACITY;
+
+/// The advertising data of the cuff with `serial`, which is an AD structure of the mesh AD type holding [`MAGIC`]
+/// followed by the serial number. Returns the data and how much of it is used.
+pub fn advertisement(serial: &Serial) -> ([u8; DATA_LENGTH], usize) {
+    let serial = serial.as_str().as_bytes();
+    let len = 2 + MAGIC.len() + serial.len();
+    let mut data = [0; DATA_LENGTH];
+
+    data[0] = (len - 1) as u8;
+    data[1] = AD_TYPE;
+    data[2..2 + MAGIC.len()].copy_from_slice(MAGIC);
+    data[2 + MAGIC.len()..len].copy_from_slice(serial);
+
+    (data, len)
+}
+
+/// Finds the serial number of the cuff in received advertising data, if it came from one.
+pub fn parse_advertisement(data: &[u8]) -> Option<&str> {
+    let header = 2 + MAGIC.len();
+    let len = 1 + *data.first()? as usize;
+
+    if len > header && len <= data.len() && data[1] == AD_TYPE && data[2..header] == *MAGIC {
+        core::str::from_utf8(&data[header..len]).ok()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,25 @@
+MEMORY {
+    /*
+        Note: The units of `K` are Kibibytes (KiB) where 1 KiB = 1024 bytes
+
+        The bootloader sits straight after BOOT2, where the RP2040 starts
+        running from, and jumps to the firmware in ACTIVE. This has to match
+        the layout in `harmoneyes-cuff/memory.x`.
+    */
+    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 24K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    ACTIVE : ORIGIN = 0x10007000, LENGTH = 512K
+    DFU : ORIGIN = 0x10087000, LENGTH = 516K
+    RAM : ORIGIN = 0x20000000, LENGTH = 264K
+}
+
+/* The flash driver takes offsets from the start of flash rather than addresses */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(BOOT2);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
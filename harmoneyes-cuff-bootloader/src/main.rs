@@ -0,0 +1,56 @@
+//! The bootloader for the Harmoneyes cuff.
+//!
+//! On every boot it checks whether the cuff firmware has left an update waiting in the `DFU` partition and swaps it
+//! with the running image if so, or swaps the old image back if the new one never confirmed itself. Then it starts
+//! the watchdog, which the firmware has to keep feeding, and jumps to the firmware.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_rp::{BootLoader, BootLoaderConfig, WatchdogFlash};
+use embassy_rp::flash::FLASH_BASE;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+/// The size of the flash chip on the cuff.
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// This has to match `WATCHDOG_TIMEOUT` in the cuff's `dfu.rs`.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_rp::init(Default::default());
+
+    // Swapping a whole image takes longer than the watchdog timeout, so the flash feeds it between operations
+    let flash = WatchdogFlash::<FLASH_SIZE>::start(p.FLASH, p.WATCHDOG, WATCHDOG_TIMEOUT);
+    let flash = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    let bootloader: BootLoader = BootLoader::prepare(config);
+
+    // SAFETY: The active partition holds a complete image, either the one that was flashed or one that was checked
+    // before it was swapped in
+    unsafe { bootloader.load(FLASH_BASE as u32 + active_offset) }
+}
+
+/// Any fault in the bootloader is most likely bad flash contents, and resetting gives the swap another go.
+#[unsafe(no_mangle)]
+#[cfg_attr(target_os = "none", unsafe(link_section = ".HardFault.user"))]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
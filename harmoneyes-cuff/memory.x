@@ -1,6 +1,14 @@
 MEMORY {
+    /*
+        The bootloader (see `harmoneyes-cuff-bootloader`) sits between BOOT2
+        and BOOTLOADER_STATE, and this has to match the layout in its
+        `memory.x`. Updates are written to DFU, which is a page larger than
+        FLASH so the bootloader has room to swap the two.
+    */
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH : ORIGIN = 0x10007000, LENGTH = 512K
+    DFU : ORIGIN = 0x10087000, LENGTH = 516K
 
     /* Pick one of the two options for RAM layout     */
 
@@ -14,4 +22,11 @@ MEMORY {
     /* RAM: ORIGIN = 0x20000000, LENGTH = 256K        */
     /* SCRATCH_A: ORIGIN = 0x20040000, LENGTH = 4K    */
     /* SCRATCH_B: ORIGIN = 0x20041000, LENGTH = 4K    */
-}
\ No newline at end of file
+}
+
+/* The flash driver takes offsets from the start of flash rather than addresses */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
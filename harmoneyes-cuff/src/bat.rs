@@ -0,0 +1,86 @@
+//! # Battery
+//!
+//! ## Hardware
+//! The cuff runs off its own LiPo rather than the controller's, and the
+//! QT Py RP2040 has no battery monitor of its own, so the battery is wired
+//! to A0 (GPIO 29) through a divider of two equal resistors. That halves
+//! the voltage to within the ADC's 3.3V range, the same way the Feather
+//! does for the controller.
+//!
+//! ## State of Charge
+//!
+//! The voltage is turned into a percentage and an estimate of the time
+//! left just like the controller's (see `harmoneyes_core::battery`),
+//! allowing for the sag while the cuff's motors are running. The cuff has
+//! no radio to raise an alert with, so the controller reads it through the
+//! battery status register and passes it on. The cuff's own LED shows
+//! when it's running low.
+
+use core::cell::Cell;
+
+use embassy_rp::{adc::{self, Adc, Channel}, bind_interrupts, gpio::Pull, peripherals::{ADC, PIN_29}};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker};
+use harmoneyes_core::{battery::{Escalation, Gauge, Level, Load, Report, Runtime}, status::State};
+use log::warn;
+
+/// The latest state of the battery, or `None` until the first sample has been taken.
+static BATTERY: Mutex<CriticalSectionRawMutex, Cell<Option<Report>>> = Mutex::new(Cell::new(None));
+
+bind_interrupts!(struct Irqs {
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+});
+
+/// The latest state of the battery, for the controller to read.
+pub fn report() -> Option<Report> {
+    BATTERY.lock(|battery| battery.get())
+}
+
+#[embassy_executor::task]
+pub async fn task(
+    adc: ADC,
+    pin: PIN_29
+) -> ! {
+    let mut adc = Adc::new(adc, Irqs, adc::Config::default());
+    let mut channel = Channel::new_pin(pin, Pull::None);
+
+    let mut ticker = Ticker::every(Duration::from_millis(2500));
+    let mut gauge = Gauge::new();
+    let mut runtime = Runtime::new();
+    let mut escalation = Escalation::new();
+
+    loop {
+        match adc.read(&mut channel).await {
+            Ok(raw) => {
+                let millivolts = as_millivolts(raw);
+                // The cuff has no ultra-wide band radio, so the motors are all there is
+                let load = Load { uwb: false, motors: crate::haptics::running() };
+                let percent = gauge.push(millivolts, load);
+
+                runtime.push(Instant::now().as_millis(), percent);
+                let minutes_remaining = runtime.minutes_remaining();
+
+                BATTERY.lock(|battery| battery.set(Some(Report { millivolts, percent, minutes_remaining })));
+
+                let shown = escalation.level();
+                escalation.update(percent, minutes_remaining);
+                if escalation.level() != shown {
+                    match escalation.level() {
+                        Some(level) => crate::status::set(State::LowBattery(level)),
+                        None => crate::status::clear(State::LowBattery(Level::Warning)),
+                    }
+                }
+            },
+            Err(e) => warn!("Failed to sample the battery: {:?}", e),
+        }
+
+        ticker.next().await;
+    }
+}
+
+fn as_millivolts(raw: u16) -> u16 {
+    // 3300 is the reference voltage in mV
+    // 4096 is for the 12-bit resolution (2^12)
+    // 2 is because of the voltage divider
+    (raw as u32 * 3300 / 4096 * 2) as u16
+}
@@ -0,0 +1,213 @@
+//! # Firmware updates
+//!
+//! The cuff is usually only cabled to the controller, so updates arrive over the two-wire interface a frame at a time
+//! (see `harmoneyes_core::transfer`) and are written to the `DFU` partition a page at a time. The controller has
+//! already checked the image's signature by then, so once the whole image is in flash and matches the CRC it was
+//! finished with, the bootloader is told to swap it in on the next reset.
+//!
+//! A freshly swapped in image runs on trial. The bootloader starts the watchdog before jumping to the firmware, and
+//! this task is what keeps it from firing, so if the new image panics or hangs before it has run for
+//! `CONFIRM_AFTER` the cuff resets and the bootloader swaps the old image back.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::peripheral::SCB;
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_futures::select::{select, Either};
+use embassy_rp::{peripherals::WATCHDOG, watchdog::Watchdog};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::nor_flash::ReadNorFlash;
+use harmoneyes_core::{crc::Crc32, status::State as LedState, transfer::{Fault, Message, Status}};
+use log::{info, warn};
+
+use crate::flash::{self, Region};
+
+/// How long a new image has to run before it confirms itself.
+const CONFIRM_AFTER: Duration = Duration::from_secs(30);
+
+/// The same timeout the bootloader starts the watchdog with.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How often the watchdog is fed. This has to be well within `WATCHDOG_TIMEOUT`.
+const FEED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The size of an erasable sector of the flash chip.
+const PAGE_SIZE: usize = 4096;
+
+/// Whether the running image is new and hasn't confirmed itself yet.
+pub static TRIAL: AtomicBool = AtomicBool::new(false);
+
+/// Asks the task to restart into the new image once the controller has had a chance to read that it was accepted.
+static RESTART: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[embassy_executor::task]
+pub async fn task(watchdog: WATCHDOG) -> ! {
+    // Carry on with the watchdog the bootloader started, or start one if the firmware was flashed without it
+    let mut watchdog = Watchdog::new(watchdog);
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+
+    let mut aligned = AlignedBuffer([0; 1]);
+    let mut state = BlockingFirmwareState::new(flash::bootloader_state(), &mut aligned.0);
+
+    match state.get_state() {
+        Ok(State::Swap) => {
+            info!("Running a new firmware image on trial");
+            TRIAL.store(true, Ordering::Relaxed);
+        },
+        Ok(_) => {},
+        Err(e) => warn!("Failed to read the bootloader state: {:?}", e),
+    }
+
+    let confirm_at = Instant::now() + CONFIRM_AFTER;
+
+    loop {
+        watchdog.feed();
+
+        if TRIAL.load(Ordering::Relaxed) && Instant::now() >= confirm_at {
+            match state.mark_booted() {
+                Ok(()) => {
+                    info!("Confirmed the new firmware image");
+                    TRIAL.store(false, Ordering::Relaxed);
+                },
+                Err(e) => warn!("Failed to confirm the new firmware image: {:?}", e),
+            }
+        }
+
+        if let Either::Second(()) = select(Timer::after(FEED_INTERVAL), RESTART.wait()).await {
+            // Give the controller time to read the status
+            Timer::after_millis(500).await;
+            info!("Restarting into the new firmware image");
+            SCB::sys_reset();
+        }
+    }
+}
+
+/// Takes in the frames of a firmware transfer from the controller and keeps track of the status it reads back.
+pub struct Receiver {
+    status: Status,
+    size: u32,
+    /// The page currently being filled in, which is written out once it's full.
+    page: [u8; PAGE_SIZE]
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self { status: Status::Idle, size: 0, page: [0xFF; PAGE_SIZE] }
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn handle(&mut self, message: Message) {
+        self.status = match (self.status, message) {
+            // Already restarting into the new image
+            (Status::Complete, _) => Status::Complete,
+            (_, Message::Begin { size }) => self.begin(size),
+            (Status::Receiving { received }, Message::Data { offset, data }) if offset == received => self.write(received, data),
+            (Status::Receiving { received }, Message::Finish { crc }) if received == self.size => self.finish(crc),
+            // Anything else is a frame sent again because its status got lost, or is from a transfer that failed, and
+            // the controller works out what to do from the status
+            (status, _) => status,
+        };
+
+        // Renewed by every frame, so an update the controller gave up on lapses on its own
+        match self.status {
+            Status::Receiving { .. } | Status::Complete => crate::status::set(LedState::Updating),
+            Status::Failed(_) => crate::status::clear(LedState::Updating),
+            Status::Idle => {},
+        }
+    }
+
+    fn begin(&mut self, size: u32) -> Status {
+        if TRIAL.load(Ordering::Relaxed) {
+            warn!("Refusing an update while the running image is on trial");
+            return Status::Failed(Fault::Unconfirmed);
+        }
+
+        // The bootloader needs a page of the DFU partition to itself while swapping
+        if size == 0 || size > flash::dfu().size() - PAGE_SIZE as u32 {
+            warn!("Refusing an update of {} bytes", size);
+            return Status::Failed(Fault::TooLarge);
+        }
+
+        // Unlike the controller nothing is erased up front, since that would hold up the bus for seconds. Each page
+        // is erased as it's written instead.
+        info!("Receiving a firmware update of {} bytes", size);
+        self.size = size;
+        self.page = [0xFF; PAGE_SIZE];
+
+        Status::Receiving { received: 0 }
+    }
+
+    fn write(&mut self, mut received: u32, mut data: &[u8]) -> Status {
+        if received + data.len() as u32 > self.size {
+            return Status::Failed(Fault::TooLarge);
+        }
+
+        while !data.is_empty() {
+            let start = received as usize % PAGE_SIZE;
+            let len = data.len().min(PAGE_SIZE - start);
+
+            self.page[start..start + len].copy_from_slice(&data[..len]);
+            received += len as u32;
+            data = &data[len..];
+
+            if received as usize % PAGE_SIZE == 0 || received == self.size {
+                let page_start = (received as usize - 1) / PAGE_SIZE * PAGE_SIZE;
+
+                let mut aligned = AlignedBuffer([0; 1]);
+                let result = updater(&mut aligned.0).write_firmware(page_start, &self.page);
+
+                self.page = [0xFF; PAGE_SIZE];
+
+                if let Err(e) = result {
+                    warn!("Failed to write the update: {:?}", e);
+                    return Status::Failed(Fault::Flash);
+                }
+            }
+        }
+
+        Status::Receiving { received }
+    }
+
+    /// Reads the image back out of flash to check it against the CRC the controller worked out as it sent it.
+    fn finish(&mut self, expected: u32) -> Status {
+        let mut dfu = flash::dfu();
+        let mut crc = Crc32::new();
+        let mut buf = [0; 256];
+        let mut offset = 0;
+
+        while offset < self.size {
+            let len = (self.size - offset).min(buf.len() as u32) as usize;
+            if let Err(e) = dfu.read(offset, &mut buf[..len]) {
+                warn!("Failed to read back the update: {:?}", e);
+                return Status::Failed(Fault::Flash);
+            }
+            crc.update(&buf[..len]);
+            offset += len as u32;
+        }
+
+        if crc.finish() != expected {
+            warn!("The update doesn't match its checksum");
+            return Status::Failed(Fault::Checksum);
+        }
+
+        let mut aligned = AlignedBuffer([0; 1]);
+        if let Err(e) = updater(&mut aligned.0).mark_updated() {
+            warn!("Failed to mark the update for the bootloader: {:?}", e);
+            return Status::Failed(Fault::Flash);
+        }
+
+        info!("The update was received");
+        RESTART.signal(());
+
+        Status::Complete
+    }
+}
+
+fn updater(aligned: &mut [u8]) -> BlockingFirmwareUpdater<'_, Region, Region> {
+    BlockingFirmwareUpdater::new(FirmwareUpdaterConfig { dfu: flash::dfu(), state: flash::bootloader_state() }, aligned)
+}
@@ -0,0 +1,67 @@
+//! # Flash
+//!
+//! The flash chip is shared between reading its unique ID and writing firmware updates, which each get a partition
+//! of flash whose bounds come from `memory.x`.
+
+use core::cell::RefCell;
+
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_rp::{flash::{Blocking, Flash}, peripherals::FLASH};
+use embassy_sync::{blocking_mutex::{raw::CriticalSectionRawMutex, Mutex}, once_lock::OnceLock};
+
+/// The size of the flash chip on the cuff.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+pub type CuffFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/// A partition of flash. Addresses are relative to its start.
+pub type Region = BlockingPartition<'static, CriticalSectionRawMutex, CuffFlash>;
+
+static SHARED: OnceLock<Mutex<CriticalSectionRawMutex, RefCell<CuffFlash>>> = OnceLock::new();
+
+/// Takes the flash peripheral. This has to be called before anything else uses flash.
+pub fn initialize(flash: FLASH) {
+    let _ = SHARED.init(Mutex::new(RefCell::new(Flash::new_blocking(flash))));
+}
+
+/// Reads the unique ID of the flash chip, which is the closest thing the cuff has to a serial number.
+pub fn unique_id() -> Option<u64> {
+    let mut id = [0u8; 8];
+
+    shared().lock(|flash| flash.borrow_mut().blocking_unique_id(&mut id)).ok()?;
+
+    Some(u64::from_be_bytes(id))
+}
+
+/// Where a firmware update is written before the bootloader swaps it in.
+pub fn dfu() -> Region {
+    unsafe extern "C" {
+        static __bootloader_dfu_start: u8;
+        static __bootloader_dfu_end: u8;
+    }
+
+    // SAFETY: Only the addresses of the linker symbols are used, they're never read through
+    unsafe { region(&raw const __bootloader_dfu_start, &raw const __bootloader_dfu_end) }
+}
+
+/// Where the bootloader keeps track of whether an update is waiting or on trial.
+pub fn bootloader_state() -> Region {
+    unsafe extern "C" {
+        static __bootloader_state_start: u8;
+        static __bootloader_state_end: u8;
+    }
+
+    // SAFETY: Only the addresses of the linker symbols are used, they're never read through
+    unsafe { region(&raw const __bootloader_state_start, &raw const __bootloader_state_end) }
+}
+
+/// The linker symbols are offsets from the start of flash rather than addresses, see `memory.x`.
+fn region(start: *const u8, end: *const u8) -> Region {
+    let (start, end) = (start as u32, end as u32);
+
+    BlockingPartition::new(shared(), start, end - start)
+}
+
+fn shared() -> &'static Mutex<CriticalSectionRawMutex, RefCell<CuffFlash>> {
+    SHARED.try_get().expect("The flash was used before it was initialized")
+}
@@ -1,47 +1,104 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use defmt::info;
 use embassy_futures::{join::join, select::{select, Either}};
 use embassy_rp::{gpio::{Level, Output}, peripherals::{PIN_3, PIN_4, PIN_5, PIN_6}};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
+use harmoneyes_core::{haptics::{Motor, Run}, telemetry::Telemetry};
+
+pub static FRONT: Signal<CriticalSectionRawMutex, Run> = Signal::new();
+pub static BACK: Signal<CriticalSectionRawMutex, Run> = Signal::new();
+pub static LEFT: Signal<CriticalSectionRawMutex, Run> = Signal::new();
+pub static RIGHT: Signal<CriticalSectionRawMutex, Run> = Signal::new();
+
+/// Whether each motor is running, so the battery gauge can allow for the current they draw.
+static RUNNING: [AtomicBool; Motor::ALL.len()] = [const { AtomicBool::new(false) }; Motor::ALL.len()];
+
+/// Runs a motor for as long and as strongly as `run` says, cutting short whatever it was doing before.
+pub fn run(run: Run) {
+    let signal = match run.motor {
+        Motor::Front => &FRONT,
+        Motor::Back => &BACK,
+        Motor::Left => &LEFT,
+        Motor::Right => &RIGHT,
+    };
+    signal.signal(run);
+
+    crate::usb::report(Telemetry::Haptic { wrist: crate::identity::wrist(), motor: run.motor, duration_ms: run.duration_ms });
+}
 
-pub static FRONT: Signal<CriticalSectionRawMutex, u64> = Signal::new();
-pub static BACK: Signal<CriticalSectionRawMutex, u64> = Signal::new();
-pub static LEFT: Signal<CriticalSectionRawMutex, u64> = Signal::new();
-pub static RIGHT: Signal<CriticalSectionRawMutex, u64> = Signal::new();
+/// Runs `motor` at full strength for `duration_ms` milliseconds.
+pub fn run_fully(motor: Motor, duration_ms: u64) {
+    run(Run { motor, duration_ms, intensity: 100 });
+}
+
+/// Stops every motor straight away.
+pub fn stop() {
+    for motor in Motor::ALL {
+        run_fully(motor, 0);
+    }
+}
+
+/// How many of the motors are running.
+pub fn running() -> u8 {
+    RUNNING.iter().filter(|running| running.load(Ordering::Relaxed)).count() as u8
+}
 
 #[embassy_executor::task]
 pub async fn task(front: PIN_3, back: PIN_4, left: PIN_5, right: PIN_6) {
     join(
         join(
-            run_motor(&FRONT, Output::new(front, Level::Low)), 
-            run_motor(&BACK, Output::new(back, Level::Low))
+            run_motor(&FRONT, &RUNNING[Motor::Front as usize], Output::new(front, Level::Low)), 
+            run_motor(&BACK, &RUNNING[Motor::Back as usize], Output::new(back, Level::Low))
         ), 
         join(
-            run_motor(&LEFT, Output::new(left, Level::Low)), 
-            run_motor(&RIGHT, Output::new(right, Level::Low))
+            run_motor(&LEFT, &RUNNING[Motor::Left as usize], Output::new(left, Level::Low)), 
+            run_motor(&RIGHT, &RUNNING[Motor::Right as usize], Output::new(right, Level::Low))
         )
     ).await;
 }
 
-async fn run_motor(signal: &Signal<CriticalSectionRawMutex, u64>, mut out: Output<'static>) {
-    let mut delay: u64 = signal.wait().await;
-
+async fn run_motor(signal: &Signal<CriticalSectionRawMutex, Run>, running: &AtomicBool, mut out: Output<'static>) {
     loop {
-        out.set_high();
-        info!("Turned motor on");
-
-        match select(signal.wait(), Timer::after_millis(delay)).await {
-            Either::First(new_delay) => {
-                delay = new_delay;
-                continue;
-            },
-            Either::Second(()) => {
-                out.set_low();
-                info!("Turned motor off");
-            },
+        let mut run = signal.wait().await;
+
+        // A duration of zero stops the motor without turning it on
+        while run.duration_ms > 0 {
+            running.store(true, Ordering::Relaxed);
+            info!("Turned motor on");
+
+            let until = Instant::now().checked_add(Duration::from_millis(run.duration_ms)).unwrap_or(Instant::MAX);
+            match select(signal.wait(), drive(&mut out, run, until)).await {
+                Either::First(next) => run = next,
+                Either::Second(()) => run.duration_ms = 0,
+            }
         }
 
-        delay = signal.wait().await;
+        if running.load(Ordering::Relaxed) {
+            out.set_low();
+            running.store(false, Ordering::Relaxed);
+            info!("Turned motor off");
+        }
     }
 }
 
+/// Keeps the motor on until `until`, switching it off for part of every period if it's running below full strength.
+async fn drive(out: &mut Output<'static>, run: Run, until: Instant) {
+    let Some((on_ms, off_ms)) = run.duty_ms() else {
+        out.set_high();
+        Timer::at(until).await;
+        return;
+    };
+
+    while Instant::now() < until {
+        if on_ms > 0 {
+            out.set_high();
+            Timer::at(until.min(Instant::now() + Duration::from_millis(on_ms))).await;
+        }
+        if off_ms > 0 {
+            out.set_low();
+            Timer::at(until.min(Instant::now() + Duration::from_millis(off_ms))).await;
+        }
+    }
+}
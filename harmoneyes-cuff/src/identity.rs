@@ -0,0 +1,42 @@
+//! The identity of this cuff, worked out from the unique ID of its flash chip since the RP2040 doesn't have one, and
+//! which wrist it's worn on, which is set by tying its strap pin (A1) to ground for the right wrist.
+
+use embassy_rp::{gpio::{Input, Pull}, peripherals::PIN_28};
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::{block_for, Duration};
+use harmoneyes_core::{constants::cuff::SERIAL_PREFIX, haptics::Wrist, identity::{self, Serial}};
+use log::{info, warn};
+
+static SERIAL: OnceLock<Serial> = OnceLock::new();
+
+static WRIST: OnceLock<Wrist> = OnceLock::new();
+
+/// Reads the flash chip's unique ID and the strap pin. This has to happen before anything else runs, since the chip
+/// can't be read from while the ID is being fetched.
+pub fn initialize(strap: PIN_28) {
+    // Pulled up, so a cuff without the strap fitted answers where cuffs always have
+    let strap = Input::new(strap, Pull::Up);
+    // Give the pull up a moment to charge the pin
+    block_for(Duration::from_micros(10));
+    let wrist = if strap.is_low() { Wrist::Right } else { Wrist::Left };
+    info!("Worn on the {} wrist", wrist.name());
+    let _ = WRIST.init(wrist);
+
+    let id = crate::flash::unique_id().unwrap_or_else(|| {
+        warn!("Failed to read the flash chip's unique ID");
+        0
+    });
+
+    let _ = SERIAL.init(identity::serial(SERIAL_PREFIX, id));
+}
+
+/// The serial number reported over USB and to the console.
+pub fn serial() -> &'static str {
+    // Falls back to an all zero ID if `initialize` was never called
+    SERIAL.get_or_init(|| identity::serial(SERIAL_PREFIX, 0)).as_str()
+}
+
+/// The wrist the strap pin says the cuff is worn on, which decides its address on the two-wire interface.
+pub fn wrist() -> Wrist {
+    *WRIST.get_or_init(|| Wrist::Left)
+}
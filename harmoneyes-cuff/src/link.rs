@@ -0,0 +1,58 @@
+//! # Link supervision
+//!
+//! The controller keeps the two-wire link alive (see `harmoneyes_core::link`), and `twi` lets this task know every
+//! time it hears from it. If the controller goes quiet for too long, because it has died or the cable has come
+//! loose, the motors are stopped so they can't be left running, every motor is pulsed a few times so the performer
+//! knows not to wait for cues, and the status LED shows the link is lost until the controller is heard from again.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use harmoneyes_core::{haptics::Motor, link::{Change, Supervisor, LOST_PULSES, LOST_PULSE_MS}, status::State};
+use log::{info, warn};
+
+/// How often the link is checked while nothing is heard.
+const CHECK_INTERVAL_MS: u64 = 250;
+
+static HEARD: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Lets the supervisor know the controller has been heard from.
+pub fn heard() {
+    HEARD.signal(());
+}
+
+#[embassy_executor::task]
+pub async fn task() -> ! {
+    let mut supervisor = Supervisor::new();
+
+    loop {
+        let change = match select(HEARD.wait(), Timer::after_millis(CHECK_INTERVAL_MS)).await {
+            Either::First(()) => supervisor.heard(Instant::now().as_millis()),
+            Either::Second(()) => supervisor.check(Instant::now().as_millis()),
+        };
+
+        match change {
+            Some(Change::Lost) => {
+                warn!("Lost the link to the controller, stopping the motors");
+                crate::haptics::stop();
+                crate::status::set(State::LinkLost);
+                pulse().await;
+            },
+            Some(Change::Restored) => {
+                info!("The link to the controller is back");
+                crate::status::clear(State::LinkLost);
+            },
+            None => {},
+        }
+    }
+}
+
+/// Pulses every motor together, in a pattern no cue uses.
+async fn pulse() {
+    for _ in 0..LOST_PULSES {
+        for motor in Motor::ALL {
+            crate::haptics::run_fully(motor, LOST_PULSE_MS);
+        }
+        Timer::after_millis(2 * LOST_PULSE_MS).await;
+    }
+}
@@ -1,7 +1,14 @@
 #![no_std]
 #![no_main]
 
+mod bat;
+mod dfu;
+mod flash;
 mod haptics;
+mod identity;
+mod link;
+mod mode;
+mod status;
 mod twi;
 mod usb;
 mod ws;
@@ -11,6 +18,7 @@ use defmt_rtt as _;
 
 use log::info;
 use embassy_executor::Spawner;
+use harmoneyes_core::status::State;
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -18,6 +26,24 @@ async fn main(spawner: Spawner) {
     info!("Initializing Embassy");
     let p = embassy_rp::init(embassy_config());
 
+    flash::initialize(p.FLASH);
+    identity::initialize(p.PIN_28);
+    info!("Cuff {}", identity::serial());
+
+    // Spawn the status LED task first, so there's something to see while everything else starts
+    info!("Spawning status LED task");
+    status::set(State::Booting);
+    spawner.must_spawn(status::task(
+        p.PIO0,
+        p.DMA_CH0,
+        p.PIN_12,
+        p.PIN_11
+    ));
+
+    // Spawn the firmware update task, which also feeds the watchdog
+    info!("Spawning firmware update task");
+    spawner.must_spawn(dfu::task(p.WATCHDOG));
+
     // Spawn the two-wire interface task
     info!("Spawning two-wire interface task");
     spawner.must_spawn(twi::task(
@@ -26,6 +52,13 @@ async fn main(spawner: Spawner) {
         p.PIN_22
     ));
 
+    // Spawn the battery monitor task
+    info!("Spawning battery monitor task");
+    spawner.must_spawn(bat::task(
+        p.ADC,
+        p.PIN_29
+    ));
+
     // Spawn the haptics task
     info!("Spawning haptics task");
     spawner.must_spawn(haptics::task(
@@ -35,17 +68,15 @@ async fn main(spawner: Spawner) {
         p.PIN_6
     ));
 
+    // Spawn the link supervisor, which stops the motors if the controller goes quiet
+    info!("Spawning link supervisor task");
+    spawner.must_spawn(link::task());
+
     // Spawn the USB task
     info!("Spawning USB task");
     spawner.must_spawn(usb::task(p.USB));
 
-    info!("Spawning Neopixel task");
-    spawner.must_spawn(ws::task(
-        p.PIO0,
-        p.DMA_CH0,
-        p.PIN_12,
-        p.PIN_11
-    ));
+    status::clear(State::Booting);
 }
 
 fn embassy_config() -> embassy_rp::config::Config {
@@ -0,0 +1,40 @@
+//! # Power modes
+//!
+//! The cuff follows the power mode of the controller it's cabled to (see `harmoneyes_core::power::Mode`), which the
+//! controller tells it over the two-wire interface whenever it changes. The motors only run when the controller
+//! asks, so it's the LED that changes, showing idle while idle and turning off while asleep (see `status`).
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use harmoneyes_core::{power::Mode, status::State};
+use log::info;
+
+/// The current mode, as its code.
+static MODE: AtomicU8 = AtomicU8::new(Mode::Active.code());
+
+pub fn current() -> Mode {
+    Mode::from_code(MODE.load(Ordering::Relaxed)).unwrap_or_default()
+}
+
+pub fn set(mode: Mode) {
+    if current() != mode {
+        info!("Going into {} mode", mode.name());
+        MODE.store(mode.code(), Ordering::Relaxed);
+
+        match mode {
+            Mode::Active => {
+                crate::status::clear(State::Idle);
+                crate::status::clear(State::Asleep);
+            },
+            Mode::Idle => {
+                crate::status::clear(State::Asleep);
+                crate::status::set(State::Idle);
+            },
+            Mode::Sleep => {
+                crate::status::clear(State::Idle);
+                crate::status::set(State::Asleep);
+            },
+        }
+    }
+}
+
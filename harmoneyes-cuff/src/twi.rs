@@ -1,5 +1,12 @@
-use log::info;
+use log::{info, warn};
 use embassy_rp::{i2c::{self}, i2c_slave::{self, Command, Error, I2cSlave}, peripherals::{I2C1, PIN_22, PIN_23}};
+use embassy_time::{Duration, Instant};
+use harmoneyes_core::{haptics::Run, link, power::Mode, registers::{Contents, Register, MAX_CONTENTS_LENGTH}, status::State, transfer::Message};
+
+use crate::dfu::Receiver;
+
+/// How often hearing from the controller renews the paired state, well within the time it takes to lapse.
+const PAIRED_RENEWAL: Duration = Duration::from_secs(5);
 
 embassy_rp::bind_interrupts!(struct Irqs {
     I2C1_IRQ => i2c::InterruptHandler<I2C1>;
@@ -12,32 +19,58 @@ pub async fn task(
     sda: PIN_22
 ) {
     let mut driver = I2cSlave::new(twi, scl, sda, Irqs, peripheral_config());
+    let mut receiver = Receiver::new();
+    let mut paired_at: Option<Instant> = None;
 
     loop {
         let mut buf = [0u8; 64];
-        match driver.listen(&mut buf).await {
+        let result = driver.listen(&mut buf).await;
+
+        // Anything the controller says keeps the link alive, not just keep alives
+        if result.is_ok() {
+            crate::link::heard();
+        }
+
+        // The controller keeps the link alive, so the state only lapses if it goes away
+        if result.is_ok() && paired_at.is_none_or(|at| at.elapsed() >= PAIRED_RENEWAL) {
+            crate::status::set(State::Paired);
+            paired_at = Some(Instant::now());
+        }
+
+        match result {
+            Ok(Command::Write(len)) if len > 0 && Message::is_transfer(buf[0]) => match Message::decode(&buf[..len]) {
+                Ok(message) => receiver.handle(message),
+                // The controller sends the frame again when it sees the status hasn't moved on
+                Err(e) => warn!("Dropped a damaged update frame: {:?}", e),
+            },
+            // Already counted as hearing from the controller, and too frequent to log
+            Ok(Command::Write(len)) if link::is_keep_alive(&buf[..len]) => {},
             Ok(Command::Write(len)) => {
                 info!("Write: {:?}", &buf[..len]);
 
-                if len >= 9 {
-                    let delay = u64::from_le_bytes(buf[1..9].try_into().unwrap());
-                    match buf[0] {
-                        0x10 => crate::haptics::FRONT.signal(delay),
-                        0x11 => crate::haptics::BACK.signal(delay),
-                        0x12 => crate::haptics::LEFT.signal(delay),
-                        0x13 => crate::haptics::RIGHT.signal(delay),
-                        _ => {}
-                    }
+                if let Some(mode) = Mode::from_command(&buf[..len]) {
+                    crate::mode::set(mode);
+                } else if let Some(run) = Run::from_command(&buf[..len]) {
+                    crate::haptics::run(run);
                 }
             },
             Ok(Command::GeneralCall(len)) => { info!("General Call: {:?}", &buf[..len]); },
-            Ok(Command::WriteRead(len)) => {
-                info!("WriteRead: {:?}", &buf[..len]);
-                let _ = driver.respond_to_read(&[0xBC]).await;
+            // The controller reads a status register by writing its code
+            Ok(Command::WriteRead(len)) => match Register::from_code(buf[0]) {
+                Some(register) if len == 1 => {
+                    let mut contents = [0; MAX_CONTENTS_LENGTH];
+                    // The buffer is sized for the largest register
+                    let len = read(register).encode(&mut contents).expect("Register did not fit in its buffer");
+                    let _ = driver.respond_to_read(&contents[..len]).await;
+                },
+                _ => {
+                    info!("WriteRead: {:?}", &buf[..len]);
+                    let _ = driver.respond_to_read(&[0xBC]).await;
+                },
             },
+            // The controller reads back the update status after every frame
             Ok(Command::Read) => {
-                info!("Read");
-                let _ = driver.respond_to_read(&[0xAB]).await;
+                let _ = driver.respond_to_read(&receiver.status().encode()).await;
             },
             Err(Error::PartialGeneralCall(len)) => { info!("Partial General: {:?}", &buf[..len]); },
             Err(Error::PartialWrite(len)) => { info!("Partial Write: {:?}", &buf[..len]); },
@@ -48,9 +81,16 @@ pub async fn task(
     }
 }
 
+/// The current contents of a status register.
+fn read(register: Register) -> Contents {
+    match register {
+        Register::Battery => Contents::Battery(crate::bat::report()),
+    }
+}
+
 fn peripheral_config() -> i2c_slave::Config {
     let mut config = i2c_slave::Config::default();
-    config.addr = harmoneyes_core::constants::cuff::I2C_ADDRESS;
+    config.addr = crate::identity::wrist().default_address() as u16;
 
     config
 }
\ No newline at end of file
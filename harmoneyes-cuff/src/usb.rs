@@ -1,7 +1,12 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use embassy_futures::select::{select, Either};
 use embassy_rp::{bind_interrupts, peripherals::USB, usb::{self, Driver}};
-use embassy_usb::{class::cdc_acm::{CdcAcmClass, State}, driver::EndpointError};
+use embassy_sync::{blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex}, channel::Channel, signal::Signal};
+use embassy_time::{Instant, Timer};
+use embassy_usb::{class::cdc_acm::{CdcAcmClass, Receiver, Sender, State}, driver::EndpointError};
 use futures::future::join;
+use harmoneyes_core::{codec::FixedStr, framing::{self, Accumulator}, protocol::{DeviceInfo, DeviceKind, Failure, Request, Response, MAX_FRAME_LENGTH, MAX_MESSAGE_LENGTH}, telemetry::{Event, Telemetry}};
 use log::info;
 use static_cell::StaticCell;
 
@@ -12,6 +17,15 @@ static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
 static ACM_STATE: StaticCell<State> = StaticCell::new();
 static LOGGER_STATE: StaticCell<State> = StaticCell::new();
 
+/// Telemetry events waiting to be sent to the console.
+static TELEMETRY: Channel<CriticalSectionRawMutex, Event, 8> = Channel::new();
+
+/// Whether the console has asked for telemetry.
+static STREAMING: AtomicBool = AtomicBool::new(false);
+
+/// Set once the console has asked for the USB bootloader.
+static REBOOT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => usb::InterruptHandler<USB>;
 });
@@ -39,15 +53,41 @@ pub async fn task(
     let mut usb = builder.build();
 
     // Run the low-level USB interface, the ACM handler, and the logger concurrently
-    join(usb.run(), join(handle_acm(acm), logger_fut)).await;
+    join(usb.run(), join(handle_acm(acm), join(logger_fut, reboot_to_bootloader()))).await;
+}
+
+/// Waits for the console to ask for the USB bootloader, then hands over to the one in the RP2040's ROM. It shows up as
+/// the same `RPI-RP2` drive as holding the boot button while plugging the cuff in.
+async fn reboot_to_bootloader() {
+    REBOOT.wait().await;
+
+    info!("Rebooting into the USB bootloader");
+    // Give the response time to reach the console
+    Timer::after_millis(100).await;
+
+    // No activity LED, and leave both the mass storage and PICOBOOT interfaces enabled
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
 }
 
-async fn handle_acm(mut acm: CdcAcmClass<'static, Driver<'static, USB>>) -> ! {
+/// Queues a telemetry event for the console if it has asked for them. Events are dropped rather than waited on
+/// when the console can't keep up so that reporting never holds up the rest of the device.
+pub fn report(telemetry: Telemetry) {
+    if STREAMING.load(Ordering::Relaxed) {
+        let _ = TELEMETRY.try_send(Event { uptime_ms: Instant::now().as_millis(), telemetry });
+    }
+}
+
+async fn handle_acm(acm: CdcAcmClass<'static, Driver<'static, USB>>) -> ! {
+    let (mut sender, mut receiver) = acm.split();
+
     loop {
-        acm.wait_connection().await;
+        receiver.wait_connection().await;
         info!("Established USB connection");
 
-        let _ = host_connection(&mut acm).await;
+        let _ = host_connection(&mut sender, &mut receiver).await;
+
+        STREAMING.store(false, Ordering::Relaxed);
+        TELEMETRY.clear();
         info!("Disconnected from USB");
     }
 }
@@ -64,26 +104,109 @@ impl From<EndpointError> for Disconnected {
 }
 
 /// Handles serial data communication to another device connected over USB.
-async fn host_connection(acm: &mut CdcAcmClass<'static, Driver<'static, USB>>) -> Result<(), Disconnected> {
+async fn host_connection(sender: &mut Sender<'static, Driver<'static, USB>>, receiver: &mut Receiver<'static, Driver<'static, USB>>) -> Result<(), Disconnected> {
+    let responses: Channel<NoopRawMutex, Response, 2> = Channel::new();
+
+    match select(read_requests(receiver, &responses), write_responses(sender, &responses)).await {
+        Either::First(res) => res,
+        Either::Second(res) => res,
+    }
+}
+
+/// Decodes requests from the console as they arrive and queues up their responses.
+async fn read_requests(receiver: &mut Receiver<'static, Driver<'static, USB>>, responses: &Channel<NoopRawMutex, Response, 2>) -> Result<(), Disconnected> {
+    let mut accumulator: Accumulator<MAX_FRAME_LENGTH> = Accumulator::new();
     let mut buf = [0; 64];
 
     loop {
-        let n = acm.read_packet(&mut buf).await?;
-        let data = &buf[..n];
-        info!("data: {:?}", data);
-        acm.write_packet(data).await?;
+        let n = receiver.read_packet(&mut buf).await?;
+
+        for byte in &buf[..n] {
+            let response = match accumulator.push(*byte) {
+                None => continue,
+                Some(Ok(message)) => match Request::decode(message) {
+                    Ok(request) => handle_request(request),
+                    Err(_) => Response::Failed(Failure::Malformed),
+                },
+                Some(Err(_)) => Response::Failed(Failure::Malformed),
+            };
+            responses.send(response).await;
+        }
+    }
+}
+
+/// Sends responses and, when the console has asked for it, telemetry.
+async fn write_responses(sender: &mut Sender<'static, Driver<'static, USB>>, responses: &Channel<NoopRawMutex, Response, 2>) -> Result<(), Disconnected> {
+    let mut message = [0; MAX_MESSAGE_LENGTH];
+    let mut frame = [0; MAX_FRAME_LENGTH];
+
+    loop {
+        let response = match select(responses.receive(), TELEMETRY.receive()).await {
+            Either::First(response) => response,
+            Either::Second(event) => Response::Telemetry(event),
+        };
+
+        // Both buffers are sized for the largest message, so neither of these can fail
+        let len = response.encode(&mut message).expect("Response did not fit in the message buffer");
+        let len = framing::encode(&message[..len], &mut frame).expect("Response did not fit in the frame buffer");
+
+        for packet in frame[..len].chunks(64) {
+            sender.write_packet(packet).await?;
+        }
+    }
+}
+
+fn handle_request(request: Request) -> Response {
+    match request {
+        Request::Info => Response::Info(DeviceInfo {
+            kind: DeviceKind::Cuff,
+            serial: FixedStr::truncated(crate::identity::serial()),
+            // The performer's name is kept on the controller
+            name: FixedStr::empty(),
+            firmware_version: FixedStr::truncated(env!("CARGO_PKG_VERSION")),
+            uptime_ms: Instant::now().as_millis(),
+            performer_id: 0,
+            section: 0
+        }),
+        // The console doesn't know which wrist a cuff plugged straight into it is on, and it doesn't matter
+        Request::HapticTest { motor, duration_ms, .. } => {
+            crate::haptics::run_fully(motor, duration_ms);
+            Response::Done
+        },
+        Request::Stream { enabled } => {
+            STREAMING.store(enabled, Ordering::Relaxed);
+            if !enabled {
+                TELEMETRY.clear();
+            }
+            Response::Done
+        },
+        // The cuff takes its settings from the controller
+        Request::ConfigGet { .. } | Request::ConfigSet { .. } | Request::ConfigReset => Response::Failed(Failure::Unsupported),
+        // The cuff is updated through the bootloader in its ROM instead
+        Request::UpdateBegin { .. } | Request::UpdateWrite { .. } | Request::UpdateFinish { .. } => Response::Failed(Failure::Unsupported),
+        Request::RebootToBootloader => {
+            REBOOT.signal(());
+            Response::Done
+        },
+        // The cuff has no radio to pass the mode on with, so `band` means nothing here
+        Request::SetPowerMode { mode, .. } => {
+            crate::mode::set(mode);
+            Response::Done
+        },
+        // Only controllers keep crash reports
+        Request::CrashGet { .. } | Request::CrashClear => Response::Failed(Failure::Unsupported),
     }
 }
 
 fn usb_config() -> embassy_usb::Config<'static> {
     let mut config = embassy_usb::Config::new(
-        harmoneyes_core::constants::USB_VENDOR_ID, 
+        harmoneyes_core::constants::USB_VENDOR_ID,
         harmoneyes_core::constants::cuff::USB_PRODUCT_ID
     );
 
     config.manufacturer = Some(harmoneyes_core::constants::MANUFACTURER);
     config.product = Some(harmoneyes_core::constants::cuff::NAME);
-    config.serial_number = Some(harmoneyes_core::constants::cuff::SERIAL_ONE);
+    config.serial_number = Some(crate::identity::serial());
 
     config
-}
\ No newline at end of file
+}
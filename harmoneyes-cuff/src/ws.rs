@@ -1,48 +1,39 @@
 use embassy_rp::{bind_interrupts, gpio::{Level, Output}, peripherals::{DMA_CH0, PIN_11, PIN_12, PIO0}, pio::{self, Pio}, pio_programs::ws2812::{PioWs2812, PioWs2812Program}};
-use embassy_time::{Duration, Ticker};
-use log::debug;
+use harmoneyes_core::status::Colour;
 use smart_leds::RGB8;
 
+// WS2812B LED driver. Drives just one light, through the PIO, with its power switched by a second pin.
+// What the LED shows is up to the status LED service (see `status`).
+
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
 });
 
-fn wheel(mut wheel_pos: u8) -> RGB8 {
-    wheel_pos = 255 - wheel_pos;
-    if wheel_pos < 85 {
-        return (255 - wheel_pos * 3, 0, wheel_pos * 3).into();
-    }
-    if wheel_pos < 170 {
-        wheel_pos -= 85;
-        return (0, wheel_pos * 3, 255 - wheel_pos * 3).into();
-    }
-    wheel_pos -= 170;
-    (wheel_pos * 3, 255 - wheel_pos * 3, 0).into()
+/// A single WS2812B (Neopixel) LED on PIN_12, powered through PIN_11.
+pub struct Led {
+    ws2812: PioWs2812<'static, PIO0, 0, 1>,
+    power: Output<'static>
 }
 
-#[embassy_executor::task]
-pub async fn task(pio: PIO0, dma: DMA_CH0, data_pin: PIN_12, power_pin: PIN_11) {
-    let Pio { mut common, sm0, .. } = Pio::new(pio, Irqs);
-
-    const NUM_LEDS: usize = 1;
-    let mut data = [RGB8::default(); NUM_LEDS];
+impl Led {
+    pub fn new(pio: PIO0, dma: DMA_CH0, data_pin: PIN_12, power_pin: PIN_11) -> Self {
+        let Pio { mut common, sm0, .. } = Pio::new(pio, Irqs);
 
-    let program = PioWs2812Program::new(&mut common);
-    let mut ws2812 = PioWs2812::new(&mut common, sm0, dma, data_pin, &program);
+        let program = PioWs2812Program::new(&mut common);
+        let ws2812 = PioWs2812::new(&mut common, sm0, dma, data_pin, &program);
 
-    let _ws2812_power = Output::new(power_pin, Level::High);
-
-    let mut ticker = Ticker::every(Duration::from_millis(10));
-    loop {
-        for j in 0..(256 * 5) {
-            debug!("New Colors:");
-            for i in 0..NUM_LEDS {
-                data[i] = wheel((((i * 256) as u16 / NUM_LEDS as u16 + j as u16) & 255) as u8);
-                debug!("R: {} G: {} B: {}", data[i].r, data[i].g, data[i].b);
-            }
-            ws2812.write(&data).await;
+        Self { ws2812, power: Output::new(power_pin, Level::High) }
+    }
 
-            ticker.next().await;
+    /// Turns the LED to `colour`, where it stays until the next write. Off cuts the LED's power too, since it draws
+    /// a little even when dark.
+    pub async fn write(&mut self, (r, g, b): Colour) {
+        if (r, g, b) == (0, 0, 0) {
+            self.ws2812.write(&[RGB8::default()]).await;
+            self.power.set_low();
+        } else {
+            self.power.set_high();
+            self.ws2812.write(&[RGB8 { r, g, b }]).await;
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use harmoneyes_core::{mesh, telemetry::Telemetry};
+use tokio::{join, sync::mpsc};
+
+use super::Controller;
+use crate::medium::Medium;
+
+pub async fn task(controller: Arc<Controller>, medium: Arc<Medium>, outbox: mpsc::Receiver<[u8; mesh::MESSAGE_LENGTH]>, inbox: mpsc::Receiver<Vec<u8>>) {
+    join!(advertise(&controller, &medium, outbox), listen(&controller, inbox));
+}
+
+async fn listen(controller: &Controller, mut inbox: mpsc::Receiver<Vec<u8>>) {
+    while let Some(data) = inbox.recv().await {
+        if let Some(packet) = mesh::parse(&data) {
+            controller.report(Telemetry::mesh(false, packet.message));
+        }
+    }
+}
+
+async fn advertise(controller: &Controller, medium: &Medium, mut outbox: mpsc::Receiver<[u8; mesh::MESSAGE_LENGTH]>) {
+    while let Some(message) = outbox.recv().await {
+        controller.report(Telemetry::mesh(true, &message));
+
+        medium.advertise(controller.id, &build_ad_data(controller.address, message));
+    }
+}
+
+/// Builds a single AD structure the same way the softdevice's advertisement builder does.
+fn build_ad_data(source: u16, message: [u8; mesh::MESSAGE_LENGTH]) -> Vec<u8> {
+    let raw = mesh::encode(source, &message);
+
+    let mut data = Vec::with_capacity(raw.len() + 2);
+    data.push(raw.len() as u8 + 1);
+    data.push(mesh::AD_TYPE);
+    data.extend_from_slice(&raw);
+
+    data
+}
@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use harmoneyes_core::{coord::{Coordinator, KeepAlives}, haptics::{Direction, Motor, Pulses, Wrist}, link::Presence, mesh::{self, Cuff, KeepAlive}, power::Mode, proximity::Warning, ranging::{self, BlockAverage}, telemetry::Telemetry};
+use tokio::{join, sync::mpsc, time::{interval, sleep}};
+
+use super::Controller;
+use crate::rng::Rng;
+
+/// Every simulated controller has a single cuff that always answers, like the one `usb` pretends to run.
+const CUFF: Cuff = Cuff { presence: Presence::Present, battery: None };
+
+/// Coordinates the distance information from nearby controllers, making the same decisions as the firmware's
+/// coordination task (see `harmoneyes_core::coord`).
+pub async fn task(controller: Arc<Controller>, outbox: mpsc::Sender<[u8; mesh::MESSAGE_LENGTH]>, distances: mpsc::Receiver<(u16, u64)>, rng: Rng) {
+    // Only the latest warning matters while the cuff is still pulsing for the last one
+    let (collisions_tx, collisions_rx) = mpsc::channel(1);
+    let (guide_cues_tx, guide_cues_rx) = mpsc::channel(1);
+
+    join!(
+        random_bluetooth(&controller, outbox, rng),
+        handle_distances(&controller, distances, collisions_tx, guide_cues_tx),
+        warn_of_collisions(&controller, collisions_rx),
+        keep_spacing(&controller, guide_cues_rx)
+    );
+}
+
+async fn handle_distances(
+    controller: &Controller,
+    mut distances: mpsc::Receiver<(u16, u64)>,
+    collisions: mpsc::Sender<Warning>,
+    guide_cues: mpsc::Sender<Direction>
+) {
+    let mut averages: BTreeMap<u16, BlockAverage<5>> = BTreeMap::new();
+    let mut coordinator = Coordinator::new();
+
+    while let Some((peer, tof)) = distances.recv().await {
+        controller.report(Telemetry::Distance { peer, tof });
+
+        let (limits, spacing) = {
+            let config = controller.config.lock().unwrap();
+            (config.collision_limits(), config.guide())
+        };
+        let decision = coordinator.update(limits, spacing, peer, ranging::tof_to_millimeters(tof), controller.uptime_ms());
+
+        if let Some(warning) = decision.warning {
+            let _ = collisions.try_send(warning);
+        }
+
+        if let Some(cue) = decision.cue {
+            controller.log(format_args!(
+                "Moving {} to keep {:.2} m from guide {peer:04x}",
+                cue.correction.name(),
+                cue.spacing.target_mm as f64 / 1000.0
+            ));
+            let _ = guide_cues.try_send(cue.direction);
+        }
+
+        if let Some(average) = averages.entry(peer).or_default().push(tof) {
+            controller.log(format_args!("Distance to {peer:04x} {:.2} m", ranging::tof_to_meters(average)));
+        }
+    }
+}
+
+/// Pulses the cuff whenever somebody is too close or closing in too fast, like the firmware does.
+async fn warn_of_collisions(controller: &Controller, mut collisions: mpsc::Receiver<Warning>) {
+    let pulses = Pulses::URGENT;
+
+    while let Some(warning) = collisions.recv().await {
+        controller.log(format_args!(
+            "Peer {:04x} is {:.2} m away and closing at {:.2} m/s",
+            warning.peer,
+            warning.distance_mm as f64 / 1000.0,
+            warning.closing_mm_per_s as f64 / 1000.0
+        ));
+        controller.report(Telemetry::Collision { warning });
+
+        for _ in 0..pulses.count {
+            for motor in Motor::ALL {
+                controller.report(Telemetry::Haptic { wrist: Wrist::Left, motor, duration_ms: pulses.on_ms as u64 });
+            }
+            sleep(Duration::from_millis(pulses.on_ms as u64 + pulses.off_ms as u64)).await;
+        }
+    }
+}
+
+/// Cues the performer back to the interval from their guide, like the firmware does.
+async fn keep_spacing(controller: &Controller, mut guide_cues: mpsc::Receiver<Direction>) {
+    while let Some(direction) = guide_cues.recv().await {
+        let duration_ms = controller.config.lock().unwrap().haptic_duration_ms as u64;
+
+        // The simulated controller's one cuff is on the left wrist
+        controller.report(Telemetry::Haptic { wrist: Wrist::Left, motor: direction.motor(), duration_ms });
+    }
+}
+
+async fn random_bluetooth(controller: &Controller, outbox: mpsc::Sender<[u8; mesh::MESSAGE_LENGTH]>, mut rng: Rng) {
+    let period: u64 = 1000;
+    let mut ticker = interval(Duration::from_millis(period));
+
+    let mut keep_alives = KeepAlives::new();
+
+    loop {
+        ticker.tick().await;
+
+        sleep(Duration::from_millis(rng.next_u64() % period)).await;
+
+        // The simulated controllers don't change power mode
+        let Some(count) = keep_alives.tick(Mode::Active) else { continue };
+
+        let (performer_id, section) = {
+            let config = controller.config.lock().unwrap();
+            (config.performer_id, config.section)
+        };
+        let keep_alive = KeepAlive { count, battery: None, cuffs: [Some(CUFF), None], performer_id, section };
+
+        if outbox.send(mesh::keep_alive(&keep_alive)).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! A simulated controller, split into the same tasks as the firmware.
+
+use std::{fmt, io, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
+
+use harmoneyes_core::{config::Config, identity, telemetry::{Event, Telemetry}};
+use tokio::{sync::mpsc, time::Instant};
+use tokio_serial::{SerialPort, SerialStream};
+
+use crate::{medium::{Antennas, Medium}, rng::Rng};
+
+mod ble;
+mod coord;
+mod usb;
+mod uwb;
+
+/// The state of a controller that its tasks share.
+pub struct Controller {
+    /// The controller's index on the medium.
+    pub id: u16,
+    pub serial: String,
+    /// The short address the controller uses on the mesh and the ultra-wide band radio.
+    pub address: u16,
+    started: Instant,
+    config: Mutex<Config>,
+    /// Whether the console has asked for telemetry.
+    streaming: AtomicBool,
+    telemetry: mpsc::Sender<Event>,
+    logs: mpsc::Sender<String>,
+    /// Also print the logs to the simulator's standard output.
+    verbose: bool
+}
+
+impl Controller {
+    pub fn uptime_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Queues a telemetry event for the console if it has asked for them, dropping it if the console can't keep up.
+    pub fn report(&self, telemetry: Telemetry) {
+        if self.streaming.load(Ordering::Relaxed) {
+            let _ = self.telemetry.try_send(Event { uptime_ms: self.uptime_ms(), telemetry });
+        }
+    }
+
+    /// Writes a line to the controller's log port.
+    pub fn log(&self, args: fmt::Arguments) {
+        if self.verbose {
+            println!("[{}] {args}", self.serial);
+        }
+        let _ = self.logs.try_send(args.to_string());
+    }
+}
+
+/// The pseudo terminals standing in for a controller's USB serial ports.
+pub struct Ports {
+    /// The controller's short address.
+    pub address: u16,
+    /// The port carrying the console protocol.
+    pub port: String,
+    /// The port carrying the controller's logs.
+    pub logger_port: String
+}
+
+fn pty() -> io::Result<(SerialStream, String)> {
+    // The other end is dropped straight away, it only exists for the console to open by name
+    let (master, slave) = SerialStream::pair()?;
+    let name = slave.name().ok_or_else(|| io::Error::other("the pseudo terminal has no name"))?;
+
+    Ok((master, name))
+}
+
+/// Starts every task of a simulated controller.
+pub fn spawn(id: u16, serial: String, medium: Arc<Medium>, antennas: Antennas, seed: u64, verbose: bool) -> io::Result<Ports> {
+    let (serial_stream, port) = pty()?;
+    let (logger_stream, logger_port) = pty()?;
+
+    let (telemetry_tx, telemetry_rx) = mpsc::channel(16);
+    let (logs_tx, logs_rx) = mpsc::channel(64);
+
+    // Every controller gets its own generator so that adding one doesn't change what the others do
+    let mut rng = Rng::new(seed ^ id as u64);
+    let start_delay = rng.next_u64();
+    // Stands in for the device ID that a real controller reads out of its chip
+    let device_id = rng.next_u64();
+
+    let address = identity::short_address(device_id);
+
+    let controller = Arc::new(Controller {
+        id,
+        serial,
+        address,
+        started: Instant::now(),
+        config: Mutex::new(Config::DEFAULT),
+        streaming: AtomicBool::new(false),
+        telemetry: telemetry_tx,
+        logs: logs_tx,
+        verbose
+    });
+
+    let (outbox_tx, outbox_rx) = mpsc::channel(1);
+    let (distances_tx, distances_rx) = mpsc::channel(20);
+
+    tokio::spawn(uwb::task(controller.clone(), uwb::Radio::new(id, medium.clone(), antennas.uwb), distances_tx, start_delay));
+    tokio::spawn(coord::task(controller.clone(), outbox_tx, distances_rx, rng));
+    tokio::spawn(ble::task(controller.clone(), medium, outbox_rx, antennas.ble));
+    tokio::spawn(usb::task(controller, serial_stream, logger_stream, telemetry_rx, logs_rx));
+
+    Ok(Ports { address, port, logger_port })
+}
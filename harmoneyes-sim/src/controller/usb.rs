@@ -0,0 +1,140 @@
+//! The simulated USB serial ports, which speak the same protocol over pseudo terminals.
+
+use std::{sync::{atomic::Ordering, Arc}, time::Duration};
+
+use harmoneyes_core::{codec::FixedStr, framing::{self, Accumulator}, protocol::{DeviceInfo, DeviceKind, Failure, Request, Response, MAX_FRAME_LENGTH, MAX_MESSAGE_LENGTH}, telemetry::{Event, Telemetry}};
+use tokio::{io::{self, AsyncReadExt, AsyncWriteExt}, join, select, sync::mpsc, time::{interval, sleep, timeout}};
+use tokio_serial::SerialStream;
+
+use super::Controller;
+
+pub async fn task(controller: Arc<Controller>, serial: SerialStream, logger: SerialStream, telemetry: mpsc::Receiver<Event>, logs: mpsc::Receiver<String>) {
+    join!(handle_serial(&controller, serial, telemetry), handle_logger(logger, logs));
+}
+
+/// Reading a pseudo terminal fails while nothing has the other end open, which stands in for the USB host
+/// disconnecting.
+async fn handle_serial(controller: &Controller, mut serial: SerialStream, mut telemetry: mpsc::Receiver<Event>) {
+    let mut accumulator: Box<Accumulator<MAX_FRAME_LENGTH>> = Box::new(Accumulator::new());
+    let mut buf = [0; 64];
+    let mut connected = false;
+
+    loop {
+        select! {
+            read = serial.read(&mut buf) => match read {
+                Ok(n) if n > 0 => {
+                    if !connected {
+                        connected = true;
+                        controller.log(format_args!("Established USB serial connection"));
+                    }
+
+                    for byte in &buf[..n] {
+                        let response = match accumulator.push(*byte) {
+                            None => continue,
+                            Some(Ok(message)) => match Request::decode(message) {
+                                Ok(request) => handle_request(controller, request),
+                                Err(_) => Response::Failed(Failure::Malformed),
+                            },
+                            Some(Err(_)) => Response::Failed(Failure::Malformed),
+                        };
+                        let _ = write_response(&mut serial, &response).await;
+                    }
+                },
+                _ => {
+                    if connected {
+                        connected = false;
+                        controller.streaming.store(false, Ordering::Relaxed);
+                        while telemetry.try_recv().is_ok() {}
+                        controller.log(format_args!("Disconnected from USB serial"));
+                    }
+                    sleep(Duration::from_millis(100)).await;
+                }
+            },
+            Some(event) = telemetry.recv() => {
+                let _ = write_response(&mut serial, &Response::Telemetry(event)).await;
+            }
+        }
+    }
+}
+
+async fn write_response(serial: &mut SerialStream, response: &Response) -> io::Result<()> {
+    let mut message = [0; MAX_MESSAGE_LENGTH];
+    let mut frame = [0; MAX_FRAME_LENGTH];
+
+    // Both buffers are sized for the largest message, so neither of these can fail
+    let len = response.encode(&mut message).expect("Response did not fit in the message buffer");
+    let len = framing::encode(&message[..len], &mut frame).expect("Response did not fit in the frame buffer");
+
+    serial.write_all(&frame[..len]).await
+}
+
+fn handle_request(controller: &Controller, request: Request) -> Response {
+    match request {
+        Request::Info => {
+            let config = *controller.config.lock().unwrap();
+            Response::Info(DeviceInfo {
+                kind: DeviceKind::Controller,
+                serial: FixedStr::truncated(&controller.serial),
+                name: config.performer_name,
+                firmware_version: FixedStr::truncated(env!("CARGO_PKG_VERSION")),
+                uptime_ms: controller.uptime_ms(),
+                performer_id: config.performer_id,
+                section: config.section
+            })
+        },
+        Request::ConfigGet { key } => Response::Config { key, value: controller.config.lock().unwrap().get(key) },
+        Request::ConfigSet { key, value } => match controller.config.lock().unwrap().set(key, value) {
+            Ok(()) => Response::Done,
+            Err(_) => Response::Failed(Failure::InvalidValue),
+        },
+        Request::ConfigReset => {
+            *controller.config.lock().unwrap() = Default::default();
+            Response::Done
+        },
+        // Every simulated controller has a cuff that always answers
+        Request::HapticTest { wrist, motor, duration_ms } => {
+            controller.log(format_args!("Running the {} motor on the {} cuff for {duration_ms} ms", motor.name(), wrist.name()));
+            controller.report(Telemetry::Haptic { wrist, motor, duration_ms });
+            Response::Done
+        },
+        Request::Stream { enabled } => {
+            controller.streaming.store(enabled, Ordering::Relaxed);
+            Response::Done
+        },
+        // There's no flash or bootloader to update
+        Request::UpdateBegin { .. } | Request::UpdateWrite { .. } | Request::UpdateFinish { .. } | Request::RebootToBootloader => {
+            Response::Failed(Failure::Unsupported)
+        },
+        // Simulated controllers draw no power, so they're always active
+        Request::SetPowerMode { .. } => Response::Failed(Failure::Unsupported),
+        // Simulated controllers don't crash, or if they do the simulator goes with them
+        Request::CrashGet { .. } => Response::Crash(None),
+        Request::CrashClear => Response::Done,
+    }
+}
+
+/// Writes the logs out while something has the log port open, and drops them otherwise so that they don't pile up
+/// in the pseudo terminal.
+async fn handle_logger(mut logger: SerialStream, mut logs: mpsc::Receiver<String>) {
+    let mut check = interval(Duration::from_millis(250));
+    let mut open = false;
+
+    loop {
+        select! {
+            _ = check.tick() => {
+                let mut buf = [0; 64];
+                open = match timeout(Duration::from_millis(1), logger.read(&mut buf)).await {
+                    // Still waiting for something to be typed
+                    Err(_) => true,
+                    Ok(Ok(n)) => n > 0,
+                    Ok(Err(_)) => false,
+                };
+            },
+            Some(line) = logs.recv() => {
+                if open {
+                    let _ = logger.write_all(format!("{line}\r\n").as_bytes()).await;
+                }
+            }
+        }
+    }
+}
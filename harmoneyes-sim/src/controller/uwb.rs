@@ -0,0 +1,103 @@
+//! The firmware's ranging state machine running against a simulated DW3000.
+
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use harmoneyes_core::{ranging::TICK_SECONDS, telemetry::Telemetry, uwb::{Outcome, Ranging, Received, UwbRadio}};
+use tokio::{sync::mpsc, time::{sleep, timeout}};
+
+use super::Controller;
+use crate::medium::{Frame, Medium};
+
+/// A DW3000 connected to the virtual medium.
+pub struct Radio {
+    id: u16,
+    medium: Arc<Medium>,
+    frames: mpsc::Receiver<Frame>,
+    /// When the last frame arrived, if nothing has been sent since.
+    last_rx: Option<u64>
+}
+
+impl Radio {
+    pub fn new(id: u16, medium: Arc<Medium>, frames: mpsc::Receiver<Frame>) -> Self {
+        Self { id, medium, frames, last_rx: None }
+    }
+}
+
+impl UwbRadio for Radio {
+    type Error = Infallible;
+
+    /// Frames sent in answer to another are timestamped exactly `delay` after it arrived, like a delayed send on a
+    /// real DW3000. The host's clock jitters by far more than a time of flight, so taking the timestamp from it would
+    /// bury every measurement.
+    async fn transmit(&mut self, payload: &[u8], delay: Duration) -> Result<u64, Self::Error> {
+        sleep(delay).await;
+
+        let timestamp = match self.last_rx.take() {
+            Some(rx) => rx + (delay.as_secs_f64() / TICK_SECONDS) as u64,
+            None => self.medium.ticks(),
+        };
+
+        self.medium.transmit(self.id, payload, timestamp);
+
+        Ok(timestamp)
+    }
+
+    async fn receive(&mut self, buf: &mut [u8], time_out: Duration) -> Result<Option<Received>, Self::Error> {
+        self.last_rx = None;
+
+        // Anything that arrived while the receiver was supposedly off would never have been heard
+        while self.frames.try_recv().is_ok() {}
+
+        self.medium.set_listening(self.id, true);
+        let frame = timeout(time_out, self.frames.recv()).await.ok().flatten();
+        self.medium.set_listening(self.id, false);
+
+        Ok(frame.map(|frame| {
+            let len = frame.payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&frame.payload[..len]);
+
+            self.last_rx = Some(frame.rx_timestamp);
+            Received { len, timestamp: frame.rx_timestamp }
+        }))
+    }
+
+    async fn reset(&mut self) -> Result<(), Self::Error> {
+        self.last_rx = None;
+        Ok(())
+    }
+}
+
+pub async fn task(controller: Arc<Controller>, mut radio: Radio, distances: mpsc::Sender<(u16, u64)>, start_delay: u64) {
+    // Real controllers never power on at exactly the same time
+    sleep(Duration::from_millis(start_delay % 50)).await;
+
+    let mut ranging = Ranging::new(controller.address);
+    let mut counter = 0;
+    // Whether another controller has been heard with our address, which is only logged the first time
+    let mut clashed = false;
+
+    loop {
+        let Ok(outcome) = ranging.step(&mut radio).await;
+
+        if outcome == Outcome::Clashed {
+            if !clashed {
+                controller.log(format_args!("Another controller is ranging with our address {:04x}", controller.address));
+                clashed = true;
+            }
+            controller.report(Telemetry::AddressClash { address: controller.address });
+        }
+
+        if let Outcome::Exchanged { peer, tof } = outcome {
+            if let Some(tof) = tof {
+                let _ = distances.send((peer, tof)).await;
+            }
+
+            if counter < 100 {
+                counter += 1;
+            } else {
+                controller.log(format_args!("100 packets exchanged"));
+                counter = 0;
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{path::PathBuf, process::ExitCode, sync::Arc};
+
+use clap::Parser;
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+use medium::Medium;
+use scenario::Scenario;
+
+mod controller;
+mod medium;
+mod rng;
+mod scenario;
+
+/// Simulates a band of Harmoneyes controllers on a virtual radio medium.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Load the positions and radio models from a JSON scenario file
+    #[arg(short, long)]
+    scenario: Option<PathBuf>,
+    /// Stand this many controllers in a line instead of using the scenario's positions
+    #[arg(short, long)]
+    devices: Option<usize>,
+    /// The distance between the controllers in the line
+    #[arg(long, default_value_t = 2.0)]
+    spacing: f64,
+    #[arg(long)]
+    seed: Option<u64>,
+    /// The probability that a bluetooth advertisement is lost
+    #[arg(long)]
+    ble_loss: Option<f64>,
+    /// The minimum bluetooth latency
+    #[arg(long)]
+    ble_latency_ms: Option<u64>,
+    /// The standard deviation of the ultra-wide band distance noise
+    #[arg(long)]
+    uwb_noise_m: Option<f64>,
+    /// The probability that an ultra-wide band frame takes an indirect path
+    #[arg(long)]
+    nlos_probability: Option<f64>,
+    /// The average extra distance of an indirect path
+    #[arg(long)]
+    nlos_bias_m: Option<f64>,
+    /// Print every controller's logs
+    #[arg(short, long)]
+    verbose: bool
+}
+
+impl Cli {
+    fn scenario(&self) -> std::io::Result<Scenario> {
+        let mut scenario = match &self.scenario {
+            Some(path) => Scenario::load(path)?,
+            None => Scenario::default(),
+        };
+
+        if let Some(count) = self.devices {
+            scenario.devices = Scenario::line(count, self.spacing);
+        } else if scenario.devices.is_empty() {
+            scenario.devices = Scenario::line(2, self.spacing);
+        }
+
+        if let Some(seed) = self.seed { scenario.seed = seed; }
+        if let Some(loss) = self.ble_loss { scenario.ble.loss = loss; }
+        if let Some(latency) = self.ble_latency_ms { scenario.ble.latency_ms = latency; }
+        if let Some(noise) = self.uwb_noise_m { scenario.uwb.noise_m = noise; }
+        if let Some(probability) = self.nlos_probability { scenario.uwb.nlos_probability = probability; }
+        if let Some(bias) = self.nlos_bias_m { scenario.uwb.nlos_bias_m = bias; }
+
+        Ok(scenario)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let scenario = match cli.scenario() {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            eprintln!("error: failed to load the scenario: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (medium, antennas) = Medium::new(scenario.ble, scenario.uwb, scenario.seed, &scenario.devices);
+    let medium = Arc::new(medium);
+
+    let mut serials = Vec::new();
+
+    println!("{:<10} {:<7} {:>8} {:>8}  {:<14} logs", "serial", "address", "x", "y", "port");
+
+    for (id, (device, antennas)) in scenario.devices.iter().zip(antennas).enumerate() {
+        let serial = device.serial.clone().unwrap_or_else(|| format!("SIM{:03}", id + 1));
+
+        match controller::spawn(id as u16, serial.clone(), medium.clone(), antennas, scenario.seed, cli.verbose) {
+            Ok(ports) => println!("{serial:<10} {:<7} {:>8.2} {:>8.2}  {:<14} {}", format!("{:04x}", ports.address), device.x, device.y, ports.port, ports.logger_port),
+            Err(e) => {
+                eprintln!("error: failed to create the serial ports for {serial}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+
+        serials.push(serial);
+    }
+
+    println!("Type `move <serial> <x> <y>` to move a controller, or press Ctrl-C to stop");
+
+    let mut lines = BufReader::new(stdin()).lines();
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else {
+                    // Keep running without a terminal attached
+                    let _ = tokio::signal::ctrl_c().await;
+                    break;
+                };
+
+                match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["move", serial, x, y] => match (serials.iter().position(|s| s == serial), x.parse(), y.parse()) {
+                        (Some(id), Ok(x), Ok(y)) => medium.move_to(id as u16, x, y),
+                        (None, _, _) => eprintln!("There is no controller {serial}"),
+                        _ => eprintln!("The position must be two numbers"),
+                    },
+                    [] => {},
+                    _ => eprintln!("Unknown command, expected `move <serial> <x> <y>`"),
+                }
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
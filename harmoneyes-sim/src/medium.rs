@@ -0,0 +1,144 @@
+//! The virtual radio medium that the simulated controllers send their bluetooth advertisements and ultra-wide band
+//! frames through.
+
+use std::{sync::{atomic::{AtomicBool, Ordering}, Mutex}, time::Duration};
+
+use harmoneyes_core::ranging::{self, TICK_SECONDS};
+use tokio::{sync::mpsc, time::{sleep, Instant}};
+
+use crate::{rng::Rng, scenario::{BleModel, DeviceSpec, UwbModel}};
+
+/// An ultra-wide band frame as it arrives at a receiver.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub payload: Vec<u8>,
+    /// When the frame arrived in DW3000 ticks, like the receive timestamp of a real DW3000.
+    pub rx_timestamp: u64
+}
+
+/// The receiving ends of a controller's radios.
+pub struct Antennas {
+    /// Raw advertising data, the same as what the softdevice hands to a scan callback.
+    pub ble: mpsc::Receiver<Vec<u8>>,
+    pub uwb: mpsc::Receiver<Frame>
+}
+
+struct Station {
+    position: Mutex<(f64, f64)>,
+    /// Whether the ultra-wide band receiver is turned on.
+    listening: AtomicBool,
+    ble: mpsc::Sender<Vec<u8>>,
+    uwb: mpsc::Sender<Frame>
+}
+
+pub struct Medium {
+    start: Instant,
+    ble: BleModel,
+    uwb: UwbModel,
+    rng: Mutex<Rng>,
+    stations: Vec<Station>
+}
+
+impl Medium {
+    pub fn new(ble: BleModel, uwb: UwbModel, seed: u64, devices: &[DeviceSpec]) -> (Self, Vec<Antennas>) {
+        let mut stations = Vec::new();
+        let mut antennas = Vec::new();
+
+        for device in devices {
+            let (ble_tx, ble_rx) = mpsc::channel(32);
+            let (uwb_tx, uwb_rx) = mpsc::channel(8);
+
+            stations.push(Station {
+                position: Mutex::new((device.x, device.y)),
+                listening: AtomicBool::new(false),
+                ble: ble_tx,
+                uwb: uwb_tx
+            });
+            antennas.push(Antennas { ble: ble_rx, uwb: uwb_rx });
+        }
+
+        let medium = Self {
+            start: Instant::now(),
+            ble,
+            uwb,
+            rng: Mutex::new(Rng::new(seed)),
+            stations
+        };
+
+        (medium, antennas)
+    }
+
+    /// The current time in DW3000 ticks, shared by every simulated radio.
+    pub fn ticks(&self) -> u64 {
+        (self.start.elapsed().as_secs_f64() / TICK_SECONDS) as u64
+    }
+
+    pub fn position(&self, id: u16) -> (f64, f64) {
+        *self.stations[id as usize].position.lock().unwrap()
+    }
+
+    pub fn move_to(&self, id: u16, x: f64, y: f64) {
+        *self.stations[id as usize].position.lock().unwrap() = (x, y);
+    }
+
+    pub fn distance(&self, a: u16, b: u16) -> f64 {
+        let (ax, ay) = self.position(a);
+        let (bx, by) = self.position(b);
+        (ax - bx).hypot(ay - by)
+    }
+
+    /// Broadcasts advertising data to every controller in range, each of which may miss it.
+    pub fn advertise(&self, from: u16, data: &[u8]) {
+        let mut rng = self.rng.lock().unwrap();
+
+        for (id, station) in self.stations.iter().enumerate() {
+            let id = id as u16;
+            if id == from || self.distance(from, id) > self.ble.range_m || rng.chance(self.ble.loss) {
+                continue;
+            }
+
+            let delay = self.ble.latency_ms + (rng.uniform() * self.ble.jitter_ms as f64) as u64;
+            let tx = station.ble.clone();
+            let data = data.to_vec();
+
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(delay)).await;
+                // A scanner that is too busy to keep up just misses advertisements
+                let _ = tx.try_send(data);
+            });
+        }
+    }
+
+    pub fn set_listening(&self, id: u16, listening: bool) {
+        self.stations[id as usize].listening.store(listening, Ordering::Relaxed);
+    }
+
+    /// Sends an ultra-wide band frame that left the antenna at `timestamp` to every controller that is listening for
+    /// one. Each receiver timestamps it according to how far the frame travelled, with noise and the occasional
+    /// indirect path added on top.
+    pub fn transmit(&self, from: u16, payload: &[u8], timestamp: u64) {
+        let mut rng = self.rng.lock().unwrap();
+
+        for (id, station) in self.stations.iter().enumerate() {
+            let id = id as u16;
+            if id == from || !station.listening.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let distance = self.distance(from, id);
+            if distance > self.uwb.range_m || rng.chance(self.uwb.loss) {
+                continue;
+            }
+
+            let mut travelled = distance + rng.gaussian() * self.uwb.noise_m;
+            if rng.chance(self.uwb.nlos_probability) {
+                travelled += rng.exponential(self.uwb.nlos_bias_m);
+            }
+
+            let _ = station.uwb.try_send(Frame {
+                payload: payload.to_vec(),
+                rx_timestamp: timestamp + ranging::meters_to_tof(travelled.max(0.0))
+            });
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! The description of what to simulate: where every controller stands and how the radios behave.
+
+use std::{fs, io, path::Path};
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    pub seed: u64,
+    pub ble: BleModel,
+    pub uwb: UwbModel,
+    pub devices: Vec<DeviceSpec>
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Places `count` controllers in a line along the x axis.
+    pub fn line(count: usize, spacing: f64) -> Vec<DeviceSpec> {
+        (0..count).map(|i| DeviceSpec { serial: None, x: i as f64 * spacing, y: 0.0 }).collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BleModel {
+    /// The probability that an advertisement is not received.
+    pub loss: f64,
+    /// The minimum time between an advertisement being sent and received.
+    pub latency_ms: u64,
+    /// The most extra time, picked uniformly, on top of `latency_ms`.
+    pub jitter_ms: u64,
+    pub range_m: f64
+}
+
+impl Default for BleModel {
+    fn default() -> Self {
+        Self { loss: 0.05, latency_ms: 5, jitter_ms: 10, range_m: 100.0 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct UwbModel {
+    /// The probability that a frame is not received.
+    pub loss: f64,
+    /// The standard deviation of the error added to every measured distance.
+    pub noise_m: f64,
+    /// The probability that a frame takes an indirect path.
+    pub nlos_probability: f64,
+    /// The average extra distance travelled by a frame that takes an indirect path.
+    pub nlos_bias_m: f64,
+    pub range_m: f64
+}
+
+impl Default for UwbModel {
+    fn default() -> Self {
+        Self { loss: 0.01, noise_m: 0.05, nlos_probability: 0.0, nlos_bias_m: 1.0, range_m: 50.0 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceSpec {
+    /// Defaults to a numbered `SIM` serial number.
+    #[serde(default)]
+    pub serial: Option<String>,
+    pub x: f64,
+    pub y: f64
+}